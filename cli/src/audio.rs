@@ -6,44 +6,48 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use rb::{SpscRb, RB, RbProducer, RbConsumer, Producer, Consumer};
 use cpal::traits::HostTrait;
 use cpal::traits::EventLoopTrait;
-use cpal::traits::DeviceTrait;
+use hex_music_container::output;
 
 pub struct AudioDevice {
-    rb: SpscRb<i16>,
-    producer: Producer<i16>,
+    rb: SpscRb<f32>,
+    producer: Producer<f32>,
+    format: cpal::Format,
     is_running: Arc<AtomicUsize>
 }
 
 impl AudioDevice {
-    pub fn new() -> AudioDevice {
-        let rb = SpscRb::new(48000 * 3);
-        let (prod, cons) = (rb.producer(), rb.consumer());
-
+    pub fn new(channels: u16) -> AudioDevice {
         let host = cpal::default_host();
         let device = host.default_output_device().expect("Failed to get default output device");
+        let format = output::find_format(&device, channels);
 
-        if device.supported_output_formats().unwrap().filter(|x| x.channels == 2 && x.data_type == cpal::SampleFormat::I16).count() == 0 {
-            panic!("No suitable device found!");
-        }
-
-        let format = cpal::Format {
-            channels: 2,
-            sample_rate: cpal::SampleRate(48000),
-            data_type: cpal::SampleFormat::I16
-        };
+        // buffer a second of audio
+        let rb = SpscRb::new(format.sample_rate.0 as usize * format.channels as usize);
+        let (prod, cons) = (rb.producer(), rb.consumer());
 
         let is_running = Arc::new(AtomicUsize::new(2));
         let tmp = is_running.clone();
-        thread::spawn(move || Self::run(cons, host, device, format, tmp));
+        thread::spawn(move || Self::run(cons, host, device, format.clone(), tmp));
 
         AudioDevice {
             rb: rb,
             producer: prod,
+            format: format,
             is_running
         }
     }
 
-    pub fn buffer(&mut self, buf: &[i16], written: usize) -> usize {
+    /// Number of channels of the output device
+    pub fn channels(&self) -> usize {
+        self.format.channels as usize
+    }
+
+    /// Sample rate of the output device
+    pub fn sample_rate(&self) -> u32 {
+        self.format.sample_rate.0
+    }
+
+    pub fn buffer(&mut self, buf: &[f32], written: usize) -> usize {
         self.producer.write(&buf[written..]).unwrap_or(0)
     }
 
@@ -63,38 +67,56 @@ impl AudioDevice {
         self.is_running.store(2, Ordering::Relaxed);
     }
 
-    pub fn run(consumer: Consumer<i16>, host: cpal::Host, device: cpal::Device, format: cpal::Format, is_running: Arc<AtomicUsize>) {
+    pub fn run(consumer: Consumer<f32>, host: cpal::Host, device: cpal::Device, format: cpal::Format, is_running: Arc<AtomicUsize>) {
         let event_loop = host.event_loop();
 
         let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
         event_loop.play_stream(stream_id.clone()).unwrap();
 
-        let mut buf = vec![0i16; format.channels as usize];
+        let mut buf = vec![0.0f32; format.channels as usize];
 
         event_loop.run(move |_, data| {
             if is_running.load(Ordering::Relaxed) == 0 {
                 panic!("ignore");
             }
 
+            // output silence while paused
+            let paused = is_running.load(Ordering::Relaxed) == 1;
+            let mut next_frame = |buf: &mut [f32]| {
+                if paused {
+                    for val in buf.iter_mut() {
+                        *val = 0.0;
+                    }
+                } else {
+                    let _ = consumer.read_blocking(buf);
+                }
+            };
+
             match data {
-                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) }) => {
-                    if is_running.load(Ordering::Relaxed) == 1 {
-                        for sample in buffer.chunks_mut(format.channels as usize) {
-                            for out in sample.iter_mut() {
-                                *out = 0;
-                            }
-                        }
-                    } else {
-                        for sample in buffer.chunks_mut(format.channels as usize) {
-                            let _ = consumer.read_blocking(&mut buf);
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) }) => {
+                    for sample in buffer.chunks_mut(format.channels as usize) {
+                        next_frame(&mut buf);
 
-                            let mut i = 0;
-                            for out in sample.iter_mut() {
-                                *out = buf[i];
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = *val;
+                        }
+                    }
+                },
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) }) => {
+                    for sample in buffer.chunks_mut(format.channels as usize) {
+                        next_frame(&mut buf);
 
-                                i += 1;
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = (*val * 32767.0) as i16;
+                        }
+                    }
+                },
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer) }) => {
+                    for sample in buffer.chunks_mut(format.channels as usize) {
+                        next_frame(&mut buf);
 
-                            }
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = ((*val + 1.0) * 32767.5) as u16;
                         }
                     }
                 },
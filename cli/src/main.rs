@@ -5,6 +5,8 @@ mod play;
 mod modify;
 mod sync;
 mod store;
+mod user;
+mod party;
 
 use std::thread;
 use std::io::{self, Write, BufRead};
@@ -15,6 +17,7 @@ use futures::future::IntoFuture;
 use futures::future::Future;
 
 use hex_database::{Instance, Reader, Writer, search::SearchQuery, Track, GossipConf, Playlist};
+use hex_music_container::Configuration;
 
 fn main() {
     env_logger::init();
@@ -39,6 +42,12 @@ fn main() {
         gossip = gossip.contacts(peer.contacts.clone());
     }
 
+    let layout = Configuration::from_name(&conf.playback.layout, &conf.playback.speakers)
+        .unwrap_or_else(|| {
+            eprintln!("Error: Unknown loudspeaker layout {}, using stereo", conf.playback.layout);
+            Configuration::Stereo
+        });
+
     let instance = Instance::from_file(&db_path, gossip);
     let (read, write, files) = (instance.reader(),instance.writer(),instance.files());
     let mut prev_lines = Vec::new();
@@ -100,7 +109,7 @@ fn main() {
                 sync::sync_tracks(&files, &data_path, tracks);
             },
             "play" => {
-                play::play_tracks(&files, &data_path, tracks);
+                play::play_tracks(&files, &data_path, tracks, &layout, &conf.playback.normalization);
             },
             "modify" => {
                 modify::modify_tracks(&write, tracks);
@@ -119,12 +128,24 @@ fn main() {
             "store" => {
                 store::store(&write, Path::new(args[1]), &data_path);
             },
+            "rebuild" => {
+                store::rebuild(&read, &write, &data_path);
+            },
+            "user" => {
+                user::manage(&db_path, args[1]);
+            },
+            "party" => {
+                let scheme = if conf.tls.is_some() { "wss" } else { "ws" };
+                let url = format!("{}://{}:{}", scheme, conf.host, conf.server.port);
+
+                party::join(&url, args[1]);
+            },
             "quit" | "q" | "exit" | "bye" => {
                 println!("Bye, have a nice day!");
                 return;
             },
             _ => {
-                println!("Unsupported action, use with <search|delete|add-playlist|sync|play|modify|store|quit>");
+                println!("Unsupported action, use with <search|delete|add-playlist|sync|play|party|modify|store|rebuild|user|quit>");
             }
         }
     }
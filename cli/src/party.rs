@@ -0,0 +1,246 @@
+//! Play the audio of a party of a remote server
+//!
+//! The command joins a party as its output and follows the state pushed by the server. Members
+//! control the party from other devices, while the tracks are streamed from the server. A server
+//! with accounts needs a login, so the name is passed after the id and the password is prompted.
+
+use std::thread;
+use std::io::{self, Write, BufRead};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel, TryRecvError};
+
+use futures::{future, Future, Stream, stream::Wait};
+use tokio::runtime::Runtime;
+
+use hex_client::{Client, Audio, Error};
+use hex_database::TrackKey;
+use hex_music_container::resample::Resampler;
+use hex_server_protocol::objects::{PartyCommand, StreamFormat};
+
+use crate::audio::AudioDevice;
+use crate::user;
+
+/// Interval between two reports of the position
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of reports, which may be still on their way to the server
+const PENDING_REPORTS: usize = 3;
+
+/// Sample rate of the streamed audio
+const STREAM_RATE: u32 = 48000;
+
+type AudioStream = Wait<Box<dyn Stream<Item = Audio, Error = Error> + Send>>;
+
+/// Convert a chunk of interleaved stereo samples to the channels of the device
+///
+/// A mono device gets the average of both channels, further channels stay silent.
+fn convert(samples: &[f32], channels: usize) -> Vec<f32> {
+    let mut out = Vec::with_capacity(samples.len() / 2 * channels);
+
+    for frame in samples.chunks(2) {
+        if channels == 1 {
+            out.push((frame[0] + frame[1]) / 2.0);
+        } else {
+            out.extend_from_slice(frame);
+            out.extend((2..channels).map(|_| 0.0));
+        }
+    }
+
+    out
+}
+
+pub fn join(url: &str, args: &str) {
+    let mut args = args.split_whitespace();
+    let id = match args.next().and_then(|x| x.parse().ok()) {
+        Some(x) => x,
+        None => {
+            println!("Invalid party, use with party <id> [name]");
+            return;
+        }
+    };
+
+    let credentials = match args.next() {
+        Some(name) => match user::read_password(name) {
+            Some(password) => Some((name.to_string(), password)),
+            None => {
+                println!("Empty password, aborting");
+                return;
+            }
+        },
+        None => None
+    };
+
+    let mut runtime = Runtime::new().expect("Could not start runtime");
+    let joined = Client::connect(url, &["parties"])
+        .and_then(move |client| match credentials {
+            Some((name, password)) => future::Either::A(client.login(&name, &password).map(move |_| client)),
+            None => future::Either::B(future::ok(client))
+        })
+        .and_then(move |client| client.join_party(id, true).map(move |(party, changes)| (client, party, changes)));
+
+    let (client, mut party, changes) = match runtime.block_on(joined) {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Could not join party {} at {}: {}", id, url, err);
+            return;
+        }
+    };
+
+    println!("Joined party {} with {} members, press enter to leave", party.name, party.members);
+
+    // forward pushed changes and the end of the input to the player
+    let (sender, receiver) = channel();
+    let sender2 = sender.clone();
+    runtime.spawn(changes.for_each(move |x| sender.send(Some(x)).map_err(|_| ())));
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let _ = stdin.lock().lines().next();
+        let _ = sender2.send(None);
+    });
+
+    // the server streams stereo with 48kHz, which is converted for the device
+    let mut device = AudioDevice::new(2);
+    let (channels, sample_rate) = (device.channels(), device.sample_rate());
+    let mut resampler = None;
+
+    let mut current: Option<(TrackKey, AudioStream)> = None;
+    let mut pos = 0.0;
+    let mut last_report = Instant::now();
+    let (mut paused, mut left) = (false, false);
+
+    // positions known to the server, a pushed position not among them comes from a seek
+    let mut reports = VecDeque::new();
+
+    'outer: loop {
+        // take the newest state of the party
+        loop {
+            match receiver.try_recv() {
+                Ok(Some(x)) => party = x,
+                Ok(None) => {
+                    left = true;
+                    break 'outer;
+                },
+                Err(TryRecvError::Disconnected) => break 'outer,
+                Err(TryRecvError::Empty) => break
+            }
+        }
+
+        let key = match party.current {
+            Some(key) => key,
+            None => {
+                if current.take().is_some() {
+                    device.clear();
+                }
+
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        // open a new stream after a switch of the track or a seek
+        let switched = current.as_ref().map(|x| x.0) != Some(key);
+        if switched || !reports.contains(&party.position) {
+            device.clear();
+
+            if switched {
+                match runtime.block_on(client.get_track(key)) {
+                    Ok(track) => println!("{} ({}) by {}", track.title.unwrap_or("Unknown".into()), track.album.unwrap_or("Unknown".into()), track.composer.unwrap_or("Unknown".into())),
+                    Err(_) => println!("Playing {}", key.to_string())
+                }
+            }
+
+            pos = party.position;
+            reports.clear();
+            reports.push_back(pos);
+            resampler = if sample_rate == STREAM_RATE { None } else { Some(Resampler::new(STREAM_RATE, sample_rate, 2)) };
+
+            let stream: Box<dyn Stream<Item = Audio, Error = Error> + Send> = Box::new(
+                client.stream_at(key, StreamFormat::Pcm, (pos * STREAM_RATE as f64) as u32));
+            current = Some((key, stream.wait()));
+        }
+
+        if paused == party.playing {
+            paused = !party.playing;
+
+            if paused {
+                device.pause();
+            } else {
+                device.cont();
+            }
+        }
+
+        if paused {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        let samples = match current.as_mut().and_then(|x| x.1.next()) {
+            Some(Ok(Audio::Pcm(buf))) => buf.chunks(2)
+                .map(|x| i16::from_le_bytes([x[0], x[1]]) as f32 / 32768.0)
+                .collect::<Vec<f32>>(),
+            Some(Ok(Audio::Opus(_))) => Vec::new(),
+            end => {
+                match end {
+                    Some(Err(err)) => println!("Could not stream {}: {}", key.to_string(), err),
+                    _ => println!(" Finished!\n")
+                }
+
+                // continue with the next track for all members
+                match runtime.block_on(client.party_command(PartyCommand::Ended(key))) {
+                    Ok(x) => party = x,
+                    Err(err) => {
+                        println!("Could not continue party: {}", err);
+                        break 'outer;
+                    }
+                }
+
+                current = None;
+                continue;
+            }
+        };
+
+        pos += samples.len() as f64 / 2.0 / STREAM_RATE as f64;
+
+        let samples = match resampler.as_mut() {
+            Some(resampler) => resampler.process(&samples),
+            None => samples
+        };
+        let buf = convert(&samples, channels);
+
+        let mut written = 0;
+        while written < buf.len() {
+            match device.buffer(&buf, written) {
+                0 => thread::sleep(Duration::from_millis(50)),
+                x => written += x
+            }
+        }
+
+        if last_report.elapsed() > REPORT_INTERVAL {
+            print!("\rPlaying {:.0}s", pos);
+            io::stdout().flush().unwrap();
+
+            runtime.spawn(client.party_command(PartyCommand::Position(pos))
+                .map(|_| ())
+                .map_err(|err| warn!("Could not report position: {}", err)));
+
+            if reports.len() == PENDING_REPORTS {
+                reports.pop_front();
+            }
+            reports.push_back(pos);
+            party.position = pos;
+            last_report = Instant::now();
+        }
+    }
+
+    device.shutdown();
+
+    let _ = runtime.block_on(client.leave_party());
+    client.close();
+
+    println!("Left party {}", party.name);
+
+    if !left {
+        println!("Please press enter");
+    }
+}
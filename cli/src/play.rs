@@ -5,6 +5,7 @@ use std::io::{self, Write, Read};
 use std::fs::File;
 use std::time::Duration;
 use std::sync::mpsc::{Receiver, channel};
+use std::collections::{HashMap, HashSet};
 use crate::audio::AudioDevice;
 use terminal_size::{Width, terminal_size};
 use std::sync::Arc;
@@ -14,7 +15,7 @@ use tokio;
 use nix::sys::termios;
 
 use hex_database::{Track, Files};
-use hex_music_container::{Container, Configuration};
+use hex_music_container::{Container, Configuration, Loudness, Normalization};
 
 #[derive(Debug)]
 pub enum Event {
@@ -51,13 +52,55 @@ fn format_time(mut secs: f64) -> String {
     out
 }
 
-pub fn player(data_path: &Path, files: &Files, tracks: Vec<Track>, events: Receiver<Event>, working: Arc<AtomicBool>) {
-    let mut device = AudioDevice::new();
+/// Choose the volume normalisation of a track by its name in the configuration
+///
+/// The loudness of each album is cached in `albums`, so its files are only read once.
+fn normalization(name: &str, track: &Track, tracks: &[Track], data_path: &Path, albums: &mut HashMap<String, Option<Loudness>>) -> Normalization {
+    match (name, &track.album) {
+        ("off", _) => Normalization::Off,
+        ("album", Some(album)) => {
+            // combine all available tracks of the same album
+            let loudness = albums.entry(album.clone()).or_insert_with(|| {
+                let mut keys = HashSet::new();
+                let album: Vec<(Loudness, u32)> = tracks.iter()
+                    .filter(|x| x.album == track.album && keys.insert(x.key))
+                    .filter_map(|x| File::open(data_path.join(x.key.to_path())).ok())
+                    .filter_map(|x| Container::load(x).ok())
+                    .filter_map(|x| x.loudness().map(|l| (l, x.samples())))
+                    .collect();
+
+                Loudness::album(&album)
+            });
+
+            loudness.map(|x| Normalization::Album(x)).unwrap_or(Normalization::Track)
+        },
+        _ => Normalization::Track
+    }
+}
+
+pub fn player(data_path: &Path, files: &Files, tracks: Vec<Track>, events: Receiver<Event>, working: Arc<AtomicBool>, layout: &Configuration, gain: &str) {
+    let mut device = AudioDevice::new(layout.num_channels() as u16);
+
+    // fall back to a layout matching the output device
+    let layout = if device.channels() == layout.num_channels() {
+        layout.clone()
+    } else {
+        match Configuration::for_channels(device.channels()) {
+            Ok(x) => x,
+            Err(_) => {
+                println!("Output device with {} channels not supported", device.channels());
+                device.shutdown();
+                return;
+            }
+        }
+    };
+    let sample_rate = device.sample_rate();
     let width = match terminal_size() {
         Some((Width(w),_)) => w,
         _ => 64
     };
 
+    let mut albums = HashMap::new();
     let mut idx = 0;
     'outer: loop {
         if idx == tracks.len() {
@@ -75,13 +118,14 @@ pub fn player(data_path: &Path, files: &Files, tracks: Vec<Track>, events: Recei
 
         let file = File::open(data_path.join(tracks[idx].key.to_path())).unwrap();
         let mut container = Container::load(file).unwrap();
+        container.set_normalization(normalization(gain, &tracks[idx], &tracks, data_path, &mut albums));
 
         println!("{} ({}) by {}", tracks[idx].title.clone().unwrap_or("Unknown".into()), tracks[idx].album.clone().unwrap_or("Unknown".into()), tracks[idx].composer.clone().unwrap_or("Unknown".into()));
 
         let mut pos = 0.0;
         let mut pause = false;
-        'inner: while let Ok(buf) = container.next_packet(Configuration::Stereo) {
-            pos += buf.len() as f64 / 48000.0 / 2.0;
+        'inner: while let Ok(buf) = container.next_packet_f32(layout.clone(), sample_rate) {
+            pos += buf.len() as f64 / sample_rate as f64 / layout.num_channels() as f64;
 
             print!("\rPlaying [");
             for i in 0..(width - 30) as usize {
@@ -164,7 +208,7 @@ pub fn player(data_path: &Path, files: &Files, tracks: Vec<Track>, events: Recei
     device.shutdown();
 }
 
-pub fn play_tracks(files: &Files, data_path: &Path, tracks: Vec<Track>) {
+pub fn play_tracks(files: &Files, data_path: &Path, tracks: Vec<Track>, layout: &Configuration, gain: &str) {
 
     // setup terminal to pass arrows
     // Querying original as a separate, since `Termios` does not implement copy
@@ -228,7 +272,7 @@ pub fn play_tracks(files: &Files, data_path: &Path, tracks: Vec<Track>) {
         }
     });
 
-    player(data_path, &files, tracks, receiver, working);
+    player(data_path, &files, tracks, receiver, working, layout, gain);
 
     termios::tcsetattr(0, termios::SetArg::TCSADRAIN, &orig_term).unwrap();
 
@@ -1,13 +1,10 @@
-use std::slice;
-use std::io::Read;
-use std::fs::File;
-use std::process::Command;
+use std::io::{self, Write};
+use std::fs::{self, File};
 use std::path::Path;
 use walkdir::WalkDir;
-use id3::Tag;
 
-use hex_database::{Track, Writer};
-use hex_music_container::{Configuration, Container};
+use hex_database::{Track, TrackKey, Reader, Writer, utils::{Tags, AUDIO_EXTENSIONS}};
+use hex_music_container::{Container, Metadata, decode::decode_file, tags};
 
 pub fn store(write: &Writer, path: &Path, data_path: &Path) {
     let mut files = Vec::new();
@@ -15,7 +12,7 @@ pub fn store(write: &Writer, path: &Path, data_path: &Path) {
         if e.metadata().unwrap().is_file() {
             let path = e.path();
             let extension = path.extension().unwrap();
-            if extension == "aac" || extension == "mp3" || extension == "wav" || extension == "ogg" {
+            if AUDIO_EXTENSIONS.iter().any(|x| extension == *x) {
                 files.push(path.to_path_buf());
             }
         }
@@ -23,65 +20,126 @@ pub fn store(write: &Writer, path: &Path, data_path: &Path) {
 
     for file in files {
         println!("Converting file {:?}", file.to_str());
-        let tag = Tag::read_from_path(&file);
-
-        // convert to pcm file 
-        let mut cmd = Command::new("ffmpeg")
-            .arg("-y")
-            .arg("-hide_banner")
-            //.arg("-loglevel").arg("panic")
-            .arg("-i").arg(&file)
-            .arg("-ar").arg("48000")
-            .arg("-ac").arg("2")
-            .arg("-f").arg("s16le")
-            .arg("/tmp/hex-cli-audio")
-            .spawn()
-            .expect("Failed to spawn ffmpeg!");
-
-        cmd.wait().unwrap();
-
-        let mut audio_file = File::open("/tmp/hex-cli-audio").unwrap();
-        let mut data = Vec::new();
-
-        audio_file.read_to_end(&mut data).unwrap();
-
-        let data: &[i16] = unsafe {
-            slice::from_raw_parts(
-                data.as_ptr() as *const i16,
-                data.len() / 2
-            )
+        let tags = Tags::from_pairs(tags::read(&file));
+
+        // decode to raw audio with 48kHz, surround and AmbiX files keep their channels
+        let (conf, data) = match decode_file(&file, |progress| {
+            print!("\rDecoding {:.0}%", progress * 100.0);
+            let _ = io::stdout().flush();
+        }) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("\nCould not decode {:?}: {:?}", file, err);
+                continue;
+            }
         };
 
-        println!("Finished converting with {} samples", data.len());
+        println!("\nFinished converting with {} samples", data.len());
 
-        let fingerprint = hex_database::utils::get_fingerprint(2, &data).unwrap();
+        let fingerprint = hex_database::utils::get_fingerprint(conf.num_channels() as u16, &data).unwrap();
 
         let mut track = Track::empty(
             fingerprint,
-            data.len() as f64 / 48000.0 / 2.0
+            data.len() as f64 / 48000.0 / conf.num_channels() as f64
         );
 
-        if let Ok(tag) = tag {
-            if let Some(title) = tag.title().map(|x| x.to_string()) {
-                track.title = Some(title);
-            } else {
-                track.title = Some(file.file_stem().unwrap().to_str().unwrap().into());
-            }
-
-            track.album = tag.album().map(|x| x.to_string());
-            track.interpret = tag.artist().map(|x| x.to_string());
-            track.composer = tag.artist().map(|x| x.to_string());
-                                   
-        } else {
+        tags.apply(&mut track);
+        if track.title.is_none() {
             track.title = Some(file.file_stem().unwrap().to_str().unwrap().into());
         }
 
+        // a stored file of the same track may carry a cover, which is kept
+        let path = data_path.join(track.key.to_path());
+        let cover = File::open(&path).ok()
+            .and_then(|x| Container::load(x).ok())
+            .and_then(|mut x| x.metadata().ok())
+            .and_then(|x| x.and_then(|x| x.cover));
+
         // store with music container
-        let file = File::create(data_path.join(track.key.to_path())).unwrap();
-        Container::save_pcm(Configuration::Stereo, data.to_vec(), file, None).unwrap();
+        let file = File::create(&path).unwrap();
+        let metadata = Metadata {
+            title: track.title.clone(),
+            album: track.album.clone(),
+            interpret: track.interpret.clone(),
+            people: track.people.clone(),
+            composer: track.composer.clone(),
+            fingerprint: Some(track.fingerprint.clone()),
+            cover,
+            peer: Some(write.peer_id())
+        };
+
+        Container::save_pcm_with_metadata(conf, data, file, None, &metadata).unwrap();
 
         println!("Add track with key {}", track.key.to_string());
 
         write.add_track(track).unwrap();
     }
 }
+
+/// Recover missing tracks from the metadata stored in the audio files of the data directory
+pub fn rebuild(read: &Reader, write: &Writer, data_path: &Path) {
+    let entries = match fs::read_dir(data_path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("Could not read data directory: {:?}", err);
+            return;
+        }
+    };
+
+    let mut num_added = 0;
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        // audio files are named after their key
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(name) if name.len() == 32 && name.chars().all(|x| x.is_digit(16)) => name.to_string(),
+            _ => continue
+        };
+
+        let key = TrackKey::from_str(&name);
+        if read.get_track(key).is_ok() {
+            continue;
+        }
+
+        let mut container = match File::open(&path).ok().and_then(|x| Container::load(x).ok()) {
+            Some(container) => container,
+            None => {
+                println!("Could not open audio file {}", name);
+                continue;
+            }
+        };
+
+        let (metadata, fingerprint) = match container.metadata() {
+            Ok(Some(mut metadata)) => match metadata.fingerprint.take() {
+                Some(fingerprint) => (metadata, fingerprint),
+                None => {
+                    println!("No fingerprint found for {}", name);
+                    continue;
+                }
+            },
+            _ => {
+                println!("No metadata found for {}", name);
+                continue;
+            }
+        };
+
+        let mut track = Track::empty(fingerprint, container.samples() as f64 / 48000.0);
+        if track.key != key {
+            println!("Fingerprint of {} doesn't match its key", name);
+            continue;
+        }
+
+        track.title = metadata.title;
+        track.album = metadata.album;
+        track.interpret = metadata.interpret;
+        track.people = metadata.people;
+        track.composer = metadata.composer;
+
+        println!("Recover track {} ({})", name, track.title.clone().unwrap_or("Unknown".into()));
+
+        match write.add_track(track) {
+            Ok(_) => num_added += 1,
+            Err(err) => println!("Could not add track {}: {:?}", name, err)
+        }
+    }
+
+    println!("Recovered {} tracks", num_added);
+}
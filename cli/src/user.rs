@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use hex_database::{Accounts, Role};
+
+/// Ask for a password on the terminal, without echoing it
+pub fn read_password(name: &str) -> Option<String> {
+    let line = rpassword::prompt_password_stdout(&format!("Password for {}: ", name)).ok()?;
+
+    if line.is_empty() {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+/// Manage the user accounts of the servers
+///
+/// The first account enables authentication, so it should be an admin.
+pub fn manage(db_path: &Path, args: &str) {
+    let accounts = match Accounts::from_file(db_path) {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            eprintln!("Error: Could not open user accounts {:?}", err);
+            return;
+        }
+    };
+
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let role = |name: Option<&&str>| name.and_then(|x| Role::from_name(x));
+
+    let res = match (args.get(0).map(|x| *x), args.get(1)) {
+        (Some("list"), _) | (None, _) => {
+            for user in accounts.users() {
+                println!("\t{} ({:?})", user.name, user.role);
+            }
+
+            Ok(())
+        },
+        (Some("add"), Some(name)) => {
+            let role = match role(args.get(2)) {
+                Some(role) => role,
+                None if !accounts.is_enabled() => Role::Admin,
+                None => Role::Listener
+            };
+
+            match read_password(name) {
+                Some(password) => accounts.add_user(name, &password, role).map(|user| println!("Created user {} ({:?})", user.name, user.role)),
+                None => {
+                    println!("Empty password, user not created");
+                    Ok(())
+                }
+            }
+        },
+        (Some("delete"), Some(name)) => accounts.delete_user(name),
+        (Some("role"), Some(name)) => match role(args.get(2)) {
+            Some(role) => accounts.set_role(name, role),
+            None => {
+                println!("Unknown role, use with <listener|editor|admin>");
+                Ok(())
+            }
+        },
+        (Some("password"), Some(name)) => match read_password(name) {
+            Some(password) => accounts.set_password(name, &password),
+            None => Ok(())
+        },
+        _ => {
+            println!("Unsupported action, use with user <list|add|delete|role|password> <name> [listener|editor|admin]");
+            Ok(())
+        }
+    };
+
+    if let Err(err) = res {
+        eprintln!("Error: {:?}", err);
+    }
+}
@@ -0,0 +1,38 @@
+//! Errors of the client
+
+use std::{fmt, result};
+
+use websocket::WebSocketError;
+use hex_server_protocol::{self, AnswerError};
+
+/// Our custom `Result` using the `Error` struct
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid address of the server
+    Url(String),
+    /// Failure of the websocket connection
+    Websocket(WebSocketError),
+    /// Request or answer which can't be encoded
+    Protocol(hex_server_protocol::Error),
+    /// The server answered the request with an error
+    Answer(AnswerError),
+    /// The server answered with another action than expected, contains its tag
+    UnexpectedAnswer(u32),
+    /// The connection was closed before the answer arrived
+    Closed
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Url(msg) => write!(f, "Invalid address: {}", msg),
+            Error::Websocket(err) => write!(f, "Websocket error: {}", err),
+            Error::Protocol(err) => write!(f, "Protocol error: {:?}", err),
+            Error::Answer(err) => write!(f, "{}", err),
+            Error::UnexpectedAnswer(tag) => write!(f, "Unexpected answer with tag {}", tag),
+            Error::Closed => write!(f, "Connection closed")
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//! Client of the websocket protocol
+//!
+//! This crate talks to a remote Hex server, so that players and scripts don't need a local copy of
+//! the database. A connection is opened with `Client::connect`, which negotiates the version and
+//! capabilities with a `Hello` request. The returned handle can be cloned and used from several
+//! tasks at the same time; every request gets a random id and is answered by a future resolving
+//! to the payload of the answer, or to `Error::Answer` if the server failed.
+//!
+//! The socket is driven by two tasks spawned on the Tokio runtime, so `connect` has to be called
+//! from within a running runtime. Changes of the library are received with `transitions`, or with
+//! `subscribe` for a filtered subset of them. Parties of the server are joined with `join_party`,
+//! as remote control or as the device playing their audio.
+//!
+//! ```no_run
+//! use futures::Future;
+//! use hex_client::Client;
+//!
+//! let search = Client::connect("ws://localhost:8080", &["subscriptions"])
+//!     .and_then(|client| client.search("Bach"))
+//!     .map(|tracks| for track in tracks { println!("{:?}", track.title); })
+//!     .map_err(|err| eprintln!("{}", err));
+//!
+//! tokio::run(search);
+//! ```
+
+use std::collections::HashMap;
+use std::result;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, stream, Future, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
+use log::{debug, warn};
+use websocket::{ClientBuilder, WebSocketError};
+use websocket::message::OwnedMessage;
+
+use hex_database::{Track, Playlist, Token, User, TrackKey, PlaylistKey, TokenId, TransitionAction, Queue};
+use hex_server_protocol::{Request, Answer, RequestAction, AnswerAction, AnswerError, PacketId, Encoding};
+use hex_server_protocol::objects::{StreamFormat, Subscription, Notification, Party, PartyId, PartyCommand};
+use hex_server_protocol::version;
+
+mod error;
+
+pub use crate::error::{Error, Result};
+
+/// Changes of the library are pushed with this id, if there is no subscription
+const TRANSITION_ID: PacketId = [0, 0, 0, 0];
+
+/// Extract the payload of the expected answer
+macro_rules! expect {
+    ($answer:expr, $pattern:pat => $value:expr) => {
+        $answer.and_then(move |answer| match answer {
+            $pattern => Ok($value),
+            answer => Err(Error::UnexpectedAnswer(answer.tag()))
+        })
+    }
+}
+
+/// Receivers of answers and pushed changes of a connection
+#[derive(Default)]
+struct Dispatch {
+    pending: HashMap<PacketId, oneshot::Sender<result::Result<AnswerAction, AnswerError>>>,
+    /// A connection has a single subscription, with the id of its request
+    subscription: Option<(PacketId, mpsc::UnboundedSender<Notification>)>,
+    /// Changes of the joined party, with the id of the joining request
+    party: Option<(PacketId, mpsc::UnboundedSender<Party>)>,
+    transitions: Vec<mpsc::UnboundedSender<TransitionAction>>,
+    closed: bool
+}
+
+impl Dispatch {
+    /// Forward an answer to the request waiting for it, or a pushed change to its receivers
+    fn dispatch(&mut self, answer: Answer) {
+        if answer.id == TRANSITION_ID {
+            if let Ok(AnswerAction::Transition(action)) = answer.msg {
+                // forget receivers which were dropped
+                self.transitions.retain(|x| x.unbounded_send(action.clone()).is_ok());
+            }
+
+            return;
+        }
+
+        let subscribed = self.subscription.as_ref().map(|x| x.0 == answer.id).unwrap_or(false);
+        let joined = self.party.as_ref().map(|x| x.0 == answer.id).unwrap_or(false);
+
+        match (answer.msg, subscribed, joined) {
+            (Ok(AnswerAction::Notification(notification)), true, _) => {
+                let dropped = self.subscription.as_ref()
+                    .map(|x| x.1.unbounded_send(notification).is_err())
+                    .unwrap_or(false);
+
+                if dropped {
+                    self.subscription = None;
+                }
+            },
+            (Ok(AnswerAction::Party(party)), _, true) => {
+                let dropped = self.party.as_ref()
+                    .map(|x| x.1.unbounded_send(party).is_err())
+                    .unwrap_or(false);
+
+                if dropped {
+                    self.party = None;
+                }
+            },
+            (msg, _, _) => match self.pending.remove(&answer.id) {
+                // the future of the request may be dropped already
+                Some(sender) => { let _ = sender.send(msg); },
+                None => debug!("Got answer without request: {:?}", answer.id)
+            }
+        }
+    }
+
+    /// Fail all pending requests and end the streams of changes
+    fn close(&mut self) {
+        self.pending.clear();
+        self.subscription = None;
+        self.party = None;
+        self.transitions.clear();
+        self.closed = true;
+    }
+}
+
+/// Audio of a stream, depending on the requested format
+#[derive(Debug)]
+pub enum Audio {
+    /// Interleaved 16bit samples with 48kHz
+    Pcm(Vec<u8>),
+    /// Opus packets, each covering 40ms
+    Opus(Vec<Vec<u8>>)
+}
+
+/// Answer to a subscription
+pub struct Subscribed {
+    /// Changes after the cursor of the request
+    pub missed: Vec<Notification>,
+    /// Sequence number of the latest change
+    pub cursor: u64,
+    /// If false, there were too many changes and everything should be reloaded
+    pub complete: bool,
+    /// Changes pushed after the subscription
+    pub notifications: mpsc::UnboundedReceiver<Notification>
+}
+
+/// Handle of a connection to a server
+#[derive(Clone)]
+pub struct Client {
+    sender: mpsc::UnboundedSender<OwnedMessage>,
+    dispatch: Arc<Mutex<Dispatch>>,
+    version: u32,
+    capabilities: Vec<String>
+}
+
+impl Client {
+    /// Connect to a server, e.g. `ws://localhost:8080`, and announce the optional capabilities
+    /// used by this client
+    pub fn connect(url: &str, capabilities: &[&str]) -> impl Future<Item = Client, Error = Error> {
+        let capabilities = capabilities.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        future::result(ClientBuilder::new(url).map_err(|err| Error::Url(err.to_string())))
+            .and_then(|builder| builder.add_protocol("rust-websocket").async_connect(None)
+                .map_err(Error::Websocket)
+            )
+            .and_then(move |(socket, _)| {
+                let (sender, dispatch) = spawn(socket);
+
+                let client = Client { sender, dispatch, version: version::VERSION, capabilities: Vec::new() };
+                let hello = client.request(RequestAction::Hello { version: version::VERSION, capabilities });
+
+                expect!(hello, AnswerAction::Hello { version, capabilities } => Client { version, capabilities, ..client })
+            })
+    }
+
+    /// Version of the protocol spoken with the server
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Capabilities supported by both sides
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Check whether both sides support a capability, e.g. "opus"
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|x| x == capability)
+    }
+
+    /// Send a request with a random id
+    pub fn request(&self, msg: RequestAction) -> impl Future<Item = AnswerAction, Error = Error> {
+        self.request_with_id(rand::random(), msg)
+    }
+
+    /// Send a request with a given id, stateful requests like searches and streams reuse the id
+    /// of their first request
+    pub fn request_with_id(&self, id: PacketId, msg: RequestAction) -> impl Future<Item = AnswerAction, Error = Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        let sent = Request::new(id, msg).encode(Encoding::Bincode)
+            .map_err(Error::Protocol)
+            .and_then(|buf| {
+                let mut dispatch = self.dispatch.lock().unwrap();
+                if dispatch.closed {
+                    return Err(Error::Closed);
+                }
+
+                dispatch.pending.insert(id, sender);
+
+                self.sender.unbounded_send(OwnedMessage::Binary(buf))
+                    .map_err(|_| Error::Closed)
+            });
+
+        future::result(sent)
+            .and_then(|_| receiver.map_err(|_| Error::Closed))
+            .and_then(|answer| answer.map_err(Error::Answer))
+    }
+
+    /// Receive every change of the library, as long as there is no subscription
+    pub fn transitions(&self) -> mpsc::UnboundedReceiver<TransitionAction> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.dispatch.lock().unwrap().transitions.push(sender);
+
+        receiver
+    }
+
+    /// Subscribe to changes of the library, e.g. `Subscription::Playlists`
+    ///
+    /// A connection has a single subscription, subscribing again replaces the filter and ends
+    /// the previous stream of notifications. Changes after `cursor` are part of the answer.
+    pub fn subscribe(&self, filter: Vec<Subscription>, cursor: Option<u64>) -> impl Future<Item = Subscribed, Error = Error> {
+        let id = rand::random();
+        let (sender, notifications) = mpsc::unbounded();
+        self.dispatch.lock().unwrap().subscription = Some((id, sender));
+
+        expect!(self.request_with_id(id, RequestAction::Subscribe { filter, cursor }),
+            AnswerAction::Subscribe { missed, cursor, complete } => Subscribed { missed, cursor, complete, notifications })
+    }
+
+    /// End the subscription, changes are pushed as transitions again
+    pub fn unsubscribe(&self) -> impl Future<Item = (), Error = Error> {
+        self.dispatch.lock().unwrap().subscription = None;
+
+        expect!(self.request(RequestAction::Unsubscribe), AnswerAction::Unsubscribe => ())
+    }
+
+    /// Get all parties of the server
+    pub fn get_parties(&self) -> impl Future<Item = Vec<Party>, Error = Error> {
+        expect!(self.request(RequestAction::GetParties), AnswerAction::GetParties(parties) => parties)
+    }
+
+    /// Start a new party
+    pub fn create_party(&self, name: &str) -> impl Future<Item = Party, Error = Error> {
+        expect!(self.request(RequestAction::CreateParty { name: name.into() }), AnswerAction::CreateParty(party) => party)
+    }
+
+    /// Join a party, optionally as the device playing its audio
+    ///
+    /// Returns the state of the party and its changes caused by other members. A connection is a
+    /// member of a single party, joining another one ends the previous stream of changes.
+    pub fn join_party(&self, id: PartyId, output: bool) -> impl Future<Item = (Party, mpsc::UnboundedReceiver<Party>), Error = Error> {
+        let request = rand::random();
+        let (sender, changes) = mpsc::unbounded();
+        self.dispatch.lock().unwrap().party = Some((request, sender));
+
+        expect!(self.request_with_id(request, RequestAction::JoinParty { id, output }),
+            AnswerAction::JoinParty(party) => (party, changes))
+    }
+
+    /// Leave the joined party
+    pub fn leave_party(&self) -> impl Future<Item = (), Error = Error> {
+        self.dispatch.lock().unwrap().party = None;
+
+        expect!(self.request(RequestAction::LeaveParty), AnswerAction::LeaveParty => ())
+    }
+
+    /// Control the joined party, returns its new state
+    pub fn party_command(&self, command: PartyCommand) -> impl Future<Item = Party, Error = Error> {
+        expect!(self.request(RequestAction::PartyCommand { command }), AnswerAction::PartyCommand(party) => party)
+    }
+
+    /// Login with name and password, returns the token of the session
+    pub fn login(&self, name: &str, password: &str) -> impl Future<Item = (String, User), Error = Error> {
+        expect!(self.request(RequestAction::Login { name: name.into(), password: password.into() }),
+            AnswerAction::Login { token, user } => (token, user))
+    }
+
+    /// Resume a session with the token of a previous login
+    pub fn authenticate(&self, token: &str) -> impl Future<Item = User, Error = Error> {
+        expect!(self.request(RequestAction::Authenticate { token: token.into() }),
+            AnswerAction::Authenticate(user) => user)
+    }
+
+    /// Search for tracks, collecting all results
+    pub fn search(&self, query: &str) -> impl Future<Item = Vec<Track>, Error = Error> {
+        let id = rand::random();
+        let client = self.clone();
+        let query = query.to_string();
+
+        // the server answers with 50 tracks at a time, as long as the id is the same
+        future::loop_fn(Vec::new(), move |mut tracks| {
+            expect!(client.request_with_id(id, RequestAction::Search { query: query.clone() }),
+                AnswerAction::SearchResult { answ, more, .. } => {
+                    tracks.extend(answ);
+
+                    if more {
+                        future::Loop::Continue(tracks)
+                    } else {
+                        future::Loop::Break(tracks)
+                    }
+                })
+        })
+    }
+
+    /// Get a single track
+    pub fn get_track(&self, key: TrackKey) -> impl Future<Item = Track, Error = Error> {
+        expect!(self.request(RequestAction::GetTrack { key }), AnswerAction::Track(track) => track)
+    }
+
+    /// Get all playlists
+    pub fn get_playlists(&self) -> impl Future<Item = Vec<Playlist>, Error = Error> {
+        expect!(self.request(RequestAction::GetPlaylists), AnswerAction::GetPlaylists(playlists) => playlists)
+    }
+
+    /// Get a playlist with its tracks
+    pub fn get_playlist(&self, key: PlaylistKey) -> impl Future<Item = (Playlist, Vec<Track>), Error = Error> {
+        expect!(self.request(RequestAction::GetPlaylist { key }), AnswerAction::GetPlaylist(playlist) => playlist)
+    }
+
+    /// Get a token with its playlist and tracks, if it has any
+    pub fn get_token(&self, token: TokenId) -> impl Future<Item = (Token, Option<(Playlist, Vec<Track>)>), Error = Error> {
+        expect!(self.request(RequestAction::GetToken { token }), AnswerAction::GetToken(token) => token)
+    }
+
+    /// Update the playlist, played tracks or position of a token
+    pub fn update_token(&self, token: TokenId, key: Option<PlaylistKey>, played: Option<Vec<TrackKey>>, pos: Option<f64>) -> impl Future<Item = (), Error = Error> {
+        expect!(self.request(RequestAction::UpdateToken { token, key, played, pos }), AnswerAction::UpdateToken => ())
+    }
+
+    /// Vote for a track
+    pub fn vote_for_track(&self, key: TrackKey) -> impl Future<Item = (), Error = Error> {
+        expect!(self.request(RequestAction::VoteForTrack { key }), AnswerAction::VoteForTrack => ())
+    }
+
+    /// Get the play queue of the logged in user, or of a device without login
+    ///
+    /// The other queue requests change the same queue afterwards.
+    pub fn get_queue(&self, device: &str) -> impl Future<Item = Queue, Error = Error> {
+        expect!(self.request(RequestAction::GetQueue { device: device.into() }), AnswerAction::GetQueue(queue) => queue)
+    }
+
+    /// Insert tracks into the queue before an index, or append them
+    pub fn enqueue(&self, tracks: Vec<TrackKey>, index: Option<u32>) -> impl Future<Item = Queue, Error = Error> {
+        expect!(self.request(RequestAction::Enqueue { tracks, index }), AnswerAction::Enqueue(queue) => queue)
+    }
+
+    /// Reorder the queue, the n-th track is taken from the index `order[n]`
+    pub fn reorder_queue(&self, order: Vec<u32>) -> impl Future<Item = Queue, Error = Error> {
+        expect!(self.request(RequestAction::ReorderQueue { order }), AnswerAction::ReorderQueue(queue) => queue)
+    }
+
+    /// Remove a single track from the queue
+    pub fn dequeue(&self, index: u32) -> impl Future<Item = Queue, Error = Error> {
+        expect!(self.request(RequestAction::Dequeue { index }), AnswerAction::Dequeue(queue) => queue)
+    }
+
+    /// Remove all tracks from the queue
+    pub fn clear_queue(&self) -> impl Future<Item = (), Error = Error> {
+        expect!(self.request(RequestAction::ClearQueue), AnswerAction::ClearQueue => ())
+    }
+
+    /// Remember the current track of the queue and the position in it in seconds
+    pub fn update_queue_position(&self, current: Option<u32>, pos: Option<f64>) -> impl Future<Item = (), Error = Error> {
+        expect!(self.request(RequestAction::UpdateQueuePosition { current, pos }), AnswerAction::UpdateQueuePosition => ())
+    }
+
+    /// Stream the audio of a track
+    ///
+    /// The stream ends with the track and frees the state of the server afterwards. Opus needs
+    /// the `opus` capability, otherwise the server sends PCM.
+    pub fn stream(&self, key: TrackKey, format: StreamFormat) -> impl Stream<Item = Audio, Error = Error> {
+        self.stream_at(key, format, 0)
+    }
+
+    /// Stream the audio of a track, starting at a sample with 48kHz
+    ///
+    /// The server seeks only in an opened stream, so the first chunk is requested and dropped.
+    pub fn stream_at(&self, key: TrackKey, format: StreamFormat, sample: u32) -> impl Stream<Item = Audio, Error = Error> {
+        let id = rand::random();
+        let client = self.clone();
+
+        let opened = if sample == 0 {
+            future::Either::A(future::ok(Some(key)))
+        } else {
+            let seek = self.clone();
+
+            future::Either::B(self.request_with_id(id, RequestAction::StreamNext { key: Some(key), binaural: false, format })
+                .and_then(move |_| expect!(seek.request_with_id(id, RequestAction::StreamSeek { sample }),
+                    AnswerAction::StreamSeek { .. } => None)))
+        };
+
+        opened.map(move |key| stream::unfold(Some(key), move |key| {
+            // `None` after the end of the track
+            let key = key?;
+
+            let next = client.request_with_id(id, RequestAction::StreamNext { key, binaural: false, format });
+            let end = client.clone();
+
+            Some(next.then(move |answer| match answer {
+                Ok(AnswerAction::StreamNext(samples)) => Ok((Some(Audio::Pcm(samples)), Some(None))),
+                Ok(AnswerAction::StreamPackets(packets)) => Ok((Some(Audio::Opus(packets)), Some(None))),
+                Ok(answer) => Err(Error::UnexpectedAnswer(answer.tag())),
+                Err(Error::Answer(AnswerError::StreamEnded)) => {
+                    let _ = end.request_with_id(id, RequestAction::StreamEnd);
+
+                    Ok((None, None))
+                },
+                Err(err) => Err(err)
+            }))
+        }))
+        .flatten_stream()
+        .filter_map(|x| x)
+    }
+
+    /// Close the connection, pending requests fail with `Error::Closed`
+    pub fn close(&self) {
+        let _ = self.sender.unbounded_send(OwnedMessage::Close(None));
+        self.dispatch.lock().unwrap().close();
+    }
+}
+
+/// Drive a websocket connection with a task sending requests and a task dispatching answers
+fn spawn<S>(socket: S) -> (mpsc::UnboundedSender<OwnedMessage>, Arc<Mutex<Dispatch>>)
+    where S: Stream<Item = OwnedMessage, Error = WebSocketError> + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError> + Send + 'static
+{
+    let (sink, stream) = socket.split();
+    let (sender, receiver) = mpsc::unbounded();
+    let dispatch = Arc::new(Mutex::new(Dispatch::default()));
+
+    let outgoing = sink.sink_map_err(|err| warn!("Could not send request: {:?}", err))
+        .send_all(receiver)
+        .map(|_| ());
+
+    let (tmp, pong, closed) = (dispatch.clone(), sender.clone(), dispatch.clone());
+    let incoming = stream.for_each(move |msg| {
+            match msg {
+                OwnedMessage::Binary(buf) => match Answer::decode(&buf, Encoding::Bincode) {
+                    Ok(answer) => tmp.lock().unwrap().dispatch(answer),
+                    Err(err) => warn!("Could not decode answer: {:?}", err)
+                },
+                OwnedMessage::Ping(data) => { let _ = pong.unbounded_send(OwnedMessage::Pong(data)); },
+                OwnedMessage::Close(_) => debug!("Connection closed by the server"),
+                _ => {}
+            }
+
+            Ok(())
+        })
+        .map_err(|err| warn!("Connection failed: {:?}", err))
+        .then(move |_| -> result::Result<(), ()> {
+            closed.lock().unwrap().close();
+
+            Ok(())
+        });
+
+    tokio::spawn(outgoing);
+    tokio::spawn(incoming);
+
+    (sender, dispatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use futures::sync::{mpsc, oneshot};
+
+    use hex_database::{TrackKey, TransitionAction};
+    use hex_server_protocol::{Answer, AnswerAction, AnswerError};
+    use hex_server_protocol::objects::{Notification, Event, Party};
+
+    use super::{Dispatch, TRANSITION_ID};
+
+    fn party() -> Party {
+        Party { id: 1, name: "Kitchen".into(), current: None, queue: Vec::new(), playing: false, position: 0.0, output: true, members: 2 }
+    }
+
+    #[test]
+    fn answers() {
+        let mut dispatch = Dispatch::default();
+
+        let (sender, receiver) = oneshot::channel();
+        dispatch.pending.insert([1, 2, 3, 4], sender);
+        let (sender, failed) = oneshot::channel();
+        dispatch.pending.insert([5, 6, 7, 8], sender);
+
+        // answers without request are ignored
+        dispatch.dispatch(Answer::new([9, 9, 9, 9], Ok(AnswerAction::Logout)));
+        dispatch.dispatch(Answer::new([5, 6, 7, 8], Err(AnswerError::NotFound)));
+        dispatch.dispatch(Answer::new([1, 2, 3, 4], Ok(AnswerAction::CreateToken(3))));
+
+        assert!(dispatch.pending.is_empty());
+        match receiver.wait().unwrap() {
+            Ok(AnswerAction::CreateToken(3)) => {},
+            answer => panic!("Unexpected answer {:?}", answer)
+        }
+        assert_eq!(failed.wait().unwrap().unwrap_err(), AnswerError::NotFound);
+    }
+
+    #[test]
+    fn pushed_changes() {
+        let mut dispatch = Dispatch::default();
+
+        let (sender, transitions) = mpsc::unbounded();
+        dispatch.transitions.push(sender);
+        let (sender, notifications) = mpsc::unbounded();
+        dispatch.subscription = Some(([1, 1, 1, 1], sender));
+
+        let key = TrackKey::from_vec(&[1; 16]);
+        dispatch.dispatch(Answer::new(TRANSITION_ID, Ok(AnswerAction::Transition(TransitionAction::DeleteTrack(key)))));
+        dispatch.dispatch(Answer::new([1, 1, 1, 1], Ok(AnswerAction::Notification(Notification { cursor: 7, event: Event::TrackDeleted(key) }))));
+
+        let (sender, parties) = mpsc::unbounded();
+        dispatch.party = Some(([2, 2, 2, 2], sender));
+        dispatch.dispatch(Answer::new([2, 2, 2, 2], Ok(AnswerAction::Party(party()))));
+
+        // the end of the connection ends the streams
+        dispatch.close();
+
+        assert_eq!(transitions.collect().wait().unwrap(), vec![TransitionAction::DeleteTrack(key)]);
+        assert_eq!(notifications.map(|x| x.cursor).collect().wait().unwrap(), vec![7]);
+        assert_eq!(parties.collect().wait().unwrap(), vec![party()]);
+    }
+
+    #[test]
+    fn closed() {
+        let mut dispatch = Dispatch::default();
+
+        let (sender, receiver) = oneshot::channel();
+        dispatch.pending.insert([1, 2, 3, 4], sender);
+        dispatch.close();
+
+        assert!(dispatch.closed);
+        assert!(receiver.wait().is_err());
+    }
+}
@@ -26,22 +26,46 @@ use std::default::Default;
 pub struct Server {
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Number of background jobs running at the same time
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+    /// Maximal size of a file uploaded in chunks, in MiB
+    #[serde(default = "default_max_upload")]
+    pub max_upload: u64,
 }
 
 /// Default host is localhost
 fn default_host() -> IpAddr { IpAddr::V4(Ipv4Addr::LOCALHOST) }
 /// Default port of the websocket server is 2798
 fn default_port() -> u16 { 2798 }
+/// Default number of concurrent jobs is two
+fn default_jobs() -> usize { 2 }
+/// Default maximal size of an upload is 2 GiB
+fn default_max_upload() -> u64 { 2048 }
 /// Default port of the webserver is 80
 fn default_port_web() -> u16 { 80 }
 /// Default port of the database peer is 8004
 fn default_port_dbpeer() -> u16 { 8004 }
 fn default_discover() -> bool { true }
+/// Default loudspeaker layout is stereo
+fn default_layout() -> String { "stereo".into() }
+/// Default volume normalisation is per track
+fn default_normalization() -> String { "track".into() }
+/// Metadata is looked up at AcoustID and MusicBrainz by default
+fn default_backends() -> Vec<String> { vec!["acoustid".into(), "musicbrainz".into()] }
+/// Client key of Hex at AcoustID
+fn default_acoustid_client() -> String { "sepmArwuV3".into() }
+/// Suggestions are applied automatically above a score of 0.8
+fn default_min_score() -> f32 { 0.8 }
+/// Lookups are cached for 30 days
+fn default_cache_days() -> u32 { 30 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
             port: 2798,
+            jobs: 2,
+            max_upload: default_max_upload(),
         }
     }
 }
@@ -62,6 +86,18 @@ pub struct WebServer {
     pub port: u16
 }
 
+/// TLS configuration of the websocket server and webserver
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tls {
+    /// Certificate chain in PEM format
+    pub certificate: PathBuf,
+    /// Private key of the certificate in PEM format (PKCS8 or RSA)
+    pub key: PathBuf,
+    /// Only accept clients with a certificate signed by one of these authorities (PEM format)
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>
+}
+
 /// Sync server configuration
 #[derive(Deserialize, Debug, Clone)]
 pub struct DatabasePeer {
@@ -112,6 +148,58 @@ impl DatabasePeer {
     }
 }
 
+/// Playback configuration of local audio devices
+#[derive(Deserialize, Debug, Clone)]
+pub struct Playback {
+    /// Loudspeaker layout, one of `mono`, `stereo`, `5.1`, `7.1`, `binaural` or `custom`
+    #[serde(default = "default_layout")]
+    pub layout: String,
+    /// Azimuth and elevation in degrees of each loudspeaker in a `custom` layout
+    #[serde(default)]
+    pub speakers: Vec<(f64, f64)>,
+    /// Volume normalisation, one of `off`, `track` or `album`
+    #[serde(default = "default_normalization")]
+    pub normalization: String
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Playback {
+            layout: default_layout(),
+            speakers: Vec::new(),
+            normalization: default_normalization()
+        }
+    }
+}
+
+/// Lookup of track metadata in online databases
+#[derive(Deserialize, Debug, Clone)]
+pub struct Metadata {
+    /// Backends asked in this order, `acoustid` and `musicbrainz`
+    #[serde(default = "default_backends")]
+    pub backends: Vec<String>,
+    /// Client key registered at AcoustID
+    #[serde(default = "default_acoustid_client")]
+    pub acoustid_client: String,
+    /// Minimal score of an AcoustID suggestion to tag a track without asking
+    #[serde(default = "default_min_score")]
+    pub min_score: f32,
+    /// Number of days until a cached lookup is repeated
+    #[serde(default = "default_cache_days")]
+    pub cache_days: u32
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            backends: default_backends(),
+            acoustid_client: default_acoustid_client(),
+            min_score: default_min_score(),
+            cache_days: default_cache_days()
+        }
+    }
+}
+
 /// Global configuration
 #[derive(Deserialize,Debug, Clone)]
 pub struct Conf {
@@ -120,8 +208,13 @@ pub struct Conf {
     #[serde(default)]
     pub server: Server,
     pub webserver: Option<WebServer>,
+    pub tls: Option<Tls>,
     pub peer: Option<DatabasePeer>,
-    pub spotify: Option<SpotifyAPI>
+    pub spotify: Option<SpotifyAPI>,
+    #[serde(default)]
+    pub playback: Playback,
+    #[serde(default)]
+    pub metadata: Metadata
 }
 
 impl Default for Conf {
@@ -130,8 +223,11 @@ impl Default for Conf {
             host: default_host(),
             server: Server::default(),
             webserver: None,
+            tls: None,
             peer: None,
-            spotify: None
+            spotify: None,
+            playback: Playback::default(),
+            metadata: Metadata::default()
         }
     }
 }
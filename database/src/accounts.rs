@@ -0,0 +1,572 @@
+//! User accounts of the servers
+//!
+//! Accounts belong to a single peer and are therefore not synchronised with the gossip protocol.
+//! They are stored in their own tables of the SQLite database together with the sessions, the
+//! votes of each user and the owners of playlists. Passwords are hashed with PBKDF2-HMAC-SHA256
+//! and a random salt. The number of iterations is stored with the hash, older hashes are replaced
+//! with the current number at the next login. A successful login creates a session token, which
+//! identifies the user in later connections.
+//!
+//! After a few failed logins of a user, every further failure doubles the time until the password
+//! is checked again, up to five minutes. Logins during that time are refused without counting as
+//! failure. This slows down guessing of passwords, from the websocket as well as the Subsonic API,
+//! without locking out the user for longer than the back-off.
+//!
+//! Links of M3U playlists are opened by players without a session, they carry a token signed with
+//! HMAC-SHA256 and a random secret of this server. The token is bound to a single playlist and
+//! expires after the lifetime of a session.
+//!
+//! As long as no account exists the accounts are disabled and every client has full access.
+
+use std::path::Path;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{self, Connection};
+use sha2::Sha256;
+use hmac::{Hmac, Mac};
+
+use crate::error::{Error, Result};
+use crate::objects::{TrackKey, PlaylistKey, User, Role};
+
+/// Number of iterations of the key derivation, as recommended by OWASP in 2023
+const ITERATIONS: u32 = 600_000;
+
+/// Iterations of the hashes of earlier versions, which didn't store the number
+const LEGACY_ITERATIONS: u32 = 10000;
+
+/// Sessions expire after thirty days
+const SESSION_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+/// Number of failed logins of a user before further logins are delayed
+const FREE_FAILED_LOGINS: i64 = 5;
+
+/// Signed links of playlists are valid as long as sessions
+const LINK_LIFETIME: i64 = SESSION_LIFETIME;
+
+/// Maximal delay after a failed login in seconds
+const MAX_DELAY: i64 = 5 * 60;
+
+/// Failed logins are remembered for an hour
+const FAILED_LOGIN_LIFETIME: i64 = 60 * 60;
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Read random bytes from the operating system
+fn random(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+
+    getrandom::getrandom(&mut buf)
+        .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::Other, err.to_string())))?;
+
+    Ok(buf)
+}
+
+/// Derive a 256bit key from a password with PBKDF2-HMAC-SHA256 (RFC 8018)
+fn derive_key(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations as usize, &mut out);
+
+    out
+}
+
+/// Seconds after the last failed login, until the password of a user is checked again
+fn delay(failed: i64) -> i64 {
+    if failed < FREE_FAILED_LOGINS {
+        0
+    } else {
+        (2i64 << (failed - FREE_FAILED_LOGINS).min(16)).min(MAX_DELAY)
+    }
+}
+
+/// Compare two hashes in constant time
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Connection to the account tables
+pub struct Accounts {
+    socket: Connection,
+    /// Iterations of new hashes
+    iterations: u32
+}
+
+impl Accounts {
+    /// Open the accounts in a database file, creating the tables if necessary
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Accounts> {
+        let socket = Connection::open(path)
+            .map_err(|err| Error::Sqlite(err))?;
+
+        socket.execute_batch(include_str!("create_accounts.sql"))
+            .map_err(|err| Error::Sqlite(err))?;
+
+        // users of databases created by earlier versions have no number of iterations
+        let columns: Vec<String> = {
+            let mut stmt = socket.prepare("PRAGMA table_info(Users)")
+                .map_err(|err| Error::Sqlite(err))?;
+
+            let columns = stmt.query_map(&[], |row| row.get(1))
+                .map_err(|err| Error::Sqlite(err))?
+                .filter_map(|x| x.ok()).collect();
+
+            columns
+        };
+
+        if !columns.iter().any(|x| x == "Iterations") {
+            socket.execute(&format!("ALTER TABLE Users ADD COLUMN Iterations INTEGER NOT NULL DEFAULT {}", LEGACY_ITERATIONS), &[])
+                .map_err(|err| Error::Sqlite(err))?;
+        }
+
+        Ok(Accounts { socket, iterations: ITERATIONS })
+    }
+
+    /// Hash new passwords with fewer iterations, which only makes sense in tests
+    pub fn with_iterations(mut self, iterations: u32) -> Accounts {
+        self.iterations = iterations;
+
+        self
+    }
+
+    /// Check whether any account exists, otherwise the accounts are disabled
+    pub fn is_enabled(&self) -> bool {
+        self.socket.query_row("SELECT COUNT(*) FROM Users", &[], |row| row.get::<usize, i64>(0))
+            .map(|x| x > 0)
+            .unwrap_or(false)
+    }
+
+    /// Get a single user
+    pub fn user(&self, name: &str) -> Result<User> {
+        self.socket.query_row("SELECT Name, Role FROM Users WHERE Name = ?", &[&name], |row| User {
+            name: row.get(0),
+            role: Role::from_id(row.get(1)).unwrap_or(Role::Listener)
+        }).map_err(|err| match err {
+            rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
+            err => Error::Sqlite(err)
+        })
+    }
+
+    /// Get all users
+    pub fn users(&self) -> Vec<User> {
+        let mut stmt = self.socket.prepare("SELECT Name, Role FROM Users ORDER BY Name").unwrap();
+
+        let users = stmt.query_map(&[], |row| User {
+            name: row.get(0),
+            role: Role::from_id(row.get(1)).unwrap_or(Role::Listener)
+        }).unwrap().filter_map(|x| x.ok()).collect();
+
+        users
+    }
+
+    /// Create a new user
+    pub fn add_user(&self, name: &str, password: &str, role: Role) -> Result<User> {
+        if self.user(name).is_ok() {
+            return Err(Error::AlreadyExists);
+        }
+
+        let salt = random(16)?;
+        let hash = derive_key(password.as_bytes(), &salt, self.iterations);
+
+        self.socket.execute("INSERT INTO Users (Name, Role, Salt, Hash, Created, Iterations) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[&name, &role.id(), &salt, &hash.as_ref(), &now(), &self.iterations])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(User { name: name.into(), role })
+    }
+
+    /// Delete a user with all sessions and votes, the playlists of the user stay
+    pub fn delete_user(&self, name: &str) -> Result<()> {
+        let changed = self.socket.execute("DELETE FROM Users WHERE Name = ?", &[&name])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        if changed == 0 {
+            return Err(Error::NotFound);
+        }
+
+        for table in &["Sessions", "Votes", "PlaylistOwners"] {
+            self.socket.execute(&format!("DELETE FROM {} WHERE Name = ?", table), &[&name])
+                .map_err(|err| Error::Sqlite(err))?;
+        }
+
+        Ok(())
+    }
+
+    /// Change the role of a user
+    pub fn set_role(&self, name: &str, role: Role) -> Result<()> {
+        let changed = self.socket.execute("UPDATE Users SET Role = ?1 WHERE Name = ?2", &[&role.id(), &name])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        if changed == 0 {
+            Err(Error::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Store a new hash of the password of a user
+    fn store_hash(&self, name: &str, password: &str) -> Result<()> {
+        let salt = random(16)?;
+        let hash = derive_key(password.as_bytes(), &salt, self.iterations);
+
+        let changed = self.socket.execute("UPDATE Users SET Salt = ?1, Hash = ?2, Iterations = ?3 WHERE Name = ?4", &[&salt, &hash.as_ref(), &self.iterations, &name])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        if changed == 0 {
+            Err(Error::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Change the password of a user, which also ends all sessions
+    pub fn set_password(&self, name: &str, password: &str) -> Result<()> {
+        self.store_hash(name, password)?;
+
+        self.socket.execute("DELETE FROM Sessions WHERE Name = ?", &[&name])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(())
+    }
+
+    /// Check the password of a user
+    ///
+    /// Fails without looking at the password, while the user has to wait after failed logins.
+    pub fn check(&self, name: &str, password: &str) -> Result<User> {
+        let now = now();
+
+        self.socket.execute("DELETE FROM FailedLogins WHERE Created <= ?", &[&(now - FAILED_LOGIN_LIFETIME)])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        let (failed, last) = self.socket.query_row("SELECT COUNT(*), MAX(Created) FROM FailedLogins WHERE Name = ?", &[&name], |row| {
+            (row.get::<usize, i64>(0), row.get::<usize, Option<i64>>(1))
+        }).map_err(|err| Error::Sqlite(err))?;
+
+        // refused logins don't count, the delay only grows with guessed passwords
+        if last.map(|last| now < last + delay(failed)).unwrap_or(false) {
+            return Err(Error::WrongCredentials);
+        }
+
+        let user = self.socket.query_row("SELECT Role, Salt, Hash, Iterations FROM Users WHERE Name = ?", &[&name], |row| {
+            (row.get::<usize, i64>(0), row.get::<usize, Vec<u8>>(1), row.get::<usize, Vec<u8>>(2), row.get::<usize, u32>(3))
+        }).ok();
+
+        let role = match user {
+            Some((role, salt, hash, iterations)) if equal(&derive_key(password.as_bytes(), &salt, iterations), &hash) => {
+                // hashes with fewer iterations are replaced, while the password is known
+                if iterations < self.iterations {
+                    self.store_hash(name, password)?;
+                }
+
+                role
+            },
+            // unknown names count as well, otherwise they could be told apart
+            _ => {
+                self.socket.execute("INSERT INTO FailedLogins (Name, Created) VALUES (?1, ?2)", &[&name, &now()])
+                    .map_err(|err| Error::Sqlite(err))?;
+
+                return Err(Error::WrongCredentials);
+            }
+        };
+
+        self.socket.execute("DELETE FROM FailedLogins WHERE Name = ?", &[&name])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(User {
+            name: name.into(),
+            role: Role::from_id(role).unwrap_or(Role::Listener)
+        })
+    }
+
+    /// Check the password of a user and start a new session
+    ///
+    /// Returns the token of the session.
+    pub fn login(&self, name: &str, password: &str) -> Result<(String, User)> {
+        let user = self.check(name, password)?;
+        let token: String = random(16)?.iter().map(|x| format!("{:02x}", x)).collect();
+
+        self.socket.execute("INSERT INTO Sessions (Token, Name, Created) VALUES (?1, ?2, ?3)", &[&token, &name, &now()])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok((token, user))
+    }
+
+    /// Get the user of a session
+    pub fn session(&self, token: &str) -> Result<User> {
+        let name = self.socket.query_row("SELECT Name FROM Sessions WHERE Token = ?1 AND Created > ?2", &[&token, &(now() - SESSION_LIFETIME)], |row| row.get::<usize, String>(0))
+            .map_err(|_| Error::WrongCredentials)?;
+
+        self.user(&name)
+    }
+
+    /// End a session
+    pub fn logout(&self, token: &str) -> Result<()> {
+        self.socket.execute("DELETE FROM Sessions WHERE Token = ?", &[&token])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Remember the vote of a user for a track
+    ///
+    /// Returns `false` if the user has already voted for the track.
+    pub fn vote(&self, name: &str, key: TrackKey) -> Result<bool> {
+        self.socket.execute("INSERT OR IGNORE INTO Votes (Name, Track, Created) VALUES (?1, ?2, ?3)", &[&name, &key.to_vec(), &now()])
+            .map(|changed| changed > 0)
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Take back the vote of a user for a track
+    ///
+    /// Returns `false` if the user hasn't voted for the track.
+    pub fn unvote(&self, name: &str, key: TrackKey) -> Result<bool> {
+        self.socket.execute("DELETE FROM Votes WHERE Name = ?1 AND Track = ?2", &[&name, &key.to_vec()])
+            .map(|changed| changed > 0)
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Get all tracks a user has voted for
+    pub fn votes(&self, name: &str) -> Vec<TrackKey> {
+        let mut stmt = self.socket.prepare("SELECT Track FROM Votes WHERE Name = ? ORDER BY Created").unwrap();
+
+        let votes = stmt.query_map(&[&name], |row| TrackKey::from_vec(&row.get::<usize, Vec<u8>>(0)))
+            .unwrap().filter_map(|x| x.ok()).collect();
+
+        votes
+    }
+
+    /// Set the owner of a playlist
+    pub fn set_owner(&self, playlist: PlaylistKey, name: &str) -> Result<()> {
+        self.socket.execute("INSERT OR REPLACE INTO PlaylistOwners (Playlist, Name) VALUES (?1, ?2)", &[&playlist, &name])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Get the owner of a playlist, playlists created without accounts have none
+    pub fn owner(&self, playlist: PlaylistKey) -> Option<String> {
+        self.socket.query_row("SELECT Name FROM PlaylistOwners WHERE Playlist = ?", &[&playlist], |row| row.get(0)).ok()
+    }
+
+    /// Get the keys of all playlists of a user
+    pub fn playlists(&self, name: &str) -> Vec<PlaylistKey> {
+        let mut stmt = self.socket.prepare("SELECT Playlist FROM PlaylistOwners WHERE Name = ?").unwrap();
+
+        let playlists = stmt.query_map(&[&name], |row| row.get(0))
+            .unwrap().filter_map(|x| x.ok()).collect();
+
+        playlists
+    }
+
+    /// Secret of signed links, created at the first use
+    fn link_secret(&self) -> Result<Vec<u8>> {
+        if let Ok(secret) = self.socket.query_row("SELECT Secret FROM LinkSecret", &[], |row| row.get(0)) {
+            return Ok(secret);
+        }
+
+        let secret = random(32)?;
+        self.socket.execute("INSERT INTO LinkSecret (Secret) VALUES (?)", &[&secret])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(secret)
+    }
+
+    /// Sign a playlist and its expiry with the secret of the links
+    fn link_signature(&self, playlist: PlaylistKey, expires: i64) -> Result<Vec<u8>> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.link_secret()?)
+            .map_err(|_| Error::Io(io::Error::new(io::ErrorKind::Other, "invalid length of secret")))?;
+        mac.input(format!("{}.{}", playlist, expires).as_bytes());
+
+        Ok(mac.result().code().to_vec())
+    }
+
+    /// Create a token for the links of a playlist
+    ///
+    /// The token has the form `<playlist>.<expiry>.<signature>` and only contains characters,
+    /// which need no escaping in a query string.
+    pub fn link_token(&self, playlist: PlaylistKey) -> Result<String> {
+        let expires = now() + LINK_LIFETIME;
+        let signature: String = self.link_signature(playlist, expires)?
+            .iter().map(|x| format!("{:02x}", x)).collect();
+
+        Ok(format!("{}.{}.{}", playlist, expires, signature))
+    }
+
+    /// Check the token of a link, returns the signed playlist
+    pub fn check_link_token(&self, token: &str) -> Result<PlaylistKey> {
+        let mut parts = token.splitn(3, '.');
+        let playlist = parts.next().and_then(|x| x.parse::<PlaylistKey>().ok());
+        let expires = parts.next().and_then(|x| x.parse::<i64>().ok());
+        let signature = parts.next().filter(|x| x.len() % 2 == 0)
+            .and_then(|x| (0..x.len()).step_by(2).map(|i| u8::from_str_radix(x.get(i..i + 2)?, 16).ok()).collect::<Option<Vec<u8>>>());
+
+        match (playlist, expires, signature) {
+            (Some(playlist), Some(expires), Some(signature)) if expires > now() => {
+                if equal(&self.link_signature(playlist, expires)?, &signature) {
+                    Ok(playlist)
+                } else {
+                    Err(Error::WrongCredentials)
+                }
+            },
+            _ => Err(Error::WrongCredentials)
+        }
+    }
+
+    /// Check whether a user may change a playlist
+    ///
+    /// Listeners can only change their own playlists, editors all of them.
+    pub fn may_modify(&self, user: &User, playlist: PlaylistKey) -> bool {
+        user.role >= Role::Editor || self.owner(playlist).map(|x| x == user.name).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_key, Accounts, LEGACY_ITERATIONS};
+    use crate::error::Error;
+    use crate::objects::{Role, TrackKey};
+
+    fn hex(buf: &[u8]) -> String {
+        buf.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn key_derivation() {
+        // the first vector is from section 11 of RFC 7914, truncated to 32 bytes, the others were
+        // computed with `hashlib.pbkdf2_hmac` of Python, the last with a password longer than the
+        // block size
+        assert_eq!(hex(&derive_key(b"passwd", b"salt", 1)), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        assert_eq!(hex(&derive_key(b"password", b"salt", 2)), "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+        assert_eq!(hex(&derive_key(&[b'k'; 100], b"salt", 2)), "2c1357648009149f57e4d5544c3435bbca87a6b231300fa3abb2a89b50f56ec3");
+    }
+
+    #[test]
+    fn sessions() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Accounts::from_file(file.path()).unwrap().with_iterations(1000);
+
+        assert!(!accounts.is_enabled());
+        accounts.add_user("alice", "secret", Role::Admin).unwrap();
+        assert!(accounts.is_enabled());
+        assert!(match accounts.add_user("alice", "other", Role::Listener) { Err(Error::AlreadyExists) => true, _ => false });
+
+        assert!(accounts.login("alice", "wrong").is_err());
+        assert!(accounts.login("bob", "secret").is_err());
+
+        let (token, user) = accounts.login("alice", "secret").unwrap();
+        assert_eq!(user.role, Role::Admin);
+        assert_eq!(accounts.session(&token).unwrap(), user);
+
+        // a new password ends all sessions
+        accounts.set_password("alice", "new").unwrap();
+        assert!(accounts.session(&token).is_err());
+
+        let (token, _) = accounts.login("alice", "new").unwrap();
+        accounts.logout(&token).unwrap();
+        assert!(accounts.session(&token).is_err());
+    }
+
+    #[test]
+    fn failed_logins() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Accounts::from_file(file.path()).unwrap().with_iterations(1000);
+        accounts.add_user("alice", "secret", Role::Listener).unwrap();
+
+        // a successful login forgets earlier failures
+        for _ in 0..4 {
+            assert!(accounts.login("alice", "wrong").is_err());
+        }
+        assert!(accounts.login("alice", "secret").is_ok());
+
+        for _ in 0..5 {
+            assert!(accounts.login("alice", "wrong").is_err());
+        }
+        assert!(accounts.login("alice", "secret").is_err());
+        assert!(accounts.check("alice", "secret").is_err());
+
+        // refused logins don't count, after the delay the password is checked again
+        let failed = |accounts: &Accounts| accounts.socket.query_row("SELECT COUNT(*) FROM FailedLogins", &[], |row| row.get::<usize, i64>(0)).unwrap();
+        assert_eq!(failed(&accounts), 5);
+        accounts.socket.execute("UPDATE FailedLogins SET Created = Created - 2", &[]).unwrap();
+        assert!(accounts.check("alice", "secret").is_ok());
+        assert_eq!(failed(&accounts), 0);
+
+        // every further failure doubles the delay
+        assert_eq!((0..8).map(super::delay).collect::<Vec<_>>(), vec![0, 0, 0, 0, 0, 2, 4, 8]);
+        assert_eq!(super::delay(100), 5 * 60);
+    }
+
+    #[test]
+    fn legacy_hashes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Accounts::from_file(file.path()).unwrap().with_iterations(1000);
+        accounts.add_user("alice", "secret", Role::Listener).unwrap();
+
+        // a hash of an earlier version is replaced at the next login
+        let salt = b"0123456789abcdef".to_vec();
+        let hash = derive_key(b"secret", &salt, LEGACY_ITERATIONS);
+        accounts.socket.execute("UPDATE Users SET Salt = ?1, Hash = ?2, Iterations = ?3", &[&salt, &hash.as_ref(), &LEGACY_ITERATIONS]).unwrap();
+
+        let accounts = accounts.with_iterations(LEGACY_ITERATIONS + 1);
+        assert!(accounts.check("alice", "secret").is_ok());
+
+        let iterations = accounts.socket.query_row("SELECT Iterations FROM Users", &[], |row| row.get::<usize, u32>(0)).unwrap();
+        assert_eq!(iterations, LEGACY_ITERATIONS + 1);
+        assert!(accounts.check("alice", "secret").is_ok());
+        assert!(accounts.check("alice", "wrong").is_err());
+    }
+
+    #[test]
+    fn link_tokens() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Accounts::from_file(file.path()).unwrap();
+
+        let token = accounts.link_token(-3).unwrap();
+        assert_eq!(accounts.check_link_token(&token).unwrap(), -3);
+        assert_eq!(accounts.check_link_token(&accounts.link_token(7).unwrap()).unwrap(), 7);
+
+        // the secret survives a restart
+        let accounts = Accounts::from_file(file.path()).unwrap();
+        assert_eq!(accounts.check_link_token(&token).unwrap(), -3);
+
+        // neither the playlist nor the expiry can be changed
+        let parts: Vec<&str> = token.splitn(3, '.').collect();
+        assert!(accounts.check_link_token(&format!("4.{}.{}", parts[1], parts[2])).is_err());
+        assert!(accounts.check_link_token(&format!("-3.{}.{}", parts[1].parse::<i64>().unwrap() + 1, parts[2])).is_err());
+        assert!(accounts.check_link_token(&format!("-3.{}.{}", parts[1], &parts[2][2..])).is_err());
+
+        // expired tokens are refused, even with a valid signature
+        let signature: String = accounts.link_signature(-3, 1).unwrap().iter().map(|x| format!("{:02x}", x)).collect();
+        assert!(accounts.check_link_token(&format!("-3.1.{}", signature)).is_err());
+        assert!(accounts.check_link_token("").is_err());
+        assert!(accounts.check_link_token("1.2.zz").is_err());
+    }
+
+    #[test]
+    fn votes_and_playlists() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Accounts::from_file(file.path()).unwrap().with_iterations(1000);
+        let listener = accounts.add_user("alice", "secret", Role::Listener).unwrap();
+        let editor = accounts.add_user("bob", "secret", Role::Editor).unwrap();
+
+        let key = TrackKey::from_vec(&[1; 16]);
+        assert!(accounts.vote("alice", key).unwrap());
+        assert!(!accounts.vote("alice", key).unwrap());
+        assert!(accounts.vote("bob", key).unwrap());
+        assert_eq!(accounts.votes("alice"), vec![key]);
+        assert!(accounts.unvote("bob", key).unwrap());
+        assert!(!accounts.unvote("bob", key).unwrap());
+        assert!(accounts.votes("bob").is_empty());
+
+        accounts.set_owner(1, "alice").unwrap();
+        assert!(accounts.may_modify(&listener, 1));
+        assert!(!accounts.may_modify(&listener, 2));
+        assert!(accounts.may_modify(&editor, 2));
+        assert_eq!(accounts.playlists("alice"), vec![1]);
+
+        accounts.delete_user("alice").unwrap();
+        assert_eq!(accounts.owner(1), None);
+        assert!(accounts.votes("alice").is_empty());
+    }
+}
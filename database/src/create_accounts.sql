@@ -0,0 +1,37 @@
+BEGIN;
+    CREATE TABLE IF NOT EXISTS Users (
+        Name        TEXT PRIMARY KEY,
+        Role        INTEGER NOT NULL,
+        Salt        BLOB NOT NULL,
+        Hash        BLOB NOT NULL,
+        Created     INTEGER NOT NULL,
+        Iterations  INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS Sessions (
+        Token       TEXT PRIMARY KEY,
+        Name        TEXT NOT NULL,
+        Created     INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS Votes (
+        Name        TEXT NOT NULL,
+        Track       BLOB NOT NULL,
+        Created     INTEGER NOT NULL,
+        PRIMARY KEY (Name, Track)
+    );
+
+    CREATE TABLE IF NOT EXISTS PlaylistOwners (
+        Playlist    INTEGER PRIMARY KEY,
+        Name        TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS FailedLogins (
+        Name        TEXT NOT NULL,
+        Created     INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS LinkSecret (
+        Secret      BLOB NOT NULL
+    );
+COMMIT;
@@ -18,7 +18,8 @@ BEGIN;
         Title   TEXT NOT NULL, 
         Desc    TEXT, 
         Tracks  BLOB NOT NULL, 
-        Author  BLOB NOT NULL
+        Author  BLOB NOT NULL,
+        Image   BLOB
     );
 
     CREATE TABLE IF NOT EXISTS Tokens (
@@ -0,0 +1,12 @@
+BEGIN;
+    CREATE TABLE IF NOT EXISTS Jobs (
+        Id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        Kind        BLOB NOT NULL,
+        Status      INTEGER NOT NULL,
+        Attempts    INTEGER NOT NULL,
+        Error       TEXT,
+        Result      BLOB,
+        Owner       TEXT,
+        Created     INTEGER NOT NULL
+    );
+COMMIT;
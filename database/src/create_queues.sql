@@ -0,0 +1,9 @@
+BEGIN;
+    CREATE TABLE IF NOT EXISTS Queues (
+        Owner       TEXT PRIMARY KEY,
+        Tracks      BLOB NOT NULL,
+        Current     INTEGER,
+        Pos         REAL,
+        Updated     INTEGER NOT NULL
+    );
+COMMIT;
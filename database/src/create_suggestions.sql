@@ -0,0 +1,7 @@
+BEGIN;
+    CREATE TABLE IF NOT EXISTS Suggestions (
+        Track       BLOB PRIMARY KEY,
+        Data        BLOB NOT NULL,
+        Created     INTEGER NOT NULL
+    );
+COMMIT;
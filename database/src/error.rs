@@ -16,6 +16,8 @@ pub enum Error {
     AlreadyExists,
     SyncFailed(String),
     NotFound,
+    WrongCredentials,
+    InvalidState,
     ReadOnly,
     AcousticId,
     Serialize,
@@ -0,0 +1,119 @@
+//! Ordered history of all changes
+//!
+//! Every change of the database, domestic or received from another peer, is stored as a transition
+//! before it is applied. A transition of another peer may wait for the transitions it references,
+//! therefore transitions are numbered in the order they are applied. This sequence number is
+//! increasing: a client remembers the last sequence number it has seen and can ask for all changes
+//! after it, even if the server was restarted in the meantime. Pending transitions are not part of
+//! the history until they are applied.
+
+use std::path::Path;
+
+use rusqlite::Connection;
+use bincode::deserialize;
+
+use crate::error::{Error, Result};
+use crate::transition::TransitionAction;
+
+/// Create the table of applied transitions
+///
+/// Databases of earlier versions have no such table, their applied transitions are numbered in
+/// the order of their arrival.
+pub(crate) fn create_table(socket: &Connection) -> Result<()> {
+    socket.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS Applied (
+            Seq     INTEGER PRIMARY KEY AUTOINCREMENT,
+            Key     BLOB NOT NULL UNIQUE
+        );
+
+        INSERT OR IGNORE INTO Applied (Key)
+            SELECT Key FROM Transitions WHERE State != 2 AND Key NOT IN (SELECT Key FROM Applied) ORDER BY rowid;
+    "#).map_err(|err| Error::Sqlite(err))
+}
+
+/// Read access to the transitions in order of their arrival
+pub struct History {
+    socket: Connection
+}
+
+impl History {
+    /// Open the history of a database file
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<History> {
+        Connection::open(path)
+            .map(|socket| History { socket })
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Sequence number of the latest change, zero for an empty database
+    pub fn cursor(&self) -> Result<u64> {
+        self.socket.query_row("SELECT IFNULL(MAX(Seq), 0) FROM Applied", &[], |row| row.get::<usize, i64>(0) as u64)
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Get at most `limit` applied changes after a sequence number
+    ///
+    /// Transitions without a valid body are skipped.
+    pub fn since(&self, cursor: u64, limit: usize) -> Result<Vec<(u64, TransitionAction)>> {
+        let mut stmt = self.socket.prepare("SELECT Applied.Seq, Transitions.Data FROM Applied JOIN Transitions ON Transitions.Key = Applied.Key WHERE Applied.Seq > ?1 ORDER BY Applied.Seq LIMIT ?2")
+            .map_err(|err| Error::Sqlite(err))?;
+
+        let changes = stmt.query_map(&[&(cursor as i64), &(limit as i64)], |row| (row.get::<usize, i64>(0), row.get::<usize, Option<Vec<u8>>>(1)))
+            .map_err(|err| Error::Sqlite(err))?
+            .filter_map(|x| x.ok())
+            .filter_map(|(seq, data)| {
+                let action = deserialize::<TransitionAction>(&data?).ok()?;
+
+                Some((seq as u64, action))
+            })
+            .collect();
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_gossip::{Inspector, Transition, TransitionKey};
+    use rusqlite::Connection;
+
+    use crate::transition::{Storage, TransitionAction};
+    use super::History;
+
+    fn transition(key: u8, action: TransitionAction, refs: Vec<TransitionKey>) -> Transition {
+        Transition {
+            key: TransitionKey([key; 32]),
+            pk: Vec::new(),
+            refs,
+            body: Some(action.to_vec()),
+            sign: [0; 32],
+            state: 2
+        }
+    }
+
+    #[test]
+    fn pending_transitions() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        Connection::open(file.path()).unwrap()
+            .execute_batch(include_str!("create_db.sql")).unwrap();
+
+        let storage = Storage::new(file.path());
+        let history = History::from_file(file.path()).unwrap();
+
+        // the second transition arrives first and waits for its reference
+        let first = transition(1, TransitionAction::DeletePlaylist(1), Vec::new());
+        let second = transition(2, TransitionAction::DeletePlaylist(2), vec![first.key.clone()]);
+
+        storage.store(second);
+        assert_eq!(history.cursor().unwrap(), 0);
+        assert!(history.since(0, 10).unwrap().is_empty());
+
+        // both are applied and numbered in this order
+        storage.store(first);
+        assert_eq!(history.cursor().unwrap(), 2);
+        assert_eq!(history.since(0, 10).unwrap(), vec![
+            (1, TransitionAction::DeletePlaylist(1)),
+            (2, TransitionAction::DeletePlaylist(2))
+        ]);
+        assert_eq!(history.since(1, 10).unwrap(), vec![(2, TransitionAction::DeletePlaylist(2))]);
+    }
+}
@@ -0,0 +1,236 @@
+//! Persistent queue of background jobs
+//!
+//! Uploads, downloads and exports take minutes and should neither depend on the connection which
+//! started them nor get lost when the server restarts. Each job is stored with its parameters and
+//! status in a table of the SQLite database, the progress of running jobs is only known to the
+//! scheduler of the server. Jobs belong to a single peer and are not synchronised.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{self, Connection, Row};
+use bincode::{serialize, deserialize};
+
+use crate::error::{Error, Result};
+use crate::objects::{Job, JobId, JobKind, JobStatus, JobResult};
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Parse a job from a row of `SELECT Id, Kind, Status, Attempts, Error, Result, Owner, Created`
+fn from_row(row: &Row) -> Option<Job> {
+    let kind = deserialize::<JobKind>(&row.get::<usize, Vec<u8>>(1)).ok()?;
+    let status = JobStatus::from_id(row.get(2))?;
+    let result = row.get::<usize, Option<Vec<u8>>>(5)
+        .and_then(|x| deserialize::<JobResult>(&x).ok());
+
+    Some(Job {
+        id: row.get(0),
+        kind, status, result,
+        stage: None,
+        progress: if status == JobStatus::Done { 1.0 } else { 0.0 },
+        attempts: row.get::<usize, i64>(3) as u32,
+        error: row.get(4),
+        owner: row.get(6),
+        created: row.get(7)
+    })
+}
+
+/// Jobs of this peer
+pub struct Jobs {
+    socket: Connection
+}
+
+impl Jobs {
+    /// Open the jobs in a database file, creating the table if necessary
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Jobs> {
+        let socket = Connection::open(path)
+            .map_err(|err| Error::Sqlite(err))?;
+
+        socket.execute_batch(include_str!("create_jobs.sql"))
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(Jobs { socket })
+    }
+
+    /// Queue a new job
+    pub fn add(&self, kind: JobKind, owner: Option<&str>) -> Result<Job> {
+        let data = serialize(&kind)
+            .map_err(|_| Error::Serialize)?;
+
+        self.socket.execute("INSERT INTO Jobs (Kind, Status, Attempts, Owner, Created) VALUES (?1, ?2, 0, ?3, ?4)",
+            &[&data, &JobStatus::Queued.id(), &owner, &now()])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        self.job(self.socket.last_insert_rowid())
+    }
+
+    /// Get a single job
+    pub fn job(&self, id: JobId) -> Result<Job> {
+        self.socket.query_row("SELECT Id, Kind, Status, Attempts, Error, Result, Owner, Created FROM Jobs WHERE Id = ?", &[&id], |row| from_row(row))
+            .map_err(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
+                err => Error::Sqlite(err)
+            })?
+            .ok_or(Error::Serialize)
+    }
+
+    /// Get all jobs, the latest first
+    pub fn jobs(&self) -> Vec<Job> {
+        self.select("SELECT Id, Kind, Status, Attempts, Error, Result, Owner, Created FROM Jobs ORDER BY Id DESC")
+    }
+
+    /// Get all waiting jobs in the order they should be started
+    pub fn queued(&self) -> Vec<Job> {
+        self.select(&format!("SELECT Id, Kind, Status, Attempts, Error, Result, Owner, Created FROM Jobs WHERE Status = {} ORDER BY Id", JobStatus::Queued.id()))
+    }
+
+    fn select(&self, query: &str) -> Vec<Job> {
+        let mut stmt = self.socket.prepare(query).unwrap();
+
+        let jobs = stmt.query_map(&[], |row| from_row(row))
+            .unwrap().filter_map(|x| x.ok()).filter_map(|x| x).collect();
+
+        jobs
+    }
+
+    /// Change the status of a job, if it currently has one of the given states
+    fn transition(&self, id: JobId, from: &[JobStatus], to: JobStatus, error: Option<&str>) -> Result<()> {
+        let status = self.job(id)?.status;
+
+        if !from.contains(&status) {
+            return Err(Error::InvalidState);
+        }
+
+        self.socket.execute("UPDATE Jobs SET Status = ?1, Error = ?2 WHERE Id = ?3", &[&to.id(), &error, &id])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Mark a queued job as running and count the attempt
+    pub fn start(&self, id: JobId) -> Result<Job> {
+        self.transition(id, &[JobStatus::Queued], JobStatus::Running, None)?;
+
+        self.socket.execute("UPDATE Jobs SET Attempts = Attempts + 1 WHERE Id = ?", &[&id])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        self.job(id)
+    }
+
+    /// Remember the failure of a running job
+    ///
+    /// The job is queued again as long as it has made less than `max_attempts` attempts, otherwise
+    /// it fails for good. Returns the new status.
+    pub fn fail(&self, id: JobId, error: &str, max_attempts: u32) -> Result<JobStatus> {
+        let status = if self.job(id)?.attempts < max_attempts {
+            JobStatus::Queued
+        } else {
+            JobStatus::Failed
+        };
+
+        self.transition(id, &[JobStatus::Running], status, Some(error))?;
+
+        Ok(status)
+    }
+
+    /// Finish a running job with its result
+    pub fn finish(&self, id: JobId, result: JobResult) -> Result<()> {
+        let data = serialize(&result)
+            .map_err(|_| Error::Serialize)?;
+
+        self.transition(id, &[JobStatus::Running], JobStatus::Done, None)?;
+
+        self.socket.execute("UPDATE Jobs SET Result = ? WHERE Id = ?", &[&data, &id])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Cancel a job which is not finished yet
+    pub fn cancel(&self, id: JobId) -> Result<()> {
+        self.transition(id, &[JobStatus::Queued, JobStatus::Running], JobStatus::Cancelled, None)
+    }
+
+    /// Queue a failed or cancelled job again, with a fresh number of attempts
+    pub fn retry(&self, id: JobId) -> Result<Job> {
+        self.transition(id, &[JobStatus::Failed, JobStatus::Cancelled], JobStatus::Queued, None)?;
+
+        self.socket.execute("UPDATE Jobs SET Attempts = 0 WHERE Id = ?", &[&id])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        self.job(id)
+    }
+
+    /// Remove finished, failed and cancelled jobs, which were created more than `max_age` seconds
+    /// ago
+    ///
+    /// Returns the number of removed jobs.
+    pub fn prune(&self, max_age: u64) -> Result<usize> {
+        self.socket.execute("DELETE FROM Jobs WHERE Status IN (?1, ?2, ?3) AND Created <= ?4",
+            &[&JobStatus::Done.id(), &JobStatus::Failed.id(), &JobStatus::Cancelled.id(), &(now() - max_age as i64)])
+            .map_err(|err| Error::Sqlite(err))
+    }
+
+    /// Queue all jobs again, which were running when the server stopped
+    ///
+    /// Returns the number of interrupted jobs.
+    pub fn requeue(&self) -> Result<usize> {
+        self.socket.execute("UPDATE Jobs SET Status = ?1 WHERE Status = ?2", &[&JobStatus::Queued.id(), &JobStatus::Running.id()])
+            .map_err(|err| Error::Sqlite(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Jobs;
+    use crate::error::Error;
+    use crate::objects::{JobKind, JobStatus, JobResult, TrackKey};
+
+    #[test]
+    fn lifecycle() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let jobs = Jobs::from_file(file.path()).unwrap();
+
+        let first = jobs.add(JobKind::Youtube { url: "https://example.com/a".into() }, Some("alice")).unwrap();
+        let second = jobs.add(JobKind::Export { format: "mp3".into(), tracks: vec![TrackKey::from_vec(&[1; 16])] }, None).unwrap();
+
+        assert_eq!(first.status, JobStatus::Queued);
+        assert_eq!(first.owner, Some("alice".into()));
+        assert_eq!(jobs.queued().iter().map(|x| x.id).collect::<Vec<_>>(), vec![first.id, second.id]);
+        assert_eq!(jobs.jobs()[0], second);
+
+        // a failed attempt queues the job again, until the limit is reached
+        assert_eq!(jobs.start(first.id).unwrap().attempts, 1);
+        assert_eq!(jobs.fail(first.id, "timeout", 2).unwrap(), JobStatus::Queued);
+        jobs.start(first.id).unwrap();
+        assert_eq!(jobs.fail(first.id, "timeout", 2).unwrap(), JobStatus::Failed);
+        assert_eq!(jobs.job(first.id).unwrap().error, Some("timeout".into()));
+
+        let retried = jobs.retry(first.id).unwrap();
+        assert_eq!((retried.status, retried.attempts, retried.error), (JobStatus::Queued, 0, None));
+
+        // finished jobs can neither be cancelled nor retried
+        jobs.start(second.id).unwrap();
+        jobs.finish(second.id, JobResult::Archive("/data/download/2.tar.gz".into())).unwrap();
+        assert_eq!(jobs.job(second.id).unwrap().result, Some(JobResult::Archive("/data/download/2.tar.gz".into())));
+        assert!(match jobs.cancel(second.id) { Err(Error::InvalidState) => true, _ => false });
+        assert!(match jobs.retry(second.id) { Err(Error::InvalidState) => true, _ => false });
+
+        // interrupted jobs are queued again after a restart
+        jobs.start(first.id).unwrap();
+        assert_eq!(jobs.requeue().unwrap(), 1);
+        assert_eq!(jobs.job(first.id).unwrap().status, JobStatus::Queued);
+
+        jobs.cancel(first.id).unwrap();
+        assert!(jobs.queued().is_empty());
+
+        // only old jobs which ended are removed
+        let third = jobs.add(JobKind::Youtube { url: "https://example.com/b".into() }, None).unwrap();
+        assert_eq!(jobs.prune(3600).unwrap(), 0);
+        assert_eq!(jobs.prune(0).unwrap(), 2);
+        assert_eq!(jobs.jobs().iter().map(|x| x.id).collect::<Vec<_>>(), vec![third.id]);
+    }
+}
@@ -30,6 +30,12 @@ extern crate rusqlite;
 extern crate serde;
 #[cfg(feature="sha2")]
 extern crate sha2;
+#[cfg(feature="hmac")]
+extern crate hmac;
+#[cfg(feature="pbkdf2")]
+extern crate pbkdf2;
+#[cfg(feature="getrandom")]
+extern crate getrandom;
 #[cfg(feature="rusqlite")]
 extern crate hex_gossip;
 #[cfg(feature="rusqlite")]
@@ -38,6 +44,8 @@ extern crate bincode;
 extern crate futures;
 #[cfg(feature="rusqlite")]
 extern crate tokio;
+#[cfg(feature="rusty-chromaprint")]
+extern crate rusty_chromaprint;
 #[macro_use]
 extern crate log;
 
@@ -51,15 +59,35 @@ mod transition;
 mod file;
 #[cfg(feature="rusqlite")]
 mod instance;
+#[cfg(feature="rusqlite")]
+mod accounts;
+#[cfg(feature="rusqlite")]
+mod history;
+#[cfg(feature="rusqlite")]
+mod jobs;
+#[cfg(feature="rusqlite")]
+mod suggestions;
+#[cfg(feature="rusqlite")]
+mod queues;
 mod read;
 mod write;
 
 pub use error::{Result, Error};
 pub use events::{Action, Event};
 pub use transition::TransitionAction;
-pub use objects::{Track, Playlist, Token, TrackKey, PlaylistKey, TokenId};
+pub use objects::{Track, Playlist, Token, TrackKey, PlaylistKey, ImageKey, TokenId, User, Role, Job, JobId, JobKind, JobStatus, JobResult, Suggestion, Queue};
 #[cfg(feature="rusqlite")]
 pub use instance::Instance;
+#[cfg(feature="rusqlite")]
+pub use accounts::Accounts;
+#[cfg(feature="rusqlite")]
+pub use history::History;
+#[cfg(feature="rusqlite")]
+pub use jobs::Jobs;
+#[cfg(feature="rusqlite")]
+pub use suggestions::Suggestions;
+#[cfg(feature="rusqlite")]
+pub use queues::Queues;
 pub use read::Reader;
 pub use write::Writer;
 pub use file::Files;
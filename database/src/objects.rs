@@ -149,6 +149,13 @@ impl Track {
 /// Playlist identification
 pub type PlaylistKey = i64;
 
+/// Image identification
+///
+/// Images are named after the first 16 bytes of the SHA-256 hash of their content. They are
+/// stored next to the audio files and replicated with the same packets, hence the key type of
+/// tracks.
+pub type ImageKey = TrackKey;
+
 /// A single playlist containing many tracks
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
@@ -162,7 +169,9 @@ pub struct Playlist {
     /// Vector of all track keys
     pub tracks: Vec<TrackKey>,
     /// The author of this playlist
-    pub origin: PeerId
+    pub origin: PeerId,
+    /// Cover image of the playlist
+    pub image: Option<ImageKey>
 }
 
 #[cfg(feature = "rusqlite")]
@@ -171,7 +180,8 @@ impl Playlist {
         Playlist {
             key, title, origin,
             desc: None,
-            tracks: Vec::new()
+            tracks: Vec::new(),
+            image: None
         }
     }
 
@@ -185,7 +195,12 @@ impl Playlist {
             title:  row.get_checked(1)?,
             desc:   row.get_checked(2)?,
             tracks: keys,
-            origin: row.get_checked(4)?
+            origin: row.get_checked(4)?,
+            // databases of earlier versions have no images
+            image: row.get_checked::<usize, Option<Vec<u8>>>(5).ok()
+                .and_then(|x| x)
+                .filter(|x| x.len() == 16)
+                .map(|x| TrackKey::from_vec(&x))
         })
     }
 }
@@ -224,6 +239,108 @@ impl Token {
         })
     }
 }
+
+/// Play queue of a user or a device
+///
+/// Unlike tokens the queue is only stored on a single peer. It remembers the current track and
+/// the position in it, so that a client continues where it stopped after a reconnect.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Queue {
+    /// Tracks in the order of playback, the same track may appear several times
+    pub tracks: Vec<TrackKey>,
+    /// Index of the current track
+    pub current: Option<u32>,
+    /// Position of the current track in seconds, like `Token::pos`
+    pub pos: Option<f64>,
+    /// Last change in seconds since the UNIX epoch
+    pub updated: i64
+}
+
+impl Queue {
+    /// Insert tracks before an index or append them, the current track stays the same
+    ///
+    /// Returns `false` if the index is beyond the end of the queue.
+    pub fn enqueue(&mut self, tracks: &[TrackKey], index: Option<u32>) -> bool {
+        let index = index.unwrap_or(self.tracks.len() as u32);
+        if index as usize > self.tracks.len() {
+            return false;
+        }
+
+        for (i, key) in tracks.iter().enumerate() {
+            self.tracks.insert(index as usize + i, *key);
+        }
+
+        if let Some(current) = self.current.as_mut().filter(|x| **x >= index) {
+            *current += tracks.len() as u32;
+        }
+
+        true
+    }
+
+    /// Reorder the queue, the n-th track is taken from the index `order[n]`
+    ///
+    /// Returns `false` if the order is not a permutation of all indices.
+    pub fn reorder(&mut self, order: &[u32]) -> bool {
+        let mut sorted = order.to_vec();
+        sorted.sort();
+
+        if sorted.into_iter().enumerate().any(|(i, x)| i as u32 != x) || order.len() != self.tracks.len() {
+            return false;
+        }
+
+        self.tracks = order.iter().map(|x| self.tracks[*x as usize]).collect();
+        self.current = self.current.and_then(|current| order.iter().position(|x| *x == current)).map(|x| x as u32);
+
+        true
+    }
+
+    /// Remove a single track, removing the current track continues with the next one
+    ///
+    /// Returns `false` if the index is beyond the end of the queue.
+    pub fn remove(&mut self, index: u32) -> bool {
+        if index as usize >= self.tracks.len() {
+            return false;
+        }
+
+        self.tracks.remove(index as usize);
+
+        match self.current {
+            Some(current) if current > index => self.current = Some(current - 1),
+            Some(current) if current == index => {
+                self.pos = None;
+
+                if current as usize == self.tracks.len() {
+                    self.current = None;
+                }
+            },
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Remove all tracks
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.current = None;
+        self.pos = None;
+    }
+
+    /// Set the current track and the position in it
+    ///
+    /// Returns `false` if the index is beyond the end of the queue.
+    pub fn seek(&mut self, current: Option<u32>, pos: Option<f64>) -> bool {
+        if current.map(|x| x as usize >= self.tracks.len()).unwrap_or(false) {
+            return false;
+        }
+
+        self.current = current;
+        self.pos = current.and(pos);
+
+        true
+    }
+}
 pub fn u32_into_u8(mut buf: Vec<u32>) -> Vec<u8> {
     unsafe {
         let ratio = 4;
@@ -288,3 +405,208 @@ pub fn u8_into_i64(mut buf: Vec<u8>) -> Vec<i64> {
     }
 }
 */
+
+/// Role of a user account, every role includes the rights of the roles before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum Role {
+    /// Listen to music, vote for tracks and manage own playlists
+    Listener,
+    /// Upload, edit and delete tracks, manage all playlists and tokens
+    Editor,
+    /// Manage user accounts
+    Admin
+}
+
+impl Role {
+    /// Parse the role from its name
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "listener" => Some(Role::Listener),
+            "editor" => Some(Role::Editor),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    /// Parse the role from its number in the database
+    pub fn from_id(id: i64) -> Option<Role> {
+        match id {
+            0 => Some(Role::Listener),
+            1 => Some(Role::Editor),
+            2 => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    /// Number of the role in the database
+    pub fn id(&self) -> i64 {
+        *self as i64
+    }
+}
+
+/// A user account of the servers
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct User {
+    /// Unique name used to login
+    pub name: String,
+    /// Rights of the user
+    pub role: Role
+}
+
+/// Identification of a background job
+pub type JobId = i64;
+
+/// Work done by a background job
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum JobKind {
+    /// Download a track with youtube-dl and add it to the library
+    Youtube {
+        url: String
+    },
+    /// Convert an uploaded file and add it to the library
+    ///
+    /// The content of the file is stored in the job directory of the data section.
+    Upload {
+        name: String,
+        format: String
+    },
+    /// Convert tracks and pack them in an archive
+    Export {
+        format: String,
+        tracks: Vec<TrackKey>
+    },
+    /// Unpack an uploaded archive and add all audio files with their embedded tags
+    ///
+    /// The archive is stored in the job directory of the data section like an upload.
+    Import {
+        name: String,
+        format: String,
+        /// Create a playlist for each album of the archive
+        playlists: bool
+    },
+    /// Look up the metadata of all tracks without title, album or interpret and apply good matches
+    Enrich
+}
+
+/// Current state of a background job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum JobStatus {
+    /// Waiting for a free slot
+    Queued,
+    Running,
+    /// Failed too often, can be retried by hand
+    Failed,
+    Done,
+    Cancelled
+}
+
+impl JobStatus {
+    /// Parse the status from its number in the database
+    pub fn from_id(id: i64) -> Option<JobStatus> {
+        match id {
+            0 => Some(JobStatus::Queued),
+            1 => Some(JobStatus::Running),
+            2 => Some(JobStatus::Failed),
+            3 => Some(JobStatus::Done),
+            4 => Some(JobStatus::Cancelled),
+            _ => None
+        }
+    }
+
+    /// Number of the status in the database
+    pub fn id(&self) -> i64 {
+        *self as i64
+    }
+
+    /// Check whether the job is finished, successfully or not
+    pub fn is_finished(&self) -> bool {
+        match self {
+            JobStatus::Queued | JobStatus::Running => false,
+            _ => true
+        }
+    }
+}
+
+/// Outcome of a successful job
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum JobResult {
+    /// The track added to the library
+    Track(TrackKey),
+    /// Path of the archive, relative to the webserver
+    Archive(String),
+    /// Tracks and playlists created by an import, with the reason for each skipped file
+    Import {
+        tracks: Vec<TrackKey>,
+        playlists: Vec<PlaylistKey>,
+        skipped: Vec<String>
+    },
+    /// Tracks tagged by an enrichment and the number of tracks without a good match
+    Enrich {
+        tagged: Vec<TrackKey>,
+        unmatched: usize
+    }
+}
+
+/// A persistent background job
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Job {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// Step of a running job, e.g. `decoding`
+    pub stage: Option<String>,
+    /// Progress of the current step between zero and one
+    pub progress: f32,
+    /// Number of started runs
+    pub attempts: u32,
+    /// Reason of the last failure
+    pub error: Option<String>,
+    pub result: Option<JobResult>,
+    /// Name of the user who created the job
+    pub owner: Option<String>,
+    /// Creation time in seconds since the UNIX epoch
+    pub created: i64
+}
+
+/// Metadata of a track suggested by an online database
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Suggestion {
+    /// Name of the backend, e.g. `acoustid`
+    pub source: String,
+    /// MusicBrainz id of the recording, if known
+    pub recording: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub interpret: Option<String>,
+    pub composer: Option<String>,
+    /// Score of the match between zero and one, the confidence of a fingerprint match at AcoustID
+    /// or the similarity of the names at MusicBrainz
+    pub score: f32
+}
+
+impl Suggestion {
+    /// Copy the suggested fields to a track, keeping those without suggestion
+    pub fn apply(&self, track: &mut Track) {
+        track.title = self.title.clone().or(track.title.take());
+        track.album = self.album.clone().or(track.album.take());
+        track.interpret = self.interpret.clone().or(track.interpret.take());
+        track.composer = self.composer.clone().or(track.composer.take());
+    }
+
+    /// Copy the suggested fields to a track, which are missing in the track
+    ///
+    /// Used when suggestions are applied without asking, existing metadata is never replaced.
+    pub fn fill(&self, track: &mut Track) {
+        track.title = track.title.take().or(self.title.clone());
+        track.album = track.album.take().or(self.album.clone());
+        track.interpret = track.interpret.take().or(self.interpret.clone());
+        track.composer = track.composer.take().or(self.composer.clone());
+    }
+}
@@ -0,0 +1,139 @@
+//! Persistent play queues of users and devices
+//!
+//! A client keeps its queue on the server, so that playback continues at the same track and
+//! position after a reconnect or on another device. The queues are stored in a table of the
+//! SQLite database, identified by an owner chosen by the server, for example the name of a user.
+//! Like accounts they belong to a single peer and are not synchronised.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{self, Connection};
+
+use crate::error::{Error, Result};
+use crate::objects::{Queue, TrackKey};
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Stored queues of this peer
+pub struct Queues {
+    socket: Connection
+}
+
+impl Queues {
+    /// Open the queues in a database file, creating the table if necessary
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Queues> {
+        let socket = Connection::open(path)
+            .map_err(|err| Error::Sqlite(err))?;
+
+        socket.execute_batch(include_str!("create_queues.sql"))
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(Queues { socket })
+    }
+
+    /// Get the queue of an owner, which is empty if it was never stored
+    pub fn get(&self, owner: &str) -> Result<Queue> {
+        let res = self.socket.query_row("SELECT Tracks, Current, Pos, Updated FROM Queues WHERE Owner = ?", &[&owner], |row| {
+            let tracks: Vec<u8> = row.get(0);
+
+            Queue {
+                tracks: tracks.chunks(16).map(|x| TrackKey::from_vec(x)).collect(),
+                current: row.get::<usize, Option<i64>>(1).map(|x| x as u32),
+                pos: row.get(2),
+                updated: row.get(3)
+            }
+        });
+
+        match res {
+            Ok(queue) => Ok(queue),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Queue::default()),
+            Err(err) => Err(Error::Sqlite(err))
+        }
+    }
+
+    /// Store the queue of an owner, returns it with the time of the change
+    pub fn set(&self, owner: &str, mut queue: Queue) -> Result<Queue> {
+        let tracks = queue.tracks.iter().map(|x| x.to_vec()).flatten().collect::<Vec<u8>>();
+        queue.updated = now();
+
+        self.socket.execute("INSERT OR REPLACE INTO Queues (Owner, Tracks, Current, Pos, Updated) VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[&owner, &tracks, &queue.current.map(|x| x as i64), &queue.pos, &queue.updated])
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(queue)
+    }
+
+    /// Delete the queue of an owner, e.g. of a deleted user
+    pub fn remove(&self, owner: &str) -> Result<()> {
+        self.socket.execute("DELETE FROM Queues WHERE Owner = ?", &[&owner])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Queues;
+    use crate::objects::{Queue, TrackKey};
+
+    fn key(x: u8) -> TrackKey {
+        TrackKey::from_vec(&[x; 16])
+    }
+
+    #[test]
+    fn storage() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let queues = Queues::from_file(file.path()).unwrap();
+
+        assert_eq!(queues.get("alice").unwrap(), Queue::default());
+
+        let queue = Queue { tracks: vec![key(1), key(2), key(1)], current: Some(2), pos: Some(12.5), updated: 0 };
+        let stored = queues.set("alice", queue.clone()).unwrap();
+        assert!(stored.updated > 0);
+        assert_eq!(queues.get("alice").unwrap(), stored);
+        assert_eq!(queues.get("bob").unwrap(), Queue::default());
+
+        queues.remove("alice").unwrap();
+        assert_eq!(queues.get("alice").unwrap(), Queue::default());
+    }
+
+    #[test]
+    fn changes() {
+        let mut queue = Queue::default();
+
+        assert!(queue.enqueue(&[key(1), key(2)], None));
+        assert!(!queue.enqueue(&[key(3)], Some(3)));
+        assert!(queue.seek(Some(1), Some(30.0)));
+        assert!(!queue.seek(Some(2), None));
+
+        // the current track follows every change
+        assert!(queue.enqueue(&[key(3)], Some(0)));
+        assert_eq!((queue.tracks.clone(), queue.current), (vec![key(3), key(1), key(2)], Some(2)));
+
+        assert!(!queue.reorder(&[0, 1, 1]));
+        assert!(!queue.reorder(&[0, 1]));
+        assert!(queue.reorder(&[2, 0, 1]));
+        assert_eq!((queue.tracks.clone(), queue.current, queue.pos), (vec![key(2), key(3), key(1)], Some(0), Some(30.0)));
+
+        // removing the current track continues with the next one
+        assert!(queue.remove(0));
+        assert_eq!((queue.current, queue.pos), (Some(0), None));
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        assert_eq!(queue.tracks, vec![key(3)]);
+
+        assert!(queue.remove(0));
+        assert_eq!(queue.current, None);
+
+        queue.enqueue(&[key(1)], None);
+        queue.seek(Some(0), Some(1.0));
+        queue.clear();
+        assert_eq!(queue, Queue::default());
+    }
+}
@@ -0,0 +1,122 @@
+//! Local cache of metadata suggestions
+//!
+//! Looking up a track at AcoustID or MusicBrainz takes a request per backend, which are limited
+//! to a few per second. The suggestions of each track are stored in a table of the SQLite database
+//! and reused until they are too old, so that tracks can be tagged again without network access.
+//! Like jobs the cache belongs to a single peer and is not synchronised.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::Connection;
+use bincode::{serialize, deserialize};
+
+use crate::error::{Error, Result};
+use crate::objects::{Suggestion, TrackKey};
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Cached suggestions of this peer
+pub struct Suggestions {
+    socket: Connection
+}
+
+impl Suggestions {
+    /// Open the cache in a database file, creating the table if necessary
+    pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Suggestions> {
+        let socket = Connection::open(path)
+            .map_err(|err| Error::Sqlite(err))?;
+
+        socket.execute_batch(include_str!("create_suggestions.sql"))
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(Suggestions { socket })
+    }
+
+    /// Get the suggestions of a track, if they were stored less than `max_age` seconds ago
+    pub fn get(&self, key: TrackKey, max_age: i64) -> Option<Vec<Suggestion>> {
+        let key = key.to_vec();
+
+        let data: Vec<u8> = self.socket.query_row("SELECT Data FROM Suggestions WHERE Track = ?1 AND Created >= ?2", &[&key, &(now() - max_age)], |row| row.get(0))
+            .ok()?;
+
+        deserialize(&data).ok()
+    }
+
+    /// Store the suggestions of a track, replacing older ones
+    pub fn set(&self, key: TrackKey, suggestions: &[Suggestion]) -> Result<()> {
+        let data = serialize(suggestions)
+            .map_err(|_| Error::Serialize)?;
+
+        self.socket.execute("INSERT OR REPLACE INTO Suggestions (Track, Data, Created) VALUES (?1, ?2, ?3)", &[&key.to_vec(), &data, &now()])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Suggestions;
+    use crate::objects::{Suggestion, Track, TrackKey};
+
+    #[test]
+    fn cache() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let cache = Suggestions::from_file(file.path()).unwrap();
+        let key = TrackKey::from_vec(&[1; 16]);
+
+        let suggestion = Suggestion {
+            source: "acoustid".into(),
+            recording: Some("a1b2".into()),
+            title: Some("Song".into()),
+            album: None,
+            interpret: Some("Band".into()),
+            composer: None,
+            score: 0.9
+        };
+
+        assert_eq!(cache.get(key, 60), None);
+
+        cache.set(key, &[suggestion.clone()]).unwrap();
+        assert_eq!(cache.get(key, 60), Some(vec![suggestion]));
+
+        // an empty result is remembered as well
+        cache.set(key, &[]).unwrap();
+        assert_eq!(cache.get(key, 60), Some(vec![]));
+
+        // outdated entries are ignored
+        assert_eq!(cache.get(key, -1), None);
+        assert_eq!(cache.get(TrackKey::from_vec(&[2; 16]), 60), None);
+    }
+
+    #[test]
+    fn apply_and_fill() {
+        let suggestion = Suggestion {
+            source: "acoustid".into(),
+            recording: None,
+            title: Some("Song".into()),
+            album: Some("Album".into()),
+            interpret: None,
+            composer: None,
+            score: 0.9
+        };
+
+        let mut track = Track::empty(vec![1, 2, 3], 10.0);
+        track.title = Some("Mine".into());
+        track.interpret = Some("Band".into());
+
+        // only missing fields are filled
+        let mut filled = track.clone();
+        suggestion.fill(&mut filled);
+        assert_eq!((filled.title, filled.album, filled.interpret), (Some("Mine".into()), Some("Album".into()), Some("Band".into())));
+
+        // suggested fields replace existing ones
+        suggestion.apply(&mut track);
+        assert_eq!((track.title, track.album, track.interpret), (Some("Song".into()), Some("Album".into()), Some("Band".into())));
+    }
+}
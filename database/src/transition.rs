@@ -7,7 +7,9 @@ use bincode::{serialize, deserialize};
 #[cfg(feature="rusqlite")]
 use hex_gossip::{Inspector, Transition, TransitionKey};
 
-use crate::objects::{self, Track, Playlist, Token, TrackKey, PlaylistKey, TokenId};
+use crate::objects::{self, Track, Playlist, Token, TrackKey, PlaylistKey, ImageKey, TokenId};
+#[cfg(feature="rusqlite")]
+use crate::history;
 
 #[cfg(feature="rusqlite")]
 static UPSERT_TRACK: &str = r#"
@@ -44,17 +46,71 @@ static UPSERT_TOKEN: &str = r#"
 "#;
 
 
+/// Encoding of playlists in transitions
+///
+/// Transitions are stored and exchanged between peers with bincode, which has no optional fields.
+/// Playlists keep the fields of the first version, so that older transitions can still be read.
+/// The cover is changed with `SetPlaylistImage` and never carried by `UpsertPlaylist`.
+mod playlist_v1 {
+    use serde::{Serializer, Deserializer};
+
+    #[cfg(feature="hex-gossip")]
+    use hex_gossip::PeerId;
+    #[cfg(not(feature="hex-gossip"))]
+    use crate::objects::PeerId;
+    use crate::objects::{Playlist, PlaylistKey, TrackKey};
+
+    #[derive(Serialize)]
+    struct PlaylistRef<'a> {
+        key: PlaylistKey,
+        title: &'a String,
+        desc: &'a Option<String>,
+        tracks: &'a Vec<TrackKey>,
+        origin: &'a PeerId
+    }
+
+    #[derive(Deserialize)]
+    struct PlaylistV1 {
+        key: PlaylistKey,
+        title: String,
+        desc: Option<String>,
+        tracks: Vec<TrackKey>,
+        origin: PeerId
+    }
+
+    pub fn serialize<S: Serializer>(playlist: &Playlist, serializer: S) -> Result<S::Ok, S::Error> {
+        let playlist = PlaylistRef {
+            key: playlist.key,
+            title: &playlist.title,
+            desc: &playlist.desc,
+            tracks: &playlist.tracks,
+            origin: &playlist.origin
+        };
+
+        serde::Serialize::serialize(&playlist, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Playlist, D::Error> {
+        let PlaylistV1 { key, title, desc, tracks, origin } = serde::Deserialize::deserialize(deserializer)?;
+
+        Ok(Playlist { key, title, desc, tracks, origin, image: None })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TransitionAction {
     // create either a Token, Playlist or Track
     UpsertTrack(Track),
-    UpsertPlaylist(Playlist),
+    UpsertPlaylist(#[serde(with = "playlist_v1")] Playlist),
     UpsertToken(Token),
 
     // delete either a Token, Playlist or Track by its key
     DeleteTrack(TrackKey),
     DeletePlaylist(PlaylistKey),
     DeleteToken(TokenId),
+
+    // set or remove the cover of a playlist
+    SetPlaylistImage(PlaylistKey, Option<ImageKey>),
 }
 
 #[cfg(feature="rusqlite")]
@@ -100,6 +156,17 @@ impl Storage {
             socket: rusqlite::Connection::open(path).unwrap()
         };
 
+        // playlists of databases created by earlier versions have no image
+        let columns: Vec<String> = storage.socket.prepare("PRAGMA table_info(Playlists)").unwrap()
+            .query_map(&[], |row| row.get(1)).unwrap()
+            .filter_map(|x| x.ok()).collect();
+
+        if !columns.is_empty() && !columns.iter().any(|x| x == "Image") {
+            storage.socket.execute("ALTER TABLE Playlists ADD COLUMN Image BLOB", &[]).unwrap();
+        }
+
+        history::create_table(&storage.socket).unwrap();
+
         {
             // check if we can apply any unfinished transitions
             let mut stmt = storage.socket.prepare("SELECT Key FROM Transitions WHERE State=2").unwrap();
@@ -179,9 +246,13 @@ impl Storage {
 
             TransitionAction::DeleteTrack(track_key) => self.socket.execute("DELETE FROM Tracks WHERE Key=?", &[&track_key.to_vec()]).unwrap(),
             TransitionAction::DeletePlaylist(playlist_key) => self.socket.execute("DELETE FROM Playlists WHERE Key=?", &[&playlist_key]).unwrap(),
-            TransitionAction::DeleteToken(token) => self.socket.execute("DELETE FROM Tokens WHERE token=?", &[&token]).unwrap()
+            TransitionAction::DeleteToken(token) => self.socket.execute("DELETE FROM Tokens WHERE token=?", &[&token]).unwrap(),
+            TransitionAction::SetPlaylistImage(playlist_key, image) => self.socket.execute("UPDATE Playlists SET Image=?1 WHERE Key=?2", &[&image.map(|x| x.to_vec()), &playlist_key]).unwrap()
         };
 
+        // number the transition in the history of applied changes
+        self.socket.execute("INSERT OR IGNORE INTO Applied (Key) VALUES (?)", &[&trans.key.0.as_ref()]).unwrap();
+
         // find references to this transitions and try to apply them too
         let mut stmt = self.socket.prepare("SELECT * FROM Transitions WHERE INSTR(Refs, ?)").unwrap();
 
@@ -284,3 +355,42 @@ impl Inspector for Storage {
     }
 }
 
+#[cfg(all(test, feature="rusqlite"))]
+mod tests {
+    use bincode::serialize;
+
+    use crate::objects::{Playlist, TrackKey};
+    use super::TransitionAction;
+
+    /// Playlist of the first version, without cover
+    #[derive(Serialize)]
+    struct PlaylistV1 {
+        key: i64,
+        title: String,
+        desc: Option<String>,
+        tracks: Vec<TrackKey>,
+        origin: Vec<u8>
+    }
+
+    #[test]
+    fn playlist_transitions() {
+        let key = TrackKey::from_vec(&[3; 16]);
+        let old = PlaylistV1 { key: 4, title: "Road".into(), desc: None, tracks: vec![key], origin: vec![1, 2] };
+
+        // transitions of earlier versions are tagged with the index of the variant
+        let action = TransitionAction::from_vec(&serialize(&(1u32, old)).unwrap());
+        let playlist = match action {
+            TransitionAction::UpsertPlaylist(playlist) => playlist,
+            x => panic!("Decoded {:?}", x)
+        };
+
+        assert_eq!((playlist.key, playlist.tracks.clone(), playlist.image), (4, vec![key], None));
+
+        // the cover is not part of the playlist in transitions
+        let with_image = Playlist { image: Some(key), ..playlist.clone() };
+        assert_eq!(TransitionAction::from_vec(&TransitionAction::UpsertPlaylist(with_image).to_vec()), TransitionAction::UpsertPlaylist(playlist));
+
+        let action = TransitionAction::SetPlaylistImage(4, Some(key));
+        assert_eq!(TransitionAction::from_vec(&action.to_vec()), action);
+    }
+}
@@ -1,36 +1,335 @@
+#[cfg(feature="rusty-chromaprint")]
+use std::fs::File;
+#[cfg(feature="rusty-chromaprint")]
+use std::io::Read;
+#[cfg(not(feature="rusty-chromaprint"))]
 use std::io::Write;
-use std::path::Path;
+#[cfg(not(feature="rusty-chromaprint"))]
 use std::process::Command;
-use std::str;
+use std::path::Path;
+
+#[cfg(feature="rusty-chromaprint")]
+use rusty_chromaprint::Configuration;
 
 use crate::error::*;
+use crate::objects::Track;
+
+/// Sample rate of the raw audio passed to the fingerprinter
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Only the first seconds of a track are fingerprinted, like the default of `fpcalc`
+const FINGERPRINT_LENGTH: usize = 120;
+
+/// Calculates the Chromaprint fingerprint of raw audio while it is passed block by block
+///
+/// Earlier versions called `fpcalc -raw` with the default algorithm and derived the keys of stored
+/// tracks from its output. This uses the same algorithm, but the results are not yet compared with
+/// `fpcalc`, see the ignored test `fpcalc_compatible`.
+#[cfg(feature="rusty-chromaprint")]
+pub struct Fingerprinter {
+    inner: rusty_chromaprint::Fingerprinter,
+    /// Number of interleaved samples until the length limit is reached
+    remaining: usize
+}
+
+#[cfg(feature="rusty-chromaprint")]
+impl Fingerprinter {
+    /// Create a fingerprinter for interleaved samples at 48kHz
+    pub fn new(num_channels: u16) -> Result<Fingerprinter> {
+        if num_channels == 0 {
+            return Err(Error::AcousticId);
+        }
+
+        let mut inner = rusty_chromaprint::Fingerprinter::new(&Configuration::preset_test2());
+        inner.start(SAMPLE_RATE, num_channels as u32)
+            .map_err(|_| Error::AcousticId)?;
+
+        Ok(Fingerprinter {
+            inner,
+            remaining: FINGERPRINT_LENGTH * SAMPLE_RATE as usize * num_channels as usize
+        })
+    }
+
+    /// Pass the next block of interleaved samples
+    pub fn consume(&mut self, data: &[i16]) {
+        let len = data.len().min(self.remaining);
+        if len > 0 {
+            self.inner.consume(&data[..len]);
+            self.remaining -= len;
+        }
+    }
+
+    /// Finish the fingerprint after the last block
+    pub fn finish(mut self) -> Vec<u32> {
+        self.inner.finish();
+
+        self.inner.fingerprint().to_vec()
+    }
+}
+
+/// Calculate the fingerprint of a file with raw audio (16bit, little endian, 48kHz)
+#[cfg(feature="rusty-chromaprint")]
+pub fn fingerprint_from_file(num_channels: u16, raw_path: &Path) -> Result<Vec<u32>> {
+    let mut file = File::open(raw_path)
+        .map_err(|err| Error::Io(err))?;
+
+    let mut printer = Fingerprinter::new(num_channels)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut len = 0;
 
-pub fn fingerprint_from_file(num_channels: u16, raw_path: &Path) -> Result<Vec<u32>> {                                                                                                                              
-    let cmd = Command::new("fpcalc")
-        .arg(raw_path.to_str().unwrap())
-        .arg("-rate").arg("48000")
+    loop {
+        let nread = file.read(&mut buf[len..])
+            .map_err(|err| Error::Io(err))?;
+
+        if nread == 0 {
+            break;
+        }
+
+        len += nread;
+
+        // keep an odd byte for the next block
+        let samples: Vec<i16> = buf[..len / 2 * 2].chunks(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]))
+            .collect();
+
+        printer.consume(&samples);
+
+        if len % 2 == 1 {
+            buf[0] = buf[len - 1];
+        }
+
+        len %= 2;
+    }
+
+    Ok(printer.finish())
+}
+
+/// Calculate the fingerprint of interleaved samples at 48kHz
+#[cfg(feature="rusty-chromaprint")]
+pub fn get_fingerprint(num_channels: u16, data: &[i16]) -> Result<Vec<u32>> {
+    let mut printer = Fingerprinter::new(num_channels)?;
+    printer.consume(data);
+
+    Ok(printer.finish())
+}
+
+/// Calculate the fingerprint of a file with raw audio (16bit, little endian, 48kHz) with `fpcalc`
+#[cfg(not(feature="rusty-chromaprint"))]
+pub fn fingerprint_from_file(num_channels: u16, raw_path: &Path) -> Result<Vec<u32>> {
+    if num_channels == 0 {
+        return Err(Error::AcousticId);
+    }
+
+    let out = Command::new("fpcalc")
+        .arg(raw_path)
+        .arg("-rate").arg(SAMPLE_RATE.to_string())
         .arg("-channels").arg(num_channels.to_string())
+        .arg("-length").arg(FINGERPRINT_LENGTH.to_string())
         .arg("-format").arg("s16le")
         .arg("-plain").arg("-raw")
-        .output().expect("Could not start ffmpeg!");
+        .output()
+        .map_err(|err| Error::Io(err))?;
 
-    let out = str::from_utf8(&cmd.stdout)
-        .map_err(|_| Error::AcousticId)?;
+    if !out.status.success() {
+        return Err(Error::AcousticId);
+    }
 
-    out.trim().split(",").map(|x| x.parse::<u32>().map_err(|_| Error::AcousticId)).collect()
+    parse_fpcalc(&String::from_utf8_lossy(&out.stdout))
 }
 
+/// Calculate the fingerprint of interleaved samples at 48kHz with `fpcalc`
+#[cfg(not(feature="rusty-chromaprint"))]
 pub fn get_fingerprint(num_channels: u16, data: &[i16]) -> Result<Vec<u32>> {
-    let mut file = tempfile::NamedTempFile::new().unwrap();
+    let mut file = tempfile::NamedTempFile::new()
+        .map_err(|err| Error::Io(err))?;
+
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+    file.write_all(&bytes)
+        .map_err(|err| Error::Io(err))?;
+
+    fingerprint_from_file(num_channels, file.path())
+}
+
+/// Parse the output of `fpcalc -plain -raw`, a list of numbers separated by commas
+#[cfg(any(test, not(feature="rusty-chromaprint")))]
+fn parse_fpcalc(out: &str) -> Result<Vec<u32>> {
+    out.trim().split(',').map(|x| x.trim().parse::<u32>().map_err(|_| Error::AcousticId)).collect()
+}
+
+/// Extensions of audio files which are imported from folders and archives
+pub const AUDIO_EXTENSIONS: &[&str] = &["aac", "mp3", "wav", "ogg", "flac", "m4a", "opus"];
+
+/// Tags embedded in an audio file
+///
+/// The tags are read by the `tags` module of the music container. A missing title should be
+/// replaced with the name of the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub interpret: Option<String>,
+    pub people: Option<String>,
+    pub composer: Option<String>,
+    /// Position of the track on its disc
+    pub track: Option<u32>,
+    /// Number of the disc in a release with many discs
+    pub disc: Option<u32>
+}
+
+impl Tags {
+    /// Interpret pairs of tag name and value, as read from an audio file
+    ///
+    /// The first occurence of a tag wins, names are compared without case.
+    pub fn from_pairs<I: IntoIterator<Item = (String, String)>>(pairs: I) -> Tags {
+        let mut tags = Tags::default();
+
+        // numbers are either plain or in the form `3/12`
+        let number = |x: &str| x.split('/').next().and_then(|x| x.trim().parse::<u32>().ok());
+
+        for (name, value) in pairs {
+            let (name, value) = (name.to_lowercase(), value.trim().to_string());
+            if value.is_empty() {
+                continue;
+            }
+
+            match name.as_str() {
+                "title" if tags.title.is_none() => tags.title = Some(value),
+                "album" if tags.album.is_none() => tags.album = Some(value),
+                "artist" | "album_artist" | "albumartist" if tags.interpret.is_none() => tags.interpret = Some(value),
+                "performer" if tags.people.is_none() => tags.people = Some(value),
+                "composer" if tags.composer.is_none() => tags.composer = Some(value),
+                "track" | "tracknumber" if tags.track.is_none() => tags.track = number(&value),
+                "disc" | "discnumber" if tags.disc.is_none() => tags.disc = number(&value),
+                _ => {}
+            }
+        }
+
+        tags
+    }
+
+    /// Copy the tags to the metadata of a track
+    pub fn apply(&self, track: &mut Track) {
+        track.title = self.title.clone().or(track.title.take());
+        track.album = self.album.clone().or(track.album.take());
+        track.interpret = self.interpret.clone().or(track.interpret.take());
+        track.people = self.people.clone().or(track.people.take());
+        track.composer = self.composer.clone().or(track.composer.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tags;
+
+    use super::parse_fpcalc;
+
+    #[cfg(feature="rusty-chromaprint")]
+    use {std::fs, std::io::Write, std::path::Path};
+    #[cfg(feature="rusty-chromaprint")]
+    use super::{Fingerprinter, get_fingerprint, fingerprint_from_file};
+
+    /// Output of `fpcalc -raw` for `melody`, lines starting with `#` are comments
+    #[cfg(feature="rusty-chromaprint")]
+    const FPCALC_MELODY: &str = "tests/fixtures/fpcalc-melody.txt";
+
+    /// Ten seconds of a melody with some noise, in stereo at 48kHz
+    fn melody() -> Vec<i16> {
+        let mut seed = 1u32;
+        (0..48000 * 10).flat_map(|i| {
+            let t = i as f64 / 48000.0;
+            let freq = [440.0, 523.25, 659.25, 783.99][(i / 12000) % 4];
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let noise = (seed >> 16) as f64 / 65536.0 - 0.5;
+
+            let val = ((2.0 * std::f64::consts::PI * freq * t).sin() * 8000.0 + noise * 500.0) as i16;
+            vec![val, val / 2]
+        }).collect()
+    }
+
+    fn pairs(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parse_tags() {
+        let tags = Tags::from_pairs(pairs(&[("encoder", "Lavf58.20.100"), ("TITLE", "Song"), ("ARTIST", "Band"), ("album", "Album"), ("track", "3/12"), ("DISCNUMBER", "2"), ("title", "Other"), ("composer", " ")]));
+
+        assert_eq!(tags.title, Some("Song".into()));
+        assert_eq!(tags.interpret, Some("Band".into()));
+        assert_eq!(tags.album, Some("Album".into()));
+        assert_eq!((tags.track, tags.disc), (Some(3), Some(2)));
+        assert_eq!(tags.composer, None);
+        assert_eq!(Tags::from_pairs(pairs(&[("title", "a=b")])), Tags { title: Some("a=b".into()), ..Tags::default() });
+    }
+
+    #[test]
+    #[cfg(feature="rusty-chromaprint")]
+    fn fingerprint_in_blocks() {
+        let pcm = melody();
+        let fingerprint = get_fingerprint(2, &pcm).unwrap();
+        assert!(fingerprint.len() > 50);
+
+        // the result doesn't depend on the size of the blocks
+        let mut printer = Fingerprinter::new(2).unwrap();
+        for block in pcm.chunks(1001) {
+            printer.consume(block);
+        }
+        assert_eq!(printer.finish(), fingerprint);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for x in &pcm {
+            file.write_all(&x.to_le_bytes()).unwrap();
+        }
+        assert_eq!(fingerprint_from_file(2, file.path()).unwrap(), fingerprint);
+    }
+
+    #[test]
+    #[cfg(feature="rusty-chromaprint")]
+    fn fingerprint_length_limit() {
+        // only the first two minutes count
+        let pcm = melody();
+        let long: Vec<i16> = pcm.iter().cycle().take(pcm.len() * 13).cloned().collect();
+        let longer: Vec<i16> = long.iter().cloned().chain(pcm.iter().map(|x| x / 3)).collect();
+
+        assert_eq!(get_fingerprint(2, &long).unwrap(), get_fingerprint(2, &longer).unwrap());
+        assert!(get_fingerprint(0, &pcm).is_err());
+    }
+
+    /// Fingerprint of `melody` which was calculated by `fpcalc`
+    #[cfg(feature="rusty-chromaprint")]
+    fn fpcalc_melody() -> Vec<u32> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(FPCALC_MELODY);
+        let content = fs::read_to_string(&path).unwrap();
+        let out = content.lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<String>();
+
+        parse_fpcalc(&out).unwrap_or_else(|_| panic!("{} contains no fingerprint, see the comments of the file to create it", path.display()))
+    }
 
-   let v_bytes: &[u8] = unsafe {
-        std::slice::from_raw_parts(
-            data.as_ptr() as *const u8,
-            data.len() * std::mem::size_of::<i16>(),
-        )
-    };
+    /// Write `melody` as raw audio for `fpcalc`, to update the fixture
+    #[test]
+    #[ignore]
+    fn write_melody() {
+        let path = std::env::temp_dir().join("hex-melody.raw");
+        let bytes: Vec<u8> = melody().iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        std::fs::write(&path, bytes).unwrap();
+    }
 
-   file.write(v_bytes).unwrap();
+    #[test]
+    fn parse_fpcalc_output() {
+        assert_eq!(parse_fpcalc("12,3456789\n").unwrap(), vec![12, 3456789]);
+        assert!(parse_fpcalc("ERROR: could not open the input file").is_err());
+        assert!(parse_fpcalc("").is_err());
+    }
 
-   fingerprint_from_file(num_channels, file.path())
+    /// Compare with the fingerprint of `fpcalc`, which calculated the keys of existing tracks
+    ///
+    /// Ignored until the fixture contains the output of `fpcalc`, see the comments of the file.
+    #[test]
+    #[ignore]
+    #[cfg(feature="rusty-chromaprint")]
+    fn fpcalc_compatible() {
+        assert_eq!(get_fingerprint(2, &melody()).unwrap(), fpcalc_melody());
+    }
 }
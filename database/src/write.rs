@@ -0,0 +1,273 @@
+//! Change the database and replicate the changes to other peers
+//!
+//! Tracks, playlists and tokens are never changed directly. Every change is wrapped in a
+//! `TransitionAction`, pushed to the gossip protocol and applied to the local database by the
+//! `Storage` inspector, which also stores it for the peers. Changes which need the previous state,
+//! like adding a track to a playlist, read it from the database first.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{self, Connection};
+use hex_gossip::{Spread, PeerId};
+
+use crate::error::{Error, Result};
+use crate::objects::{Track, Playlist, Token, TrackKey, PlaylistKey, ImageKey, TokenId};
+use crate::transition::{Storage, TransitionAction};
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Write access to the database, created by `Instance::writer`
+pub struct Writer {
+    socket: Connection,
+    gossip: Spread<Storage>,
+    peer_id: PeerId
+}
+
+impl Writer {
+    pub(crate) fn from_file<T: AsRef<Path>>(path: T, gossip: Spread<Storage>, peer_id: PeerId) -> Result<Writer> {
+        let socket = Connection::open(path)
+            .map_err(|err| Error::Sqlite(err))?;
+
+        Ok(Writer { socket, gossip, peer_id })
+    }
+
+    /// Id of this peer, which is the origin of new playlists
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id.clone()
+    }
+
+    /// Apply a change locally and spread it to all peers
+    fn commit(&self, action: TransitionAction) -> Result<()> {
+        self.gossip.push(action.to_vec());
+
+        Ok(())
+    }
+
+    fn track(&self, key: TrackKey) -> Result<Track> {
+        let res = self.socket.query_row("SELECT * FROM Tracks WHERE Key = ?", &[&key.to_vec()], |row| Track::from_row(row));
+
+        match res {
+            Ok(track) => track,
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::NotFound),
+            Err(err) => Err(Error::Sqlite(err))
+        }
+    }
+
+    fn playlist(&self, key: PlaylistKey) -> Result<Playlist> {
+        let res = self.socket.query_row("SELECT * FROM Playlists WHERE Key = ?", &[&key], |row| Playlist::from_row(row));
+
+        match res {
+            Ok(playlist) => playlist,
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::NotFound),
+            Err(err) => Err(Error::Sqlite(err))
+        }
+    }
+
+    fn token(&self, token: TokenId) -> Result<Token> {
+        let res = self.socket.query_row("SELECT * FROM Tokens WHERE Token = ?", &[&token], |row| Token::from_row(row));
+
+        match res {
+            Ok(token) => token,
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::NotFound),
+            Err(err) => Err(Error::Sqlite(err))
+        }
+    }
+
+    /// Add a new track or replace an existing one
+    pub fn add_track(&self, track: Track) -> Result<()> {
+        self.commit(TransitionAction::UpsertTrack(track))
+    }
+
+    /// Update the metadata of a track, fields with `None` stay the same
+    pub fn update_track(&self, key: TrackKey, title: Option<&str>, album: Option<&str>, interpret: Option<&str>, people: Option<&str>, composer: Option<&str>) -> Result<TrackKey> {
+        let mut track = self.track(key)?;
+
+        if let Some(title) = title {
+            track.title = Some(title.into());
+        }
+        if let Some(album) = album {
+            track.album = Some(album.into());
+        }
+        if let Some(interpret) = interpret {
+            track.interpret = Some(interpret.into());
+        }
+        if let Some(people) = people {
+            track.people = Some(people.into());
+        }
+        if let Some(composer) = composer {
+            track.composer = Some(composer.into());
+        }
+
+        self.commit(TransitionAction::UpsertTrack(track))
+            .map(|_| key)
+    }
+
+    pub fn delete_track(&self, key: TrackKey) -> Result<()> {
+        self.commit(TransitionAction::DeleteTrack(key))
+    }
+
+    /// Increase the number of votes of a track
+    pub fn vote_for_track(&self, key: TrackKey) -> Result<()> {
+        let mut track = self.track(key)?;
+        track.favs_count += 1;
+
+        self.commit(TransitionAction::UpsertTrack(track))
+    }
+
+    /// Take back a vote for a track, the number of votes never drops below zero
+    pub fn unvote_for_track(&self, key: TrackKey) -> Result<()> {
+        let mut track = self.track(key)?;
+        track.favs_count = track.favs_count.saturating_sub(1);
+
+        self.commit(TransitionAction::UpsertTrack(track))
+    }
+
+    pub fn add_playlist(&self, playlist: Playlist) -> Result<()> {
+        self.commit(TransitionAction::UpsertPlaylist(playlist))
+    }
+
+    /// Update a playlist, fields with `None` stay the same
+    pub fn update_playlist(&self, key: PlaylistKey, title: Option<String>, desc: Option<String>, tracks: Option<Vec<TrackKey>>) -> Result<()> {
+        let mut playlist = self.playlist(key)?;
+
+        if let Some(title) = title {
+            playlist.title = title;
+        }
+        if let Some(desc) = desc {
+            playlist.desc = Some(desc);
+        }
+        if let Some(tracks) = tracks {
+            playlist.tracks = tracks;
+        }
+
+        self.commit(TransitionAction::UpsertPlaylist(playlist))
+    }
+
+    pub fn delete_playlist(&self, key: PlaylistKey) -> Result<()> {
+        self.commit(TransitionAction::DeletePlaylist(key))
+    }
+
+    pub fn add_to_playlist(&self, key: TrackKey, playlist: PlaylistKey) -> Result<()> {
+        let mut playlist = self.playlist(playlist)?;
+        playlist.tracks.push(key);
+
+        self.commit(TransitionAction::UpsertPlaylist(playlist))
+    }
+
+    pub fn delete_from_playlist(&self, key: TrackKey, playlist: PlaylistKey) -> Result<()> {
+        let mut playlist = self.playlist(playlist)?;
+        playlist.tracks.retain(|x| *x != key);
+
+        self.commit(TransitionAction::UpsertPlaylist(playlist))
+    }
+
+    /// Set or remove the cover of a playlist, the image has to be stored already
+    pub fn set_playlist_image(&self, key: PlaylistKey, image: Option<ImageKey>) -> Result<()> {
+        // check that the playlist exists, peers ignore changes of unknown playlists
+        self.playlist(key)?;
+
+        self.commit(TransitionAction::SetPlaylistImage(key, image))
+    }
+
+    /// Add a new token, returns its id
+    pub fn add_token(&self, token: Token) -> Result<TokenId> {
+        let id = token.token;
+
+        self.commit(TransitionAction::UpsertToken(token))
+            .map(|_| id)
+    }
+
+    /// Update a token, fields with `None` stay the same
+    pub fn update_token(&self, token: TokenId, key: Option<PlaylistKey>, played: Option<Vec<TrackKey>>, pos: Option<f64>) -> Result<()> {
+        let mut token = self.token(token)?;
+
+        if let Some(key) = key {
+            token.key = Some(key);
+        }
+        if let Some(played) = played {
+            token.played = played;
+        }
+        if let Some(pos) = pos {
+            token.pos = Some(pos);
+        }
+
+        token.last_use = now();
+
+        self.commit(TransitionAction::UpsertToken(token))
+    }
+
+    /// Remember that a token was used now
+    pub fn use_token(&self, token: TokenId) -> Result<()> {
+        let mut token = self.token(token)?;
+        token.last_use = now();
+
+        self.commit(TransitionAction::UpsertToken(token))
+    }
+
+    /// Store the number of changes of a day, summaries are not replicated
+    pub fn summarise_day(&self, day: String, transitions: u32, tracks: u32) -> Result<()> {
+        self.socket.execute("INSERT INTO Summary (Day, Transitions, Tracks) VALUES (?1, ?2, ?3)",
+            &[&day, &transitions, &tracks])
+            .map(|_| ())
+            .map_err(|err| Error::Sqlite(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_gossip::GossipConf;
+
+    use crate::{Instance, Playlist, Track, TrackKey};
+
+    #[test]
+    fn votes() {
+        let dir = tempfile::tempdir().unwrap();
+        let instance = Instance::from_file(&dir.path().join("music.db"), GossipConf::new());
+        let (read, write) = (instance.reader(), instance.writer());
+
+        let track = Track::empty(vec![1, 2, 3], 10.0);
+        write.add_track(track.clone()).unwrap();
+
+        write.vote_for_track(track.key).unwrap();
+        write.vote_for_track(track.key).unwrap();
+        write.unvote_for_track(track.key).unwrap();
+        assert_eq!(read.get_track(track.key).unwrap().favs_count, 1);
+
+        // the number of votes stays at zero
+        write.unvote_for_track(track.key).unwrap();
+        write.unvote_for_track(track.key).unwrap();
+        assert_eq!(read.get_track(track.key).unwrap().favs_count, 0);
+
+        assert!(write.unvote_for_track(TrackKey::from_vec(&[3; 16])).is_err());
+    }
+
+    #[test]
+    fn playlist_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let instance = Instance::from_file(&dir.path().join("music.db"), GossipConf::new());
+        let (read, write) = (instance.reader(), instance.writer());
+
+        write.add_playlist(Playlist::new(1, "Covers".into(), write.peer_id())).unwrap();
+
+        let image = TrackKey::from_vec(&[3; 16]);
+        write.set_playlist_image(1, Some(image)).unwrap();
+        assert_eq!(read.get_playlist(1).unwrap().0.image, Some(image));
+
+        // the title is changed without the cover
+        write.update_playlist(1, Some("Albums".into()), None, None).unwrap();
+        let (playlist, _) = read.get_playlist(1).unwrap();
+        assert_eq!((playlist.title.as_str(), playlist.image), ("Albums", Some(image)));
+
+        write.set_playlist_image(1, None).unwrap();
+        assert_eq!(read.get_playlist(1).unwrap().0.image, None);
+
+        // unknown playlists have no cover
+        assert!(write.set_playlist_image(2, Some(image)).is_err());
+    }
+}
@@ -8,6 +8,7 @@ import HeaderAction from 'Component/header_action';
 import Protocol from 'Lib/protocol';
 import Upload from 'Component/upload';
 import Zyklop from 'Component/zyklop';
+import Login from 'Component/login';
 
 export default class Header extends Component {
 	render(props, {tags}) {
@@ -30,9 +31,10 @@ export default class Header extends Component {
                     <Icon icon="search" />
                 </div>
             </div>
-            <HeaderAction icons={["nfc", "file_upload", "info_outline"]}>
+            <HeaderAction icons={["nfc", "file_upload", "account_circle", "info_outline"]}>
                 <Zyklop />
                 <Upload />
+                <Login />
                 <div class={style.about_hex}><img src="/assets/hex.png" /><span>Hey there,<br /><br />I´m Hex, to learn more about me, please visit <a href="http://github.com/bytesnake/hex">Github</a>.</span></div>
             </HeaderAction>
             </Layout.HeaderRow>
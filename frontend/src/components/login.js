@@ -0,0 +1,57 @@
+import {h, Component} from 'preact';
+import {Button, TextField} from 'preact-mdl';
+
+import style from 'Style/login';
+import Protocol from 'Lib/protocol';
+
+export default class Login extends Component {
+    state = {
+        user: null,
+        name: "",
+        password: "",
+        failed: false
+    };
+
+    componentWillMount() {
+        // the session is resumed by the protocol, ask for the votes to find out if it is still valid
+        const name = localStorage.getItem("user");
+        if(name && localStorage.getItem("session"))
+            Protocol.get_votes().then(_ => this.setState({user: name})).catch(_ => {});
+    }
+
+    login = () => {
+        Protocol.login(this.state.name, this.state.password)
+            .then(x => {
+                localStorage.setItem("user", x.user.name);
+                this.setState({user: x.user.name, password: "", failed: false});
+            })
+            .catch(_ => this.setState({failed: true}));
+    }
+
+    logout = () => {
+        Protocol.logout().then(_ => {
+            localStorage.removeItem("user");
+            this.setState({user: null});
+        });
+    }
+
+    render(props, {user, name, password, failed}) {
+        if(user != null) {
+            return (
+                <div class={style.login}>
+                    <span>Angemeldet als {user}</span>
+                    <Button onClick={this.logout}>Abmelden</Button>
+                </div>
+            );
+        }
+
+        return (
+            <div class={style.login}>
+                <TextField label="Name" value={name} onInput={e => this.setState({name: e.target.value})} />
+                <TextField label="Passwort" type="password" value={password} onInput={e => this.setState({password: e.target.value})} onKeyDown={e => e.keyCode == 13 && this.login()} />
+                {failed && (<span class={style.failed}>Falscher Name oder Passwort!</span>)}
+                <Button onClick={this.login}>Anmelden</Button>
+            </div>
+        );
+    }
+}
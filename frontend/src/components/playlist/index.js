@@ -83,6 +83,26 @@ export default class Playlist extends Component {
         Protocol.update_playlist(this.state.playlist[0].key, null, e.target.value);
     }
 
+    change_image = (e) => {
+        const file = e.target.files[0];
+        if(!file)
+            return;
+
+        const reader = new FileReader();
+        reader.onload = event => {
+            const image = Array.from(new Uint8Array(event.target.result));
+
+            Protocol.set_playlist_image(this.state.playlist[0].key, image).then(key => {
+                const playlist = this.state.playlist;
+                playlist[0].image = key;
+
+                this.setState({ playlist });
+            }).catch(err => console.error("Could not set image: " + err));
+        };
+
+        reader.readAsArrayBuffer(file);
+    }
+
     render({}, {pl_key, playlist, updating, downloading}) {
         if(!updating && playlist) {
             const header = playlist[0];
@@ -91,7 +111,11 @@ export default class Playlist extends Component {
             return (
                 <div class={style.playlist}>
                     <div class={style.header}>
-                        <Icon icon="queue music" />
+                        <label class={style.cover}>
+                            { header.image && (<img src={Protocol.image_link(header.image, true)} />) }
+                            { !header.image && (<Icon icon="queue music" />) }
+                            <input type="file" accept="image/*" onChange={this.change_image} />
+                        </label>
                         <div class={style.header_text}>
                             <input type="text" onChange={this.change_title} value={header.title} />
                             <textarea onChange={this.change_desc} value={header.desc} />
@@ -21,21 +21,32 @@ export default class Sidebar extends Component {
             self.setState({playlists: x});
         });
 
-        Protocol.ontransition(action => {
-            if("DeletePlaylist" in action["Transition"]) {
-                let playlists = self.state.playlists.filter(x => x.key != action["Transition"]["DeletePlaylist"]);
-                console.log(playlists)
+        Protocol.subscribe(["Playlists"], event => {
+            // reload everything if too many changes were missed
+            if(event === null) {
+                Protocol.get_playlists().then(playlists => self.setState({playlists}));
+                return;
+            }
+
+            if("PlaylistDeleted" in event) {
+                let playlists = self.state.playlists.filter(x => x.key != event["PlaylistDeleted"]);
+                self.setState({playlists});
+            }
+
+            if("PlaylistImage" in event) {
+                const [key, image] = event["PlaylistImage"];
+                const playlists = self.state.playlists.map(x => x.key == key ? Object.assign({}, x, {image}) : x);
                 self.setState({playlists});
             }
 
-            if("UpsertPlaylist" in action["Transition"]) {
-                const index = self.state.playlists.findIndex(e => e.key == action["Transition"]["UpsertPlaylist"].key);
+            if("Playlist" in event) {
+                const index = self.state.playlists.findIndex(e => e.key == event["Playlist"].key);
 
                 let playlists = self.state.playlists;
                 if(index === -1)
-                    playlists.push(action["Transition"]["UpsertPlaylist"]);
+                    playlists.push(event["Playlist"]);
                 else
-                    playlists[index] = action["Transition"]["UpsertPlaylist"];
+                    playlists[index] = event["Playlist"];
 
                 self.setState({playlists});
             }
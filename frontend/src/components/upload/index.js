@@ -14,6 +14,9 @@ function matchYoutubeUrl(url) {
     return false;
 }
 
+// extensions of archives, which are unpacked by the server
+const ARCHIVES = ["zip", "tar", "tar.gz", "tgz", "tar.bz2", "tar.xz"];
+
 export default class Upload extends Component {
     state = {
         link_empty: true
@@ -33,6 +36,13 @@ export default class Upload extends Component {
         for(const entry of e.target.files) {
             let file = "webkitGetAsEntry" in entry ? entry.webkitGetAsEntry() : entry;
 
+            // archives are imported as a whole, with a playlist for each album
+            const archive = ARCHIVES.find(x => file.name.toLowerCase().endsWith("." + x));
+            if(archive) {
+                Protocol.import_archive(file.name, archive, file.slice(), true);
+                continue;
+            }
+
             var name;
             switch(file.type) {
                 case "audio/mpeg":
@@ -23,12 +23,11 @@ export default class TrackMeta extends Component {
         };
 
         Protocol.get_suggestion(track_key)
-        .then(suggestions => {
-            console.log("Suggestion: " + suggestions);
-            if(suggestions && suggestions[0] && suggestions[0].data)
-                var suggestion = suggestion_flatten(JSON.parse(suggestions[0].data));
+        .then(answer => {
+            const suggestions = answer.suggestions || [];
+            const suggestion = suggestion_flatten(suggestions);
 
-            this.setState({ track, suggestion });
+            this.setState({ track, suggestion, best: suggestions[0] });
         }).catch(e => {
             console.error("Could not get suggestions: " + e);
 
@@ -57,6 +56,13 @@ export default class TrackMeta extends Component {
         
     }
 
+    apply = () => {
+        Protocol.apply_suggestion(this.state.track.key, this.state.best)
+        .then(track => {
+            this.setState({ track });
+        });
+    }
+
     update = (value, kind) => {
         const track = this.state.track;
 
@@ -66,7 +72,7 @@ export default class TrackMeta extends Component {
 
     }
     
-    render({},{track}) {
+    render({},{track, best}) {
         if(!track) return (<div />);
 
         return (
@@ -85,6 +91,9 @@ export default class TrackMeta extends Component {
                     onConfirm={() => this.update(document.getElementById("autocomplete_composer").value, "composer")} displayMenu='overlay' />
 
                 <Button onclick={this.save}>Save</Button>
+                {best && (
+                    <Button onclick={this.apply}>Apply {[best.title, best.interpret].filter(x => x).join(" - ")} ({Math.round(best.score*100)}%)</Button>
+                )}
             </div>
         );
     }
@@ -99,12 +108,18 @@ class TrackItem extends Component {
         const p = Math.floor(progress*100);
         if(kind == "converting_opus")
             return p+"% (convert to opus)";
-        if(kind == "converting_ffmpeg")
-            return p +"% (convert to wav)";
+        if(kind == "decoding")
+            return p + "% (decode audio)";
         if(kind == "youtube_download")
             return p + "% (download from youtube)";
         if(kind == "finished")
             return "Finished";
+        if(kind == "queued")
+            return "Waiting";
+        if(kind == "failed")
+            return "Failed";
+        if(kind == "cancelled")
+            return "Cancelled";
     }
 
     render({idx, desc, kind, progress, track_key}, {show, track}) {
@@ -0,0 +1,68 @@
+// Generated by `hex_server_protocol::typescript`, do not edit
+
+// parameters of every request, in the order of the arguments of the generated functions
+export const CALLS = {
+    Search: ["query"],
+    GetTrack: ["key"],
+    StreamNext: ["key", "binaural", "format"],
+    StreamEnd: [],
+    StreamSeek: ["sample"],
+    UpdateTrack: ["key", "title", "album", "interpret", "people", "composer"],
+    GetSuggestion: ["key"],
+    AddPlaylist: ["name"],
+    DeletePlaylist: ["key"],
+    SetPlaylistImage: ["key", "image"],
+    AddToPlaylist: ["key", "playlist"],
+    DeleteFromPlaylist: ["key", "playlist"],
+    UpdatePlaylist: ["key", "title", "desc"],
+    GetPlaylists: [],
+    GetPlaylist: ["key"],
+    GetPlaylistsOfTrack: ["key"],
+    DeleteTrack: ["key"],
+    UploadYoutube: ["path"],
+    UploadTrack: ["name", "format", "data"],
+    VoteForTrack: ["key"],
+    AskUploadProgress: [],
+    GetToken: ["token"],
+    UpdateToken: ["token", "key", "played", "pos"],
+    CreateToken: [],
+    LastToken: [],
+    GetSummary: [],
+    GetTransitions: [],
+    Download: ["format", "tracks"],
+    AskDownloadProgress: [],
+    Login: ["name", "password"],
+    Authenticate: ["token"],
+    Logout: [],
+    ChangePassword: ["password"],
+    GetUsers: [],
+    AddUser: ["name", "password", "role"],
+    DeleteUser: ["name"],
+    SetUserRole: ["name", "role"],
+    GetVotes: [],
+    Subscribe: ["filter", "cursor"],
+    Unsubscribe: [],
+    GetJobs: [],
+    CancelJob: ["id"],
+    RetryJob: ["id"],
+    BeginUpload: ["name", "format", "size", "hash"],
+    UploadChunk: ["id", "index", "data"],
+    GetUploadSession: ["id"],
+    CommitUpload: ["id"],
+    AbortUpload: ["id"],
+    CommitImport: ["id", "playlists"],
+    ApplySuggestion: ["key", "suggestion"],
+    EnrichTracks: [],
+    Hello: ["version", "capabilities"],
+    GetParties: [],
+    CreateParty: ["name"],
+    JoinParty: ["id", "output"],
+    LeaveParty: [],
+    PartyCommand: ["command"],
+    GetQueue: ["device"],
+    Enqueue: ["tracks", "index"],
+    ReorderQueue: ["order"],
+    Dequeue: ["index"],
+    ClearQueue: [],
+    UpdateQueuePosition: ["current", "pos"]
+};
@@ -0,0 +1,184 @@
+// @ts-check
+
+/**
+ * Typed client of the websocket protocol, speaking JSON in text frames
+ *
+ * The types are generated from `hex_server_protocol`, see `messages.d.ts`. Unlike `protocol.js`
+ * it doesn't need the wasm module, so it works in workers, tests or other applications.
+ *
+ * @typedef {import("./messages").Requests} Requests
+ * @typedef {import("./messages").Responses} Responses
+ * @typedef {import("./messages").AnswerAction} AnswerAction
+ * @typedef {import("./messages").AnswerError} AnswerError
+ * @typedef {import("./messages").Answer} Answer
+ * @typedef {import("./messages").Notification} Notification
+ * @typedef {import("./messages").TransitionAction} TransitionAction
+ */
+
+/** version of the protocol spoken by this client */
+export const VERSION = 1;
+
+// changes of the library are pushed with this id
+const TRANSITION_ID = [0, 0, 0, 0];
+
+/** Error of a failed request, with the typed error of the server */
+export class RequestError extends Error {
+    /** @param {AnswerError} error */
+    constructor(error) {
+        super(typeof error === "string" ? error : JSON.stringify(error));
+        this.error = error;
+    }
+}
+
+export class Client {
+    /**
+     * @param {string} url address of the websocket, e.g. "ws://localhost:8081/ws"
+     * @param {string[]} capabilities optional parts of the protocol, announced with `Hello`
+     */
+    constructor(url, capabilities = []) {
+        this.url = url;
+        this.capabilities = capabilities;
+
+        /** @type {Map<string, [(x: any) => void, (err: RequestError) => void]>} */
+        this.pending = new Map();
+        /** @type {Array<(action: TransitionAction) => void>} */
+        this.transition_fncs = [];
+        /** @type {Map<string, (notification: Notification) => void>} */
+        this.subscribers = new Map();
+
+        /** @type {{version: number, capabilities: string[]} | null} */
+        this.server = null;
+        /** @type {WebSocket | null} */
+        this.socket = null;
+    }
+
+    /**
+     * Connect to the server and negotiate the version
+     *
+     * @returns {Promise<{version: number, capabilities: string[]}>}
+     */
+    connect() {
+        return new Promise((resolve, reject) => {
+            const socket = new WebSocket(this.url, "rust-websocket");
+            socket.onmessage = event => this.message(event.data);
+            socket.onerror = _ => reject(new Error("Could not connect to " + this.url));
+            socket.onclose = _ => this.close_pending();
+            socket.onopen = _ => {
+                this.socket = socket;
+
+                this.request("Hello", {version: VERSION, capabilities: this.capabilities})
+                    .then(server => resolve(this.server = server), reject);
+            };
+        });
+    }
+
+    /** Check whether both sides support a capability, e.g. "opus" */
+    supports(capability) {
+        return this.server !== null && this.server.capabilities.includes(capability);
+    }
+
+    /**
+     * Send a request and wait for its answer
+     *
+     * @template {keyof Requests & keyof Responses} K
+     * @param {K} name name of the request
+     * @param {Requests[K]} params parameters, `null` for requests without any
+     * @param {number[]} [id] reuse the id of an earlier request, e.g. for streams and searches
+     * @returns {Promise<Responses[K]>}
+     */
+    request(name, params, id) {
+        if(this.socket === null || this.socket.readyState != WebSocket.OPEN)
+            return Promise.reject(new Error("Not connected"));
+
+        if(id === undefined)
+            id = dice_id();
+
+        // requests without parameters are written as string
+        const msg = params === null ? name : {[name]: params};
+        const promise = new Promise((resolve, reject) => this.pending.set(id.join(), [resolve, reject]));
+
+        this.socket.send(JSON.stringify({id, msg}));
+
+        return promise;
+    }
+
+    /** @param {(action: TransitionAction) => void} fn called with every change of the library */
+    ontransition(fn) {
+        this.transition_fncs.push(fn);
+    }
+
+    /**
+     * Subscribe to changes, the callback is called with every notification
+     *
+     * @param {Requests["Subscribe"]} params
+     * @param {(notification: Notification) => void} fn
+     */
+    subscribe(params, fn) {
+        const id = dice_id();
+        this.subscribers.set(id.join(), fn);
+
+        return this.request("Subscribe", params, id);
+    }
+
+    /** @param {string} data */
+    message(data) {
+        /** @type {Answer} */
+        const answer = JSON.parse(data);
+        const id = answer.id.join();
+
+        if(id == TRANSITION_ID.join()) {
+            if("Ok" in answer.msg && typeof answer.msg.Ok === "object" && "Transition" in answer.msg.Ok)
+                for(const fn of this.transition_fncs)
+                    fn(answer.msg.Ok.Transition);
+
+            return;
+        }
+
+        // notifications are pushed with the id of the subscription
+        const subscriber = this.subscribers.get(id);
+        if(subscriber && "Ok" in answer.msg && typeof answer.msg.Ok === "object" && "Notification" in answer.msg.Ok) {
+            subscriber(answer.msg.Ok.Notification);
+            return;
+        }
+
+        const pending = this.pending.get(id);
+        if(pending === undefined) {
+            console.error("Got answer without request!");
+            return;
+        }
+
+        this.pending.delete(id);
+        const [resolve, reject] = pending;
+
+        if("Err" in answer.msg)
+            reject(new RequestError(answer.msg.Err));
+        else
+            resolve(payload(answer.msg.Ok));
+    }
+
+    close_pending() {
+        for(const [_, reject] of this.pending.values())
+            reject(new RequestError({"Internal": "Connection closed"}));
+
+        this.pending.clear();
+        this.socket = null;
+        this.server = null;
+    }
+}
+
+/**
+ * Content of an answer, `null` for answers without any
+ *
+ * @param {AnswerAction} action
+ */
+function payload(action) {
+    if(typeof action === "string")
+        return null;
+
+    return Object.values(action)[0];
+}
+
+/** @returns {number[]} random id of a request */
+function dice_id() {
+    return Array.from({length: 4}, () => Math.floor(Math.random() * (2 ** 32)));
+}
@@ -0,0 +1,558 @@
+// Generated by `hex_server_protocol::typescript`, do not edit
+
+export interface Answer {
+    id: Array<number>;
+    msg: Result;
+}
+
+export type AnswerAction =
+    | { SearchResult: { query: string, answ: Array<Track>, more: boolean } }
+    | { Track: Track }
+    | "ClearBuffer"
+    | { StreamNext: Array<number> }
+    | { StreamSeek: { sample: number } }
+    | "StreamEnd"
+    | { UpdateTrack: TrackKey }
+    | { GetSuggestion: { key: TrackKey, suggestions: Array<Suggestion> } }
+    | { AddPlaylist: Playlist }
+    | "DeletePlaylist"
+    | "UpdatePlaylist"
+    | { SetPlaylistImage: TrackKey | null }
+    | "AddToPlaylist"
+    | "DeleteFromPlaylist"
+    | { GetPlaylists: Array<Playlist> }
+    | { GetPlaylist: [Playlist, Array<Track>] }
+    | { GetPlaylistsOfTrack: Array<Playlist> }
+    | { DeleteTrack: null }
+    | "UploadYoutube"
+    | "UploadTrack"
+    | "VoteForTrack"
+    | { AskUploadProgress: Array<UploadProgress> }
+    | { GetToken: [Token, [Playlist, Array<Track>] | null] }
+    | "UpdateToken"
+    | { CreateToken: number }
+    | { LastToken: number | null }
+    | { GetSummary: Array<[string, number, number]> }
+    | { GetTransitions: Array<Transition> }
+    | "Download"
+    | { AskDownloadProgress: Array<DownloadProgress> }
+    | { Transition: TransitionAction }
+    | { Login: { token: string, user: User } }
+    | { Authenticate: User }
+    | "Logout"
+    | { ChangePassword: string }
+    | { GetUsers: Array<User> }
+    | { AddUser: User }
+    | "DeleteUser"
+    | "SetUserRole"
+    | { GetVotes: Array<TrackKey> }
+    | { Subscribe: { missed: Array<Notification>, cursor: number, complete: boolean } }
+    | "Unsubscribe"
+    | { Notification: Notification }
+    | { GetJobs: Array<Job> }
+    | "CancelJob"
+    | { RetryJob: Job }
+    | { BeginUpload: UploadSession }
+    | "UploadChunk"
+    | { GetUploadSession: UploadSession }
+    | { CommitUpload: number }
+    | "AbortUpload"
+    | { CommitImport: number }
+    | { ApplySuggestion: Track }
+    | { EnrichTracks: number }
+    | { Hello: { version: number, capabilities: Array<string> } }
+    | { GetParties: Array<Party> }
+    | { CreateParty: Party }
+    | { JoinParty: Party }
+    | "LeaveParty"
+    | { PartyCommand: Party }
+    | { Party: Party }
+    | { GetQueue: Queue }
+    | { Enqueue: Queue }
+    | { ReorderQueue: Queue }
+    | { Dequeue: Queue }
+    | "ClearQueue"
+    | "UpdateQueuePosition"
+    | { StreamPackets: Array<Array<number>> };
+
+export type AnswerError =
+    | "NotAuthenticated"
+    | "PermissionDenied"
+    | "WrongCredentials"
+    | "NotFound"
+    | "AlreadyExists"
+    | { InvalidRequest: string }
+    | "StreamEnded"
+    | { Upload: string }
+    | { Export: string }
+    | { Image: string }
+    | { Metadata: string }
+    | { Audio: string }
+    | { Database: string }
+    | { Internal: string }
+    | { Incompatible: { version: number, min_version: number } };
+
+export interface DownloadProgress {
+    id: Array<number>;
+    format: string;
+    progress: number;
+    download: string | null;
+}
+
+export type Event =
+    | { Track: Track }
+    | { TrackDeleted: TrackKey }
+    | { Playlist: Playlist }
+    | { PlaylistDeleted: number }
+    | { Token: Token }
+    | { TokenDeleted: number }
+    | { Upload: UploadProgress }
+    | { PlaylistImage: [number, TrackKey | null] };
+
+export interface Job {
+    id: number;
+    kind: JobKind;
+    status: JobStatus;
+    stage: string | null;
+    progress: number;
+    attempts: number;
+    error: string | null;
+    result: JobResult | null;
+    owner: string | null;
+    created: number;
+}
+
+export type JobKind =
+    | { Youtube: { url: string } }
+    | { Upload: { name: string, format: string } }
+    | { Export: { format: string, tracks: Array<TrackKey> } }
+    | { Import: { name: string, format: string, playlists: boolean } }
+    | "Enrich";
+
+export type JobResult =
+    | { Track: TrackKey }
+    | { Archive: string }
+    | { Import: { tracks: Array<TrackKey>, playlists: Array<number>, skipped: Array<string> } }
+    | { Enrich: { tagged: Array<TrackKey>, unmatched: number } };
+
+export type JobStatus =
+    | "Queued"
+    | "Running"
+    | "Failed"
+    | "Done"
+    | "Cancelled";
+
+export interface Notification {
+    cursor: number;
+    event: Event;
+}
+
+export interface Party {
+    id: number;
+    name: string;
+    current: TrackKey | null;
+    queue: Array<QueuedTrack>;
+    playing: boolean;
+    position: number;
+    output: boolean;
+    members: number;
+}
+
+export type PartyCommand =
+    | { Queue: TrackKey }
+    | { Remove: TrackKey }
+    | { Vote: TrackKey }
+    | "Play"
+    | "Pause"
+    | "Skip"
+    | { Seek: number }
+    | { Position: number }
+    | { Ended: TrackKey };
+
+export interface Playlist {
+    key: number;
+    title: string;
+    desc: string | null;
+    tracks: Array<TrackKey>;
+    origin: Array<number>;
+    image: TrackKey | null;
+}
+
+export interface Queue {
+    tracks: Array<TrackKey>;
+    current: number | null;
+    pos: number | null;
+    updated: number;
+}
+
+export interface QueuedTrack {
+    key: TrackKey;
+    votes: number;
+}
+
+export interface Request {
+    id: Array<number>;
+    msg: RequestAction;
+}
+
+export type RequestAction =
+    | { Search: { query: string } }
+    | { GetTrack: { key: TrackKey } }
+    | { StreamNext: { key: TrackKey | null, binaural: boolean, format: StreamFormat } }
+    | "StreamEnd"
+    | { StreamSeek: { sample: number } }
+    | { UpdateTrack: { key: TrackKey, title: string | null, album: string | null, interpret: string | null, people: string | null, composer: string | null } }
+    | { GetSuggestion: { key: TrackKey } }
+    | { AddPlaylist: { name: string } }
+    | { DeletePlaylist: { key: number } }
+    | { SetPlaylistImage: { key: number, image: Array<number> } }
+    | { AddToPlaylist: { key: TrackKey, playlist: number } }
+    | { DeleteFromPlaylist: { key: TrackKey, playlist: number } }
+    | { UpdatePlaylist: { key: number, title: string | null, desc: string | null } }
+    | "GetPlaylists"
+    | { GetPlaylist: { key: number } }
+    | { GetPlaylistsOfTrack: { key: TrackKey } }
+    | { DeleteTrack: { key: TrackKey } }
+    | { UploadYoutube: { path: string } }
+    | { UploadTrack: { name: string, format: string, data: Array<number> } }
+    | { VoteForTrack: { key: TrackKey } }
+    | "AskUploadProgress"
+    | { GetToken: { token: number } }
+    | { UpdateToken: { token: number, key: number | null, played: Array<TrackKey> | null, pos: number | null } }
+    | "CreateToken"
+    | "LastToken"
+    | "GetSummary"
+    | "GetTransitions"
+    | { Download: { format: string, tracks: Array<TrackKey> } }
+    | "AskDownloadProgress"
+    | { Login: { name: string, password: string } }
+    | { Authenticate: { token: string } }
+    | "Logout"
+    | { ChangePassword: { password: string } }
+    | "GetUsers"
+    | { AddUser: { name: string, password: string, role: Role } }
+    | { DeleteUser: { name: string } }
+    | { SetUserRole: { name: string, role: Role } }
+    | "GetVotes"
+    | { Subscribe: { filter: Array<Subscription>, cursor: number | null } }
+    | "Unsubscribe"
+    | "GetJobs"
+    | { CancelJob: { id: number } }
+    | { RetryJob: { id: number } }
+    | { BeginUpload: { name: string, format: string, size: number, hash: string } }
+    | { UploadChunk: { id: string, index: number, data: Array<number> } }
+    | { GetUploadSession: { id: string } }
+    | { CommitUpload: { id: string } }
+    | { AbortUpload: { id: string } }
+    | { CommitImport: { id: string, playlists: boolean } }
+    | { ApplySuggestion: { key: TrackKey, suggestion: Suggestion } }
+    | "EnrichTracks"
+    | { Hello: { version: number, capabilities: Array<string> } }
+    | "GetParties"
+    | { CreateParty: { name: string } }
+    | { JoinParty: { id: number, output: boolean } }
+    | "LeaveParty"
+    | { PartyCommand: { command: PartyCommand } }
+    | { GetQueue: { device: string } }
+    | { Enqueue: { tracks: Array<TrackKey>, index: number | null } }
+    | { ReorderQueue: { order: Array<number> } }
+    | { Dequeue: { index: number } }
+    | "ClearQueue"
+    | { UpdateQueuePosition: { current: number | null, pos: number | null } };
+
+export type Result =
+    | { Ok: AnswerAction }
+    | { Err: AnswerError };
+
+export type Role =
+    | "Listener"
+    | "Editor"
+    | "Admin";
+
+export type StreamFormat =
+    | "Pcm"
+    | "Opus";
+
+export type Subscription =
+    | "Tracks"
+    | { Track: TrackKey }
+    | "Playlists"
+    | { Playlist: number }
+    | "Tokens"
+    | { Token: number }
+    | "Uploads";
+
+export interface Suggestion {
+    source: string;
+    recording: string | null;
+    title: string | null;
+    album: string | null;
+    interpret: string | null;
+    composer: string | null;
+    score: number;
+}
+
+export interface Token {
+    token: number;
+    key: number | null;
+    played: Array<TrackKey>;
+    pos: number | null;
+    last_use: number;
+}
+
+export interface Track {
+    key: TrackKey;
+    fingerprint: Array<number>;
+    title: string | null;
+    album: string | null;
+    interpret: string | null;
+    people: string | null;
+    composer: string | null;
+    duration: number;
+    favs_count: number;
+}
+
+export type TrackKey = Array<number>;
+
+export interface Transition {
+    key: TransitionKey;
+    pk: Array<number>;
+    refs: Array<TransitionKey>;
+    body: Array<number> | null;
+    sign: Array<number>;
+    state: number;
+}
+
+export type TransitionAction =
+    | { UpsertTrack: Track }
+    | { UpsertPlaylist: Playlist }
+    | { UpsertToken: Token }
+    | { DeleteTrack: TrackKey }
+    | { DeletePlaylist: number }
+    | { DeleteToken: number }
+    | { SetPlaylistImage: [number, TrackKey | null] };
+
+export type TransitionKey = Array<number>;
+
+export interface UploadProgress {
+    desc: string;
+    kind: string;
+    progress: number;
+    id: Array<number>;
+    key: TrackKey | null;
+}
+
+export interface UploadSession {
+    id: string;
+    chunk_size: number;
+    chunks: number;
+    received: Array<[number, number]>;
+}
+
+export interface User {
+    name: string;
+    role: Role;
+}
+
+export interface Requests {
+    Search: { query: string };
+    GetTrack: { key: TrackKey };
+    StreamNext: { key: TrackKey | null, binaural: boolean, format: StreamFormat };
+    StreamEnd: null;
+    StreamSeek: { sample: number };
+    UpdateTrack: { key: TrackKey, title: string | null, album: string | null, interpret: string | null, people: string | null, composer: string | null };
+    GetSuggestion: { key: TrackKey };
+    AddPlaylist: { name: string };
+    DeletePlaylist: { key: number };
+    SetPlaylistImage: { key: number, image: Array<number> };
+    AddToPlaylist: { key: TrackKey, playlist: number };
+    DeleteFromPlaylist: { key: TrackKey, playlist: number };
+    UpdatePlaylist: { key: number, title: string | null, desc: string | null };
+    GetPlaylists: null;
+    GetPlaylist: { key: number };
+    GetPlaylistsOfTrack: { key: TrackKey };
+    DeleteTrack: { key: TrackKey };
+    UploadYoutube: { path: string };
+    UploadTrack: { name: string, format: string, data: Array<number> };
+    VoteForTrack: { key: TrackKey };
+    AskUploadProgress: null;
+    GetToken: { token: number };
+    UpdateToken: { token: number, key: number | null, played: Array<TrackKey> | null, pos: number | null };
+    CreateToken: null;
+    LastToken: null;
+    GetSummary: null;
+    GetTransitions: null;
+    Download: { format: string, tracks: Array<TrackKey> };
+    AskDownloadProgress: null;
+    Login: { name: string, password: string };
+    Authenticate: { token: string };
+    Logout: null;
+    ChangePassword: { password: string };
+    GetUsers: null;
+    AddUser: { name: string, password: string, role: Role };
+    DeleteUser: { name: string };
+    SetUserRole: { name: string, role: Role };
+    GetVotes: null;
+    Subscribe: { filter: Array<Subscription>, cursor: number | null };
+    Unsubscribe: null;
+    GetJobs: null;
+    CancelJob: { id: number };
+    RetryJob: { id: number };
+    BeginUpload: { name: string, format: string, size: number, hash: string };
+    UploadChunk: { id: string, index: number, data: Array<number> };
+    GetUploadSession: { id: string };
+    CommitUpload: { id: string };
+    AbortUpload: { id: string };
+    CommitImport: { id: string, playlists: boolean };
+    ApplySuggestion: { key: TrackKey, suggestion: Suggestion };
+    EnrichTracks: null;
+    Hello: { version: number, capabilities: Array<string> };
+    GetParties: null;
+    CreateParty: { name: string };
+    JoinParty: { id: number, output: boolean };
+    LeaveParty: null;
+    PartyCommand: { command: PartyCommand };
+    GetQueue: { device: string };
+    Enqueue: { tracks: Array<TrackKey>, index: number | null };
+    ReorderQueue: { order: Array<number> };
+    Dequeue: { index: number };
+    ClearQueue: null;
+    UpdateQueuePosition: { current: number | null, pos: number | null };
+}
+
+export interface Answers {
+    SearchResult: { query: string, answ: Array<Track>, more: boolean };
+    Track: Track;
+    ClearBuffer: null;
+    StreamNext: Array<number>;
+    StreamSeek: { sample: number };
+    StreamEnd: null;
+    UpdateTrack: TrackKey;
+    GetSuggestion: { key: TrackKey, suggestions: Array<Suggestion> };
+    AddPlaylist: Playlist;
+    DeletePlaylist: null;
+    UpdatePlaylist: null;
+    SetPlaylistImage: TrackKey | null;
+    AddToPlaylist: null;
+    DeleteFromPlaylist: null;
+    GetPlaylists: Array<Playlist>;
+    GetPlaylist: [Playlist, Array<Track>];
+    GetPlaylistsOfTrack: Array<Playlist>;
+    DeleteTrack: null;
+    UploadYoutube: null;
+    UploadTrack: null;
+    VoteForTrack: null;
+    AskUploadProgress: Array<UploadProgress>;
+    GetToken: [Token, [Playlist, Array<Track>] | null];
+    UpdateToken: null;
+    CreateToken: number;
+    LastToken: number | null;
+    GetSummary: Array<[string, number, number]>;
+    GetTransitions: Array<Transition>;
+    Download: null;
+    AskDownloadProgress: Array<DownloadProgress>;
+    Transition: TransitionAction;
+    Login: { token: string, user: User };
+    Authenticate: User;
+    Logout: null;
+    ChangePassword: string;
+    GetUsers: Array<User>;
+    AddUser: User;
+    DeleteUser: null;
+    SetUserRole: null;
+    GetVotes: Array<TrackKey>;
+    Subscribe: { missed: Array<Notification>, cursor: number, complete: boolean };
+    Unsubscribe: null;
+    Notification: Notification;
+    GetJobs: Array<Job>;
+    CancelJob: null;
+    RetryJob: Job;
+    BeginUpload: UploadSession;
+    UploadChunk: null;
+    GetUploadSession: UploadSession;
+    CommitUpload: number;
+    AbortUpload: null;
+    CommitImport: number;
+    ApplySuggestion: Track;
+    EnrichTracks: number;
+    Hello: { version: number, capabilities: Array<string> };
+    GetParties: Array<Party>;
+    CreateParty: Party;
+    JoinParty: Party;
+    LeaveParty: null;
+    PartyCommand: Party;
+    Party: Party;
+    GetQueue: Queue;
+    Enqueue: Queue;
+    ReorderQueue: Queue;
+    Dequeue: Queue;
+    ClearQueue: null;
+    UpdateQueuePosition: null;
+    StreamPackets: Array<Array<number>>;
+}
+
+export interface Responses {
+    Search: Answers["SearchResult"];
+    GetTrack: Answers["Track"];
+    StreamNext: Answers["StreamNext"] | Answers["StreamPackets"];
+    StreamEnd: Answers["StreamEnd"];
+    StreamSeek: Answers["StreamSeek"];
+    UpdateTrack: Answers["UpdateTrack"];
+    GetSuggestion: Answers["GetSuggestion"];
+    AddPlaylist: Answers["AddPlaylist"];
+    DeletePlaylist: Answers["DeletePlaylist"];
+    SetPlaylistImage: Answers["SetPlaylistImage"];
+    AddToPlaylist: Answers["AddToPlaylist"];
+    DeleteFromPlaylist: Answers["DeleteFromPlaylist"];
+    UpdatePlaylist: Answers["UpdatePlaylist"];
+    GetPlaylists: Answers["GetPlaylists"];
+    GetPlaylist: Answers["GetPlaylist"];
+    GetPlaylistsOfTrack: Answers["GetPlaylistsOfTrack"];
+    DeleteTrack: Answers["DeleteTrack"];
+    UploadYoutube: Answers["UploadYoutube"];
+    UploadTrack: Answers["UploadTrack"];
+    VoteForTrack: Answers["VoteForTrack"];
+    AskUploadProgress: Answers["AskUploadProgress"];
+    GetToken: Answers["GetToken"];
+    UpdateToken: Answers["UpdateToken"];
+    CreateToken: Answers["CreateToken"];
+    LastToken: Answers["LastToken"];
+    GetSummary: Answers["GetSummary"];
+    GetTransitions: Answers["GetTransitions"];
+    Download: Answers["Download"];
+    AskDownloadProgress: Answers["AskDownloadProgress"];
+    Login: Answers["Login"];
+    Authenticate: Answers["Authenticate"];
+    Logout: Answers["Logout"];
+    ChangePassword: Answers["ChangePassword"];
+    GetUsers: Answers["GetUsers"];
+    AddUser: Answers["AddUser"];
+    DeleteUser: Answers["DeleteUser"];
+    SetUserRole: Answers["SetUserRole"];
+    GetVotes: Answers["GetVotes"];
+    Subscribe: Answers["Subscribe"];
+    Unsubscribe: Answers["Unsubscribe"];
+    GetJobs: Answers["GetJobs"];
+    CancelJob: Answers["CancelJob"];
+    RetryJob: Answers["RetryJob"];
+    BeginUpload: Answers["BeginUpload"];
+    UploadChunk: Answers["UploadChunk"];
+    GetUploadSession: Answers["GetUploadSession"];
+    CommitUpload: Answers["CommitUpload"];
+    AbortUpload: Answers["AbortUpload"];
+    CommitImport: Answers["CommitImport"];
+    ApplySuggestion: Answers["ApplySuggestion"];
+    EnrichTracks: Answers["EnrichTracks"];
+    Hello: Answers["Hello"];
+    GetParties: Answers["GetParties"];
+    CreateParty: Answers["CreateParty"];
+    JoinParty: Answers["JoinParty"];
+    LeaveParty: Answers["LeaveParty"];
+    PartyCommand: Answers["PartyCommand"];
+    GetQueue: Answers["GetQueue"];
+    Enqueue: Answers["Enqueue"];
+    ReorderQueue: Answers["ReorderQueue"];
+    Dequeue: Answers["Dequeue"];
+    ClearQueue: Answers["ClearQueue"];
+    UpdateQueuePosition: Answers["UpdateQueuePosition"];
+}
@@ -118,6 +118,42 @@ class AudioBuffer {
 
         this.finished = finished;
         this.filling = false;
+
+        // render the sound field for headphones
+        this.binaural = localStorage.getItem("binaural") === "true";
+
+        // transmit compressed packets if the browser can decode them
+        this.format = window.AudioDecoder ? "Opus" : "Pcm";
+    }
+
+    create_decoder() {
+        if(this.decoder)
+            this.decoder.close();
+
+        this.decoder = new AudioDecoder({
+            output: this.decoded.bind(this),
+            error: e => console.error("Could not decode stream: " + e)
+        });
+
+        this.decoder.configure({codec: "opus", sampleRate: 48000, numberOfChannels: 2});
+        this.timestamp = 0;
+    }
+
+    // convert the decoded audio to interleaved 16bit samples of the ring buffer
+    decoded(data) {
+        const frames = data.numberOfFrames;
+        let pcm = new Int16Array(frames * this.channels);
+        let plane = new Float32Array(frames);
+
+        for(let c = 0; c < this.channels; c++) {
+            data.copyTo(plane, {planeIndex: Math.min(c, data.numberOfChannels - 1), format: "f32-planar"});
+
+            for(let i = 0; i < frames; i++)
+                pcm[i * this.channels + c] = Math.max(-32768, Math.min(32767, Math.round(plane[i] * 32768)));
+        }
+
+        data.close();
+        this.buffer.push(pcm.buffer);
     }
 
     next(length) {
@@ -145,11 +181,14 @@ class AudioBuffer {
         this.buffer.clear();
         this.track = track;
 
-        let [stream_next, stream_seek, stream_end] = Protocol.start_stream(track.key);
+        let [stream_next, stream_seek, stream_end] = Protocol.start_stream(track.key, this.binaural, this.format);
         this.stream_next = stream_next;
         this.stream_seek = stream_seek;
         this.stream_end = stream_end;
 
+        if(this.format == "Opus")
+            this.create_decoder();
+
         this.fill_buf();
     }
 
@@ -159,6 +198,29 @@ class AudioBuffer {
 
         this.filling = true;
 
+        if(this.format == "Opus") {
+            this.stream_next().then(packets => {
+                for(const packet of packets) {
+                    this.decoder.decode(new EncodedAudioChunk({type: "key", timestamp: this.timestamp, data: packet}));
+
+                    // every packet contains 40ms
+                    this.timestamp += 40000;
+                    this.pos_loaded += 1920;
+                }
+
+                // wait for the decoder before deciding whether to continue
+                return this.decoder.flush();
+            }).then(_ => {
+                if(this.buffer.should_fill()) {
+                    setImmediate(this.fill_buf.bind(this));
+                } else {
+                    this.filling = false;
+                }
+            });
+
+            return;
+        }
+
         this.stream_next().then(x => {
             this.pos_loaded += x.length / this.channels / 2;
 
@@ -181,6 +243,10 @@ class AudioBuffer {
             this.pos_loaded = new_pos;
             this.buffer.clear();
 
+            // drop packets of the old position still in the decoder
+            if(this.format == "Opus")
+                this.create_decoder();
+
             console.log("Loaded: " + this.pos_loaded);
             this.fill_buf();
         });
@@ -192,6 +258,8 @@ class AudioBuffer {
 }
 
 const PLAY_BUFFER_SIZE = 8192 * 2;
+// interval of storing the position in the queue of the server
+const SAVE_INTERVAL = 5000; // ms
 
 export default class Player {
     constructor(numChannel, new_track_cb, set_playing_cb, set_queue_cb, set_queue_pos_cb) {
@@ -213,6 +281,63 @@ export default class Player {
         this.set_playing_cb = set_playing_cb;
         this.set_queue_cb = set_queue_cb;
         this.set_queue_pos_cb = set_queue_pos_cb;
+
+        // continue with the queue stored on the server
+        Protocol.get_queue().then(this.restore, err => console.error("Could not get queue: " + err));
+        Protocol.onreconnect(this.resume);
+
+        setInterval(_ => {
+            if(this.playing)
+                this.save_position();
+        }, SAVE_INTERVAL);
+    }
+
+    // load the stored queue, without starting playback
+    restore = (stored) => {
+        // tracks added before the answer replace the stored queue
+        if(this.queue.length > 0) {
+            Protocol.clear_queue();
+            Protocol.enqueue(this.queue.map(x => x.key), null);
+            Protocol.update_queue_position(this.queue_pos, this.time);
+            return;
+        }
+
+        if(stored.tracks.length == 0)
+            return;
+
+        Promise.all(stored.tracks.map(x => Protocol.get_track(x))).then(tracks => {
+            // tracks added in the meantime are appended on the server as well
+            this.queue.unshift(...tracks);
+            this.queue_pos = stored.current === null ? 0 : stored.current;
+
+            this.set_queue_cb(this.queue);
+            this.set_queue_pos_cb(this.queue_pos);
+
+            const track = this.queue[this.queue_pos];
+            this.new_track_cb(track);
+            this.buffer.load_track(track);
+
+            if(stored.pos !== null)
+                this.seek(stored.pos);
+        }, err => console.error("Could not restore queue: " + err));
+    }
+
+    // open the stream of the current track again at the same position
+    resume = () => {
+        if(this.queue.length == 0)
+            return;
+
+        const time = this.time;
+        this.buffer.load_track(this.queue[this.queue_pos]);
+        this.seek(time);
+    }
+
+    // remember the current track and position on the server
+    save_position() {
+        if(this.queue.length == 0)
+            return;
+
+        Protocol.update_queue_position(this.queue_pos, this.time);
     }
 
     // forward to audio output
@@ -247,6 +372,8 @@ export default class Player {
 
         this.set_queue_cb(this.queue);
         this.set_queue_pos_cb(this.queue_pos);
+
+        Protocol.clear_queue();
     }
 
     // add a new track to play
@@ -259,6 +386,7 @@ export default class Player {
             tmp = Protocol.get_track(key);
             tmp.then(x => {
                 queue.push(x);
+                Protocol.enqueue([x.key], null);
 
                 this.set_queue_cb(queue);
 
@@ -278,6 +406,7 @@ export default class Player {
 
             tmp.then(x => {
                 queue.push.apply(queue, x);
+                Protocol.enqueue(x.map(track => track.key), null);
 
                 this.set_queue_cb(queue);
 
@@ -309,6 +438,8 @@ export default class Player {
 
         this.playing = false;
         this.processor.disconnect(this.audioContext.destination);
+
+        this.save_position();
     }
 
     seek(pos) {
@@ -328,6 +459,7 @@ export default class Player {
         this.queue_pos ++;
 
         this.set_queue_pos_cb(this.queue_pos);
+        Protocol.update_queue_position(this.queue_pos, 0);
 
         // if we are playing the same track again, just reset the position
         if(this.queue[this.queue_pos].key == this.queue[this.queue_pos-1].key)
@@ -348,6 +480,7 @@ export default class Player {
             this.queue_pos --;
 
             this.set_queue_pos_cb(this.queue_pos);
+            Protocol.update_queue_position(this.queue_pos, 0);
 
             this.new_track_cb(this.queue[this.queue_pos]);
             this.buffer.load_track(this.queue[this.queue_pos]);
@@ -358,30 +491,55 @@ export default class Player {
     }
 
     shuffle_below_current = () => {
+        // shuffle the indices, the server reorders its queue in the same way
+        let order = this.queue.map((_, i) => i);
+
         var j, x, i;
-        for (i = this.queue.length - 1; i > this.queue_pos+1; i--) {
+        for (i = order.length - 1; i > this.queue_pos+1; i--) {
             j = this.queue_pos + 1 + Math.floor(Math.random() * (i - this.queue_pos));
-            x = this.queue[i];
-            this.queue[i] = this.queue[j];
-            this.queue[j] = x;
+            x = order[i];
+            order[i] = order[j];
+            order[j] = x;
         }
 
+        this.queue = order.map(i => this.queue[i]);
         this.set_queue_cb(this.queue);
+
+        Protocol.reorder_queue(order);
     }
 
     set_queue_pos = (new_pos) => {
         this.queue_pos = new_pos;
 
         this.set_queue_pos_cb(this.queue_pos);
+        Protocol.update_queue_position(this.queue_pos, 0);
 
         this.new_track_cb(this.queue[this.queue_pos]);
         this.buffer.load_track(this.queue[this.queue_pos]);
     }
 
     remove_track = (pos) => {
+        // removing the last track while it is current ends the playback
+        const current = pos == this.queue_pos;
+        if(current && pos == this.queue.length - 1)
+            this.stop();
+
         this.queue.splice(pos, 1);
+        Protocol.dequeue(pos);
+
+        // keep the current track like the queue of the server, removing it continues with the next one
+        if(pos < this.queue_pos)
+            this.queue_pos --;
+        else if(current && this.queue_pos == this.queue.length)
+            this.queue_pos = 0;
 
         this.set_queue_cb(this.queue);
+        this.set_queue_pos_cb(this.queue_pos);
+
+        if(current && this.queue.length > 0) {
+            this.new_track_cb(this.queue[this.queue_pos]);
+            this.buffer.load_track(this.queue[this.queue_pos]);
+        }
     }
 
     get time() {
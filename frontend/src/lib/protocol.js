@@ -1,37 +1,47 @@
 import { guid } from './uuid.js'
+import { CALLS } from './calls.js'
 const _proto = import(/* webpackChunkName: "hex_server_protocol" */ './hex_server_protocol');
 
 _proto.catch(x => console.log("REJECT: " + x));
 
-const CALLS = {
-    Search: ["query"],
-    GetTrack: ["key"],
-    StreamNext: ["key"],
-    StreamEnd: [],
-    StreamSeek: ["sample"],
-    UpdateTrack: ["key", "title", "album", "interpret", "people", "composer"],
-    GetSuggestion: ["key"],
-    AddPlaylist: ["name"],
-    DeletePlaylist: ["key"],
-    SetPlaylistImage: ["key"],
-    AddToPlaylist: ["key", "playlist"],
-    DeleteFromPlaylist: ["key", "playlist"],
-    UpdatePlaylist: ["key", "title", "desc"],
-    GetPlaylists: [],
-    GetPlaylist: ["key"],
-    GetPlaylistsOfTrack: ["key"],
-    DeleteTrack: ["key"],
-    UploadYoutube: ["path"],
-    VoteForTrack: ["key"],
-    AskUploadProgress: [],
-    GetToken: ["token"],
-    UpdateToken: ["token", "key", "played", "pos"],
-    CreateToken: [],
-    LastToken: [],
-    GetSummary: [],
-    GetTransitions: [],
-    Download: ["format", "tracks"],
-    AskDownloadProgress: []
+// optional parts of the protocol used by the frontend, announced with `Hello`
+const CAPABILITIES = ["opus", "subscriptions", "chunked-upload", "playlist-covers", "suggestions", "queue"];
+
+// an unanswered chunk is sent again after this time
+const CHUNK_TIMEOUT = 30000;
+// give up after this number of failed chunks in a row
+const CHUNK_RETRIES = 10;
+
+// numbers of the chunks missing in an upload session
+function missing_chunks(session) {
+    let missing = [];
+    for(let i = 0; i < session.chunks; i++)
+        if(!session.received.some(([start, end]) => start <= i && i < end))
+            missing.push(i);
+
+    return missing;
+}
+
+// remember the session, links served over HTTP are authorized with the cookie
+function store_session(token) {
+    localStorage.setItem("session", token);
+
+    const secure = window.location.protocol == "https:" ? "; Secure" : "";
+    document.cookie = "session=" + encodeURIComponent(token) + "; path=/; SameSite=Strict" + secure;
+}
+
+function remove_session() {
+    localStorage.removeItem("session");
+    document.cookie = "session=; path=/; max-age=0";
+}
+
+function read_file(data) {
+    return new Promise((resolve, reject) => {
+        let reader = new FileReader();
+        reader.onload = event => resolve(event.target.result);
+        reader.onerror = reject;
+        reader.readAsArrayBuffer(data);
+    });
 }
 
 let proto = null;
@@ -43,16 +53,60 @@ class Protocol {
         this.pending_requests = {};
         this.transaction_fncs = [];
 
+        // version and capabilities of the server, known after the handshake
+        this.server = null;
+
+        // a single subscription per connection, the filter is the union of all subscribers
+        this.subscription = {id: this.dice_id(), filter: [], cursor: null};
+        this.subscribers = [];
+
+        // the joined party, changes by other members are pushed with the id of the joining request
+        this.party = null;
+
+        // the play queue belongs to this device, unless a user is logged in
+        this.device = localStorage.getItem("device");
+        if(!this.device) {
+            this.device = guid();
+            localStorage.setItem("device", this.device);
+        }
+
+        // called after the connection was lost and opened again
+        this.reconnect_fncs = [];
+        this.connected = false;
+
         // create function calls to the protocol
         for(const call in CALLS) {
             // convert CamelCase to underscore_case for function calls
             const under = call.split(/(?=[A-Z])/).join('_').toLowerCase();
+            // some requests are wrapped by hand, e.g. `subscribe` or `upload_track`
+            if(under in this)
+                continue;
+
             if(CALLS[call].length == 0)
                 this[under] = new Function("", "return this.request('" + call + "', null);");
             else
                 this[under] = new Function(CALLS[call].join(", "), "return this.request('" + call + "', {" + CALLS[call].join(",") + "});");
         }
 
+        // remember the session, so that it can be resumed after reconnecting
+        const login = this.login;
+        this.login = (name, password) => login.call(this, name, password).then(x => {
+            store_session(x.token);
+            return x;
+        });
+
+        const change_password = this.change_password;
+        this.change_password = password => change_password.call(this, password).then(token => {
+            store_session(token);
+            return token;
+        });
+
+        const logout = this.logout;
+        this.logout = () => {
+            remove_session();
+            return logout.call(this);
+        };
+
         _proto.then(x => {
             proto = x;
             self.try_connect();
@@ -62,7 +116,9 @@ class Protocol {
     }
 
     try_connect() {
-        this.socket = new WebSocket('ws://localhost:2794', "rust-websocket");
+        // the webserver forwards websocket connections, use TLS if the page is encrypted
+        const scheme = window.location.protocol == "https:" ? "wss://" : "ws://";
+        this.socket = new WebSocket(scheme + window.location.host + "/ws", "rust-websocket");
         this.socket.binaryType = 'arraybuffer';
 
         let self = this;
@@ -76,6 +132,31 @@ class Protocol {
 
         this.socket.onopen = function() {
             console.log("Connection opened!");
+
+            // announce our version first, a server without handshake just ignores it
+            const hello = self.dice_id();
+            self.pending_requests[hello] = ["Hello",
+                server => self.server = server,
+                err => console.error("Incompatible server: " + err)];
+
+            const buf = proto.request_to_buf(hello, {"Hello": {"version": proto.protocol_version(), "capabilities": CAPABILITIES}});
+            self.socket.send(buf.buffer);
+
+            // resume the session before any other request
+            const session = localStorage.getItem("session");
+            if(session) {
+                const id = self.dice_id();
+                self.pending_requests[id] = ["Authenticate", _ => store_session(session), _ => remove_session()];
+
+                const buf = proto.request_to_buf(id, {"Authenticate": {"token": session}});
+                self.socket.send(buf.buffer);
+            }
+
+            // select the queue of this device, before any queue request
+            const queue = self.dice_id();
+            self.pending_requests[queue] = ["GetQueue", _ => {}, err => console.error("Could not get queue: " + err)];
+            self.socket.send(proto.request_to_buf(queue, {"GetQueue": {"device": self.device}}).buffer);
+
             const buffered = self.buffered_requests.splice(0, self.buffered_requests.length);
 
             for(const idx in buffered) {
@@ -84,6 +165,21 @@ class Protocol {
                 const buf = proto.request_to_buf(id, req);
                 self.socket.send(buf.buffer);
             }
+
+            // resume the subscription and receive the missed changes
+            if(self.subscription.cursor !== null)
+                self.resubscribe();
+
+            // a party forgets its members with the connection
+            if(self.party !== null)
+                self.rejoin().then(self.party.fn, err => console.error("Could not join party again: " + err));
+
+            // running streams are lost as well
+            if(self.connected)
+                for(const fn of self.reconnect_fncs)
+                    fn();
+
+            self.connected = true;
         }
 
         this.socket.onerror = function(err) {
@@ -96,6 +192,77 @@ class Protocol {
         this.transaction_fncs.push(fn);
     }
 
+    onreconnect(fn) {
+        this.reconnect_fncs.push(fn);
+    }
+
+    /// Get the stored play queue of the user or this device
+    get_queue() {
+        return this.request("GetQueue", {"device": this.device});
+    }
+
+    /// Check whether the server announced a capability in the handshake, e.g. "opus"
+    supports(capability) {
+        return this.server !== null && this.server.capabilities.includes(capability);
+    }
+
+    /// Subscribe to changes of the library, e.g. ["Playlists"] or [{"Track": key}]
+    ///
+    /// The callback is called with every event, or with `null` if too many changes were missed
+    /// while disconnected and everything should be reloaded.
+    subscribe(filter, fn) {
+        const known = this.subscription.filter.map(x => JSON.stringify(x));
+        for(const item of filter)
+            if(!known.includes(JSON.stringify(item)))
+                this.subscription.filter.push(item);
+
+        this.subscribers.push(fn);
+
+        return this.resubscribe();
+    }
+
+    resubscribe() {
+        const sub = this.subscription;
+
+        return this.request("Subscribe", {"filter": sub.filter, "cursor": sub.cursor}, sub.id).then(x => {
+            if(!x.complete)
+                for(const fn of this.subscribers)
+                    fn(null);
+
+            for(const notification of x.missed)
+                this.notify(notification);
+
+            sub.cursor = x.cursor;
+        });
+    }
+
+    /// Join a party, the callback is called with the state whenever another member changes it
+    join_party(id, output, fn) {
+        this.party = {id: this.dice_id(), party: id, output: output, fn: fn};
+
+        return this.rejoin();
+    }
+
+    rejoin() {
+        const party = this.party;
+
+        return this.request("JoinParty", {"id": party.party, "output": party.output}, party.id);
+    }
+
+    leave_party() {
+        this.party = null;
+
+        return this.request("LeaveParty", null);
+    }
+
+    notify(notification) {
+        if(this.subscription.cursor === null || notification.cursor > this.subscription.cursor)
+            this.subscription.cursor = notification.cursor;
+
+        for(const fn of this.subscribers)
+            fn(notification.event);
+    }
+
     message(msg) {
         let answ = new proto.Wrapper(new Uint8Array(msg.data));
         
@@ -115,12 +282,44 @@ class Protocol {
             return;
         }
 
+        // changes are pushed with the id of the subscription
+        if(id.join() == this.subscription.id.join()) {
+            const action = answ.action();
+
+            if(typeof action === "object" && "Notification" in action) {
+                this.notify(action["Notification"]);
+                return;
+            }
+        }
+
+        if(this.party !== null && id.join() == this.party.id.join()) {
+            const action = answ.action();
+
+            if(typeof action === "object" && "Party" in action) {
+                this.party.fn(action["Party"]);
+                return;
+            }
+        }
+
         if(this.pending_requests[id] == null) {
             console.error("Got answer without request!");
             return;
         }
         
         const [type, resolve, reject] = this.pending_requests[id];
+
+        // copy Opus packets directly instead of converting them to JSON
+        const num_packets = answ.num_packets();
+        if(num_packets > 0) {
+            const packets = Array.from({length: num_packets}, (_, i) => answ.packet(i));
+
+            answ.free();
+            delete this.pending_requests[id];
+
+            resolve(packets);
+            return;
+        }
+
         let action = answ.action();
         console.log(action);
         console.log(id);
@@ -190,7 +389,81 @@ class Protocol {
         };
     }
 
+    // upload a file in chunks, an interrupted upload continues with the missing chunks
     upload_track(name, format, data) {
+        // hashing is only available in secure contexts, send the whole file otherwise
+        if(!window.crypto || !window.crypto.subtle)
+            return this.upload_track_whole(name, format, data);
+
+        return this.upload_file(name, format, data, id => this.commit_upload(id));
+    }
+
+    // upload a zip or tar archive and import all tracks in it, optionally with a playlist for each album
+    import_archive(name, format, data, playlists) {
+        if(!window.crypto || !window.crypto.subtle)
+            return Promise.reject("Archives can only be uploaded in a secure context");
+
+        return this.upload_file(name, format, data, id => this.commit_import(id, playlists));
+    }
+
+    upload_file(name, format, data, commit) {
+        return read_file(data).then(buf => crypto.subtle.digest("SHA-256", buf).then(hash => {
+            const hex = Array.from(new Uint8Array(hash)).map(x => x.toString(16).padStart(2, "0")).join("");
+
+            return this.begin_upload(name, format, buf.byteLength, hex)
+                .then(session => this.upload_chunks(session, new Uint8Array(buf), missing_chunks(session), 0, commit));
+        }));
+    }
+
+    upload_chunks(session, buf, missing, failures, commit) {
+        if(missing.length == 0)
+            return commit(session.id);
+
+        if(failures >= CHUNK_RETRIES)
+            return Promise.reject("Could not upload " + session.id);
+
+        const index = missing[0];
+        const chunk = buf.subarray(index * session.chunk_size, (index + 1) * session.chunk_size);
+
+        return this.upload_chunk(session.id, index, chunk)
+            .then(_ => this.upload_chunks(session, buf, missing.slice(1), 0, commit))
+            // ask for the missing chunks, this waits until the connection is back
+            .catch(_ => new Promise(resolve => setTimeout(resolve, 1000))
+                .then(_ => this.get_upload_session(session.id))
+                .then(x => this.upload_chunks(session, buf, missing_chunks(x), failures + 1, commit))
+            );
+    }
+
+    upload_chunk(session, index, data) {
+        const id = this.dice_id();
+        const promise = new Promise((resolve, reject) => {
+            this.pending_requests[id] = ["UploadChunk", resolve, reject];
+
+            setTimeout(_ => {
+                if(this.pending_requests[id]) {
+                    delete this.pending_requests[id];
+                    reject("timeout");
+                }
+            }, CHUNK_TIMEOUT);
+        });
+
+        if(!proto || this.socket.readyState != WebSocket.OPEN) {
+            delete this.pending_requests[id];
+            return Promise.reject("not connected");
+        }
+
+        const buf = proto.upload_chunk(id, session, index, data);
+        if(!buf) {
+            delete this.pending_requests[id];
+            return Promise.reject("could not serialize");
+        }
+
+        this.socket.send(buf.buffer);
+
+        return promise;
+    }
+
+    upload_track_whole(name, format, data) {
         const id = this.dice_id();
         const promise = new Promise((resolve, reject) => this.pending_requests[id] = ["UploadTrack", resolve, reject]);
 
@@ -214,7 +487,7 @@ class Protocol {
         return promise;
     }
 
-    start_stream(key) {
+    start_stream(key, binaural = false, format = "Pcm") {
         const id = this.dice_id();
 
         let self = this;
@@ -223,9 +496,9 @@ class Protocol {
             function() {
                 if(first) {
                     first = false;
-                    return self.request("StreamNext", {"key": key}, id);
+                    return self.request("StreamNext", {"key": key, "binaural": binaural, "format": format}, id);
                 } else 
-                    return self.request("StreamNext", {"key": null}, id);
+                    return self.request("StreamNext", {"key": null, "binaural": binaural, "format": format}, id);
             },
             function(sample) {
                 return self.request("StreamSeek", {"sample": sample}, id);
@@ -245,6 +518,13 @@ class Protocol {
         return Promise.all(promises);
     }
 
+    // cover images are served over HTTP, keys are sent as arrays of bytes
+    image_link(key, thumbnail) {
+        const hex = key.map(x => x.toString(16).padStart(2, "0")).join("").toUpperCase();
+
+        return "/" + (thumbnail ? "thumbnails" : "images") + "/" + hex + ".jpg";
+    }
+
     upload_tracks(tracks) {
         let promises = [];
         for(const track of tracks) {
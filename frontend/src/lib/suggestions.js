@@ -1,26 +1,19 @@
-export default function suggestion_flatten(obj) {
+// collect the distinct values of all suggestions, the best first
+export default function suggestion_flatten(suggestions) {
     var titles = [];
     var albums = [];
     var artists = [];
 
-    for(var item of obj) {
-        if(item.recordings && item.recordings.length > 0)
-            for(var recording of item.recordings) {
-                if(recording.title && titles.indexOf(recording.title) == -1)
-                    titles.push(recording.title);
+    for(var item of suggestions) {
+        if(item.title && titles.indexOf(item.title) == -1)
+            titles.push(item.title);
 
-                if(recording.artists)
-                    for(var artist of recording.artists) {
-                        if(artist.name && artists.indexOf(artist.name) == -1)
-                            artists.push(artist.name);
-                    }
+        if(item.album && albums.indexOf(item.album) == -1)
+            albums.push(item.album);
 
-                if(recording.releasegroups)
-                    for(var group of recording.releasegroups) {
-                        if(group.title && albums.indexOf(group.title) == -1)
-                            albums.push(group.title);
-                    }
-            }
+        for(var artist of [item.interpret, item.composer])
+            if(artist && artists.indexOf(artist) == -1)
+                artists.push(artist);
     }
 
     return {
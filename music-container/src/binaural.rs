@@ -0,0 +1,270 @@
+//! Binaural rendering of spherical harmonics
+//!
+//! The sound field is decoded to a set of virtual loudspeakers surrounding the listener, each of
+//! them filtered with the head related impulse responses (HRIR) of its direction. Both steps are
+//! linear, therefore they are combined to a single pair of filters per harmonic.
+//!
+//! The bundled HRTF set is calculated from the spherical head model of Brown and Duda ("A
+//! structural model for binaural sound synthesis", 1998). It models the interaural time
+//! difference and the head shadow, but no pinna cues. Without them sources above or below the
+//! listener can't be told apart from the horizontal plane, and sources in the front from those in
+//! the back. A measured set can be used instead with `Hrtf::new`.
+
+use std::f64::consts::PI;
+
+use crate::error::{Error, Result};
+use crate::spherical::{self, Direction};
+
+/// Length of a single head related impulse response
+pub const HRIR_LENGTH: usize = 96;
+
+/// Highest SH order rendered binaurally, higher orders are truncated
+const MAX_BINAURAL_ORDER: u8 = 2;
+
+/// Radius of the average human head in meters
+const HEAD_RADIUS: f64 = 0.0875;
+
+/// Speed of sound in meters per second
+const SPEED_OF_SOUND: f64 = 343.0;
+
+/// A set of head related impulse responses
+#[derive(Debug, Clone)]
+pub struct Hrtf {
+    directions: Vec<Direction>,
+    left: Vec<Vec<f64>>,
+    right: Vec<Vec<f64>>
+}
+
+impl Hrtf {
+    /// Create a HRTF set from impulse responses measured in certain directions
+    ///
+    /// The directions should cover the whole sphere evenly, because they are used as virtual
+    /// loudspeakers. Every impulse response has to be sampled with 48kHz. Returns
+    /// `Error::InvalidHrtf` if there are no directions or not a pair of impulse responses for each.
+    pub fn new(directions: Vec<Direction>, left: Vec<Vec<f64>>, right: Vec<Vec<f64>>) -> Result<Hrtf> {
+        if directions.is_empty() || directions.len() != left.len() || directions.len() != right.len() {
+            return Err(Error::InvalidHrtf);
+        }
+
+        Ok(Hrtf { directions, left, right })
+    }
+
+    /// Calculate a HRTF set with the spherical head model
+    ///
+    /// The virtual loudspeakers are placed in three rings at -45°, 0° and 45° elevation and at
+    /// the poles, which is sufficient for decoding up to the second order.
+    pub fn spherical_head(sample_rate: u32) -> Hrtf {
+        let mut directions = Vec::new();
+        for i in 0..8 {
+            directions.push(Direction::horizontal(i as f64 * 45.0));
+        }
+        for i in 0..4 {
+            directions.push(Direction::new(45.0 + i as f64 * 90.0, 45.0));
+            directions.push(Direction::new(i as f64 * 90.0, -45.0));
+        }
+        directions.push(Direction::new(0.0, 90.0));
+        directions.push(Direction::new(0.0, -90.0));
+
+        // the left ear points to 90° azimuth, the right one to -90°
+        let left = directions.iter().map(|dir| head_model(sample_rate, *dir, 90.0)).collect();
+        let right = directions.iter().map(|dir| head_model(sample_rate, *dir, -90.0)).collect();
+
+        Hrtf { directions, left, right }
+    }
+}
+
+impl Default for Hrtf {
+    fn default() -> Hrtf {
+        Hrtf::spherical_head(48000)
+    }
+}
+
+/// Impulse response of a single ear after the spherical head model
+fn head_model(sample_rate: u32, dir: Direction, ear_azimuth: f64) -> Vec<f64> {
+    let fs = sample_rate as f64;
+
+    // angle of incidence between the source and the ear axis
+    let (az, el) = (dir.azimuth.to_radians(), dir.elevation.to_radians());
+    let ear = ear_azimuth.to_radians();
+    let cos_theta = (el.cos() * az.cos() * ear.cos() + el.cos() * az.sin() * ear.sin()).max(-1.0).min(1.0);
+    let theta = cos_theta.acos();
+
+    // time of arrival relative to the earliest possible arrival
+    let delay = if theta < PI / 2.0 {
+        HEAD_RADIUS / SPEED_OF_SOUND * (1.0 - cos_theta)
+    } else {
+        HEAD_RADIUS / SPEED_OF_SOUND * (1.0 + theta - PI / 2.0)
+    } * fs;
+
+    // head shadow as one-pole/one-zero filter, boosting the near and damping the far side
+    let (alpha_min, theta_min) = (0.1, 150.0f64.to_radians());
+    let alpha = (1.0 + alpha_min / 2.0) + (1.0 - alpha_min / 2.0) * (theta / theta_min * PI).cos();
+
+    // bilinear transform of (alpha s + 2 w0) / (s + 2 w0)
+    let (w0, k) = (SPEED_OF_SOUND / HEAD_RADIUS, 2.0 * fs);
+    let a0 = k + 2.0 * w0;
+    let (b0, b1, a1) = ((alpha * k + 2.0 * w0) / a0, (2.0 * w0 - alpha * k) / a0, (2.0 * w0 - k) / a0);
+
+    // fractionally delayed impulse, the delay exceeds the response at very high sample rates
+    let delay = delay.min((HRIR_LENGTH - 2) as f64);
+    let mut impulse = vec![0.0; HRIR_LENGTH];
+    let pos = delay.floor() as usize;
+    let frac = delay - delay.floor();
+    impulse[pos] = 1.0 - frac;
+    impulse[pos + 1] = frac;
+
+    let mut hrir = vec![0.0; HRIR_LENGTH];
+    let (mut x1, mut y1) = (0.0, 0.0);
+    for (x, y) in impulse.into_iter().zip(hrir.iter_mut()) {
+        *y = b0 * x + b1 * x1 - a1 * y1;
+        x1 = x;
+        y1 = *y;
+    }
+
+    hrir
+}
+
+/// Binaural renderer keeping the filter state between blocks
+pub struct Binaural {
+    hrtf: Hrtf,
+    /// SH order the filters were built for
+    order: Option<u8>,
+    /// Left and right filter of each harmonic
+    filters: Vec<(Vec<f64>, Vec<f64>)>,
+    /// The last `HRIR_LENGTH - 1` samples of each harmonic
+    history: Vec<Vec<f64>>
+}
+
+impl Binaural {
+    /// Create a new renderer with a HRTF set
+    pub fn new(hrtf: Hrtf) -> Binaural {
+        Binaural {
+            hrtf,
+            order: None,
+            filters: Vec::new(),
+            history: Vec::new()
+        }
+    }
+
+    /// Combine the virtual loudspeaker decoder and HRIRs to filters for each harmonic
+    fn build_filters(&mut self, order: u8) {
+        let num_harmonics = spherical::num_harmonics(order);
+        let decoder = spherical::decoder_matrix(order, &self.hrtf.directions);
+
+        self.filters = (0..num_harmonics).map(|h| {
+            let mut left = vec![0.0; HRIR_LENGTH];
+            let mut right = vec![0.0; HRIR_LENGTH];
+
+            for v in 0..self.hrtf.directions.len() {
+                let gain = decoder[v * num_harmonics + h];
+
+                for k in 0..HRIR_LENGTH.min(self.hrtf.left[v].len()) {
+                    left[k] += gain * self.hrtf.left[v][k];
+                }
+                for k in 0..HRIR_LENGTH.min(self.hrtf.right[v].len()) {
+                    right[k] += gain * self.hrtf.right[v][k];
+                }
+            }
+
+            (left, right)
+        }).collect();
+
+        self.history = vec![vec![0.0; HRIR_LENGTH - 1]; num_harmonics];
+        self.order = Some(order);
+    }
+
+    /// Render a block of harmonics to interleaved left and right channels
+    ///
+    /// The harmonics are given blockwise with `samples` samples for each harmonic.
+    pub fn render(&mut self, order: u8, harmonics: &[f64], samples: usize) -> Vec<f64> {
+        let order = order.min(MAX_BINAURAL_ORDER);
+
+        if self.order != Some(order) {
+            self.build_filters(order);
+        }
+
+        let mut out = vec![0.0; samples * 2];
+
+        for (h, (left, right)) in self.filters.iter().enumerate() {
+            // prepend the history of the last block
+            let mut input = self.history[h].clone();
+            input.extend_from_slice(&harmonics[h * samples..(h + 1) * samples]);
+
+            for j in 0..samples {
+                let (mut acc_left, mut acc_right) = (0.0, 0.0);
+                for k in 0..HRIR_LENGTH {
+                    let x = input[j + HRIR_LENGTH - 1 - k];
+                    acc_left += left[k] * x;
+                    acc_right += right[k] * x;
+                }
+
+                out[j * 2] += acc_left;
+                out[j * 2 + 1] += acc_right;
+            }
+
+            self.history[h] = input[input.len() - (HRIR_LENGTH - 1)..].to_vec();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Binaural, Hrtf};
+    use crate::spherical::{self, Direction};
+
+    /// Energy of the left and right ear for a noise burst from a direction
+    fn ear_energy(dir: Direction) -> (f64, f64) {
+        let mut renderer = Binaural::new(Hrtf::default());
+        let samples = 1920;
+
+        let coeffs = spherical::coefficients(1, dir);
+        let signal: Vec<f64> = (0..samples).map(|i| ((i * 7919) % 200) as f64 - 100.0).collect();
+
+        let mut harmonics = vec![0.0; 4 * samples];
+        for h in 0..4 {
+            for j in 0..samples {
+                harmonics[h * samples + j] = coeffs[h] * signal[j];
+            }
+        }
+
+        let out = renderer.render(1, &harmonics, samples);
+
+        out.chunks(2).fold((0.0, 0.0), |(l, r), x| (l + x[0] * x[0], r + x[1] * x[1]))
+    }
+
+    #[test]
+    fn lateralisation() {
+        // a source on the left is louder on the left ear
+        let (left, right) = ear_energy(Direction::horizontal(90.0));
+        assert!(left > right * 1.2);
+
+        // and mirrored for a source on the right
+        let (left_mirror, right_mirror) = ear_energy(Direction::horizontal(-90.0));
+        assert!((left - right_mirror).abs() < 1e-6 * left);
+        assert!((right - left_mirror).abs() < 1e-6 * left);
+
+        let (left, right) = ear_energy(Direction::horizontal(0.0));
+        assert!((left - right).abs() < 0.01 * left);
+    }
+
+    #[test]
+    fn measured_set() {
+        let directions = vec![Direction::horizontal(90.0), Direction::horizontal(-90.0)];
+        let (near, far) = (vec![1.0, 0.5], vec![0.0, 0.25]);
+
+        assert!(Hrtf::new(directions.clone(), vec![near.clone(), far.clone()], vec![far.clone(), near.clone()]).is_ok());
+        assert!(Hrtf::new(directions.clone(), vec![near.clone()], vec![far.clone(), near.clone()]).is_err());
+        assert!(Hrtf::new(directions, vec![near.clone(), far.clone()], vec![far]).is_err());
+        assert!(Hrtf::new(Vec::new(), Vec::new(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn high_sample_rate() {
+        // the interaural delay of a source behind the ear is longer than the impulse response
+        let hrtf = Hrtf::spherical_head(384000);
+
+        assert!(hrtf.left.iter().chain(hrtf.right.iter()).all(|x| x.iter().all(|y| y.is_finite())));
+    }
+}
@@ -2,28 +2,110 @@
 //!
 //! This module is used in `hex_music_container` to describe the loudspeaker configuration of raw
 //! audio. It can also be disabled in case the raw audio is already in SH format.
+//!
+//! ## Channel ordering
+//!
+//! Raw audio is always interleaved and the channels of each frame are expected in the following
+//! order:
+//!
+//! | Configuration          | Channels                                        |
+//! |------------------------|-------------------------------------------------|
+//! | `Omnidirectional`      | M                                               |
+//! | `Stereo`, `Binaural`   | L, R                                            |
+//! | `Surround51`           | L, R, C, LFE, Ls, Rs                            |
+//! | `Surround71`           | L, R, C, LFE, Lb, Rb, Ls, Rs                    |
+//! | `SphericalHarmonics(n)`| ACN 0 .. (n+1)², SN3D normalised (AmbiX)        |
+//! | `Loudspeakers(..)`     | in the order of the given speakers              |
+//!
+//! The loudspeakers are placed after ITU-R BS.775 (front at ±30°, center at 0°, surround at
+//! ±110°) and for 7.1 with the side pair at ±90° and the back pair at ±135°. The LFE channel has
+//! no direction and is folded into the omnidirectional component while encoding.
+//!
+//! ## Decoding
+//!
+//! Loudspeaker layouts are decoded with a mode-matching decoder (see
+//! `spherical::decoder_matrix`), the LFE channel stays silent and should be fed by the bass
+//! management of the receiver. `Binaural` renders the sound field for headphones with a HRTF set,
+//! see the `binaural` module.
 
 use crate::error::{Error, Result};
+use crate::spherical::{self, Direction};
+use crate::binaural::{Binaural, Hrtf};
 
 /// A loudspeaker configuration
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Configuration {
     /// One channel describes sound coming from all directions at the same time
     Omnidirectional,
     /// Two channels contains sound coming from the left and right direction
     Stereo,
+    /// Six channels of a 5.1 surround setup
+    Surround51,
+    /// Eight channels of a 7.1 surround setup
+    Surround71,
     /// Binaural coding (only useful in decoding, not encoding)
     Binaural,
-    /// Spherical Harmonic format
-    SphericalHarmonics(u8)
+    /// Spherical Harmonic format with ACN ordering and SN3D normalisation (AmbiX)
+    SphericalHarmonics(u8),
+    /// Arbitrary loudspeaker layout, one channel for each speaker
+    Loudspeakers(Vec<Speaker>)
+}
+
+/// A single loudspeaker of a configuration
+#[derive(Clone, Debug, PartialEq)]
+pub enum Speaker {
+    /// A loudspeaker placed in a certain direction
+    Directional(Direction),
+    /// The low frequency channel, which is not directional
+    LowFrequency
 }
 
 impl Configuration {
+    /// Parse a configuration by its name
+    ///
+    /// Known names are `mono`, `stereo`, `5.1`, `7.1`, `binaural` and `ambix1` to `ambix6`. The
+    /// name `custom` creates a layout from pairs of azimuth and elevation in degrees.
+    pub fn from_name(name: &str, speakers: &[(f64, f64)]) -> Option<Configuration> {
+        let conf = match name {
+            "mono" => Configuration::Omnidirectional,
+            "stereo" => Configuration::Stereo,
+            "5.1" => Configuration::Surround51,
+            "7.1" => Configuration::Surround71,
+            "binaural" => Configuration::Binaural,
+            "custom" if !speakers.is_empty() => Configuration::Loudspeakers(
+                speakers.iter().map(|(az, el)| Speaker::Directional(Direction::new(*az, *el))).collect()
+            ),
+            _ if name.starts_with("ambix") => {
+                let order = name[5..].parse().ok()?;
+                if order == 0 || order > spherical::MAX_ORDER {
+                    return None;
+                }
+
+                Configuration::SphericalHarmonics(order)
+            },
+            _ => return None
+        };
+
+        Some(conf)
+    }
+
+    /// Choose a loudspeaker configuration for a number of output channels
+    ///
+    /// Devices with one channel are fed the omnidirectional component, two channels are assumed
+    /// to be stereo and six or eight channels a surround setup. Other devices are not supported.
+    pub fn for_channels(num: usize) -> Result<Configuration> {
+        match num {
+            1 => Ok(Configuration::Omnidirectional),
+            2 => Ok(Configuration::Stereo),
+            6 => Ok(Configuration::Surround51),
+            8 => Ok(Configuration::Surround71),
+            _ => Err(Error::NotSupported)
+        }
+    }
+
     /// Get the number of SH channels
     pub fn num_harmonics(&self) -> usize {
-        let order = self.sh_order() as usize;
-
-        (order+1)*(order+1)
+        spherical::num_harmonics(self.sh_order())
     }
 
     /// Get the SH order
@@ -31,8 +113,16 @@ impl Configuration {
         match *self {
             Configuration::Omnidirectional => 0,
             Configuration::Stereo => 1,
+            Configuration::Surround51 => 2,
+            Configuration::Surround71 => 2,
             Configuration::Binaural => 1,
-            Configuration::SphericalHarmonics(x) => x
+            Configuration::SphericalHarmonics(x) => x,
+            Configuration::Loudspeakers(ref speakers) => {
+                // a ring of 2n+1 loudspeakers resolves the horizontal harmonics of order n
+                let num = speakers.iter().filter(|x| **x != Speaker::LowFrequency).count();
+
+                (num.saturating_sub(1) / 2).max(1).min(spherical::MAX_ORDER as usize) as u8
+            }
         }
     }
 
@@ -41,120 +131,266 @@ impl Configuration {
         match *self {
             Configuration::Omnidirectional => 1,
             Configuration::Stereo => 2,
+            Configuration::Surround51 => 6,
+            Configuration::Surround71 => 8,
             Configuration::Binaural => 2,
-            Configuration::SphericalHarmonics(x) => (x as usize +1)*(x as usize +1)
+            Configuration::SphericalHarmonics(x) => spherical::num_harmonics(x),
+            Configuration::Loudspeakers(ref speakers) => speakers.len()
+        }
+    }
+
+    /// Get the loudspeakers in channel order, if this is a loudspeaker configuration
+    pub fn speakers(&self) -> Option<Vec<Speaker>> {
+        let dir = |azimuth| Speaker::Directional(Direction::horizontal(azimuth));
+
+        match *self {
+            Configuration::Omnidirectional => Some(vec![dir(0.0)]),
+            Configuration::Stereo => Some(vec![dir(30.0), dir(-30.0)]),
+            Configuration::Surround51 => Some(vec![
+                dir(30.0), dir(-30.0), dir(0.0), Speaker::LowFrequency, dir(110.0), dir(-110.0)
+            ]),
+            Configuration::Surround71 => Some(vec![
+                dir(30.0), dir(-30.0), dir(0.0), Speaker::LowFrequency,
+                dir(135.0), dir(-135.0), dir(90.0), dir(-90.0)
+            ]),
+            Configuration::Loudspeakers(ref speakers) => Some(speakers.clone()),
+            _ => None
         }
     }
 
     /// Create a codec from this configuration
     pub fn codec(&self) -> Codec {
+        let binaural = match *self {
+            Configuration::Binaural => Some(Binaural::new(Hrtf::default())),
+            _ => None
+        };
+
         Codec {
-            conf: self.clone()
+            conf: self.clone(),
+            encoder: self.encoder_matrix(),
+            decoder: None,
+            binaural
+        }
+    }
+
+    /// Matrix with `num_harmonics` rows and `num_channels` columns, mapping channels to harmonics
+    fn encoder_matrix(&self) -> Option<Vec<f64>> {
+        let (num_channels, num_harmonics) = (self.num_channels(), self.num_harmonics());
+        let mut matrix = vec![0.0; num_harmonics * num_channels];
+
+        match *self {
+            // a single channel is already the omnidirectional component
+            Configuration::Omnidirectional => matrix[0] = 1.0,
+            Configuration::SphericalHarmonics(_) => {
+                for i in 0..num_harmonics {
+                    matrix[i * num_channels + i] = 1.0;
+                }
+            },
+            Configuration::Binaural => return None,
+            _ => {
+                // encode each loudspeaker as a plane wave from its direction
+                for (c, speaker) in self.speakers()?.into_iter().enumerate() {
+                    match speaker {
+                        Speaker::Directional(dir) => {
+                            for (h, coeff) in spherical::coefficients(self.sh_order(), dir).into_iter().enumerate() {
+                                matrix[h * num_channels + c] = coeff;
+                            }
+                        },
+                        Speaker::LowFrequency => matrix[c] = 1.0
+                    }
+                }
+            }
         }
+
+        Some(matrix)
     }
 }
 
 /// This codec converts a block of raw audio to a loudspeaker independent audio representation
 pub struct Codec {
-    conf: Configuration
+    conf: Configuration,
+    encoder: Option<Vec<f64>>,
+    /// Decoder matrix of the directional loudspeakers for a certain SH order
+    decoder: Option<(u8, Vec<f64>)>,
+    /// Binaural renderer, keeping the filter state between blocks
+    binaural: Option<Binaural>
 }
 
 impl Codec {
+    /// Get the configuration of this codec
+    pub fn configuration(&self) -> &Configuration {
+        &self.conf
+    }
+
+    /// Replace the HRTF set used for binaural decoding
+    pub fn set_hrtf(&mut self, hrtf: Hrtf) {
+        if self.binaural.is_some() {
+            self.binaural = Some(Binaural::new(hrtf));
+        }
+    }
+
+    /// Get the decoder matrix for a certain SH order, creating it if necessary
+    fn decoder_matrix(&mut self, order: u8) -> Result<&[f64]> {
+        let cached = match self.decoder {
+            Some((x, _)) => x == order,
+            None => false
+        };
+
+        if !cached {
+            let directions: Vec<Direction> = self.conf.speakers().ok_or(Error::NotSupported)?
+                .into_iter()
+                .filter_map(|x| match x {
+                    Speaker::Directional(dir) => Some(dir),
+                    Speaker::LowFrequency => None
+                })
+                .collect();
+
+            self.decoder = Some((order, spherical::decoder_matrix(order, &directions)));
+        }
+
+        Ok(&self.decoder.as_ref().unwrap().1)
+    }
+
+    /// Encode a single frame of interleaved channels to harmonics
+    fn encode_frame(&self, encoder: &[f64], frame: &[i16], out: &mut [f64]) {
+        let num_channels = frame.len();
+
+        for (h, val) in out.iter_mut().enumerate() {
+            *val = frame.iter().zip(&encoder[h * num_channels..(h + 1) * num_channels])
+                .map(|(x, y)| *x as f64 * y)
+                .sum();
+        }
+    }
+
+    /// Find the scales, which stretch each harmonic to the full 16bit range
     pub fn scales(&self, channels: &[i16]) -> Result<Vec<f32>> {
-        let num_channels = self.conf.num_channels() as usize;
-        let num_harmonics = self.conf.num_harmonics() as usize;
-
-        let mut scales = vec![std::f32::MAX; num_harmonics];
-            
-        for sample in channels.chunks(num_channels) {
-            match self.conf {
-                Configuration::Omnidirectional => {
-                    if sample[0] as f32 * 0.2820948 > scales[0] {
-                        scales[0] = sample[0] as f32 * 0.2820948;
-                    }
-                },
-                Configuration::Stereo => {
-                    let val = 32767.0 / ((sample[0] as f32 + sample[1] as f32) * 0.2820948).abs();
-                    if val < scales[0] {
-                        scales[0] = val;
-                    }
+        let encoder = self.encoder.as_ref().ok_or(Error::NotSupported)?;
+        let num_channels = self.conf.num_channels();
+        let num_harmonics = self.conf.num_harmonics();
 
-                    let val = 32767.0 / ((sample[0] as f32 - sample[1] as f32) * 0.3454941).abs();
-                    if val < scales[1] {
-                        scales[1] = val;
-                    }
+        let mut max = vec![0.0f64; num_harmonics];
+        let mut harmonics = vec![0.0; num_harmonics];
 
-                    let val = 32767.0 / ((sample[1] as f32 - sample[0] as f32) * 0.3454941).abs();
-                    if val < scales[3] {
-                        scales[3] = val;
-                    }
+        for frame in channels.chunks(num_channels) {
+            self.encode_frame(encoder, frame, &mut harmonics);
 
-                    scales[2] = 1.0;
-                },
-                _ => return Err(Error::NotSupported)
+            for (m, h) in max.iter_mut().zip(harmonics.iter()) {
+                if h.abs() > *m {
+                    *m = h.abs();
+                }
             }
         }
-        
-        Ok(scales)
+
+        // silent harmonics are not scaled at all
+        Ok(max.into_iter().map(|m| if m > 0.0 { (32767.0 / m) as f32 } else { 1.0 }).collect())
     }
 
     /// Converts raw audio to SH representation
+    ///
+    /// The harmonics are written blockwise, each SH channel occupying one block with as many
+    /// samples as there are frames in `channels`.
     pub fn to_harmonics(&self, scales: &[f32], channels: &[i16], harmonics: &mut [i16]) {
-        let num_channels = self.conf.num_channels() as usize;
-        //let num_harmonics = self.conf.num_harmonics() as usize;
+        let encoder = match self.encoder {
+            Some(ref encoder) => encoder,
+            None => return
+        };
+
+        let num_channels = self.conf.num_channels();
+        let num_harmonics = self.conf.num_harmonics();
 
         // calculate the number of samples per block
         let block_length = channels.len() / num_channels;
+        let mut frame = vec![0.0; num_harmonics];
 
-        let first = 1.0 / (4.0 * std::f64::consts::PI).sqrt();
-        let secon = (3.0 / 8.0 / std::f64::consts::PI).sqrt();
-
-        //println!("{} {} {}", num_channels, num_harmonics, block_length);
-        let mut i = 0;
-        for sample in channels.chunks(num_channels) {
-            match self.conf {
-                Configuration::Omnidirectional => {
-                    harmonics[i] = (sample[0] as f64 * first / scales[0] as f64) as i16;
-                },
-                Configuration::Stereo => {
-                    harmonics[i] = ((sample[0] as f64 * 0.7 + sample[1] as f64 * 0.7) * first * scales[0] as f64).round() as i16;
-                    harmonics[i + block_length] = ((sample[0] as f64 * 0.7 - sample[1] as f64 * 0.7) * secon * scales[1] as f64).round() as i16;
-                    harmonics[i + block_length*2] = 0;
-                    harmonics[i + block_length*3] = ((sample[1] as f64 * 0.7 - sample[0] as f64 * 0.7) * secon * scales[3] as f64).round() as i16;
-                },
-                _ => {}
-            }
+        for (i, sample) in channels.chunks(num_channels).enumerate() {
+            self.encode_frame(encoder, sample, &mut frame);
 
-            i += 1;
+            for h in 0..num_harmonics {
+                harmonics[i + block_length * h] = (frame[h] * scales[h] as f64).round() as i16;
+            }
         }
     }
 
     /// Converts SH representation to loudspeaker dependent representation
-    pub fn to_channels(&self, scales: &[f32], harmonics: &[i16], from_harmonics: u8) -> Result<Vec<i16>> {
-        let num_channels = self.conf.num_channels() as usize;
-        let num_from_harmonics = (from_harmonics as usize + 1) * (from_harmonics as usize + 1);
+    ///
+    /// Stereo is decoded with a mode-matching decoder for loudspeakers at ±30°, which is the exact
+    /// inverse of the stereo encoder. Other loudspeaker layouts use a mode-matching decoder up to
+    /// the order of the layout and binaural decoding renders up to the second order. Any missing
+    /// harmonics of a lower order file are assumed to be silent.
+    pub fn to_channels(&mut self, scales: &[f32], harmonics: &[i16], from_harmonics: u8) -> Result<Vec<i16>> {
+        let num_channels = self.conf.num_channels();
+        let num_from_harmonics = spherical::num_harmonics(from_harmonics);
         let samples = harmonics.len() / num_from_harmonics;
-        
+
         let mut channels = vec![0; samples * num_channels];
-        let sample = harmonics;
-
-        let first = (4.0 * std::f64::consts::PI / 4.0).sqrt();
-        let secon = (8.0 * std::f64::consts::PI / 3.0 / 4.0).sqrt();
-
-        let mut i = 0;
-        for j in 0..samples {
-            match self.conf {
-                Configuration::Omnidirectional => {
-                    channels[i] = ((sample[j] as f64) / scales[0] as f64 * first) as i16;
-                },
-                Configuration::Stereo => {
-                    channels[i] = (secon * (sample[j + samples] as f64 / scales[1] as f64) + first * (sample[j] as f64 / scales[0] as f64)).round() as i16;
-                    channels[i+1] = (secon * (sample[j + samples * 3] as f64 / scales[3] as f64) + first * (sample[j] as f64 / scales[0] as f64)).round() as i16;
-                },
-                _ => return Err(Error::NotSupported)
+
+        // get the unscaled harmonic `h` of sample `j`
+        let harmonic = |h: usize, j: usize| -> f64 {
+            if h < num_from_harmonics {
+                harmonics[j + samples * h] as f64 / scales[h] as f64
+            } else {
+                0.0
             }
+        };
+
+        match self.conf {
+            Configuration::Omnidirectional => {
+                for (j, frame) in channels.chunks_mut(num_channels).enumerate() {
+                    frame[0] = harmonic(0, j).round() as i16;
+                }
+            },
+            Configuration::Stereo => {
+                for (j, frame) in channels.chunks_mut(num_channels).enumerate() {
+                    // sin(30°) = 0.5 relates the Y component to the difference signal
+                    frame[0] = (0.5 * harmonic(0, j) + harmonic(1, j)).round() as i16;
+                    frame[1] = (0.5 * harmonic(0, j) - harmonic(1, j)).round() as i16;
+                }
+            },
+            Configuration::SphericalHarmonics(_) => {
+                for (j, frame) in channels.chunks_mut(num_channels).enumerate() {
+                    for (h, out) in frame.iter_mut().enumerate() {
+                        *out = harmonic(h, j).round() as i16;
+                    }
+                }
+            },
+            Configuration::Binaural => {
+                let binaural = self.binaural.as_mut().ok_or(Error::NotSupported)?;
+                let order = from_harmonics.min(2);
+
+                let mut input = vec![0.0; spherical::num_harmonics(order) * samples];
+                for (i, val) in input.iter_mut().enumerate() {
+                    *val = harmonic(i / samples, i % samples);
+                }
+
+                for (out, val) in channels.iter_mut().zip(binaural.render(order, &input, samples)) {
+                    *out = val.round() as i16;
+                }
+            },
+            Configuration::Surround51 | Configuration::Surround71 | Configuration::Loudspeakers(_) => {
+                let order = from_harmonics.min(self.conf.sh_order());
+                let num_harmonics = spherical::num_harmonics(order);
+                let speakers = self.conf.speakers().ok_or(Error::NotSupported)?;
+                let decoder = self.decoder_matrix(order)?;
+
+                let mut frame_harmonics = vec![0.0; num_harmonics];
+                for (j, frame) in channels.chunks_mut(num_channels).enumerate() {
+                    for (h, val) in frame_harmonics.iter_mut().enumerate() {
+                        *val = harmonic(h, j);
+                    }
+
+                    // the decoder has a row for each directional loudspeaker, LFE stays silent
+                    let mut row = 0;
+                    for (out, speaker) in frame.iter_mut().zip(speakers.iter()) {
+                        if let Speaker::Directional(_) = *speaker {
+                            let gains = &decoder[row * num_harmonics..(row + 1) * num_harmonics];
+                            *out = gains.iter().zip(frame_harmonics.iter())
+                                .map(|(g, x)| g * x).sum::<f64>().round() as i16;
 
-            i += self.conf.num_channels() as usize;
+                            row += 1;
+                        }
+                    }
+                }
+            }
         }
 
         Ok(channels)
@@ -169,7 +405,7 @@ mod test {
     #[test]
     fn test_linear_sequence_stereo() {
         let conf = Configuration::Stereo;
-        let codec = conf.codec();
+        let mut codec = conf.codec();
 
         let buf: Vec<i16> = (-100..100).collect();
 
@@ -186,7 +422,7 @@ mod test {
     #[test]
     fn test_sine_sequence_stereo() {
         let conf = Configuration::Stereo;
-        let codec = conf.codec();
+        let mut codec = conf.codec();
 
         let buf: Vec<i16> = (0..48000).map(|x| {
             let arg = x as f64 / 48000.0 * 20.0;
@@ -204,4 +440,85 @@ mod test {
 
         assert_eq!(buf_channels, buf);
     }
+
+    #[test]
+    fn test_ambix_passthrough() {
+        let conf = Configuration::SphericalHarmonics(2);
+        let mut codec = conf.codec();
+
+        // nine channels with different content each
+        let buf: Vec<i16> = (0..9 * 480).map(|x| ((x % 9) as i16 - 4) * (x / 9) as i16).collect();
+
+        let scales = codec.scales(&buf).unwrap();
+
+        let mut buf_harmonics = vec![0; buf.len()];
+        codec.to_harmonics(&scales, &buf, &mut buf_harmonics);
+        let buf_channels = codec.to_channels(&scales, &buf_harmonics, 2).unwrap();
+
+        assert_eq!(buf_channels, buf);
+    }
+
+    #[test]
+    fn test_surround_to_ambix() {
+        let conf = Configuration::Surround51;
+        let codec = conf.codec();
+
+        // a single signal on the left surround channel
+        let mut buf = vec![0i16; 6 * 100];
+        for i in 0..100 {
+            buf[i * 6 + 4] = 1000;
+        }
+
+        let scales = codec.scales(&buf).unwrap();
+        let mut buf_harmonics = vec![0; 9 * 100];
+        codec.to_harmonics(&scales, &buf, &mut buf_harmonics);
+
+        let ambix = Configuration::SphericalHarmonics(2).codec()
+            .to_channels(&scales, &buf_harmonics, 2).unwrap();
+
+        // W carries the full amplitude, Y and X point to the back left at 110°
+        assert_eq!(ambix[0], 1000);
+        assert_eq!(ambix[1], (1000.0 * 110f64.to_radians().sin()).round() as i16);
+        assert_eq!(ambix[2], 0);
+        assert_eq!(ambix[3], (1000.0 * 110f64.to_radians().cos()).round() as i16);
+    }
+
+    #[test]
+    fn test_surround_roundtrip() {
+        let conf = Configuration::Surround51;
+        let mut codec = conf.codec();
+
+        // different signals on the left front and right surround channel, but no LFE
+        let mut buf = vec![0i16; 6 * 100];
+        for i in 0..100 {
+            buf[i * 6] = 10 * i as i16;
+            buf[i * 6 + 5] = -5 * i as i16;
+        }
+
+        let scales = codec.scales(&buf).unwrap();
+        let mut buf_harmonics = vec![0; 9 * 100];
+        codec.to_harmonics(&scales, &buf, &mut buf_harmonics);
+        let buf_channels = codec.to_channels(&scales, &buf_harmonics, 2).unwrap();
+
+        for (a, b) in buf_channels.iter().zip(buf.iter()) {
+            assert!((a - b).abs() <= 1, "{:?} != {:?}", buf_channels, buf);
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Configuration::from_name("5.1", &[]), Some(Configuration::Surround51));
+        assert_eq!(Configuration::from_name("ambix3", &[]), Some(Configuration::SphericalHarmonics(3)));
+        assert_eq!(Configuration::from_name("ambix7", &[]), None);
+        assert_eq!(Configuration::from_name("custom", &[]), None);
+
+        let quad = Configuration::from_name("custom", &[(45.0, 0.0), (-45.0, 0.0), (135.0, 0.0), (-135.0, 0.0)]).unwrap();
+        assert_eq!(quad.num_channels(), 4);
+        assert_eq!(quad.sh_order(), 1);
+    }
+
+    #[test]
+    fn test_binaural_not_encodable() {
+        assert!(Configuration::Binaural.codec().scales(&[0, 0]).is_err());
+    }
 }
@@ -0,0 +1,644 @@
+//! Decode audio files to the raw format expected by `Container::save_pcm`
+//!
+//! MP3, AAC, FLAC, Vorbis, WAV and their common containers are demultiplexed and decoded with
+//! `symphonia`, Ogg Opus packets are passed to the Opus decoder of this crate. Everything else
+//! falls back to an `ffmpeg` process, which writes the raw audio to a pipe. In all cases the
+//! result is interleaved at 48kHz, other sample rates are converted with the `resample` module.
+//!
+//! Stereo, 5.1, 7.1 and AmbiX files keep their channels in the order of the `Configuration`
+//! returned by `Decoder::configuration`, so that spatial recordings are stored without
+//! downmixing. Mono is duplicated to stereo and all other layouts are mixed into the left and
+//! right channel. `ffmpeg` always converts to stereo.
+//!
+//! The native decoders know the number of frames in advance for most formats, therefore the
+//! progress is accurate. The progress of `ffmpeg` is unknown until it finished.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::error::{Error, Result};
+use crate::resample::Resampler;
+use crate::spherical;
+use crate::{Configuration, SAMPLE_RATE};
+
+/// Maximal number of samples per channel in an Opus packet (120ms)
+const MAX_OPUS_FRAMES: usize = 5760;
+
+/// Number of bytes read from `ffmpeg` at once
+const FFMPEG_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Decoder of a single codec
+enum Codec {
+    Native(Box<dyn symphonia::core::codecs::Decoder>),
+    /// Opus is always decoded to stereo at 48kHz, the first frames are skipped (pre-skip)
+    Opus(opus::Decoder, usize)
+}
+
+impl Codec {
+    /// Reset the state after a discontinuity of the stream, e.g. a new chained Ogg stream
+    fn reset(&mut self) -> Result<()> {
+        match self {
+            Codec::Native(decoder) => {
+                decoder.reset();
+
+                Ok(())
+            },
+            Codec::Opus(decoder, _) => decoder.reset_state().map_err(|err| Error::Opus(err))
+        }
+    }
+}
+
+/// Source of the decoded audio
+enum Source {
+    Native {
+        format: Box<dyn FormatReader>,
+        track: u32,
+        codec: Codec
+    },
+    Ffmpeg {
+        child: Child,
+        /// An odd byte left over from the last read
+        rest: Option<u8>
+    }
+}
+
+/// Decodes an audio file block by block
+pub struct Decoder {
+    source: Source,
+    /// Configuration of the decoded blocks, known once the channels of the file are known
+    configuration: Option<Configuration>,
+    /// Share of each channel of the file in the channels of the configuration
+    mix: Option<(Channels, Vec<Vec<f32>>)>,
+    /// Created with the sample rate of the first decoded block, if it differs
+    resampler: Option<Resampler>,
+    /// Number of frames decoded so far, in the original sample rate
+    frames: u64,
+    /// Number of frames in the file, if known
+    total: Option<u64>,
+    finished: bool
+}
+
+fn symphonia_error(err: SymphoniaError) -> Error {
+    match err {
+        SymphoniaError::IoError(err) => Error::File(err),
+        SymphoniaError::Unsupported(_) => Error::NotSupported,
+        err => Error::Decode(err.to_string())
+    }
+}
+
+/// Weights of each channel in the left and right channel of a stereo downmix
+///
+/// Centre and surround channels are attenuated by 3dB like in ITU-R BS.775, the LFE channel is
+/// left out. The weights are normalised, so that the downmix can't clip.
+fn downmix(channels: Channels) -> Vec<(f32, f32)> {
+    const LEFT: Channels = Channels::from_bits_truncate(
+        Channels::REAR_LEFT.bits() | Channels::SIDE_LEFT.bits() | Channels::FRONT_LEFT_CENTRE.bits() |
+        Channels::REAR_LEFT_CENTRE.bits() | Channels::FRONT_LEFT_WIDE.bits() | Channels::FRONT_LEFT_HIGH.bits() |
+        Channels::TOP_FRONT_LEFT.bits() | Channels::TOP_REAR_LEFT.bits());
+    const RIGHT: Channels = Channels::from_bits_truncate(
+        Channels::REAR_RIGHT.bits() | Channels::SIDE_RIGHT.bits() | Channels::FRONT_RIGHT_CENTRE.bits() |
+        Channels::REAR_RIGHT_CENTRE.bits() | Channels::FRONT_RIGHT_WIDE.bits() | Channels::FRONT_RIGHT_HIGH.bits() |
+        Channels::TOP_FRONT_RIGHT.bits() | Channels::TOP_REAR_RIGHT.bits());
+    const ATTENUATION: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    let weights: Vec<(f32, f32)> = channels.iter().map(|channel| {
+        if channel == Channels::FRONT_LEFT {
+            (1.0, 0.0)
+        } else if channel == Channels::FRONT_RIGHT {
+            (0.0, 1.0)
+        } else if channel == Channels::LFE1 || channel == Channels::LFE2 {
+            (0.0, 0.0)
+        } else if LEFT.contains(channel) {
+            (ATTENUATION, 0.0)
+        } else if RIGHT.contains(channel) {
+            (0.0, ATTENUATION)
+        } else {
+            (ATTENUATION, ATTENUATION)
+        }
+    }).collect();
+
+    let left: f32 = weights.iter().map(|x| x.0).sum();
+    let right: f32 = weights.iter().map(|x| x.1).sum();
+    let norm = left.max(right).max(1.0);
+
+    weights.into_iter().map(|(l, r)| (l / norm, r / norm)).collect()
+}
+
+/// Loudspeaker layouts which are stored without downmixing, with their channels in the order of
+/// the configuration
+fn layouts() -> Vec<(Configuration, Vec<Channels>)> {
+    let front = [Channels::FRONT_LEFT, Channels::FRONT_RIGHT, Channels::FRONT_CENTRE, Channels::LFE1];
+    let with = |rest: &[Channels]| front.iter().chain(rest).cloned().collect::<Vec<_>>();
+
+    vec![
+        (Configuration::Stereo, vec![Channels::FRONT_LEFT, Channels::FRONT_RIGHT]),
+        // the surround pair of 5.1 is placed either at the back or at the sides
+        (Configuration::Surround51, with(&[Channels::REAR_LEFT, Channels::REAR_RIGHT])),
+        (Configuration::Surround51, with(&[Channels::SIDE_LEFT, Channels::SIDE_RIGHT])),
+        (Configuration::Surround71, with(&[Channels::REAR_LEFT, Channels::REAR_RIGHT, Channels::SIDE_LEFT, Channels::SIDE_RIGHT]))
+    ]
+}
+
+/// Order of the spherical harmonics, if the channels belong to an AmbiX file
+///
+/// AmbiX files have no loudspeaker positions, symphonia numbers their channels consecutively from
+/// the front left channel on. A file with (n+1)² of these channels is taken as order n.
+fn ambix_order(channels: Channels) -> Option<u8> {
+    let count = channels.count();
+    let consecutive = count < 32 && channels.bits() == (1u32 << count) - 1;
+
+    (1..=spherical::MAX_ORDER)
+        .find(|order| spherical::num_harmonics(*order) == count)
+        .filter(|_| consecutive)
+}
+
+/// Choose the configuration of the decoded audio for the channels of a file
+///
+/// Mono and layouts without a matching configuration are stored as stereo.
+fn configuration(channels: Channels) -> Configuration {
+    let layout = layouts().into_iter()
+        .find(|(_, order)| order.iter().fold(Channels::empty(), |all, x| all | *x) == channels)
+        .map(|(conf, _)| conf);
+
+    match (layout, ambix_order(channels)) {
+        (Some(conf), _) => conf,
+        (None, Some(order)) => Configuration::SphericalHarmonics(order),
+        (None, None) => Configuration::Stereo
+    }
+}
+
+/// Share of each channel of a file in the channels of a configuration
+///
+/// Matching layouts are reordered, other channels are mixed to stereo with `downmix`. Fails if
+/// the channels don't fit to a surround or AmbiX configuration, e.g. after a chained stream
+/// changed its layout.
+fn mix(channels: Channels, conf: &Configuration) -> Result<Vec<Vec<f32>>> {
+    let num = conf.num_channels();
+    let one_hot = |idx: usize| (0..num).map(|i| if i == idx { 1.0 } else { 0.0 }).collect::<Vec<f32>>();
+
+    if *conf == Configuration::Stereo && channels.count() == 1 {
+        return Ok(vec![vec![1.0, 1.0]]);
+    }
+
+    let layout = layouts().into_iter()
+        .filter(|(x, _)| x == conf)
+        .find(|(_, order)| order.iter().fold(Channels::empty(), |all, x| all | *x) == channels);
+
+    if let Some((_, order)) = layout {
+        // symphonia iterates the channels in the order of their bits
+        return Ok(channels.iter().map(|x| one_hot(order.iter().position(|y| *y == x).unwrap())).collect());
+    }
+
+    match conf {
+        Configuration::Stereo => Ok(downmix(channels).into_iter().map(|(l, r)| vec![l, r]).collect()),
+        Configuration::SphericalHarmonics(order) if ambix_order(channels) == Some(*order) => Ok((0..num).map(one_hot).collect()),
+        _ => Err(Error::Decode(format!("Channels {} don't match the configuration {:?}", channels, conf)))
+    }
+}
+
+/// Convert interleaved samples to the channels of a configuration
+///
+/// `weights` contains the share of each channel in the channels of the configuration, see `mix`.
+fn remix(samples: &[f32], weights: &[Vec<f32>]) -> Vec<f32> {
+    let identity = weights.iter().enumerate()
+        .all(|(i, x)| x.len() == weights.len() && x.iter().enumerate().all(|(j, w)| *w == if i == j { 1.0 } else { 0.0 }));
+
+    if identity {
+        return samples.to_vec();
+    }
+
+    let num = weights.first().map(|x| x.len()).unwrap_or(0);
+
+    samples.chunks(weights.len()).flat_map(|frame| {
+        let mut out = vec![0.0; num];
+        for (x, weights) in frame.iter().zip(weights) {
+            for (out, w) in out.iter_mut().zip(weights) {
+                *out += x * w;
+            }
+        }
+
+        out
+    }).collect()
+}
+
+fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter()
+        .map(|x| (x * 32768.0).round().max(-32768.0).min(32767.0) as i16)
+        .collect()
+}
+
+impl Decoder {
+    /// Open an audio file, falling back to `ffmpeg` for unsupported formats
+    pub fn open(path: &Path) -> Result<Decoder> {
+        match Decoder::native(path) {
+            Err(Error::NotSupported) => Decoder::ffmpeg(path),
+            res => res
+        }
+    }
+
+    /// Open an audio file with the native decoders
+    ///
+    /// Returns `Error::NotSupported` if the format or codec is unknown.
+    pub fn native(path: &Path) -> Result<Decoder> {
+        let file = File::open(path).map_err(|err| Error::File(err))?;
+        let stream = MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions::default());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        // an unknown container is reported as unsupported as well
+        let probed = symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|_| Error::NotSupported)?;
+
+        let format = probed.format;
+        let params = match format.tracks().iter().find(|x| x.codec_params.codec != CODEC_TYPE_NULL) {
+            Some(track) => track.clone(),
+            None => return Err(Error::NotSupported)
+        };
+
+        let codec = if params.codec_params.codec == CODEC_TYPE_OPUS {
+            // the identification header contains the pre-skip and channel mapping family, only
+            // mono and stereo streams (family 0) can be decoded without a multistream decoder
+            let skip = match params.codec_params.extra_data {
+                Some(ref head) if head.len() >= 19 && head[18] == 0 => head[10] as usize | (head[11] as usize) << 8,
+                _ => return Err(Error::NotSupported)
+            };
+
+            let decoder = opus::Decoder::new(SAMPLE_RATE, opus::Channels::Stereo)
+                .map_err(|err| Error::Opus(err))?;
+
+            Codec::Opus(decoder, skip)
+        } else {
+            let decoder = symphonia::default::get_codecs()
+                .make(&params.codec_params, &DecoderOptions::default())
+                .map_err(symphonia_error)?;
+
+            Codec::Native(decoder)
+        };
+
+        // Opus is decoded to stereo, other codecs may report their channels with the first packet
+        let configuration = match codec {
+            Codec::Opus(..) => Some(Configuration::Stereo),
+            Codec::Native(_) => params.codec_params.channels.map(configuration)
+        };
+
+        Ok(Decoder {
+            source: Source::Native { format, track: params.id, codec },
+            configuration,
+            mix: None,
+            resampler: None,
+            frames: 0,
+            total: params.codec_params.n_frames,
+            finished: false
+        })
+    }
+
+    /// Convert an audio file with `ffmpeg`
+    pub fn ffmpeg(path: &Path) -> Result<Decoder> {
+        let child = Command::new("ffmpeg")
+            .arg("-loglevel").arg("error")
+            .arg("-i").arg(path)
+            .arg("-ar").arg(SAMPLE_RATE.to_string())
+            .arg("-ac").arg("2")
+            .arg("-f").arg("s16le")
+            .arg("pipe:1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| Error::File(err))?;
+
+        Ok(Decoder {
+            source: Source::Ffmpeg { child, rest: None },
+            configuration: Some(Configuration::Stereo),
+            mix: None,
+            resampler: None,
+            frames: 0,
+            total: None,
+            finished: false
+        })
+    }
+
+    /// Whether the file is decoded without `ffmpeg`
+    pub fn is_native(&self) -> bool {
+        match self.source {
+            Source::Native { .. } => true,
+            Source::Ffmpeg { .. } => false
+        }
+    }
+
+    /// Configuration of the decoded blocks
+    ///
+    /// Some formats report their channels only with the first packet, until then this is stereo.
+    pub fn configuration(&self) -> Configuration {
+        self.configuration.clone().unwrap_or(Configuration::Stereo)
+    }
+
+    /// Progress of the decoding between zero and one, if the length of the file is known
+    pub fn progress(&self) -> Option<f32> {
+        if self.finished {
+            return Some(1.0);
+        }
+
+        match self.total {
+            Some(total) if total > 0 => Some((self.frames as f32 / total as f32).min(1.0)),
+            _ => None
+        }
+    }
+
+    /// Decode the next block of interleaved samples at 48kHz, see `configuration`
+    ///
+    /// Returns `Error::ReachedEnd` after the last block. Blocks may be empty, e.g. while the
+    /// resampler collects enough frames.
+    pub fn next_block(&mut self) -> Result<Vec<i16>> {
+        if self.finished {
+            return Err(Error::ReachedEnd);
+        }
+
+        let block = match self.source {
+            Source::Native { ref mut format, track, ref mut codec } => {
+                let packet = loop {
+                    match format.next_packet() {
+                        Ok(packet) if packet.track_id() == track => break Some(packet),
+                        Ok(_) => continue,
+                        // the end of a stream is reported as an unexpected end of file
+                        Err(SymphoniaError::IoError(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => break None,
+                        // the stream continues after a discontinuity
+                        Err(SymphoniaError::ResetRequired) => {
+                            codec.reset()?;
+
+                            continue;
+                        },
+                        Err(err) => return Err(symphonia_error(err))
+                    }
+                };
+
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => {
+                        self.finished = true;
+
+                        return Ok(self.resampler.as_mut()
+                            .map(|x| to_i16(&x.flush()))
+                            .unwrap_or_default());
+                    }
+                };
+
+                let (samples, channels, rate) = match codec {
+                    Codec::Native(ref mut decoder) => match decoder.decode(&packet) {
+                        Ok(buf) => {
+                            let spec = *buf.spec();
+                            let mut samples = SampleBuffer::<f32>::new(buf.capacity() as u64, spec);
+                            samples.copy_interleaved_ref(buf);
+
+                            (samples.samples().to_vec(), spec.channels, spec.rate)
+                        },
+                        // skip corrupted packets, like most players do
+                        Err(SymphoniaError::DecodeError(_)) => return Ok(Vec::new()),
+                        Err(err) => return Err(symphonia_error(err))
+                    },
+                    Codec::Opus(ref mut decoder, ref mut skip) => {
+                        let mut samples = vec![0.0; MAX_OPUS_FRAMES * 2];
+                        let num = decoder.decode_float(&packet.data, &mut samples, false)
+                            .map_err(|err| Error::Opus(err))?;
+
+                        let skipped = num.min(*skip);
+                        *skip -= skipped;
+                        samples.truncate(num * 2);
+                        samples.drain(..skipped * 2);
+
+                        (samples, Channels::FRONT_LEFT | Channels::FRONT_RIGHT, SAMPLE_RATE)
+                    }
+                };
+
+                if channels.count() == 0 {
+                    return Ok(Vec::new());
+                }
+
+                self.frames += (samples.len() / channels.count()) as u64;
+
+                // the configuration is chosen with the first channels and kept for the whole file
+                let conf = self.configuration.get_or_insert_with(|| configuration(channels)).clone();
+                if self.mix.as_ref().map(|x| x.0) != Some(channels) {
+                    self.mix = Some((channels, mix(channels, &conf)?));
+                }
+
+                let samples = match self.mix {
+                    Some((_, ref weights)) => remix(&samples, weights),
+                    None => samples
+                };
+
+                if rate == SAMPLE_RATE && self.resampler.is_none() {
+                    to_i16(&samples)
+                } else {
+                    let resampler = self.resampler.get_or_insert_with(|| Resampler::new(rate, SAMPLE_RATE, conf.num_channels()));
+
+                    to_i16(&resampler.process(&samples))
+                }
+            },
+            Source::Ffmpeg { ref mut child, ref mut rest } => {
+                let mut buf = vec![0u8; FFMPEG_BLOCK_SIZE];
+                let mut len = 0;
+                if let Some(byte) = rest.take() {
+                    buf[0] = byte;
+                    len = 1;
+                }
+
+                // fill the whole block, unless the end was reached
+                let stdout = child.stdout.as_mut().ok_or(Error::NotSupported)?;
+                while len < buf.len() {
+                    match stdout.read(&mut buf[len..]) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(Error::File(err))
+                    }
+                }
+
+                if len < buf.len() {
+                    let status = child.wait().map_err(|err| Error::File(err))?;
+                    if !status.success() {
+                        return Err(Error::Decode(format!("ffmpeg exited with {}", status)));
+                    }
+
+                    self.finished = true;
+                }
+
+                if len % 2 == 1 {
+                    *rest = Some(buf[len - 1]);
+                    len -= 1;
+                }
+
+                buf[..len].chunks(2)
+                    .map(|x| i16::from_le_bytes([x[0], x[1]]))
+                    .collect()
+            }
+        };
+
+        Ok(block)
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        // don't leave a running process behind if the decoding was aborted
+        if let Source::Ffmpeg { ref mut child, .. } = self.source {
+            if !self.finished {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// Decode a whole audio file to interleaved samples at 48kHz and their configuration
+///
+/// The `progress` closure is called after every block with a value between zero and one.
+pub fn decode_file<F: FnMut(f32)>(path: &Path, mut progress: F) -> Result<(Configuration, Vec<i16>)> {
+    let mut decoder = Decoder::open(path)?;
+    let mut pcm = Vec::new();
+
+    loop {
+        match decoder.next_block() {
+            Ok(block) => pcm.extend_from_slice(&block),
+            Err(Error::ReachedEnd) => break,
+            Err(err) => return Err(err)
+        }
+
+        if let Some(value) = decoder.progress() {
+            progress(value);
+        }
+    }
+
+    progress(1.0);
+
+    Ok((decoder.configuration(), pcm))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use symphonia::core::audio::Channels;
+    use crate::Configuration;
+    use super::{Decoder, decode_file, downmix, configuration, mix, remix};
+
+    /// Write a mono WAV file with 16 bit samples
+    fn write_wav(path: &std::path::Path, rate: u32, samples: &[i16]) {
+        let mut buf = b"RIFF".to_vec();
+        buf.extend_from_slice(&(36 + samples.len() as u32 * 2).to_le_bytes());
+        buf.extend_from_slice(b"WAVEfmt ");
+        buf.extend_from_slice(&16u32.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&rate.to_le_bytes());
+        buf.extend_from_slice(&(rate * 2).to_le_bytes());
+        buf.extend_from_slice(&2u16.to_le_bytes());
+        buf.extend_from_slice(&16u16.to_le_bytes());
+        buf.extend_from_slice(b"data");
+        buf.extend_from_slice(&(samples.len() as u32 * 2).to_le_bytes());
+        for x in samples {
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+
+        File::create(path).unwrap().write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn wav_to_stereo_48k() {
+        let path = std::env::temp_dir().join(format!("hex-decode-{}.wav", std::process::id()));
+        let input: Vec<i16> = (0..44100)
+            .map(|i| ((2.0 * PI * 440.0 * i as f64 / 44100.0).sin() * 16000.0) as i16)
+            .collect();
+
+        write_wav(&path, 44100, &input);
+
+        assert!(Decoder::open(&path).unwrap().is_native());
+
+        let mut progress = Vec::new();
+        let (conf, pcm) = decode_file(&path, |x| progress.push(x)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // one second of stereo audio with identical channels
+        assert_eq!(conf, Configuration::Stereo);
+        assert!((pcm.len() as i64 / 2 - 48000).abs() <= 2);
+        assert!(pcm.chunks(2).all(|x| x[0] == x[1]));
+        assert!(progress.windows(2).all(|x| x[0] <= x[1]));
+        assert_eq!(progress.last(), Some(&1.0));
+
+        for i in 100..47900 {
+            let expected = (2.0 * PI * 440.0 * i as f64 / 48000.0).sin() * 16000.0;
+            assert!((pcm[i * 2] as f64 - expected).abs() < 50.0);
+        }
+    }
+
+    #[test]
+    fn downmix_surround() {
+        // 5.1 in the order of WAV files
+        let channels = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::REAR_LEFT | Channels::REAR_RIGHT;
+        let weights = downmix(channels);
+        let norm = 1.0 + 2.0 * std::f32::consts::FRAC_1_SQRT_2;
+
+        assert_eq!(weights.len(), 6);
+        assert!((weights[0].0 - 1.0 / norm).abs() < 1e-6 && weights[0].1 == 0.0);
+        assert!((weights[2].0 - weights[2].1).abs() < 1e-6);
+        assert_eq!(weights[3], (0.0, 0.0));
+
+        // the centre is shared, the LFE channel is left out
+        let weights = weights.into_iter().map(|(l, r)| vec![l, r]).collect::<Vec<_>>();
+        let stereo = remix(&[0.0, 0.0, 0.5, 1.0, 0.0, 0.3], &weights);
+        assert!((stereo[0] - 0.5 * std::f32::consts::FRAC_1_SQRT_2 / norm).abs() < 1e-6);
+        assert!((stereo[1] - (0.5 + 0.3) * std::f32::consts::FRAC_1_SQRT_2 / norm).abs() < 1e-6);
+
+        // stereo is passed through
+        assert_eq!(downmix(Channels::FRONT_LEFT | Channels::FRONT_RIGHT), vec![(1.0, 0.0), (0.0, 1.0)]);
+    }
+
+    #[test]
+    fn keep_layouts() {
+        let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE | Channels::LFE1;
+        let back = Channels::REAR_LEFT | Channels::REAR_RIGHT;
+        let side = Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+
+        assert_eq!(configuration(Channels::FRONT_LEFT), Configuration::Stereo);
+        assert_eq!(configuration(front | back), Configuration::Surround51);
+        assert_eq!(configuration(front | side), Configuration::Surround51);
+        assert_eq!(configuration(front | back | side), Configuration::Surround71);
+
+        // AmbiX files have consecutive channels without positions
+        assert_eq!(configuration(front), Configuration::SphericalHarmonics(1));
+        assert_eq!(configuration(Channels::from_bits(0xffff).unwrap()), Configuration::SphericalHarmonics(3));
+
+        // unknown layouts are mixed to stereo
+        assert_eq!(configuration(Channels::FRONT_LEFT | Channels::FRONT_RIGHT | back), Configuration::Stereo);
+        assert_eq!(mix(Channels::FRONT_LEFT | Channels::FRONT_RIGHT | back, &Configuration::Stereo).unwrap().len(), 4);
+
+        // the channels of 5.1 are passed through, the 7.1 file stores the side pair last
+        let weights = mix(front | side, &Configuration::Surround51).unwrap();
+        assert_eq!(remix(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &weights), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let weights = mix(front | back | side, &Configuration::Surround71).unwrap();
+        assert_eq!(remix(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0], &weights), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+
+        // mono is duplicated
+        let weights = mix(Channels::FRONT_CENTRE, &Configuration::Stereo).unwrap();
+        assert_eq!(remix(&[0.5, 0.25], &weights), vec![0.5, 0.5, 0.25, 0.25]);
+
+        // a chained stream can't change from 5.1 to another layout
+        assert!(mix(Channels::FRONT_LEFT | Channels::FRONT_RIGHT, &Configuration::Surround51).is_err());
+    }
+}
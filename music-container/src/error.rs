@@ -12,5 +12,9 @@ pub enum Error {
     InvalidRange,
     NotSupported,
     SendFailed,
-    ReachedEnd
+    ReachedEnd,
+    /// Could not decode an audio file
+    Decode(String),
+    /// The impulse responses of a HRTF set don't match its directions
+    InvalidHrtf
 }
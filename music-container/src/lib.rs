@@ -9,27 +9,54 @@
 //! ## File format
 //!
 //! The file format is the following:
-//! |       |    1    |     1    |     4    | (order+1)**2 * 4 | (order+1)**2 * samples * 2 |
-//! |-------|---------|----------|----------|------------------|----------------------------|
-//! | field | version | sh order | samples  | scales ..        | audio data ...             |
+//! |       |    1    |     1    |     4    | (order+1)**2 * 4 |     4    |     4     | (order+1)**2 * samples * 2 |
+//! |-------|---------|----------|----------|------------------|----------|-----------|----------------------------|
+//! | field | version | sh order | samples  | scales ..        | loudness | true peak | audio data ...             |
+//!
+//! The loudness (in LUFS) and true peak (in dBTP) fields were added in version 3 and are missing in
+//! older files, see the `loudness` module. Version 4 adds the length of a metadata chunk (4 bytes)
+//! and the chunk itself between the true peak and audio data, see the `metadata` module.
+//!
+//! The audio data is stored in Spherical Harmonics with ACN ordering and SN3D normalisation
+//! (AmbiX), see the `spherical` module. Version 1 files were written with an ad-hoc stereo
+//! layout and are converted to this representation while decoding.
 //!
 pub mod error;
 pub mod configuration;
+pub mod spherical;
+pub mod binaural;
+pub mod resample;
+pub mod loudness;
+pub mod metadata;
+pub mod decode;
+pub mod tags;
+#[cfg(feature = "cpal")]
+pub mod output;
 
 use std::path::Path;
-use std::io::{Seek, SeekFrom};
-use std::fs::File;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::fs::{self, File};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use futures::sync::mpsc::Sender;
 use opus::{Channels, Application};
 
 use crate::error::{Error, Result};
+use crate::configuration::Codec;
+use crate::resample::Resampler;
 pub use crate::configuration::Configuration;
+pub use crate::loudness::{Loudness, Normalization};
+pub use crate::metadata::Metadata;
 
 /// Size of a single raw audio block
 const RAW_BLOCK_SIZE: usize = 1920;
 
+/// Sample rate of the stored audio data
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Version of the file format written by `save_pcm`
+const VERSION: u8 = 4;
+
 /// Represents an open audio file
 pub struct Container<T> {
     /// Each SH channel needs its own decoder
@@ -41,7 +68,21 @@ pub struct Container<T> {
     /// Number of samples in the audio file
     samples: u32,
     /// SH scales for each SH channel
-    scales: Vec<f32>
+    scales: Vec<f32>,
+    /// Version 1 files lack the X component, which has to be restored from W
+    legacy: bool,
+    /// Codec of the last decoded packet, keeping the decoder state between packets
+    codec: Option<Codec>,
+    /// Resampler of the last decoded packet with its target sample rate
+    resampler: Option<(u32, Resampler)>,
+    /// Encoder of the last compressed packet with its number of channels
+    encoder: Option<(usize, opus::Encoder)>,
+    /// Loudness of the track, only available since version 3
+    loudness: Option<Loudness>,
+    /// Normalisation of the playback volume
+    normalization: Normalization,
+    /// Size of the metadata chunk, only available since version 4
+    metadata_size: Option<u32>
 }
 
 impl<T> Container<T> 
@@ -54,7 +95,14 @@ impl<T> Container<T>
             sh_order: sh_order,
             samples: samples,
             scales: scales,
-            inner: inner 
+            inner: inner,
+            legacy: false,
+            codec: None,
+            resampler: None,
+            encoder: None,
+            loudness: None,
+            normalization: Normalization::Off,
+            metadata_size: None
         };
 
         ct.seek_to_data();
@@ -82,16 +130,38 @@ impl<T> Container<T>
             scales.push(inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?);
         }
 
-        // Only this version is supported at the moment
-        if version != 1 {
+        // Only these versions are supported at the moment
+        if version == 0 || version > VERSION {
             return Err(Error::CorruptedFile);
         }
 
+        // read in the loudness measurement
+        let loudness = if version >= 3 {
+            Some(Loudness {
+                integrated: inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?,
+                true_peak: inner.read_f32::<LittleEndian>().map_err(|err| Error::File(err))?
+            })
+        } else {
+            None
+        };
+
+        // skip the metadata chunk, it is only read on demand
+        let metadata_size = if version >= 4 {
+            Some(inner.read_u32::<LittleEndian>().map_err(|err| Error::File(err))?)
+        } else {
+            None
+        };
+
         // There will never be a order larger than 6
-        if sh_order > 6 {
+        if sh_order > spherical::MAX_ORDER {
             return Err(Error::CorruptedFile);
         }
 
+        let legacy = version == 1 && sh_order == 1;
+        if legacy {
+            legacy_scales(&mut scales);
+        }
+
         
         //let header_size = 6 + 4 * (sh_order as u64 + 1) * (sh_order as u64 + 1);
         //let mut rem = inner.seek(SeekFrom::End(0)).unwrap() -  header_size;
@@ -101,7 +171,13 @@ impl<T> Container<T>
 
         //println!("Compression ratio {}", samples as f32 * 2.0 / rem as f32);
 
-        Ok(Container::new(sh_order, samples, scales, inner))
+        let mut ct = Container::new(sh_order, samples, scales, inner);
+        ct.legacy = legacy;
+        ct.loudness = loudness;
+        ct.metadata_size = metadata_size;
+        ct.seek_to_data();
+
+        Ok(ct)
     }
 
     /// Open a audio file from a certain path
@@ -116,9 +192,76 @@ impl<T> Container<T>
         self.samples
     }
 
+    /// Get the loudness of the track, if it was measured while encoding
+    pub fn loudness(&self) -> Option<Loudness> {
+        self.loudness
+    }
+
+    /// Set the normalisation of the playback volume
+    ///
+    /// Files without a loudness measurement are always played without normalisation.
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    /// Read the metadata chunk, if there is any
+    ///
+    /// The position in the audio data is preserved.
+    pub fn metadata(&mut self) -> Result<Option<Metadata>> {
+        let size = match self.metadata_size {
+            Some(0) | None => return Ok(None),
+            Some(x) => x as usize
+        };
+
+        let pos = self.inner.seek(SeekFrom::Current(0)).map_err(|err| Error::File(err))?;
+        let start = self.header_size() - size as u64;
+
+        let mut buf = vec![0u8; size];
+        self.inner.seek(SeekFrom::Start(start)).map_err(|err| Error::File(err))?;
+        let res = self.inner.read_exact(&mut buf).map_err(|err| Error::File(err));
+        self.inner.seek(SeekFrom::Start(pos)).map_err(|err| Error::File(err))?;
+        res?;
+
+        Metadata::from_bytes(&buf)
+    }
+
+    /// Size of the header including the metadata chunk
+    fn header_size(&self) -> u64 {
+        let mut header_size = 6 + 4 * (self.sh_order as u64 + 1) * (self.sh_order as u64 + 1);
+        if self.loudness.is_some() {
+            header_size += 8;
+        }
+        if let Some(size) = self.metadata_size {
+            header_size += 4 + size as u64;
+        }
+
+        header_size
+    }
+
     /// Seek to the beginning of the data section
     pub fn seek_to_data(&mut self) {
-        self.inner.seek(SeekFrom::Start(6 + 4 * (self.sh_order as u64 + 1) * (self.sh_order as u64 + 1))).unwrap();
+        let header_size = self.header_size();
+
+        self.inner.seek(SeekFrom::Start(header_size)).unwrap();
+    }
+
+    /// Write the header of the current file format
+    fn write_header(inner: &mut T, sh_order: u8, samples: u32, scales: &[f32], loudness: Loudness, metadata: &[u8]) -> Result<()> {
+        inner.write_u8(VERSION).map_err(|err| Error::File(err))?;
+        inner.write_u8(sh_order).map_err(|err| Error::File(err))?;
+        inner.write_u32::<LittleEndian>(samples).map_err(|err| Error::File(err))?;
+
+        for scale in scales {
+            inner.write_f32::<LittleEndian>(*scale).map_err(|err| Error::File(err))?;
+        }
+
+        inner.write_f32::<LittleEndian>(loudness.integrated).map_err(|err| Error::File(err))?;
+        inner.write_f32::<LittleEndian>(loudness.true_peak).map_err(|err| Error::File(err))?;
+
+        inner.write_u32::<LittleEndian>(metadata.len() as u32).map_err(|err| Error::File(err))?;
+        inner.write_all(metadata).map_err(|err| Error::File(err))?;
+
+        Ok(())
     }
 
     /*pub fn check_samplesize(&mut self) -> Result<()> {
@@ -144,6 +287,10 @@ impl<T> Container<T>
     pub fn seek_to_sample(&mut self, sample: u32) {
         self.seek_to_data();
 
+        // the old signal shouldn't bleed into the new position
+        self.resampler = None;
+        self.encoder = None;
+
         let mut pos = 0;
         while pos + RAW_BLOCK_SIZE < sample as usize {
             let mut skip = 0i64;
@@ -167,7 +314,16 @@ impl<T> Container<T>
         let sizes: Vec<Result<u8>> = (0..self.num_harmonics()).map(|_| {
             self.inner.read_u8().map_err(|_| Error::ReachedEnd)
         }).collect();
-        let codec = conf.codec();
+
+        // only create a new codec if the configuration changed
+        let codec_changed = match self.codec {
+            Some(ref codec) => *codec.configuration() != conf,
+            None => true
+        };
+
+        if codec_changed {
+            self.codec = Some(conf.codec());
+        }
 
         let mut buf = vec![0u8; 256];
         let mut single_harmonic = vec![0i16; RAW_BLOCK_SIZE];
@@ -202,17 +358,118 @@ impl<T> Container<T>
             i += 1;
         }
 
-        codec.to_channels(&self.scales, &harmonics, self.sh_order)
+        // X is a scaled copy of W in legacy stereo files
+        if self.legacy {
+            let (w, x) = harmonics.split_at_mut(3 * RAW_BLOCK_SIZE);
+            x[..RAW_BLOCK_SIZE].copy_from_slice(&w[..RAW_BLOCK_SIZE]);
+        }
+
+        // apply the normalisation gain by adjusting the scales
+        let gain = match (self.normalization, self.loudness) {
+            (Normalization::Track, Some(loudness)) => loudness.gain(),
+            (Normalization::Album(album), Some(_)) => album.gain(),
+            _ => 0.0
+        };
+
+        if gain == 0.0 {
+            self.codec.as_mut().unwrap().to_channels(&self.scales, &harmonics, self.sh_order)
+        } else {
+            let factor = 10f32.powf(-gain / 20.0);
+            let scales: Vec<f32> = self.scales.iter().map(|x| x * factor).collect();
+
+            self.codec.as_mut().unwrap().to_channels(&scales, &harmonics, self.sh_order)
+        }
+    }
+
+    /// Decode a single audio buffer to floating point samples with a certain sample rate
+    ///
+    /// The samples are interleaved with the channels of `conf` and lie in the range `[-1, 1]`. If
+    /// the sample rate differs from the one of the file, the audio is resampled. The resampler
+    /// delays the signal by a few samples, therefore the number of returned samples can vary
+    /// slightly between packets. The delayed samples are returned after the last packet, before
+    /// the end of the stream is reached.
+    pub fn next_packet_f32(&mut self, conf: Configuration, sample_rate: u32) -> Result<Vec<f32>> {
+        let num_channels = conf.num_channels();
+        let pcm: Vec<f32> = match self.next_packet(conf) {
+            Ok(pcm) => pcm.into_iter().map(|x| x as f32 / 32768.0).collect(),
+            Err(Error::ReachedEnd) => {
+                // pass on the delayed samples of the resampler once
+                return match self.resampler.take() {
+                    Some((_, mut resampler)) => Ok(resampler.flush()),
+                    None => Err(Error::ReachedEnd)
+                };
+            },
+            Err(err) => return Err(err)
+        };
+
+        if sample_rate == SAMPLE_RATE {
+            return Ok(pcm);
+        }
+
+        // only create a new resampler if the rate or number of channels changed
+        let resampler_changed = match self.resampler {
+            Some((rate, ref resampler)) => rate != sample_rate || resampler.channels() != num_channels,
+            None => true
+        };
+
+        if resampler_changed {
+            self.resampler = Some((sample_rate, Resampler::new(SAMPLE_RATE, sample_rate, num_channels)));
+        }
+
+        Ok(self.resampler.as_mut().unwrap().1.process(&pcm))
+    }
+
+    /// Decode a single audio buffer and compress it again to an Opus packet
+    ///
+    /// The stored packets can't be passed on directly, because they contain each harmonic as a
+    /// separate stream with its own scale. Instead the audio is rendered with `conf` and encoded
+    /// as a single mono or stereo stream, which any Opus decoder understands. Each packet contains
+    /// `RAW_BLOCK_SIZE` samples at 48kHz.
+    pub fn next_packet_opus(&mut self, conf: Configuration, bitrate: i32) -> Result<Vec<u8>> {
+        let num_channels = conf.num_channels();
+        let channels = match num_channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => return Err(Error::NotSupported)
+        };
+
+        let pcm = self.next_packet(conf)?;
+
+        // only create a new encoder if the number of channels changed
+        let encoder_changed = match self.encoder {
+            Some((channels, _)) => channels != num_channels,
+            None => true
+        };
+
+        if encoder_changed {
+            let mut encoder = opus::Encoder::new(SAMPLE_RATE, channels, Application::Audio)
+                .map_err(|err| Error::Opus(err))?;
+
+            encoder.set_bitrate(opus::Bitrate::Bits(bitrate))
+                .map_err(|err| Error::Opus(err))?;
+
+            self.encoder = Some((num_channels, encoder));
+        }
+
+        let mut buf = vec![0u8; 4000];
+        let nwritten = self.encoder.as_mut().unwrap().1.encode(&pcm, &mut buf)
+            .map_err(|err| Error::Opus(err))?;
+
+        buf.truncate(nwritten);
+
+        Ok(buf)
     }
 
     /// Converts raw audio with loudspeaker configuration to a new `Container`
     ///
     /// The `progress` field can be used to connect a channel to the convesion process and get live
     /// updates of the progress.
-    pub fn save_pcm(conf: Configuration, mut pcm: Vec<i16>, mut inner: T, mut progress: Option<Sender<f32>>) -> Result<Container<T>> {
-        inner.write_u8(1).map_err(|err| Error::File(err))?;
-        inner.write_u8(conf.sh_order()).map_err(|err| Error::File(err))?;
-    
+    pub fn save_pcm(conf: Configuration, pcm: Vec<i16>, inner: T, progress: Option<Sender<f32>>) -> Result<Container<T>> {
+        Container::save_pcm_with_metadata(conf, pcm, inner, progress, &Metadata::default())
+    }
+
+    /// Converts raw audio to a new `Container` and stores metadata alongside
+    pub fn save_pcm_with_metadata(conf: Configuration, mut pcm: Vec<i16>, mut inner: T, mut progress: Option<Sender<f32>>, metadata: &Metadata) -> Result<Container<T>> {
         // fill the audio signal encoded as channels up to multiple of RAW_BLOCK_SIZE
         let mut samples = pcm.len() / conf.num_channels() as usize;
 
@@ -222,24 +479,19 @@ impl<T> Container<T>
             samples += rem;
         }
 
-        inner.write_u32::<LittleEndian>(samples as u32).map_err(|err| Error::File(err))?;
-
-        // scale the audio data to the max 16bit range
-        let max_value = pcm.iter().map(|x| *x).max().ok_or(Error::InvalidRange)?;
-        let scale = 32767.0 / max_value as f32;
-
-        for val in pcm.iter_mut() {
-            *val = (*val as f32 * scale) as i16;
+        if pcm.is_empty() {
+            return Err(Error::InvalidRange);
         }
 
         // find the scales
         let sh_codec = conf.codec();
         let scales = sh_codec.scales(&pcm)?;
 
+        // measure the loudness of the original audio
+        let loudness = loudness::measure(&conf, &pcm);
 
-        for scale in &scales {
-            inner.write_f32::<LittleEndian>(*scale).map_err(|err| Error::File(err))?;
-        }
+        let metadata = if metadata.is_empty() { Vec::new() } else { metadata.to_bytes() };
+        Container::write_header(&mut inner, conf.sh_order(), samples as u32, &scales, loudness, &metadata)?;
 
         // the audio signal encoded in spherical harmonics
         let mut harmonics = vec![0i16; RAW_BLOCK_SIZE * conf.num_harmonics()];
@@ -284,10 +536,57 @@ impl<T> Container<T>
                 .map_err(|_| Error::SendFailed)?;
         }
 
-        Ok(Container::new(conf.sh_order(), samples as u32, scales, inner))
+        let mut ct = Container::new(conf.sh_order(), samples as u32, scales, inner);
+        ct.loudness = Some(loudness);
+        ct.metadata_size = Some(metadata.len() as u32);
+        ct.seek_to_data();
+
+        Ok(ct)
     }
 }
 
+/// Replace the metadata chunk of an audio file
+///
+/// The file is rewritten to a temporary file, which replaces the original afterwards. Files
+/// older than version 3 lack the loudness measurement and can't be upgraded.
+pub fn write_metadata(path: &Path, metadata: &Metadata) -> Result<()> {
+    let file = File::open(path).map_err(|err| Error::File(err))?;
+    let mut ct = Container::load(file)?;
+
+    let loudness = ct.loudness.ok_or(Error::NotSupported)?;
+
+    // copy the audio data
+    ct.seek_to_data();
+    let mut data = Vec::new();
+    ct.inner.read_to_end(&mut data).map_err(|err| Error::File(err))?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path).map_err(|err| Error::File(err))?;
+
+    let metadata = if metadata.is_empty() { Vec::new() } else { metadata.to_bytes() };
+    Container::write_header(&mut tmp, ct.sh_order, ct.samples, &ct.scales, loudness, &metadata)?;
+    tmp.write_all(&data).map_err(|err| Error::File(err))?;
+
+    fs::rename(&tmp_path, path).map_err(|err| Error::File(err))
+}
+
+/// Convert the scales of a version 1 stereo file to ACN/SN3D
+///
+/// Version 1 stored `0.7 * Y_00 * (L+R)` in the first and `0.7 * Y_1-1 * (L-R)` in the second
+/// harmonic. Both are proportional to W and Y of a stereo pair at ±30°, so only the scales have
+/// to be adjusted. The X component is recovered from W, because it equals `sqrt(3)/2 * W`.
+fn legacy_scales(scales: &mut [f32]) {
+    let first = 1.0 / (4.0 * std::f64::consts::PI).sqrt();
+    let secon = (3.0 / 8.0 / std::f64::consts::PI).sqrt();
+
+    let w = scales[0] as f64 * 0.7 * first;
+
+    scales[0] = w as f32;
+    scales[1] = (scales[1] as f64 * 1.4 * secon) as f32;
+    scales[2] = 1.0;
+    scales[3] = (w * 2.0 / 3f64.sqrt()) as f32;
+}
+
 #[cfg(test)]
 mod tests {
     use std::i16;
@@ -296,7 +595,95 @@ mod tests {
     use std::slice;
     use std::cmp::min;
 
-    use super::{Container, Configuration, RAW_BLOCK_SIZE};
+    use super::{Container, Configuration, Metadata, RAW_BLOCK_SIZE, legacy_scales};
+
+    #[test]
+    fn legacy_stereo() {
+        let first = 1.0 / (4.0 * std::f64::consts::PI).sqrt();
+        let secon = (3.0 / 8.0 / std::f64::consts::PI).sqrt();
+
+        // encode a single frame like version 1 did
+        let (left, right) = (1000.0, 200.0);
+        let w = ((left + right) * 0.7 * first * 10.0f64).round() as i16;
+        let y = ((left - right) * 0.7 * secon * 20.0f64).round() as i16;
+
+        let mut scales = vec![10.0, 20.0, 1.0, 30.0];
+        legacy_scales(&mut scales);
+
+        let pcm = Configuration::Stereo.codec().to_channels(&scales, &[w, y, 0, w], 1).unwrap();
+
+        assert_eq!(pcm, vec![1000, 200]);
+    }
+
+    #[test]
+    fn loudness_header() {
+        let pcm: Vec<i16> = (0..48000 * 2).map(|x| ((x as f32 / 48000.0 * 2.0 * 3.1415 * 440.0).sin() * 1000.0) as i16).collect();
+
+        let container = Container::save_pcm(Configuration::Stereo, pcm, Cursor::new(Vec::new()), None).unwrap();
+        let loudness = container.loudness().unwrap();
+
+        // the header is read back with the same measurement and data position
+        let mut loaded = Container::load(Cursor::new(container.inner.into_inner())).unwrap();
+        assert_eq!(loaded.loudness(), Some(loudness));
+        assert_eq!(loaded.metadata().unwrap(), None);
+        assert!(loaded.next_packet(Configuration::Stereo).is_ok());
+    }
+
+    #[test]
+    fn metadata_header() {
+        let pcm: Vec<i16> = (0..48000).map(|x| ((x as f32 / 48000.0 * 2.0 * 3.1415 * 440.0).sin() * 1000.0) as i16).collect();
+        let metadata = Metadata {
+            title: Some("Title".into()),
+            fingerprint: Some(vec![1, 2, 3]),
+            ..Metadata::default()
+        };
+
+        let container = Container::save_pcm_with_metadata(Configuration::Stereo, pcm, Cursor::new(Vec::new()), None, &metadata).unwrap();
+        let mut loaded = Container::load(Cursor::new(container.inner.into_inner())).unwrap();
+
+        // reading the metadata doesn't change the position in the audio data
+        assert!(loaded.next_packet(Configuration::Stereo).is_ok());
+        assert_eq!(loaded.metadata().unwrap(), Some(metadata));
+        assert!(loaded.next_packet(Configuration::Stereo).is_ok());
+    }
+
+    #[test]
+    fn opus_stream() {
+        let pcm: Vec<i16> = (0..48000).map(|x| ((x as f32 / 48000.0 * 2.0 * 3.1415 * 440.0).sin() * 1000.0) as i16).collect();
+
+        let container = Container::save_pcm(Configuration::Stereo, pcm, Cursor::new(Vec::new()), None).unwrap();
+        let mut loaded = Container::load(Cursor::new(container.inner.into_inner())).unwrap();
+
+        // every packet can be decoded by a plain stereo decoder
+        let mut decoder = opus::Decoder::new(48000, opus::Channels::Stereo).unwrap();
+        let mut out = vec![0i16; RAW_BLOCK_SIZE * 2];
+        let packet = loaded.next_packet_opus(Configuration::Stereo, 128000).unwrap();
+        assert_eq!(decoder.decode(&packet, &mut out, false).unwrap(), RAW_BLOCK_SIZE);
+
+        // the re-encoded stream is limited to mono and stereo
+        assert!(loaded.next_packet_opus(Configuration::Surround51, 128000).is_err());
+    }
+
+    #[test]
+    fn resampled_tail() {
+        let pcm: Vec<i16> = (0..48000).map(|x| ((x as f32 / 48000.0 * 2.0 * 3.1415 * 440.0).sin() * 1000.0) as i16).collect();
+        let container = Container::save_pcm(Configuration::Stereo, pcm, Cursor::new(Vec::new()), None).unwrap();
+        let data = container.inner.into_inner();
+
+        let frames = |sample_rate| {
+            let mut loaded = Container::load(Cursor::new(data.clone())).unwrap();
+            let mut num = 0;
+            while let Ok(buf) = loaded.next_packet_f32(Configuration::Stereo, sample_rate) {
+                num += buf.len() / 2;
+            }
+
+            num as i64
+        };
+
+        // the resampler is flushed at the end, so no samples are lost
+        let (native, resampled) = (frames(48000), frames(44100));
+        assert!((resampled - native * 44100 / 48000).abs() <= 2);
+    }
 
     #[test]
     fn amplitute() {
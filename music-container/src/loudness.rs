@@ -0,0 +1,258 @@
+//! Loudness measurement after EBU R128
+//!
+//! The integrated loudness is measured with the algorithm of ITU-R BS.1770-4: every channel is
+//! K-weighted, the mean square is calculated in blocks of 400ms overlapping by 75% and the blocks
+//! are gated, first absolutely at -70 LUFS and then relatively at 10 LU below the loudness of the
+//! remaining blocks. The true peak is found by oversampling the signal four times.
+//!
+//! The measurement is stored in the container header and used to normalise the playback volume,
+//! similar to ReplayGain.
+
+use std::f64::consts::PI;
+
+use crate::configuration::{Configuration, Speaker};
+
+/// Target loudness of the normalisation in LUFS
+pub const REFERENCE_LOUDNESS: f32 = -18.0;
+
+/// Maximal true peak after applying the gain in dBTP
+const MAX_TRUE_PEAK: f32 = -1.0;
+
+/// Loudness of a track or album
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, negative infinity for silence
+    pub integrated: f32,
+    /// Maximal true peak in dBTP
+    pub true_peak: f32
+}
+
+/// Normalisation of the playback volume
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Play the track as it was encoded
+    Off,
+    /// Normalise every track to the reference loudness
+    Track,
+    /// Normalise the whole album, keeping the loudness differences of its tracks
+    Album(Loudness)
+}
+
+impl Loudness {
+    /// Combine the loudness of several tracks, given with their number of samples
+    ///
+    /// This approximates the album loudness with the energy average of all tracks weighted by
+    /// their length.
+    pub fn album(tracks: &[(Loudness, u32)]) -> Option<Loudness> {
+        let (mut energy, mut length) = (0.0, 0.0);
+        let mut true_peak = std::f32::NEG_INFINITY;
+
+        for (loudness, samples) in tracks {
+            if loudness.integrated.is_finite() {
+                energy += *samples as f64 * 10f64.powf(loudness.integrated as f64 / 10.0);
+                length += *samples as f64;
+            }
+
+            true_peak = true_peak.max(loudness.true_peak);
+        }
+
+        if tracks.is_empty() {
+            return None;
+        }
+
+        let integrated = if length > 0.0 {
+            (10.0 * (energy / length).log10()) as f32
+        } else {
+            std::f32::NEG_INFINITY
+        };
+
+        Some(Loudness { integrated, true_peak })
+    }
+
+    /// Gain in dB to reach the reference loudness without exceeding the maximal true peak
+    pub fn gain(&self) -> f32 {
+        if !self.integrated.is_finite() {
+            return 0.0;
+        }
+
+        (REFERENCE_LOUDNESS - self.integrated).min(MAX_TRUE_PEAK - self.true_peak)
+    }
+}
+
+/// Second order IIR filter in direct form I
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2]
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// K-weighting filter at 48kHz, a high shelf followed by a high pass
+fn k_weighting() -> (Biquad, Biquad) {
+    (
+        Biquad::new([1.53512485958697, -2.69169618940638, 1.19839281085285], [-1.69065929318241, 0.73248077421585]),
+        Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621])
+    )
+}
+
+/// Weight of each channel in the loudness sum, LFE channels are ignored
+fn channel_weights(conf: &Configuration) -> Vec<f64> {
+    match conf.speakers() {
+        Some(speakers) => speakers.into_iter().map(|x| match x {
+            // surround channels at the side are perceived louder
+            Speaker::Directional(dir) if dir.azimuth.abs() >= 60.0 && dir.azimuth.abs() <= 120.0 && dir.elevation.abs() < 30.0 => 1.41,
+            Speaker::Directional(_) => 1.0,
+            Speaker::LowFrequency => 0.0
+        }).collect(),
+        // only the omnidirectional component of a sound field is measured
+        None => {
+            let mut weights = vec![0.0; conf.num_channels()];
+            weights[0] = 1.0;
+
+            weights
+        }
+    }
+}
+
+/// Polyphase filters of the four times oversampling
+fn oversampling_filters() -> Vec<Vec<f64>> {
+    let (phases, half_taps) = (4, 6);
+
+    (0..phases).map(|p| {
+        (0..2 * half_taps).map(|k| {
+            // distance to the interpolated position in input samples
+            let x = k as f64 - half_taps as f64 + 1.0 - p as f64 / phases as f64;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let window = 0.42 + 0.5 * (PI * x / half_taps as f64).cos() + 0.08 * (2.0 * PI * x / half_taps as f64).cos();
+
+            sinc * window
+        }).collect()
+    }).collect()
+}
+
+/// Measure the integrated loudness and true peak of interleaved 48kHz audio
+pub fn measure(conf: &Configuration, pcm: &[i16]) -> Loudness {
+    let num_channels = conf.num_channels();
+    let weights = channel_weights(conf);
+
+    let mut filters = vec![k_weighting(); num_channels];
+    let oversampling = oversampling_filters();
+    let mut history = vec![vec![0.0; oversampling[0].len()]; num_channels];
+
+    // mean square of every 100ms step
+    let step = 4800;
+    let mut steps = Vec::new();
+    let (mut sum, mut count) = (0.0, 0);
+    let mut true_peak = 0.0f64;
+
+    for frame in pcm.chunks(num_channels) {
+        for (c, sample) in frame.iter().enumerate() {
+            let x = *sample as f64 / 32768.0;
+
+            if weights[c] > 0.0 {
+                let (ref mut shelf, ref mut highpass) = filters[c];
+                let y = highpass.process(shelf.process(x));
+
+                sum += weights[c] * y * y;
+            }
+
+            // shift the new sample into the history and interpolate between the samples
+            history[c].remove(0);
+            history[c].push(x);
+
+            for filter in &oversampling {
+                let val: f64 = filter.iter().zip(history[c].iter()).map(|(a, b)| a * b).sum();
+                true_peak = true_peak.max(val.abs());
+            }
+        }
+
+        count += 1;
+        if count == step {
+            steps.push(sum / step as f64);
+            sum = 0.0;
+            count = 0;
+        }
+    }
+
+    // gating blocks of 400ms are made of four steps
+    let blocks: Vec<f64> = steps.windows(4).map(|x| x.iter().sum::<f64>() / 4.0).collect();
+    let loudness = |x: f64| -0.691 + 10.0 * x.log10();
+
+    let absolute: Vec<f64> = blocks.into_iter().filter(|x| loudness(*x) > -70.0).collect();
+    let integrated = if absolute.is_empty() {
+        std::f64::NEG_INFINITY
+    } else {
+        let threshold = loudness(absolute.iter().sum::<f64>() / absolute.len() as f64) - 10.0;
+        let relative: Vec<f64> = absolute.into_iter().filter(|x| loudness(*x) > threshold).collect();
+
+        loudness(relative.iter().sum::<f64>() / relative.len() as f64)
+    };
+
+    Loudness {
+        integrated: integrated as f32,
+        true_peak: (20.0 * true_peak.log10()) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{measure, Loudness};
+    use crate::Configuration;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn sine_stereo() {
+        // a 1kHz sine at -23 dBFS in both channels has a loudness of -23 LUFS (EBU Tech 3341)
+        let amplitude = 10f64.powf(-23.0 / 20.0) * 32768.0;
+        let pcm: Vec<i16> = (0..48000 * 20).flat_map(|i| {
+            let val = (amplitude * (2.0 * PI * 1000.0 * i as f64 / 48000.0).sin()).round() as i16;
+
+            vec![val, val]
+        }).collect();
+
+        let loudness = measure(&Configuration::Stereo, &pcm);
+
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{:?}", loudness);
+        assert!((loudness.true_peak + 23.0).abs() < 0.1, "{:?}", loudness);
+    }
+
+    #[test]
+    fn silence() {
+        let loudness = measure(&Configuration::Omnidirectional, &vec![0; 48000]);
+
+        assert!(loudness.integrated.is_infinite());
+        assert_eq!(loudness.gain(), 0.0);
+    }
+
+    #[test]
+    fn gain() {
+        let quiet = Loudness { integrated: -30.0, true_peak: -20.0 };
+        let loud = Loudness { integrated: -8.0, true_peak: 0.0 };
+
+        // the gain of quiet tracks is limited by their peak
+        assert_eq!(quiet.gain(), 12.0);
+        assert_eq!(loud.gain(), -10.0);
+        assert_eq!(Loudness { integrated: -30.0, true_peak: -5.0 }.gain(), 4.0);
+
+        let album = Loudness::album(&[(quiet, 100), (loud, 100)]).unwrap();
+        assert!((album.integrated + 11.0).abs() < 0.1);
+        assert_eq!(album.true_peak, 0.0);
+    }
+}
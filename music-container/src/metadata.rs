@@ -0,0 +1,189 @@
+//! Metadata chunk of the container
+//!
+//! Since version 4 the header contains an optional metadata chunk, which describes the track
+//! without the need of a database. The chunk is prefixed with its length, so readers not
+//! interested in the metadata can skip it. It starts with its own version, followed by any
+//! number of fields:
+//!
+//! |       |    1    |     1    |     4    | length      |
+//! |-------|---------|----------|----------|-------------|
+//! | field | version | tag      | length   | data ...    |
+//!
+//! Unknown tags and chunk versions are ignored, which allows adding fields without breaking
+//! older readers.
+
+use std::io::{Cursor, Read};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use crate::error::{Error, Result};
+
+/// Version of the metadata chunk
+const METADATA_VERSION: u8 = 1;
+
+const TAG_TITLE: u8 = 1;
+const TAG_ALBUM: u8 = 2;
+const TAG_INTERPRET: u8 = 3;
+const TAG_PEOPLE: u8 = 4;
+const TAG_COMPOSER: u8 = 5;
+const TAG_FINGERPRINT: u8 = 6;
+const TAG_COVER: u8 = 7;
+const TAG_PEER: u8 = 8;
+
+/// Descriptive information about a track
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// The title of the track
+    pub title: Option<String>,
+    /// The album containing the track
+    pub album: Option<String>,
+    /// The interpret
+    pub interpret: Option<String>,
+    /// All people who helped to perform the track
+    pub people: Option<String>,
+    /// The original composer
+    pub composer: Option<String>,
+    /// The acoustic fingerprint of the track
+    pub fingerprint: Option<Vec<u32>>,
+    /// Cover image in its original format (JPEG, PNG, ..)
+    pub cover: Option<Vec<u8>>,
+    /// Id of the peer which imported the track
+    pub peer: Option<Vec<u8>>
+}
+
+impl Metadata {
+    /// Check if no field is set
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Serialize the metadata to a chunk
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![METADATA_VERSION];
+
+        {
+            let mut field = |tag: u8, data: &[u8]| {
+                buf.push(tag);
+                buf.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+                buf.extend_from_slice(data);
+            };
+
+            let strings = [
+                (TAG_TITLE, &self.title), (TAG_ALBUM, &self.album), (TAG_INTERPRET, &self.interpret),
+                (TAG_PEOPLE, &self.people), (TAG_COMPOSER, &self.composer)
+            ];
+
+            for (tag, val) in strings.iter() {
+                if let Some(ref val) = val {
+                    field(*tag, val.as_bytes());
+                }
+            }
+
+            if let Some(ref fingerprint) = self.fingerprint {
+                let mut data = Vec::with_capacity(fingerprint.len() * 4);
+                for val in fingerprint {
+                    data.write_u32::<LittleEndian>(*val).unwrap();
+                }
+
+                field(TAG_FINGERPRINT, &data);
+            }
+
+            if let Some(ref cover) = self.cover {
+                field(TAG_COVER, cover);
+            }
+
+            if let Some(ref peer) = self.peer {
+                field(TAG_PEER, peer);
+            }
+        }
+
+        buf
+    }
+
+    /// Parse a metadata chunk
+    ///
+    /// Returns `None` for an empty chunk or an unknown version.
+    pub fn from_bytes(buf: &[u8]) -> Result<Option<Metadata>> {
+        let mut cursor = Cursor::new(buf);
+
+        match cursor.read_u8() {
+            Ok(METADATA_VERSION) => {},
+            _ => return Ok(None)
+        }
+
+        let mut metadata = Metadata::default();
+
+        while (cursor.position() as usize) < buf.len() {
+            let tag = cursor.read_u8().map_err(|_| Error::CorruptedFile)?;
+            let length = cursor.read_u32::<LittleEndian>().map_err(|_| Error::CorruptedFile)? as usize;
+
+            if cursor.position() as usize + length > buf.len() {
+                return Err(Error::CorruptedFile);
+            }
+
+            let mut data = vec![0u8; length];
+            cursor.read_exact(&mut data).map_err(|_| Error::CorruptedFile)?;
+
+            let string = |data: Vec<u8>| String::from_utf8(data).map_err(|_| Error::CorruptedFile);
+
+            match tag {
+                TAG_TITLE => metadata.title = Some(string(data)?),
+                TAG_ALBUM => metadata.album = Some(string(data)?),
+                TAG_INTERPRET => metadata.interpret = Some(string(data)?),
+                TAG_PEOPLE => metadata.people = Some(string(data)?),
+                TAG_COMPOSER => metadata.composer = Some(string(data)?),
+                TAG_FINGERPRINT => {
+                    metadata.fingerprint = Some(data.chunks(4)
+                        .map(|mut x| x.read_u32::<LittleEndian>().map_err(|_| Error::CorruptedFile))
+                        .collect::<Result<Vec<u32>>>()?);
+                },
+                TAG_COVER => metadata.cover = Some(data),
+                TAG_PEER => metadata.peer = Some(data),
+                // skip fields of newer versions
+                _ => {}
+            }
+        }
+
+        Ok(Some(metadata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+
+    #[test]
+    fn roundtrip() {
+        let metadata = Metadata {
+            title: Some("Für Elise".into()),
+            composer: Some("Ludwig van Beethoven".into()),
+            fingerprint: Some(vec![1, 2, 0xdeadbeef]),
+            cover: Some(vec![0xff, 0xd8, 0xff]),
+            ..Metadata::default()
+        };
+
+        assert_eq!(Metadata::from_bytes(&metadata.to_bytes()).unwrap(), Some(metadata));
+    }
+
+    #[test]
+    fn skip_unknown() {
+        let mut buf = Metadata { album: Some("Album".into()), ..Metadata::default() }.to_bytes();
+
+        // a field of a future version
+        buf.extend_from_slice(&[200, 2, 0, 0, 0, 1, 2]);
+
+        let metadata = Metadata::from_bytes(&buf).unwrap().unwrap();
+        assert_eq!(metadata.album, Some("Album".into()));
+
+        // an unknown chunk version is ignored completely
+        assert_eq!(Metadata::from_bytes(&[2, 1, 0, 0, 0, 0]).unwrap(), None);
+        assert_eq!(Metadata::from_bytes(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = Metadata { title: Some("Title".into()), ..Metadata::default() }.to_bytes();
+
+        assert!(Metadata::from_bytes(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! Output formats of local audio devices
+//!
+//! Only available with the `cpal` feature, it is shared by the players of the CLI and zyklop.
+
+use cpal::traits::DeviceTrait;
+
+use crate::SAMPLE_RATE;
+
+/// Find an output format with the requested number of channels
+///
+/// A sample rate of 48kHz is preferred, because no resampling is necessary then. Otherwise
+/// 44.1kHz or the highest supported sample rate is used. If no format has the requested number
+/// of channels, the default format of the device is taken.
+pub fn find_format(device: &cpal::Device, channels: u16) -> cpal::Format {
+    let formats: Vec<cpal::SupportedFormat> = device.supported_output_formats()
+        .map(|x| x.filter(|x| x.channels == channels).collect())
+        .unwrap_or_else(|_| Vec::new());
+
+    let contains = |x: &cpal::SupportedFormat, rate: u32| x.min_sample_rate.0 <= rate && x.max_sample_rate.0 >= rate;
+
+    let format = formats.iter().find(|x| contains(x, SAMPLE_RATE)).map(|x| (x, SAMPLE_RATE))
+        .or_else(|| formats.iter().find(|x| contains(x, 44100)).map(|x| (x, 44100)))
+        .or_else(|| formats.iter().max_by_key(|x| x.max_sample_rate.0).map(|x| (x, x.max_sample_rate.0)));
+
+    match format {
+        Some((format, rate)) => cpal::Format {
+            channels: channels,
+            sample_rate: cpal::SampleRate(rate),
+            data_type: format.data_type
+        },
+        None => device.default_output_format().expect("Failed to get default output format")
+    }
+}
@@ -0,0 +1,171 @@
+//! Sample rate conversion of interleaved audio
+//!
+//! The resampler interpolates with a windowed sinc kernel, which is tabulated with a high
+//! oversampling factor and linearly interpolated between the table entries. When converting to a
+//! lower sample rate the cutoff frequency is lowered accordingly to avoid aliasing.
+//!
+//! The state is kept between calls, so a stream can be converted block by block. The kernel
+//! introduces a delay of `HALF_TAPS` input frames at the end of a stream; these frames are
+//! dropped unless `flush` is called.
+
+use std::f64::consts::PI;
+
+/// Number of kernel taps on each side of the interpolated position
+const HALF_TAPS: usize = 32;
+
+/// Number of table entries between two zero crossings of the kernel
+const PHASES: usize = 256;
+
+/// Converts interleaved audio from one sample rate to another
+pub struct Resampler {
+    channels: usize,
+    /// Input frames advanced for each output frame
+    step: f64,
+    /// Tabulated kernel from zero to `HALF_TAPS`
+    kernel: Vec<f64>,
+    /// Interleaved input frames, not yet consumed
+    buffer: Vec<f32>,
+    /// Position of the next output frame in `buffer`
+    pos: f64
+}
+
+impl Resampler {
+    /// Create a new resampler for a number of interleaved channels
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Resampler {
+        let step = from_rate as f64 / to_rate as f64;
+
+        // leave some room for the transition band below the Nyquist frequency
+        let cutoff = 0.95 * (1.0 / step).min(1.0);
+
+        let kernel = (0..=HALF_TAPS * PHASES).map(|i| {
+            let x = i as f64 / PHASES as f64;
+            let sinc = if i == 0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+
+            // Blackman window over the whole kernel length
+            let w = PI * x / HALF_TAPS as f64;
+            let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+
+            cutoff * sinc * window
+        }).collect();
+
+        Resampler {
+            channels,
+            step,
+            kernel,
+            // start with silence in the past, so the first output frame lies at the first input
+            buffer: vec![0.0; HALF_TAPS * channels],
+            pos: HALF_TAPS as f64
+        }
+    }
+
+    /// Number of interleaved channels
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Look up the kernel at a distance from the interpolated position
+    fn kernel(&self, x: f64) -> f64 {
+        let idx = x.abs() * PHASES as f64;
+        let (i, frac) = (idx.floor() as usize, idx.fract());
+
+        if i + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+
+        self.kernel[i] * (1.0 - frac) + self.kernel[i + 1] * frac
+    }
+
+    /// Convert a block of interleaved frames
+    ///
+    /// The number of returned frames depends on the frames processed so far, but converges to
+    /// `to_rate / from_rate` times the number of input frames.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.buffer.extend_from_slice(input);
+
+        let num_frames = self.buffer.len() / self.channels;
+        let mut out = Vec::new();
+
+        // interpolate while all taps right of the position are available
+        while (self.pos.floor() as usize) + HALF_TAPS < num_frames {
+            let center = self.pos.floor() as usize;
+            let frac = self.pos - center as f64;
+
+            for c in 0..self.channels {
+                let mut acc = 0.0;
+                for k in (center + 1 - HALF_TAPS)..=(center + HALF_TAPS) {
+                    acc += self.buffer[k * self.channels + c] as f64 * self.kernel(k as f64 - center as f64 - frac);
+                }
+
+                out.push(acc as f32);
+            }
+
+            self.pos += self.step;
+        }
+
+        // drop the frames which are no longer needed
+        let consumed = (self.pos.floor() as usize + 1).saturating_sub(HALF_TAPS).min(num_frames);
+        self.buffer.drain(..consumed * self.channels);
+        self.pos -= consumed as f64;
+
+        out
+    }
+
+    /// Process the remaining frames at the end of a stream
+    pub fn flush(&mut self) -> Vec<f32> {
+        let silence = vec![0.0; HALF_TAPS * self.channels];
+
+        self.process(&silence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resampler;
+    use std::f64::consts::PI;
+
+    fn sine(rate: u32, freq: f64, frames: usize) -> Vec<f32> {
+        (0..frames).flat_map(|i| {
+            let val = (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32;
+
+            vec![val, -val]
+        }).collect()
+    }
+
+    #[test]
+    fn sine_to_44100() {
+        let mut resampler = Resampler::new(48000, 44100, 2);
+
+        // convert in blocks, like the container does
+        let input = sine(48000, 1000.0, 48000);
+        let mut out = Vec::new();
+        for block in input.chunks(1920 * 2) {
+            out.extend(resampler.process(block));
+        }
+        out.extend(resampler.flush());
+
+        assert!((out.len() as i64 / 2 - 44100).abs() <= 2);
+
+        // compare with the ideal sine, ignoring the fade in and out of the kernel
+        let expected = sine(44100, 1000.0, 44100);
+        for i in 100..44000 {
+            assert!((out[i * 2] - expected[i * 2]).abs() < 1e-3, "{} != {} at {}", out[i * 2], expected[i * 2], i);
+            assert!((out[i * 2 + 1] - expected[i * 2 + 1]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn sine_to_96000() {
+        let mut resampler = Resampler::new(48000, 96000, 1);
+
+        let input: Vec<f32> = (0..4800).map(|i| (2.0 * PI * 440.0 * i as f64 / 48000.0).sin() as f32).collect();
+        let mut out = resampler.process(&input);
+        out.extend(resampler.flush());
+
+        assert!((out.len() as i64 - 9600).abs() <= 2);
+
+        for i in 100..9500 {
+            let expected = (2.0 * PI * 440.0 * i as f64 / 96000.0).sin() as f32;
+            assert!((out[i] - expected).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Real spherical harmonics in the AmbiX convention
+//!
+//! All harmonics are ordered with the Ambisonic Channel Number (ACN), which places the harmonic
+//! of degree `n` and order `m` at index `n*n + n + m`, and normalised with SN3D (Schmidt
+//! semi-normalised, no Condon-Shortley phase). A unit source therefore always has an
+//! omnidirectional component `W` of one, and every first order component lies in `[-1, 1]`.
+//!
+//! Directions are given in degrees. The azimuth is measured counter-clockwise from the front
+//! (positive values are on the left side) and the elevation upwards from the horizontal plane.
+
+use std::f64::consts::PI;
+
+/// Maximal SH order supported by the file format
+pub const MAX_ORDER: u8 = 6;
+
+/// Direction of a sound source or loudspeaker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Direction {
+    /// Angle in degrees, counter-clockwise from the front
+    pub azimuth: f64,
+    /// Angle in degrees above the horizontal plane
+    pub elevation: f64
+}
+
+impl Direction {
+    /// Create a new direction from azimuth and elevation in degrees
+    pub fn new(azimuth: f64, elevation: f64) -> Direction {
+        Direction { azimuth, elevation }
+    }
+
+    /// Create a direction on the horizontal plane
+    pub fn horizontal(azimuth: f64) -> Direction {
+        Direction::new(azimuth, 0.0)
+    }
+}
+
+/// Number of harmonics up to (and including) a certain order
+pub fn num_harmonics(order: u8) -> usize {
+    (order as usize + 1) * (order as usize + 1)
+}
+
+/// Ambisonic Channel Number of degree `n` and order `m`
+pub fn acn(n: u8, m: i8) -> usize {
+    let n = n as i32;
+
+    (n * n + n + m as i32) as usize
+}
+
+/// Associated Legendre polynomial `P_n^m(x)` without the Condon-Shortley phase
+fn legendre(n: u32, m: u32, x: f64) -> f64 {
+    // start with P_m^m = (2m-1)!! (1-x²)^(m/2)
+    let mut pmm = 1.0;
+    let root = (1.0 - x * x).max(0.0).sqrt();
+    for i in 0..m {
+        pmm *= (2 * i + 1) as f64 * root;
+    }
+
+    if n == m {
+        return pmm;
+    }
+
+    // P_(m+1)^m = x (2m+1) P_m^m
+    let mut pmm1 = x * (2 * m + 1) as f64 * pmm;
+    if n == m + 1 {
+        return pmm1;
+    }
+
+    // climb up in degree with the three-term recurrence
+    let mut pnm = 0.0;
+    for l in (m + 2)..=n {
+        pnm = ((2 * l - 1) as f64 * x * pmm1 - (l + m - 1) as f64 * pmm) / (l - m) as f64;
+        pmm = pmm1;
+        pmm1 = pnm;
+    }
+
+    pnm
+}
+
+/// SN3D normalisation factor of degree `n` and absolute order `m`
+fn sn3d(n: u32, m: u32) -> f64 {
+    // (n-m)! / (n+m)! as a product to stay in range for high orders
+    let mut ratio = 1.0;
+    for k in (n - m + 1)..=(n + m) {
+        ratio /= k as f64;
+    }
+
+    let delta = if m == 0 { 1.0 } else { 2.0 };
+
+    (delta * ratio).sqrt()
+}
+
+/// Evaluate all real spherical harmonics up to `order` in a direction
+///
+/// The result contains `(order+1)²` coefficients in ACN ordering with SN3D normalisation.
+pub fn coefficients(order: u8, dir: Direction) -> Vec<f64> {
+    let azimuth = dir.azimuth / 180.0 * PI;
+    let elevation = dir.elevation / 180.0 * PI;
+    let sin_el = elevation.sin();
+
+    let mut coeffs = vec![0.0; num_harmonics(order)];
+
+    for n in 0..=order {
+        for m in -(n as i8)..=(n as i8) {
+            let abs_m = m.abs() as u32;
+            let radial = sn3d(n as u32, abs_m) * legendre(n as u32, abs_m, sin_el);
+
+            let circular = if m >= 0 {
+                (abs_m as f64 * azimuth).cos()
+            } else {
+                (abs_m as f64 * azimuth).sin()
+            };
+
+            coeffs[acn(n, m)] = radial * circular;
+        }
+    }
+
+    coeffs
+}
+
+/// Create a mode-matching decoder for a set of loudspeaker directions
+///
+/// The decoder is the (Tikhonov regularised) pseudo-inverse of the matrix re-encoding each
+/// loudspeaker as plane wave. It has `directions.len()` rows and `(order+1)²` columns. If the
+/// loudspeakers can represent all harmonics, re-encoding the decoded signal reproduces the
+/// harmonics exactly. Harmonics the layout can't represent (e.g. height in a horizontal layout)
+/// are dropped instead of blowing up the gains.
+pub fn decoder_matrix(order: u8, directions: &[Direction]) -> Vec<f64> {
+    let num_speakers = directions.len();
+    let num_harmonics = num_harmonics(order);
+
+    // Y has a row for each speaker with its harmonics (transposed encoding matrix)
+    let y: Vec<Vec<f64>> = directions.iter().map(|dir| coefficients(order, *dir)).collect();
+
+    // build the gram matrix Y Y^T of the loudspeakers
+    let mut gram = vec![vec![0.0; num_speakers]; num_speakers];
+    for i in 0..num_speakers {
+        for j in 0..num_speakers {
+            gram[i][j] = y[i].iter().zip(y[j].iter()).map(|(a, b)| a * b).sum();
+        }
+    }
+
+    // regularise relative to the largest diagonal element
+    let max_diag = (0..num_speakers).map(|i| gram[i][i]).fold(0.0, f64::max);
+    for i in 0..num_speakers {
+        gram[i][i] += 1e-9 * max_diag.max(1.0);
+    }
+
+    // solve (Y Y^T + λI) D = Y for all harmonics at once
+    let mut rhs = y;
+    solve(&mut gram, &mut rhs);
+
+    let mut matrix = vec![0.0; num_speakers * num_harmonics];
+    for (i, row) in rhs.into_iter().enumerate() {
+        matrix[i * num_harmonics..(i + 1) * num_harmonics].copy_from_slice(&row);
+    }
+
+    matrix
+}
+
+/// Solve the linear system `a x = b` in-place with Gaussian elimination
+///
+/// The matrix `a` has to be square and invertible, every row of `b` is transformed to the
+/// corresponding row of the solution.
+fn solve(a: &mut Vec<Vec<f64>>, b: &mut Vec<Vec<f64>>) {
+    let n = a.len();
+
+    for col in 0..n {
+        // partial pivoting for numerical stability
+        let pivot = (col..n).max_by(|x, y| a[*x][col].abs().partial_cmp(&a[*y][col].abs()).unwrap()).unwrap();
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in 0..n {
+            if row == col {
+                continue;
+            }
+
+            let factor = a[row][col] / a[col][col];
+            if factor == 0.0 {
+                continue;
+            }
+
+            for k in col..n {
+                a[row][k] -= factor * a[col][k];
+            }
+            for k in 0..b[row].len() {
+                b[row][k] -= factor * b[col][k];
+            }
+        }
+    }
+
+    for row in 0..n {
+        let diag = a[row][row];
+        for val in b[row].iter_mut() {
+            *val /= diag;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coefficients, decoder_matrix, num_harmonics, Direction};
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len());
+
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn first_order() {
+        // a source on the left side has only W and Y
+        assert_close(&coefficients(1, Direction::horizontal(90.0)), &[1.0, 1.0, 0.0, 0.0]);
+        // a source above has only W and Z
+        assert_close(&coefficients(1, Direction::new(0.0, 90.0)), &[1.0, 0.0, 1.0, 0.0]);
+        // a source in front has only W and X
+        assert_close(&coefficients(1, Direction::horizontal(0.0)), &[1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn second_order() {
+        let s3 = 3.0f64.sqrt() / 2.0;
+
+        // compare with the closed form of the AmbiX second order harmonics
+        let (az, el) = (40.0f64.to_radians(), 25.0f64.to_radians());
+        let expected = [
+            1.0,
+            az.sin() * el.cos(),
+            el.sin(),
+            az.cos() * el.cos(),
+            s3 * (2.0 * az).sin() * el.cos() * el.cos(),
+            s3 * az.sin() * (2.0 * el).sin(),
+            0.5 * (3.0 * el.sin() * el.sin() - 1.0),
+            s3 * az.cos() * (2.0 * el).sin(),
+            s3 * (2.0 * az).cos() * el.cos() * el.cos()
+        ];
+
+        assert_close(&coefficients(2, Direction::new(40.0, 25.0)), &expected);
+    }
+
+    #[test]
+    fn mode_matching() {
+        // a cube of loudspeakers can reproduce a first order field exactly
+        let dirs: Vec<Direction> = [45.0, 135.0, -135.0, -45.0].iter()
+            .flat_map(|az| vec![Direction::new(*az, 35.26), Direction::new(*az, -35.26)])
+            .collect();
+
+        let decoder = decoder_matrix(1, &dirs);
+        let source = coefficients(1, Direction::new(20.0, 10.0));
+
+        // decode to the loudspeakers and encode again
+        let mut encoded = vec![0.0; num_harmonics(1)];
+        for (i, dir) in dirs.iter().enumerate() {
+            let gain: f64 = (0..4).map(|h| decoder[i * 4 + h] * source[h]).sum();
+
+            for (h, coeff) in coefficients(1, *dir).into_iter().enumerate() {
+                encoded[h] += gain * coeff;
+            }
+        }
+
+        assert_close(&encoded, &source);
+    }
+}
@@ -0,0 +1,123 @@
+//! Read the tags embedded in audio files
+//!
+//! ID3 frames, Vorbis comments and MP4 atoms are read by the demuxers of `symphonia`, which are
+//! also used by the `decode` module. Only files in other formats are passed to `ffprobe`. Both
+//! normalise the names of common tags, which are returned as pairs of lowercase name and value in
+//! the order of the file. The interpretation is left to the caller.
+
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+/// Name and value of a tag, common tags are named like by `ffprobe`
+fn pair(tag: &Tag) -> (String, String) {
+    let name = match tag.std_key {
+        Some(StandardTagKey::TrackTitle) => "title",
+        Some(StandardTagKey::Album) => "album",
+        Some(StandardTagKey::Artist) => "artist",
+        Some(StandardTagKey::AlbumArtist) => "album_artist",
+        Some(StandardTagKey::Performer) => "performer",
+        Some(StandardTagKey::Composer) => "composer",
+        Some(StandardTagKey::TrackNumber) => "track",
+        Some(StandardTagKey::DiscNumber) => "disc",
+        _ => return (tag.key.to_lowercase(), tag.value.to_string())
+    };
+
+    (name.to_string(), tag.value.to_string())
+}
+
+/// Read the tags with `symphonia`, returns `None` if the format is unknown
+fn native(path: &Path) -> Option<Vec<(String, String)>> {
+    let file = File::open(path).ok()?;
+    let stream = MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions::default());
+
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+
+    let mut tags = Vec::new();
+
+    // tags in front of the container, e.g. ID3v2 of MP3 files, come first
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            tags.extend(revision.tags().iter().map(pair));
+        }
+    }
+
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend(revision.tags().iter().map(pair));
+    }
+
+    Some(tags)
+}
+
+/// Parse the output of `ffprobe -show_entries format_tags:stream_tags -of default=nw=1`
+///
+/// Tags with an empty value are dropped.
+fn parse(out: &str) -> Vec<(String, String)> {
+    out.lines().filter_map(|line| {
+        match line.trim().splitn(2, '=').collect::<Vec<_>>().as_slice() {
+            [name, value] if name.starts_with("TAG:") && !value.trim().is_empty() => Some((name[4..].to_lowercase(), value.trim().to_string())),
+            _ => None
+        }
+    }).collect()
+}
+
+/// Read the tags of an audio file with `ffprobe`
+fn ffprobe(path: &Path) -> Vec<(String, String)> {
+    let cmd = Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-show_entries").arg("format_tags:stream_tags")
+        .arg("-of").arg("default=nw=1")
+        .arg(path)
+        .output();
+
+    match cmd {
+        Ok(ref out) if out.status.success() => parse(&String::from_utf8_lossy(&out.stdout)),
+        _ => Vec::new()
+    }
+}
+
+/// Read the tags of an audio file, files without tags or in unknown formats have none
+pub fn read(path: &Path) -> Vec<(String, String)> {
+    native(path).unwrap_or_else(|| ffprobe(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{parse, native};
+
+    #[test]
+    fn parse_ffprobe() {
+        let out = "TAG:encoder=Lavf58.20.100\nTAG:TITLE=Song\n[FORMAT]\nTAG:composer=\nTAG:title=a=b\n";
+
+        assert_eq!(parse(out), vec![
+            ("encoder".to_string(), "Lavf58.20.100".to_string()),
+            ("title".to_string(), "Song".to_string()),
+            ("title".to_string(), "a=b".to_string())
+        ]);
+    }
+
+    #[test]
+    fn unknown_format() {
+        // unknown formats are left to ffprobe
+        let path = std::env::temp_dir().join(format!("hex-tags-{}.mp3", std::process::id()));
+        fs::write(&path, b"no audio at all").unwrap();
+
+        let tags = native(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(tags.is_none());
+    }
+}
@@ -15,7 +15,7 @@ use rspotify::spotify::client::Spotify as SpotifyAPI;
 use rspotify::spotify::oauth2::SpotifyClientCredentials;
 
 use hex_database::{Track, utils::fingerprint_from_file};
-use hex_music_container::{Configuration, Container};
+use hex_music_container::{Configuration, Container, Metadata};
 
 type PseudoTrack = (String, String, String, String);
 type PseudoPlaylist = (String, Vec<PseudoTrack>);
@@ -114,6 +114,14 @@ impl ExternalMusic {
                     track.composer = Some(metadata.2.clone());
                     
                     let track_path = track.key.to_path();
+                    let track_metadata = Metadata {
+                        title: track.title.clone(),
+                        album: track.album.clone(),
+                        interpret: track.interpret.clone(),
+                        composer: track.composer.clone(),
+                        fingerprint: Some(track.fingerprint.clone()),
+                        ..Metadata::default()
+                    };
 
                     //println!("Added track {:?}", track.title);
 
@@ -125,7 +133,7 @@ impl ExternalMusic {
                     
                     let file = File::create(data_path.join("data").join(track_path)).unwrap();
 
-                    Container::save_pcm(Configuration::Stereo, samples.to_vec(), file, None).unwrap();
+                    Container::save_pcm_with_metadata(Configuration::Stereo, samples.to_vec(), file, None, &track_metadata).unwrap();
 
                     fs::remove_file(path).unwrap();
                 }
@@ -1,20 +1,18 @@
 use std::thread;
 use std::path::PathBuf;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::process::Command;
+use std::fs::{self, File};
+use std::io::Write;
 use std::ffi::OsStr;
 
 use futures::IntoFuture;
 use futures::sync::oneshot::{channel, Sender, Receiver};
-use hex_database::{Track, utils::fingerprint_from_file};
-use hex_music_container::{Container, Configuration};
+use hex_database::{Track, utils::get_fingerprint};
+use hex_music_container::{Container, Metadata, decode::decode_file};
 
 use crate::error::*;
 
 fn worker(sender: Sender<Track>, file_name: String, samples: Vec<u8>, data_path: PathBuf) -> Result<()> {
     let encoded_path = data_path.join("download").join(&file_name);
-    let raw_path = data_path.join("download").join(&file_name).with_extension("pcm");
 
     let mut file = File::create(&encoded_path)
         .map_err(|x| Error::Io(x))?;
@@ -22,41 +20,32 @@ fn worker(sender: Sender<Track>, file_name: String, samples: Vec<u8>, data_path:
     file.write(&samples)
         .map_err(|x| Error::Io(x))?;
 
-    Command::new("ffmpeg")
-        .arg("-y").arg("-loglevel").arg("0").arg("-nostats")
-        .arg("-i").arg(encoded_path.to_str().unwrap())
-        .arg("-ar").arg("48k")
-        .arg("-ac").arg("2")
-        .arg("-f").arg("s16le")
-        .arg("-acodec").arg("pcm_s16le")
-        .arg(raw_path.to_str().unwrap())
-        .spawn().expect("Could not start ffmpeg!").wait().unwrap();
-
-    let mut samples = Vec::new();
-    let mut file = File::open(&raw_path)
-        .map_err(|x| Error::Io(x))?; 
-
-    file.read_to_end(&mut samples)
-        .map_err(|x| Error::Io(x))?;
-
-    let duration = samples.len() as f64 / 48000.0 / 2.0;
+    // decode with 48kHz, surround and AmbiX files keep their channels
+    let (conf, samples) = decode_file(&encoded_path, |_| {})
+        .map_err(|x| Error::MusicContainer(x))?;
 
-    let samples: &[i16] = unsafe { ::std::slice::from_raw_parts(samples.as_ptr() as *const i16, samples.len() / 2) };
+    let duration = samples.len() as f64 / 48000.0 / conf.num_channels() as f64;
 
-    let fingerprint = fingerprint_from_file(2, &raw_path)
+    let fingerprint = get_fingerprint(conf.num_channels() as u16, &samples)
         .map_err(|x| Error::Database(x))?;
 
     let mut track = Track::empty(fingerprint, duration.into());
 
     let file = File::create(data_path.join("data").join(track.key.to_path())).unwrap();
 
+    // store the fingerprint, so the track can be recovered without the database
+    let metadata = Metadata {
+        fingerprint: Some(track.fingerprint.clone()),
+        ..Metadata::default()
+    };
+
     // TODO realtime
-    Container::save_pcm(Configuration::Stereo, samples.to_vec(), file, None)
+    Container::save_pcm_with_metadata(conf, samples, file, None, &metadata)
         .map_err(|err| Error::MusicContainer(err))?;
 
     match encoded_path.extension().and_then(OsStr::to_str) {
         Some("mp3") => {
-            if let Ok(metadata) = mp3_metadata::read_from_file(encoded_path) {
+            if let Ok(metadata) = mp3_metadata::read_from_file(&encoded_path) {
                 if let Some(metadata) = metadata.tag {
 
                     track.title = Some(metadata.title);
@@ -82,6 +71,8 @@ fn worker(sender: Sender<Track>, file_name: String, samples: Vec<u8>, data_path:
         _ => {}
     }
 
+    let _ = fs::remove_file(&encoded_path);
+
     sender.send(track).map_err(|_| Error::ChannelFailed)
 }
 
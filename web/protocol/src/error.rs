@@ -1,9 +1,13 @@
 use std::result;
 use bincode;
+#[cfg(feature = "json")]
+use serde_json;
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    Bincode(bincode::Error)
+    Bincode(bincode::Error),
+    #[cfg(feature = "json")]
+    Json(serde_json::Error)
 }
@@ -2,6 +2,10 @@
 extern crate serde_derive;
 extern crate bincode;
 extern crate hex_database;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "typescript")]
+extern crate serde_reflection;
 
 #[cfg(target_arch = "wasm32")]
 extern crate wasm_bindgen;
@@ -12,6 +16,9 @@ extern crate cfg_if;
 
 pub mod error;
 pub mod objects;
+pub mod version;
+#[cfg(feature = "typescript")]
+pub mod typescript;
 
 #[cfg(target_arch = "wasm32")]
 cfg_if! {
@@ -27,5 +34,5 @@ cfg_if! {
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
 
-pub use objects::{Request, Answer, RequestAction, AnswerAction, PacketId};
+pub use objects::{Request, Answer, RequestAction, AnswerAction, AnswerError, PacketId, Encoding};
 pub use error::Error;
@@ -1,19 +1,46 @@
 //! Protocol of the websocket server
 //!
-//! The protocol uses JSON as coding and a request/answer id for every packet to know where to put
-//! the answer. 
+//! Packets are encoded with bincode, or with JSON for clients connected with the `json` feature,
+//! and carry a request/answer id to know where to put the answer.
+//!
+//! In bincode the variants of an enum are identified by their index. These tags are part of the
+//! protocol: new variants are appended with the next free tag and existing ones are never
+//! reordered or removed. Incompatible changes increase the version in `version`, which is
+//! exchanged with a `Hello` request before anything else.
 
-use std::result;
+use std::{fmt, result};
 use error::{Error, Result};
 
 use bincode::{serialize, deserialize};
+#[cfg(feature = "json")]
+use serde_json;
 
-use hex_database::{Track, Playlist, Token, TrackKey, PlaylistKey, TokenId, TransitionAction, Transition};
+use hex_database::{Track, Playlist, Token, TrackKey, PlaylistKey, TokenId, TransitionAction, Transition, User, Role, Job, JobId, Suggestion, ImageKey, Queue};
 
 /// Identification of a packet
 ///
 /// A request should contain a random number associating it with the pending answer.
 pub type PacketId = [u32; 4];
+
+/// Identification of a party
+pub type PartyId = u32;
+
+/// Encoding of the packets of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Compact binary encoding, enums are identified by the tag of their variant
+    Bincode,
+    /// Externally tagged JSON, enums are identified by the name of their variant
+    #[cfg(feature = "json")]
+    Json
+}
+
+/// Header of a packet, which is readable even if the rest can't be decoded
+#[cfg(feature = "server")]
+#[derive(Deserialize)]
+struct Header {
+    id: PacketId
+}
 /// Incoming message
 ///
 /// The incoming message is wrapped in a packet struct containing the `id` field. Any `fn` field is
@@ -32,7 +59,11 @@ pub enum RequestAction {
     },
     /// Get the next packet in a stream (`key` has to be available in first call)
     StreamNext {
-        key: Option<TrackKey>
+        key: Option<TrackKey>,
+        /// Render the stream binaurally for headphones instead of stereo loudspeakers
+        binaural: bool,
+        /// Format of the transmitted audio, only respected in the first call
+        format: StreamFormat
     },
     /// End a stream
     StreamEnd,
@@ -49,7 +80,7 @@ pub enum RequestAction {
         people: Option<String>,
         composer: Option<String>
     },
-    /// Get suggestions for a track from acousticid
+    /// Get suggestions for the metadata of a track from the configured backends
     GetSuggestion {
         key: TrackKey
     },
@@ -61,7 +92,7 @@ pub enum RequestAction {
     DeletePlaylist {
         key: PlaylistKey
     },
-    /// Set a playlist image
+    /// Set the cover image of a playlist, an empty image removes it
     SetPlaylistImage {
         key: PlaylistKey,
         image: Vec<u8>
@@ -137,7 +168,232 @@ pub enum RequestAction {
         tracks: Vec<TrackKey>
     },
     /// Ask for the download progress
-    AskDownloadProgress
+    AskDownloadProgress,
+    /// Login with name and password, starts a new session
+    Login {
+        name: String,
+        password: String
+    },
+    /// Resume a session with the token of a previous login
+    Authenticate {
+        token: String
+    },
+    /// End the current session
+    Logout,
+    /// Change the password of the current user
+    ChangePassword {
+        password: String
+    },
+    /// Get all user accounts
+    GetUsers,
+    /// Create a new user account
+    AddUser {
+        name: String,
+        password: String,
+        role: Role
+    },
+    /// Delete a user account
+    DeleteUser {
+        name: String
+    },
+    /// Change the role of a user account
+    SetUserRole {
+        name: String,
+        role: Role
+    },
+    /// Get all tracks the current user voted for
+    GetVotes,
+    /// Subscribe to changes of the library
+    ///
+    /// Changes after the `cursor` are part of the answer, all later changes are pushed with the id
+    /// of this request. Without any subscription every change is pushed as `Transition`.
+    Subscribe {
+        /// Objects of interest, every change is pushed if empty
+        filter: Vec<Subscription>,
+        /// Sequence number of the last change seen by the client
+        cursor: Option<u64>
+    },
+    /// End the subscription
+    Unsubscribe,
+    /// Get all background jobs of the server
+    GetJobs,
+    /// Cancel a queued or running job
+    CancelJob {
+        id: JobId
+    },
+    /// Queue a failed or cancelled job again
+    RetryJob {
+        id: JobId
+    },
+    /// Begin or resume an upload in chunks
+    ///
+    /// The session is identified by the SHA-256 hash of the file in hex, beginning an upload with
+    /// the same hash returns the chunks already received.
+    BeginUpload {
+        name: String,
+        format: String,
+        size: u64,
+        hash: String
+    },
+    /// Send a single chunk of an upload, all but the last one have the size of the session
+    UploadChunk {
+        id: String,
+        index: u32,
+        data: Vec<u8>
+    },
+    /// Get the received chunks of an upload
+    GetUploadSession {
+        id: String
+    },
+    /// Check the complete upload and convert it in the background
+    CommitUpload {
+        id: String
+    },
+    /// Abort an upload and delete the received chunks
+    AbortUpload {
+        id: String
+    },
+    /// Check a complete upload of a zip or tar archive and import all audio files in it
+    ///
+    /// The tracks are tagged with the tags embedded in the files, with `playlists` a playlist is
+    /// created for each album.
+    CommitImport {
+        id: String,
+        playlists: bool
+    },
+    /// Update a track with the fields of a suggestion
+    ApplySuggestion {
+        key: TrackKey,
+        suggestion: Suggestion
+    },
+    /// Look up all tracks with missing metadata in the background and apply good matches
+    EnrichTracks,
+    /// Exchange the version and capabilities of the protocol, sent first after connecting
+    Hello {
+        version: u32,
+        capabilities: Vec<String>
+    },
+    /// Get all parties of the server
+    GetParties,
+    /// Start a new party with an empty queue
+    CreateParty {
+        name: String
+    },
+    /// Join a party, its changes are pushed with the id of this request
+    ///
+    /// With `output` the connection plays the audio of the party and replaces the previous
+    /// output. A connection is a member of a single party at a time.
+    JoinParty {
+        id: PartyId,
+        output: bool
+    },
+    /// Leave the joined party, a party ends with its last member
+    LeaveParty,
+    /// Control the playback of the joined party
+    PartyCommand {
+        command: PartyCommand
+    },
+    /// Get the play queue of this connection
+    ///
+    /// A logged in user has a single queue on all devices, otherwise the queue belongs to the
+    /// `device`, a random id chosen by the client. Further queue requests change the same queue.
+    GetQueue {
+        device: String
+    },
+    /// Insert tracks into the queue before `index`, or append them
+    Enqueue {
+        tracks: Vec<TrackKey>,
+        index: Option<u32>
+    },
+    /// Reorder the queue, the n-th track is taken from the index `order[n]`
+    ReorderQueue {
+        order: Vec<u32>
+    },
+    /// Remove a single track from the queue
+    Dequeue {
+        index: u32
+    },
+    ClearQueue,
+    /// Remember the current track and the position in it in seconds
+    UpdateQueuePosition {
+        current: Option<u32>,
+        pos: Option<f64>
+    }
+}
+
+impl RequestAction {
+    /// Tag of the variant in the binary encoding
+    ///
+    /// The tag is the index of the variant and must never change for an existing variant, new
+    /// variants get the next free tag. The fixtures in `tests/fixtures` pin the encoding
+    /// of every variant.
+    pub fn tag(&self) -> u32 {
+        match *self {
+            RequestAction::Search { .. } => 0,
+            RequestAction::GetTrack { .. } => 1,
+            RequestAction::StreamNext { .. } => 2,
+            RequestAction::StreamEnd => 3,
+            RequestAction::StreamSeek { .. } => 4,
+            RequestAction::UpdateTrack { .. } => 5,
+            RequestAction::GetSuggestion { .. } => 6,
+            RequestAction::AddPlaylist { .. } => 7,
+            RequestAction::DeletePlaylist { .. } => 8,
+            RequestAction::SetPlaylistImage { .. } => 9,
+            RequestAction::AddToPlaylist { .. } => 10,
+            RequestAction::DeleteFromPlaylist { .. } => 11,
+            RequestAction::UpdatePlaylist { .. } => 12,
+            RequestAction::GetPlaylists => 13,
+            RequestAction::GetPlaylist { .. } => 14,
+            RequestAction::GetPlaylistsOfTrack { .. } => 15,
+            RequestAction::DeleteTrack { .. } => 16,
+            RequestAction::UploadYoutube { .. } => 17,
+            RequestAction::UploadTrack { .. } => 18,
+            RequestAction::VoteForTrack { .. } => 19,
+            RequestAction::AskUploadProgress => 20,
+            RequestAction::GetToken { .. } => 21,
+            RequestAction::UpdateToken { .. } => 22,
+            RequestAction::CreateToken => 23,
+            RequestAction::LastToken => 24,
+            RequestAction::GetSummary => 25,
+            RequestAction::GetTransitions => 26,
+            RequestAction::Download { .. } => 27,
+            RequestAction::AskDownloadProgress => 28,
+            RequestAction::Login { .. } => 29,
+            RequestAction::Authenticate { .. } => 30,
+            RequestAction::Logout => 31,
+            RequestAction::ChangePassword { .. } => 32,
+            RequestAction::GetUsers => 33,
+            RequestAction::AddUser { .. } => 34,
+            RequestAction::DeleteUser { .. } => 35,
+            RequestAction::SetUserRole { .. } => 36,
+            RequestAction::GetVotes => 37,
+            RequestAction::Subscribe { .. } => 38,
+            RequestAction::Unsubscribe => 39,
+            RequestAction::GetJobs => 40,
+            RequestAction::CancelJob { .. } => 41,
+            RequestAction::RetryJob { .. } => 42,
+            RequestAction::BeginUpload { .. } => 43,
+            RequestAction::UploadChunk { .. } => 44,
+            RequestAction::GetUploadSession { .. } => 45,
+            RequestAction::CommitUpload { .. } => 46,
+            RequestAction::AbortUpload { .. } => 47,
+            RequestAction::CommitImport { .. } => 48,
+            RequestAction::ApplySuggestion { .. } => 49,
+            RequestAction::EnrichTracks => 50,
+            RequestAction::Hello { .. } => 51,
+            RequestAction::GetParties => 52,
+            RequestAction::CreateParty { .. } => 53,
+            RequestAction::JoinParty { .. } => 54,
+            RequestAction::LeaveParty => 55,
+            RequestAction::PartyCommand { .. } => 56,
+            RequestAction::GetQueue { .. } => 57,
+            RequestAction::Enqueue { .. } => 58,
+            RequestAction::ReorderQueue { .. } => 59,
+            RequestAction::Dequeue { .. } => 60,
+            RequestAction::ClearQueue => 61,
+            RequestAction::UpdateQueuePosition { .. } => 62
+        }
+    }
 }
 
 /// Wrapper for the Incoming message
@@ -167,6 +423,39 @@ impl Request {
     pub fn to_buf(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| Error::Bincode(err))
     }
+
+    #[cfg(feature="server")]
+    pub fn decode(buf: &[u8], encoding: Encoding) -> Result<Request> {
+        match encoding {
+            Encoding::Bincode => Request::try_from(buf),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::from_slice(buf).map_err(|err| Error::Json(err))
+        }
+    }
+
+    #[cfg(feature="client")]
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Bincode => self.to_buf(),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::to_vec(self).map_err(|err| Error::Json(err))
+        }
+    }
+
+    /// Read the id of a request, which can't be decoded
+    ///
+    /// A request of a newer client may contain an unknown variant, but can still be answered with
+    /// an error.
+    #[cfg(feature="server")]
+    pub fn peek_id(buf: &[u8], encoding: Encoding) -> Option<PacketId> {
+        let header: Header = match encoding {
+            Encoding::Bincode => deserialize(buf.get(0..16)?).ok()?,
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::from_slice(buf).ok()?
+        };
+
+        Some(header.id)
+    }
 }
 
 /// Outgoing packets
@@ -192,14 +481,16 @@ pub enum AnswerAction {
     },
     StreamEnd,
     UpdateTrack(TrackKey),
+    /// Suggestions ordered by their score, the best first
     GetSuggestion {
         key: TrackKey,
-        data: String
+        suggestions: Vec<Suggestion>
     },
     AddPlaylist(Playlist),
     DeletePlaylist,
     UpdatePlaylist,
-    SetPlaylistImage,
+    /// Key of the stored cover, served at `/images/<key>.jpg`
+    SetPlaylistImage(Option<ImageKey>),
     AddToPlaylist,
     DeleteFromPlaylist,
     GetPlaylists(Vec<Playlist>),
@@ -218,7 +509,233 @@ pub enum AnswerAction {
     GetTransitions(Vec<Transition>),
     Download,
     AskDownloadProgress(Vec<DownloadProgress>),
-    Transition(TransitionAction)
+    Transition(TransitionAction),
+    /// Token of the new session and the logged in user
+    Login {
+        token: String,
+        user: User
+    },
+    /// User of the resumed session
+    Authenticate(User),
+    Logout,
+    /// Token of a new session, all other sessions of the user are ended
+    ChangePassword(String),
+    GetUsers(Vec<User>),
+    AddUser(User),
+    DeleteUser,
+    SetUserRole,
+    GetVotes(Vec<TrackKey>),
+    Subscribe {
+        /// Changes after the cursor of the request
+        missed: Vec<Notification>,
+        /// Sequence number of the latest change
+        cursor: u64,
+        /// If false, there were too many changes and the client should reload everything
+        complete: bool
+    },
+    Unsubscribe,
+    /// A pushed change of a subscription
+    Notification(Notification),
+    GetJobs(Vec<Job>),
+    CancelJob,
+    RetryJob(Job),
+    BeginUpload(UploadSession),
+    UploadChunk,
+    GetUploadSession(UploadSession),
+    /// Id of the job converting the upload
+    CommitUpload(JobId),
+    AbortUpload,
+    /// Id of the job importing the archive
+    CommitImport(JobId),
+    /// The updated track
+    ApplySuggestion(Track),
+    /// Id of the enrichment job
+    EnrichTracks(JobId),
+    /// Version spoken by the server and capabilities supported by both sides
+    Hello {
+        version: u32,
+        capabilities: Vec<String>
+    },
+    GetParties(Vec<Party>),
+    CreateParty(Party),
+    /// State of the joined party
+    JoinParty(Party),
+    LeaveParty,
+    /// State of the party after the command
+    PartyCommand(Party),
+    /// A pushed change of the joined party
+    Party(Party),
+    /// The stored queue, with the track and position to continue with
+    GetQueue(Queue),
+    /// The changed queue
+    Enqueue(Queue),
+    ReorderQueue(Queue),
+    Dequeue(Queue),
+    ClearQueue,
+    UpdateQueuePosition,
+    /// Opus packets of a stream with `StreamFormat::Opus`, each covering 40ms
+    StreamPackets(Vec<Vec<u8>>)
+}
+
+impl AnswerAction {
+    /// Tag of the variant in the binary encoding, see `RequestAction::tag`
+    pub fn tag(&self) -> u32 {
+        match *self {
+            AnswerAction::SearchResult { .. } => 0,
+            AnswerAction::Track(..) => 1,
+            AnswerAction::ClearBuffer => 2,
+            AnswerAction::StreamNext(..) => 3,
+            AnswerAction::StreamSeek { .. } => 4,
+            AnswerAction::StreamEnd => 5,
+            AnswerAction::UpdateTrack(..) => 6,
+            AnswerAction::GetSuggestion { .. } => 7,
+            AnswerAction::AddPlaylist(..) => 8,
+            AnswerAction::DeletePlaylist => 9,
+            AnswerAction::UpdatePlaylist => 10,
+            AnswerAction::SetPlaylistImage(..) => 11,
+            AnswerAction::AddToPlaylist => 12,
+            AnswerAction::DeleteFromPlaylist => 13,
+            AnswerAction::GetPlaylists(..) => 14,
+            AnswerAction::GetPlaylist(..) => 15,
+            AnswerAction::GetPlaylistsOfTrack(..) => 16,
+            AnswerAction::DeleteTrack(..) => 17,
+            AnswerAction::UploadYoutube => 18,
+            AnswerAction::UploadTrack => 19,
+            AnswerAction::VoteForTrack => 20,
+            AnswerAction::AskUploadProgress(..) => 21,
+            AnswerAction::GetToken(..) => 22,
+            AnswerAction::UpdateToken => 23,
+            AnswerAction::CreateToken(..) => 24,
+            AnswerAction::LastToken(..) => 25,
+            AnswerAction::GetSummary(..) => 26,
+            AnswerAction::GetTransitions(..) => 27,
+            AnswerAction::Download => 28,
+            AnswerAction::AskDownloadProgress(..) => 29,
+            AnswerAction::Transition(..) => 30,
+            AnswerAction::Login { .. } => 31,
+            AnswerAction::Authenticate(..) => 32,
+            AnswerAction::Logout => 33,
+            AnswerAction::ChangePassword(..) => 34,
+            AnswerAction::GetUsers(..) => 35,
+            AnswerAction::AddUser(..) => 36,
+            AnswerAction::DeleteUser => 37,
+            AnswerAction::SetUserRole => 38,
+            AnswerAction::GetVotes(..) => 39,
+            AnswerAction::Subscribe { .. } => 40,
+            AnswerAction::Unsubscribe => 41,
+            AnswerAction::Notification(..) => 42,
+            AnswerAction::GetJobs(..) => 43,
+            AnswerAction::CancelJob => 44,
+            AnswerAction::RetryJob(..) => 45,
+            AnswerAction::BeginUpload(..) => 46,
+            AnswerAction::UploadChunk => 47,
+            AnswerAction::GetUploadSession(..) => 48,
+            AnswerAction::CommitUpload(..) => 49,
+            AnswerAction::AbortUpload => 50,
+            AnswerAction::CommitImport(..) => 51,
+            AnswerAction::ApplySuggestion(..) => 52,
+            AnswerAction::EnrichTracks(..) => 53,
+            AnswerAction::Hello { .. } => 54,
+            AnswerAction::GetParties(..) => 55,
+            AnswerAction::CreateParty(..) => 56,
+            AnswerAction::JoinParty(..) => 57,
+            AnswerAction::LeaveParty => 58,
+            AnswerAction::PartyCommand(..) => 59,
+            AnswerAction::Party(..) => 60,
+            AnswerAction::GetQueue(..) => 61,
+            AnswerAction::Enqueue(..) => 62,
+            AnswerAction::ReorderQueue(..) => 63,
+            AnswerAction::Dequeue(..) => 64,
+            AnswerAction::ClearQueue => 65,
+            AnswerAction::UpdateQueuePosition => 66,
+            AnswerAction::StreamPackets(..) => 67
+        }
+    }
+}
+
+/// Reason of a failed request
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub enum AnswerError {
+    /// The request needs a login
+    NotAuthenticated,
+    /// The role of the user doesn't allow the request
+    PermissionDenied,
+    /// Wrong name or password
+    WrongCredentials,
+    /// The requested object doesn't exist
+    NotFound,
+    /// An object with the same key or name exists already
+    AlreadyExists,
+    /// The request doesn't fit the pending requests, e.g. seeking without a stream
+    InvalidRequest(String),
+    /// The stream has reached the end of the track
+    StreamEnded,
+    /// Invalid chunk or session of an upload
+    Upload(String),
+    /// Invalid format or tracks of an export
+    Export(String),
+    /// Invalid or unsupported image
+    Image(String),
+    /// Failed lookup of metadata
+    Metadata(String),
+    /// Audio file which can't be read or decoded
+    Audio(String),
+    /// Failure of the database
+    Database(String),
+    /// Failure of the server, e.g. reading a file or starting a program
+    Internal(String),
+    /// The version of the client is older than the oldest version supported by the server
+    Incompatible {
+        version: u32,
+        min_version: u32
+    }
+}
+
+impl AnswerError {
+    /// Tag of the variant in the binary encoding, see `RequestAction::tag`
+    pub fn tag(&self) -> u32 {
+        match *self {
+            AnswerError::NotAuthenticated => 0,
+            AnswerError::PermissionDenied => 1,
+            AnswerError::WrongCredentials => 2,
+            AnswerError::NotFound => 3,
+            AnswerError::AlreadyExists => 4,
+            AnswerError::InvalidRequest(..) => 5,
+            AnswerError::StreamEnded => 6,
+            AnswerError::Upload(..) => 7,
+            AnswerError::Export(..) => 8,
+            AnswerError::Image(..) => 9,
+            AnswerError::Metadata(..) => 10,
+            AnswerError::Audio(..) => 11,
+            AnswerError::Database(..) => 12,
+            AnswerError::Internal(..) => 13,
+            AnswerError::Incompatible { .. } => 14
+        }
+    }
+}
+
+impl fmt::Display for AnswerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AnswerError::NotAuthenticated => write!(f, "Login required"),
+            AnswerError::PermissionDenied => write!(f, "Permission denied"),
+            AnswerError::WrongCredentials => write!(f, "Wrong name or password"),
+            AnswerError::NotFound => write!(f, "Not found"),
+            AnswerError::AlreadyExists => write!(f, "Already exists"),
+            AnswerError::InvalidRequest(ref msg) => write!(f, "Invalid request: {}", msg),
+            AnswerError::StreamEnded => write!(f, "End of stream"),
+            AnswerError::Upload(ref msg) => write!(f, "Upload failed: {}", msg),
+            AnswerError::Export(ref msg) => write!(f, "Export failed: {}", msg),
+            AnswerError::Image(ref msg) => write!(f, "Invalid image: {}", msg),
+            AnswerError::Metadata(ref msg) => write!(f, "Metadata lookup failed: {}", msg),
+            AnswerError::Audio(ref msg) => write!(f, "Audio error: {}", msg),
+            AnswerError::Database(ref msg) => write!(f, "Database error: {}", msg),
+            AnswerError::Internal(ref msg) => write!(f, "Internal error: {}", msg),
+            AnswerError::Incompatible { version, min_version } => write!(f, "Protocol version {} is not supported, at least version {} is needed", version, min_version)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -226,11 +743,11 @@ pub enum AnswerAction {
 #[cfg_attr(feature="server", derive(Serialize))]
 pub struct Answer {
     pub id: PacketId,
-    pub msg: result::Result<AnswerAction, String>
+    pub msg: result::Result<AnswerAction, AnswerError>
 }
 
 impl Answer {
-    pub fn new(id: PacketId, msg: result::Result<AnswerAction, String>) -> Answer {
+    pub fn new(id: PacketId, msg: result::Result<AnswerAction, AnswerError>) -> Answer {
         Answer {
             id, 
             msg
@@ -246,9 +763,212 @@ impl Answer {
     pub fn to_buf(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| Error::Bincode(err))
     }
+
+    #[cfg(feature="client")]
+    pub fn decode(buf: &[u8], encoding: Encoding) -> Result<Answer> {
+        match encoding {
+            Encoding::Bincode => Answer::try_from(buf),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::from_slice(buf).map_err(|err| Error::Json(err))
+        }
+    }
+
+    #[cfg(feature="server")]
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Bincode => self.to_buf(),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::to_vec(self).map_err(|err| Error::Json(err))
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Format of the audio transmitted in a stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Deserialize))]
+#[cfg_attr(feature="client", derive(Serialize))]
+pub enum StreamFormat {
+    /// Interleaved 16bit samples with 48kHz
+    Pcm,
+    /// Stereo Opus packets with 48kHz, decodable with WebCodecs
+    Opus
+}
+
+impl Default for StreamFormat {
+    fn default() -> StreamFormat {
+        StreamFormat::Pcm
+    }
+}
+
+/// Objects and kinds a client can subscribe to
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Deserialize))]
+#[cfg_attr(feature="client", derive(Serialize))]
+pub enum Subscription {
+    /// All tracks
+    Tracks,
+    /// A single track
+    Track(TrackKey),
+    /// All playlists
+    Playlists,
+    /// A single playlist
+    Playlist(PlaylistKey),
+    /// All tokens
+    Tokens,
+    /// A single token
+    Token(TokenId),
+    /// Progress of the uploads of this connection
+    Uploads
+}
+
+impl Subscription {
+    /// Check whether an event concerns the subscription
+    pub fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (Subscription::Tracks, Event::Track(_)) | (Subscription::Tracks, Event::TrackDeleted(_)) => true,
+            (Subscription::Track(key), Event::Track(track)) => *key == track.key,
+            (Subscription::Track(key), Event::TrackDeleted(track)) => key == track,
+            (Subscription::Playlists, Event::Playlist(_)) | (Subscription::Playlists, Event::PlaylistDeleted(_)) |
+            (Subscription::Playlists, Event::PlaylistImage(..)) => true,
+            (Subscription::Playlist(key), Event::Playlist(playlist)) => *key == playlist.key,
+            (Subscription::Playlist(key), Event::PlaylistDeleted(playlist)) => key == playlist,
+            (Subscription::Playlist(key), Event::PlaylistImage(playlist, _)) => key == playlist,
+            (Subscription::Tokens, Event::Token(_)) | (Subscription::Tokens, Event::TokenDeleted(_)) => true,
+            (Subscription::Token(id), Event::Token(token)) => *id == token.token,
+            (Subscription::Token(id), Event::TokenDeleted(token)) => id == token,
+            (Subscription::Uploads, Event::Upload(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// A change of the library
+#[derive(Debug, Clone)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub enum Event {
+    /// A track was added or updated
+    Track(Track),
+    TrackDeleted(TrackKey),
+    /// A playlist was added or updated
+    Playlist(Playlist),
+    PlaylistDeleted(PlaylistKey),
+    /// A token was added or updated
+    Token(Token),
+    TokenDeleted(TokenId),
+    /// An upload made progress
+    Upload(UploadProgress),
+    /// The cover of a playlist was set or removed
+    PlaylistImage(PlaylistKey, Option<ImageKey>)
+}
+
+impl From<TransitionAction> for Event {
+    fn from(action: TransitionAction) -> Event {
+        match action {
+            TransitionAction::UpsertTrack(track) => Event::Track(track),
+            TransitionAction::UpsertPlaylist(playlist) => Event::Playlist(playlist),
+            TransitionAction::UpsertToken(token) => Event::Token(token),
+            TransitionAction::DeleteTrack(key) => Event::TrackDeleted(key),
+            TransitionAction::DeletePlaylist(key) => Event::PlaylistDeleted(key),
+            TransitionAction::DeleteToken(id) => Event::TokenDeleted(id),
+            TransitionAction::SetPlaylistImage(key, image) => Event::PlaylistImage(key, image)
+        }
+    }
+}
+
+/// An event with the sequence number of the latest change
+///
+/// The sequence number can be used as `cursor` to resume a subscription after reconnecting.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub struct Notification {
+    pub cursor: u64,
+    pub event: Event
+}
+
+/// State of an upload in chunks
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub struct UploadSession {
+    pub id: String,
+    /// Size of a single chunk in bytes
+    pub chunk_size: u64,
+    /// Number of chunks of the file
+    pub chunks: u32,
+    /// Ranges of received chunks, the end is exclusive
+    pub received: Vec<(u32, u32)>
+}
+
+impl UploadSession {
+    /// Check whether all chunks are received
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().map(|(a, b)| b - a).sum::<u32>() == self.chunks
+    }
+
+    /// Numbers of the chunks still missing
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.chunks)
+            .filter(|x| !self.received.iter().any(|(a, b)| a <= x && x < b))
+            .collect()
+    }
+}
+
+/// Shared playback of several connections
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub struct Party {
+    pub id: PartyId,
+    pub name: String,
+    /// Track played by the output
+    pub current: Option<TrackKey>,
+    /// Upcoming tracks, the most voted first
+    pub queue: Vec<QueuedTrack>,
+    /// Is the current track playing or paused?
+    pub playing: bool,
+    /// Position in the current track in seconds
+    pub position: f64,
+    /// Is a connection playing the audio of the party?
+    pub output: bool,
+    /// Number of connections in the party
+    pub members: u32
+}
+
+/// A track in the queue of a party
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature="client", derive(Deserialize))]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
+pub struct QueuedTrack {
+    pub key: TrackKey,
+    pub votes: u32
+}
+
+/// Change of the playback of a party
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Deserialize))]
+#[cfg_attr(feature="client", derive(Serialize))]
+pub enum PartyCommand {
+    /// Append a track to the queue
+    Queue(TrackKey),
+    /// Remove a track from the queue
+    Remove(TrackKey),
+    /// Vote for a queued track, every user has a single vote per track
+    Vote(TrackKey),
+    Play,
+    Pause,
+    /// Continue with the next track of the queue
+    Skip,
+    /// Jump to a position of the current track in seconds
+    Seek(f64),
+    /// Position of the output in seconds, reported regularly while playing
+    Position(f64),
+    /// The output reached the end of a track
+    Ended(TrackKey)
+}
+
+#[derive(Debug, Clone)]
 #[cfg_attr(feature="client", derive(Deserialize))]
 #[cfg_attr(any(feature="server", target_arch = "wasm32"), derive(Serialize))]
 pub struct UploadProgress {
@@ -279,3 +999,417 @@ impl DownloadProgress {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use std::path::{Path, PathBuf};
+
+    use hex_database::{JobKind, JobStatus, JobResult};
+
+    use super::*;
+
+    #[test]
+    fn subscription_filter() {
+        let key = TrackKey::from_vec(&[1; 16]);
+        let other = TrackKey::from_vec(&[2; 16]);
+
+        assert!(Subscription::Tracks.matches(&Event::TrackDeleted(other)));
+        assert!(Subscription::Track(key).matches(&Event::TrackDeleted(key)));
+        assert!(!Subscription::Track(key).matches(&Event::TrackDeleted(other)));
+        assert!(Subscription::Playlist(3).matches(&Event::PlaylistDeleted(3)));
+        assert!(!Subscription::Playlist(3).matches(&Event::PlaylistDeleted(4)));
+        assert!(!Subscription::Playlists.matches(&Event::TokenDeleted(3)));
+        assert!(Subscription::Tokens.matches(&Event::TokenDeleted(3)));
+        assert!(Subscription::Playlist(3).matches(&Event::PlaylistImage(3, Some(key))));
+        assert!(!Subscription::Playlist(3).matches(&Event::PlaylistImage(4, None)));
+
+        match Event::from(TransitionAction::DeletePlaylist(5)) {
+            Event::PlaylistDeleted(5) => {},
+            event => panic!("Wrong event {:?}", event)
+        }
+    }
+
+    #[test]
+    fn upload_session() {
+        let mut session = UploadSession {
+            id: "00".into(),
+            chunk_size: 16,
+            chunks: 5,
+            received: vec![(0, 2), (3, 4)]
+        };
+
+        assert_eq!(session.missing(), vec![2, 4]);
+        assert!(!session.is_complete());
+
+        session.received = vec![(0, 5)];
+        assert!(session.missing().is_empty());
+        assert!(session.is_complete());
+    }
+
+    const ID: PacketId = [1, 2, 3, 4];
+
+    fn key() -> TrackKey {
+        TrackKey::from_vec(&[1; 16])
+    }
+
+    fn track() -> Track {
+        Track {
+            key: key(),
+            fingerprint: vec![1, 2],
+            title: Some("Title".into()),
+            album: Some("Album".into()),
+            interpret: None,
+            people: None,
+            composer: None,
+            duration: 1.5,
+            favs_count: 2
+        }
+    }
+
+    fn playlist() -> Playlist {
+        Playlist {
+            key: 3,
+            title: "Mix".into(),
+            desc: None,
+            tracks: vec![key()],
+            origin: vec![9, 9],
+            image: Some(TrackKey::from_vec(&[2; 16]))
+        }
+    }
+
+    fn user() -> User {
+        User { name: "alice".into(), role: Role::Admin }
+    }
+
+    fn suggestion() -> Suggestion {
+        Suggestion {
+            source: "musicbrainz".into(),
+            recording: Some("rec".into()),
+            title: Some("Title".into()),
+            album: None,
+            interpret: Some("Artist".into()),
+            composer: None,
+            score: 0.5
+        }
+    }
+
+    fn job() -> Job {
+        Job {
+            id: 5,
+            kind: JobKind::Upload { name: "a.mp3".into(), format: "mp3".into() },
+            status: JobStatus::Running,
+            stage: Some("converting".into()),
+            progress: 0.5,
+            attempts: 1,
+            error: None,
+            result: Some(JobResult::Track(key())),
+            owner: Some("alice".into()),
+            created: 100
+        }
+    }
+
+    fn session() -> UploadSession {
+        UploadSession { id: "00ff".into(), chunk_size: 1024, chunks: 3, received: vec![(0, 2)] }
+    }
+
+    fn party() -> Party {
+        Party {
+            id: 2,
+            name: "Kitchen".into(),
+            current: Some(key()),
+            queue: vec![QueuedTrack { key: TrackKey::from_vec(&[2; 16]), votes: 3 }],
+            playing: true,
+            position: 12.5,
+            output: true,
+            members: 4
+        }
+    }
+
+    fn queue() -> Queue {
+        Queue { tracks: vec![key(), TrackKey::from_vec(&[2; 16])], current: Some(1), pos: Some(12.5), updated: 100 }
+    }
+
+    /// A request of every variant, ordered by their tags
+    fn requests() -> Vec<(&'static str, RequestAction)> {
+        vec![
+            ("Search", RequestAction::Search { query: "hex".into() }),
+            ("GetTrack", RequestAction::GetTrack { key: key() }),
+            ("StreamNext", RequestAction::StreamNext { key: Some(key()), binaural: true, format: StreamFormat::Opus }),
+            ("StreamEnd", RequestAction::StreamEnd),
+            ("StreamSeek", RequestAction::StreamSeek { sample: 48000 }),
+            ("UpdateTrack", RequestAction::UpdateTrack { key: key(), title: Some("Title".into()), album: None, interpret: Some("Artist".into()), people: None, composer: None }),
+            ("GetSuggestion", RequestAction::GetSuggestion { key: key() }),
+            ("AddPlaylist", RequestAction::AddPlaylist { name: "Mix".into() }),
+            ("DeletePlaylist", RequestAction::DeletePlaylist { key: 3 }),
+            ("SetPlaylistImage", RequestAction::SetPlaylistImage { key: 3, image: vec![0xff, 0xd8, 0xff] }),
+            ("AddToPlaylist", RequestAction::AddToPlaylist { key: key(), playlist: 3 }),
+            ("DeleteFromPlaylist", RequestAction::DeleteFromPlaylist { key: key(), playlist: 3 }),
+            ("UpdatePlaylist", RequestAction::UpdatePlaylist { key: 3, title: Some("Mix".into()), desc: None }),
+            ("GetPlaylists", RequestAction::GetPlaylists),
+            ("GetPlaylist", RequestAction::GetPlaylist { key: 3 }),
+            ("GetPlaylistsOfTrack", RequestAction::GetPlaylistsOfTrack { key: key() }),
+            ("DeleteTrack", RequestAction::DeleteTrack { key: key() }),
+            ("UploadYoutube", RequestAction::UploadYoutube { path: "https://youtu.be/x".into() }),
+            ("UploadTrack", RequestAction::UploadTrack { name: "a.mp3".into(), format: "mp3".into(), data: vec![1, 2, 3] }),
+            ("VoteForTrack", RequestAction::VoteForTrack { key: key() }),
+            ("AskUploadProgress", RequestAction::AskUploadProgress),
+            ("GetToken", RequestAction::GetToken { token: 7 }),
+            ("UpdateToken", RequestAction::UpdateToken { token: 7, key: Some(3), played: Some(vec![key()]), pos: Some(1.5) }),
+            ("CreateToken", RequestAction::CreateToken),
+            ("LastToken", RequestAction::LastToken),
+            ("GetSummary", RequestAction::GetSummary),
+            ("GetTransitions", RequestAction::GetTransitions),
+            ("Download", RequestAction::Download { format: "mp3".into(), tracks: vec![key()] }),
+            ("AskDownloadProgress", RequestAction::AskDownloadProgress),
+            ("Login", RequestAction::Login { name: "alice".into(), password: "secret".into() }),
+            ("Authenticate", RequestAction::Authenticate { token: "abc".into() }),
+            ("Logout", RequestAction::Logout),
+            ("ChangePassword", RequestAction::ChangePassword { password: "secret".into() }),
+            ("GetUsers", RequestAction::GetUsers),
+            ("AddUser", RequestAction::AddUser { name: "bob".into(), password: "secret".into(), role: Role::Editor }),
+            ("DeleteUser", RequestAction::DeleteUser { name: "bob".into() }),
+            ("SetUserRole", RequestAction::SetUserRole { name: "bob".into(), role: Role::Admin }),
+            ("GetVotes", RequestAction::GetVotes),
+            ("Subscribe", RequestAction::Subscribe { filter: vec![Subscription::Playlists, Subscription::Track(key())], cursor: Some(42) }),
+            ("Unsubscribe", RequestAction::Unsubscribe),
+            ("GetJobs", RequestAction::GetJobs),
+            ("CancelJob", RequestAction::CancelJob { id: 5 }),
+            ("RetryJob", RequestAction::RetryJob { id: 5 }),
+            ("BeginUpload", RequestAction::BeginUpload { name: "a.flac".into(), format: "flac".into(), size: 1024, hash: "00ff".into() }),
+            ("UploadChunk", RequestAction::UploadChunk { id: "00ff".into(), index: 2, data: vec![1, 2, 3] }),
+            ("GetUploadSession", RequestAction::GetUploadSession { id: "00ff".into() }),
+            ("CommitUpload", RequestAction::CommitUpload { id: "00ff".into() }),
+            ("AbortUpload", RequestAction::AbortUpload { id: "00ff".into() }),
+            ("CommitImport", RequestAction::CommitImport { id: "00ff".into(), playlists: true }),
+            ("ApplySuggestion", RequestAction::ApplySuggestion { key: key(), suggestion: suggestion() }),
+            ("EnrichTracks", RequestAction::EnrichTracks),
+            ("Hello", RequestAction::Hello { version: 1, capabilities: vec!["opus".into()] }),
+            ("GetParties", RequestAction::GetParties),
+            ("CreateParty", RequestAction::CreateParty { name: "Kitchen".into() }),
+            ("JoinParty", RequestAction::JoinParty { id: 2, output: true }),
+            ("LeaveParty", RequestAction::LeaveParty),
+            ("PartyCommand", RequestAction::PartyCommand { command: PartyCommand::Vote(key()) }),
+            ("GetQueue", RequestAction::GetQueue { device: "00ff".into() }),
+            ("Enqueue", RequestAction::Enqueue { tracks: vec![key()], index: Some(1) }),
+            ("ReorderQueue", RequestAction::ReorderQueue { order: vec![1, 0] }),
+            ("Dequeue", RequestAction::Dequeue { index: 1 }),
+            ("ClearQueue", RequestAction::ClearQueue),
+            ("UpdateQueuePosition", RequestAction::UpdateQueuePosition { current: Some(1), pos: Some(1.5) })
+        ]
+    }
+
+    /// An answer of every variant, ordered by their tags
+    fn answers() -> Vec<(&'static str, AnswerAction)> {
+        vec![
+            ("SearchResult", AnswerAction::SearchResult { query: "hex".into(), answ: vec![track()], more: false }),
+            ("Track", AnswerAction::Track(track())),
+            ("ClearBuffer", AnswerAction::ClearBuffer),
+            ("StreamNext", AnswerAction::StreamNext(vec![1, 0, 2, 0])),
+            ("StreamSeek", AnswerAction::StreamSeek { sample: 48000 }),
+            ("StreamEnd", AnswerAction::StreamEnd),
+            ("UpdateTrack", AnswerAction::UpdateTrack(key())),
+            ("GetSuggestion", AnswerAction::GetSuggestion { key: key(), suggestions: vec![suggestion()] }),
+            ("AddPlaylist", AnswerAction::AddPlaylist(playlist())),
+            ("DeletePlaylist", AnswerAction::DeletePlaylist),
+            ("UpdatePlaylist", AnswerAction::UpdatePlaylist),
+            ("SetPlaylistImage", AnswerAction::SetPlaylistImage(Some(TrackKey::from_vec(&[2; 16])))),
+            ("AddToPlaylist", AnswerAction::AddToPlaylist),
+            ("DeleteFromPlaylist", AnswerAction::DeleteFromPlaylist),
+            ("GetPlaylists", AnswerAction::GetPlaylists(vec![playlist()])),
+            ("GetPlaylist", AnswerAction::GetPlaylist((playlist(), vec![track()]))),
+            ("GetPlaylistsOfTrack", AnswerAction::GetPlaylistsOfTrack(vec![playlist()])),
+            ("DeleteTrack", AnswerAction::DeleteTrack(())),
+            ("UploadYoutube", AnswerAction::UploadYoutube),
+            ("UploadTrack", AnswerAction::UploadTrack),
+            ("VoteForTrack", AnswerAction::VoteForTrack),
+            ("AskUploadProgress", AnswerAction::AskUploadProgress(vec![UploadProgress { desc: "a.mp3".into(), kind: "converting".into(), progress: 0.5, id: ID, key: None }])),
+            ("GetToken", AnswerAction::GetToken((Token { token: 7, key: Some(3), played: vec![key()], pos: Some(1.5), last_use: 100 }, Some((playlist(), Vec::new()))))),
+            ("UpdateToken", AnswerAction::UpdateToken),
+            ("CreateToken", AnswerAction::CreateToken(7)),
+            ("LastToken", AnswerAction::LastToken(Some(7))),
+            ("GetSummary", AnswerAction::GetSummary(vec![("2026-10-18".into(), 3, 1)])),
+            ("GetTransitions", AnswerAction::GetTransitions(Vec::new())),
+            ("Download", AnswerAction::Download),
+            ("AskDownloadProgress", AnswerAction::AskDownloadProgress(vec![DownloadProgress { id: ID, format: "mp3".into(), progress: 1.0, download: Some("x.zip".into()) }])),
+            ("Transition", AnswerAction::Transition(TransitionAction::DeletePlaylist(3))),
+            ("Login", AnswerAction::Login { token: "abc".into(), user: user() }),
+            ("Authenticate", AnswerAction::Authenticate(user())),
+            ("Logout", AnswerAction::Logout),
+            ("ChangePassword", AnswerAction::ChangePassword("abc".into())),
+            ("GetUsers", AnswerAction::GetUsers(vec![user()])),
+            ("AddUser", AnswerAction::AddUser(user())),
+            ("DeleteUser", AnswerAction::DeleteUser),
+            ("SetUserRole", AnswerAction::SetUserRole),
+            ("GetVotes", AnswerAction::GetVotes(vec![key()])),
+            ("Subscribe", AnswerAction::Subscribe { missed: vec![Notification { cursor: 42, event: Event::PlaylistDeleted(3) }], cursor: 42, complete: true }),
+            ("Unsubscribe", AnswerAction::Unsubscribe),
+            ("Notification", AnswerAction::Notification(Notification { cursor: 43, event: Event::Track(track()) })),
+            ("GetJobs", AnswerAction::GetJobs(vec![job()])),
+            ("CancelJob", AnswerAction::CancelJob),
+            ("RetryJob", AnswerAction::RetryJob(job())),
+            ("BeginUpload", AnswerAction::BeginUpload(session())),
+            ("UploadChunk", AnswerAction::UploadChunk),
+            ("GetUploadSession", AnswerAction::GetUploadSession(session())),
+            ("CommitUpload", AnswerAction::CommitUpload(5)),
+            ("AbortUpload", AnswerAction::AbortUpload),
+            ("CommitImport", AnswerAction::CommitImport(5)),
+            ("ApplySuggestion", AnswerAction::ApplySuggestion(track())),
+            ("EnrichTracks", AnswerAction::EnrichTracks(5)),
+            ("Hello", AnswerAction::Hello { version: 1, capabilities: vec!["opus".into()] }),
+            ("GetParties", AnswerAction::GetParties(vec![party()])),
+            ("CreateParty", AnswerAction::CreateParty(party())),
+            ("JoinParty", AnswerAction::JoinParty(party())),
+            ("LeaveParty", AnswerAction::LeaveParty),
+            ("PartyCommand", AnswerAction::PartyCommand(party())),
+            ("Party", AnswerAction::Party(party())),
+            ("GetQueue", AnswerAction::GetQueue(queue())),
+            ("Enqueue", AnswerAction::Enqueue(queue())),
+            ("ReorderQueue", AnswerAction::ReorderQueue(queue())),
+            ("Dequeue", AnswerAction::Dequeue(queue())),
+            ("ClearQueue", AnswerAction::ClearQueue),
+            ("UpdateQueuePosition", AnswerAction::UpdateQueuePosition),
+            ("StreamPackets", AnswerAction::StreamPackets(vec![vec![1, 2], vec![3]]))
+        ]
+    }
+
+    /// An error of every variant, ordered by their tags
+    fn errors() -> Vec<(&'static str, AnswerError)> {
+        vec![
+            ("NotAuthenticated", AnswerError::NotAuthenticated),
+            ("PermissionDenied", AnswerError::PermissionDenied),
+            ("WrongCredentials", AnswerError::WrongCredentials),
+            ("NotFound", AnswerError::NotFound),
+            ("AlreadyExists", AnswerError::AlreadyExists),
+            ("InvalidRequest", AnswerError::InvalidRequest("reason".into())),
+            ("StreamEnded", AnswerError::StreamEnded),
+            ("Upload", AnswerError::Upload("reason".into())),
+            ("Export", AnswerError::Export("reason".into())),
+            ("Image", AnswerError::Image("reason".into())),
+            ("Metadata", AnswerError::Metadata("reason".into())),
+            ("Audio", AnswerError::Audio("reason".into())),
+            ("Database", AnswerError::Database("reason".into())),
+            ("Internal", AnswerError::Internal("reason".into())),
+            ("Incompatible", AnswerError::Incompatible { version: 0, min_version: 1 })
+        ]
+    }
+
+    fn fixture(folder: &str, name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(folder).join(name)
+    }
+
+    /// Compare an encoded packet with its fixture, or update the fixture with `HEX_UPDATE_FIXTURES`
+    ///
+    /// Fixtures may only change together with the version of the protocol.
+    #[allow(dead_code)]
+    fn golden(path: &Path, buf: &[u8]) {
+        if env::var("HEX_UPDATE_FIXTURES").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, buf).unwrap();
+        }
+
+        assert_eq!(fs::read(path).unwrap(), buf, "{:?} changed", path);
+    }
+
+    fn tags<T>(items: &[(&str, T)], tag: fn(&T) -> u32) -> Vec<u32> {
+        items.iter().map(|(_, x)| tag(x)).collect()
+    }
+
+    #[test]
+    fn request_fixtures() {
+        let requests = requests();
+        assert_eq!(tags(&requests, RequestAction::tag), (0..requests.len() as u32).collect::<Vec<_>>());
+
+        for (name, action) in requests {
+            let path = fixture("requests", &format!("{}.bin", name));
+            let req = Request::new(ID, action);
+
+            #[cfg(feature = "client")]
+            golden(&path, &req.to_buf().unwrap());
+
+            let buf = fs::read(&path).unwrap();
+            assert_eq!(buf[16..20], serialize(&req.msg.tag()).unwrap()[..], "{}", name);
+
+            #[cfg(feature = "server")]
+            assert_eq!(format!("{:?}", Request::try_from(&buf).unwrap()), format!("{:?}", req), "{}", name);
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn unknown_request() {
+        let mut buf = serialize(&ID).unwrap();
+        buf.extend(serialize(&(requests().len() as u32)).unwrap());
+
+        // a request of a newer client can't be decoded, but answered
+        assert!(Request::try_from(&buf).is_err());
+        assert_eq!(Request::peek_id(&buf, Encoding::Bincode), Some(ID));
+        assert_eq!(Request::peek_id(&buf[0..8], Encoding::Bincode), None);
+    }
+
+    #[test]
+    fn answer_fixtures() {
+        let answers = answers();
+        assert_eq!(tags(&answers, AnswerAction::tag), (0..answers.len() as u32).collect::<Vec<_>>());
+
+        for (name, action) in answers {
+            let path = fixture("answers", &format!("{}.bin", name));
+            let answer = Answer::new(ID, Ok(action));
+
+            #[cfg(feature = "server")]
+            golden(&path, &answer.to_buf().unwrap());
+
+            let buf = fs::read(&path).unwrap();
+            assert_eq!(buf[20..24], serialize(&answer.msg.as_ref().unwrap().tag()).unwrap()[..], "{}", name);
+
+            #[cfg(feature = "client")]
+            assert_eq!(format!("{:?}", Answer::try_from(&buf).unwrap()), format!("{:?}", answer), "{}", name);
+        }
+    }
+
+    #[test]
+    fn error_fixtures() {
+        let errors = errors();
+        assert_eq!(tags(&errors, AnswerError::tag), (0..errors.len() as u32).collect::<Vec<_>>());
+
+        for (name, error) in errors {
+            let path = fixture("errors", &format!("{}.bin", name));
+            let tag = error.tag();
+            let answer = Answer::new(ID, Err(error));
+
+            #[cfg(feature = "server")]
+            golden(&path, &answer.to_buf().unwrap());
+
+            let buf = fs::read(&path).unwrap();
+            assert_eq!(buf[20..24], serialize(&tag).unwrap()[..], "{}", name);
+
+            #[cfg(feature = "client")]
+            assert_eq!(format!("{:?}", Answer::try_from(&buf).unwrap()), format!("{:?}", answer), "{}", name);
+        }
+    }
+
+    #[cfg(all(feature = "server", feature = "json"))]
+    #[test]
+    fn json_fixtures() {
+        for (name, action) in requests().into_iter().filter(|(name, _)| ["Search", "StreamNext", "Subscribe", "Hello"].contains(name)) {
+            let buf = fs::read(fixture("json", &format!("request{}.json", name))).unwrap();
+            let req = Request::decode(&buf, Encoding::Json).unwrap();
+
+            assert_eq!(format!("{:?}", req), format!("{:?}", Request::new(ID, action)), "{}", name);
+            assert_eq!(Request::peek_id(&buf, Encoding::Json), Some(ID));
+        }
+
+        let answers = answers().into_iter()
+            .filter(|(name, _)| ["GetPlaylist", "Subscribe", "Hello"].contains(name))
+            .map(|(name, action)| (name, Answer::new(ID, Ok(action))))
+            .chain(Some(("Error", Answer::new(ID, Err(AnswerError::Upload("reason".into()))))));
+
+        for (name, answer) in answers {
+            let buf = fs::read(fixture("json", &format!("answer{}.json", name))).unwrap();
+            let expected: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+
+            let buf = answer.encode(Encoding::Json).unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&buf).unwrap(), expected, "{}", name);
+        }
+    }
+}
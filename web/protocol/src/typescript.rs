@@ -0,0 +1,247 @@
+//! TypeScript definitions of the protocol
+//!
+//! The types are traced with their serde implementation and written as they appear in JSON, the
+//! encoding seen by the frontend and by clients using the `json` feature. Besides a type for every
+//! struct and enum, the definitions contain the parameters of every request in `Requests` and the
+//! payload of the answer to it in `Responses`. The parameters are also written as `CALLS` for the
+//! generated functions of the frontend.
+//!
+//! The generated files are part of the frontend and compared with the output of this module in
+//! the tests, run them with `HEX_UPDATE_FIXTURES=1` to update them after changing the protocol.
+
+use std::result;
+
+use serde_reflection::{Tracer, TracerConfig, Registry, ContainerFormat, Format, Named, VariantFormat};
+
+use hex_database::{Role, JobKind, JobStatus, JobResult, TransitionAction};
+
+use objects::{Request, Answer, RequestAction, AnswerAction, AnswerError, StreamFormat, Subscription, Event, PartyCommand};
+
+pub type Result<T> = result::Result<T, serde_reflection::Error>;
+
+/// Answers of requests, which are not named like the request
+const RESPONSES: &[(&str, &[&str])] = &[
+    ("Search", &["SearchResult"]),
+    ("GetTrack", &["Track"]),
+    // depends on the format of the stream
+    ("StreamNext", &["StreamNext", "StreamPackets"])
+];
+
+/// Trace all types of the protocol
+pub fn registry() -> Result<Registry> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+
+    // every enum is traced on its own, so that all variants are known
+    tracer.trace_simple_type::<RequestAction>()?;
+    tracer.trace_simple_type::<AnswerAction>()?;
+    tracer.trace_simple_type::<AnswerError>()?;
+    tracer.trace_simple_type::<result::Result<AnswerAction, AnswerError>>()?;
+    tracer.trace_simple_type::<StreamFormat>()?;
+    tracer.trace_simple_type::<Subscription>()?;
+    tracer.trace_simple_type::<Event>()?;
+    tracer.trace_simple_type::<PartyCommand>()?;
+    tracer.trace_simple_type::<Role>()?;
+    tracer.trace_simple_type::<JobKind>()?;
+    tracer.trace_simple_type::<JobStatus>()?;
+    tracer.trace_simple_type::<JobResult>()?;
+    tracer.trace_simple_type::<TransitionAction>()?;
+
+    tracer.trace_simple_type::<Request>()?;
+    tracer.trace_simple_type::<Answer>()?;
+
+    tracer.registry()
+}
+
+/// Type of a value in JSON
+fn format(value: &Format) -> String {
+    match *value {
+        Format::TypeName(ref name) => name.clone(),
+        Format::Unit => "null".into(),
+        Format::Bool => "boolean".into(),
+        Format::I8 | Format::I16 | Format::I32 | Format::I64 | Format::I128 |
+        Format::U8 | Format::U16 | Format::U32 | Format::U64 | Format::U128 |
+        Format::F32 | Format::F64 => "number".into(),
+        Format::Char | Format::Str => "string".into(),
+        Format::Bytes => "Array<number>".into(),
+        Format::Option(ref inner) => format!("{} | null", format(inner)),
+        Format::Seq(ref inner) | Format::TupleArray { content: ref inner, .. } => format!("Array<{}>", format(inner)),
+        Format::Map { ref value, .. } => format!("{{ [key: string]: {} }}", format(value)),
+        Format::Tuple(ref items) => format!("[{}]", items.iter().map(format).collect::<Vec<_>>().join(", ")),
+        Format::Variable(_) => "unknown".into()
+    }
+}
+
+/// Fields of a struct in a single line
+fn fields(fields: &[Named<Format>]) -> String {
+    if fields.is_empty() {
+        return "{}".into();
+    }
+
+    let fields = fields.iter()
+        .map(|x| format!("{}: {}", x.name, format(&x.value)))
+        .collect::<Vec<_>>();
+
+    format!("{{ {} }}", fields.join(", "))
+}
+
+/// Content of an enum variant, `null` for unit variants
+fn payload(variant: &VariantFormat) -> String {
+    match *variant {
+        VariantFormat::Unit => "null".into(),
+        VariantFormat::NewType(ref inner) => format(inner),
+        VariantFormat::Tuple(ref items) => format(&Format::Tuple(items.clone())),
+        VariantFormat::Struct(ref items) => fields(items),
+        VariantFormat::Variable(_) => "unknown".into()
+    }
+}
+
+/// Enums are externally tagged, unit variants are written as string
+fn variant(variant: &Named<VariantFormat>) -> String {
+    match variant.value {
+        VariantFormat::Unit => format!("\"{}\"", variant.name),
+        ref value => format!("{{ {}: {} }}", variant.name, payload(value))
+    }
+}
+
+fn container(name: &str, container: &ContainerFormat) -> String {
+    match *container {
+        ContainerFormat::UnitStruct => format!("export type {} = null;\n", name),
+        ContainerFormat::NewTypeStruct(ref inner) => format!("export type {} = {};\n", name, format(inner)),
+        ContainerFormat::TupleStruct(ref items) => format!("export type {} = {};\n", name, format(&Format::Tuple(items.clone()))),
+        ContainerFormat::Struct(ref items) => {
+            let items = items.iter()
+                .map(|x| format!("    {}: {};\n", x.name, format(&x.value)))
+                .collect::<String>();
+
+            format!("export interface {} {{\n{}}}\n", name, items)
+        },
+        ContainerFormat::Enum(ref variants) => {
+            let variants = variants.values()
+                .map(|x| format!("\n    | {}", variant(x)))
+                .collect::<String>();
+
+            format!("export type {} ={};\n", name, variants)
+        }
+    }
+}
+
+/// Variants of an enum in the registry
+fn variants<'a>(registry: &'a Registry, name: &str) -> Vec<&'a Named<VariantFormat>> {
+    match registry.get(name) {
+        Some(&ContainerFormat::Enum(ref variants)) => variants.values().collect(),
+        _ => Vec::new()
+    }
+}
+
+/// TypeScript definitions of all types and requests
+pub fn definitions() -> Result<String> {
+    let registry = registry()?;
+
+    let mut out = String::from("// Generated by `hex_server_protocol::typescript`, do not edit\n");
+
+    for (name, value) in &registry {
+        out.push('\n');
+        out.push_str(&container(name, value));
+    }
+
+    // parameters of every request
+    out.push_str("\nexport interface Requests {\n");
+    for request in variants(&registry, "RequestAction") {
+        out.push_str(&format!("    {}: {};\n", request.name, payload(&request.value)));
+    }
+    out.push_str("}\n");
+
+    // payload of every answer
+    out.push_str("\nexport interface Answers {\n");
+    for answer in variants(&registry, "AnswerAction") {
+        out.push_str(&format!("    {}: {};\n", answer.name, payload(&answer.value)));
+    }
+    out.push_str("}\n");
+
+    // payload of the answer to every request
+    let answers = variants(&registry, "AnswerAction");
+    out.push_str("\nexport interface Responses {\n");
+    for request in variants(&registry, "RequestAction") {
+        let names = RESPONSES.iter()
+            .find(|x| x.0 == request.name)
+            .map(|x| x.1.to_vec())
+            .unwrap_or_else(|| vec![request.name.as_str()]);
+
+        let types = names.into_iter()
+            .filter(|x| answers.iter().any(|answer| answer.name == *x))
+            .map(|x| format!("Answers[\"{}\"]", x))
+            .collect::<Vec<_>>();
+
+        if !types.is_empty() {
+            out.push_str(&format!("    {}: {};\n", request.name, types.join(" | ")));
+        }
+    }
+    out.push_str("}\n");
+
+    Ok(out)
+}
+
+/// Parameters of every request, ordered like their fields
+pub fn calls() -> Result<String> {
+    let registry = registry()?;
+
+    let mut out = String::from("// Generated by `hex_server_protocol::typescript`, do not edit\n\n");
+    out.push_str("// parameters of every request, in the order of the arguments of the generated functions\n");
+    out.push_str("export const CALLS = {\n");
+
+    let requests = variants(&registry, "RequestAction").into_iter()
+        .filter_map(|request| {
+            let params = match request.value {
+                VariantFormat::Unit => Vec::new(),
+                VariantFormat::Struct(ref fields) => fields.iter().map(|x| format!("\"{}\"", x.name)).collect(),
+                _ => return None
+            };
+
+            Some(format!("    {}: [{}]", request.name, params.join(", ")))
+        })
+        .collect::<Vec<_>>();
+
+    out.push_str(&requests.join(",\n"));
+    out.push_str("\n};\n");
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use std::path::PathBuf;
+
+    use super::{definitions, calls};
+
+    fn frontend(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../frontend/src/lib").join(name)
+    }
+
+    #[test]
+    fn bindings() {
+        let files = vec![
+            (frontend("messages.d.ts"), definitions().unwrap()),
+            (frontend("calls.js"), calls().unwrap())
+        ];
+
+        for (path, content) in files {
+            if env::var("HEX_UPDATE_FIXTURES").is_ok() {
+                fs::write(&path, &content).unwrap();
+            }
+
+            assert_eq!(fs::read_to_string(&path).unwrap(), content, "{:?} is outdated", path);
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let definitions = definitions().unwrap();
+
+        assert!(definitions.contains("    | { Search: { query: string } }\n"));
+        assert!(definitions.contains("    | \"StreamEnd\"\n"));
+        assert!(definitions.contains("    Search: Answers[\"SearchResult\"];\n"));
+        assert!(definitions.contains("export type TrackKey = Array<number>;\n"));
+        assert!(calls().unwrap().contains("    StreamNext: [\"key\", \"binaural\", \"format\"],\n"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Version and capabilities of the protocol
+//!
+//! A client sends `Hello` with its version and capabilities as first request. The server answers
+//! with the version it speaks and the capabilities supported by both sides, or fails with
+//! `AnswerError::Incompatible` if the client is older than `MIN_VERSION`. Compatible additions,
+//! like a new variant with the next free tag, only add a capability; the version is increased if
+//! existing packets change their encoding.
+
+use std::result;
+
+use objects::AnswerError;
+
+/// Version of the protocol
+pub const VERSION: u32 = 1;
+
+/// Oldest version of a client still understood
+pub const MIN_VERSION: u32 = 1;
+
+/// Optional parts of the protocol
+pub const CAPABILITIES: &[&str] = &[
+    // answer requests in text frames with JSON
+    "json",
+    // re-encode streams to Opus with `StreamFormat::Opus`
+    "opus",
+    // push typed notifications to subscribers
+    "subscriptions",
+    // upload files in chunks and resume them
+    "chunked-upload",
+    // store cover images of playlists
+    "playlist-covers",
+    // look up metadata of tracks
+    "suggestions",
+    // shared playback sessions with a queue
+    "parties",
+    // play queues stored on the server
+    "queue"
+];
+
+/// Negotiate the version and capabilities with a client
+pub fn negotiate(version: u32, capabilities: &[String]) -> result::Result<(u32, Vec<String>), AnswerError> {
+    if version < MIN_VERSION {
+        return Err(AnswerError::Incompatible { version, min_version: MIN_VERSION });
+    }
+
+    let shared = capabilities.iter()
+        .filter(|x| CAPABILITIES.contains(&x.as_str()))
+        .cloned()
+        .collect();
+
+    Ok((version.min(VERSION), shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use objects::AnswerError;
+    use super::{negotiate, VERSION, MIN_VERSION};
+
+    #[test]
+    fn negotiation() {
+        let (version, capabilities) = negotiate(VERSION, &["opus".into(), "telepathy".into()]).unwrap();
+        assert_eq!(version, VERSION);
+        assert_eq!(capabilities, vec!["opus".to_string()]);
+
+        // a newer client falls back to our version
+        assert_eq!(negotiate(VERSION + 1, &[]).unwrap().0, VERSION);
+
+        assert_eq!(negotiate(MIN_VERSION - 1, &[]), Err(AnswerError::Incompatible { version: MIN_VERSION - 1, min_version: MIN_VERSION }));
+    }
+}
@@ -5,10 +5,19 @@ use objects::PacketId;
 use objects::Request;
 use objects::Answer;
 use objects::RequestAction;
+use objects::AnswerAction;
+use version;
+
 fn vec_to_id(buf: Vec<u32>) -> PacketId {
     [buf[0], buf[1], buf[2], buf[3]]
 }
 
+/// Version of the protocol, sent with `Hello`
+#[wasm_bindgen]
+pub fn protocol_version() -> u32 {
+    version::VERSION
+}
+
 #[wasm_bindgen]
 pub fn request_to_buf(id: Vec<u32>, msg: JsValue) -> Option<Vec<u8>> {
     let msg: RequestAction = msg.into_serde().ok()?;
@@ -29,6 +38,18 @@ pub fn upload_track(id: Vec<u32>, name: String, format: String, data: Vec<u8>) -
     bincode::serialize(&req).ok()
 }
 
+/// Serialize a chunk of an upload without converting the data to JSON
+#[wasm_bindgen]
+pub fn upload_chunk(id: Vec<u32>, session: String, index: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+    let msg = RequestAction::UploadChunk {
+        id: session, index, data
+    };
+
+    let req = Request::new(vec_to_id(id), msg);
+
+    bincode::serialize(&req).ok()
+}
+
 #[wasm_bindgen]
 pub struct Wrapper(Option<Answer>);
 
@@ -55,7 +76,7 @@ impl Wrapper {
                     JsValue::from_serde(&answer).unwrap_or(JsValue::null())
                 },
                 Err(ref err) => {
-                    JsValue::from_str(&err)
+                    JsValue::from_str(&err.to_string())
                 }
             }
         } else {
@@ -63,6 +84,33 @@ impl Wrapper {
         }
     }
 
+    /// Get the typed error of a failed request, e.g. `"NotFound"` or `{"Upload": "reason"}`
+    pub fn error(&self) -> JsValue {
+        match self.0 {
+            Some(Answer { msg: Err(ref err), .. }) => JsValue::from_serde(err).unwrap_or(JsValue::null()),
+            _ => JsValue::null()
+        }
+    }
+
+    /// Number of Opus packets in a `StreamPackets` answer
+    pub fn num_packets(&self) -> usize {
+        match self.0 {
+            Some(Answer { msg: Ok(AnswerAction::StreamPackets(ref packets)), .. }) => packets.len(),
+            _ => 0
+        }
+    }
+
+    /// Get a single Opus packet of a `StreamPackets` answer
+    ///
+    /// The packets are copied separately, because a conversion to JSON would blow up the binary
+    /// data to an array of numbers.
+    pub fn packet(&self, idx: usize) -> Option<Vec<u8>> {
+        match self.0 {
+            Some(Answer { msg: Ok(AnswerAction::StreamPackets(ref packets)), .. }) => packets.get(idx).cloned(),
+            _ => None
+        }
+    }
+
     /*pub fn buffer(&self) -> Option<Vec<u8>> {
         if let Some(ref inner) = self.0 {
             match &inner.msg {
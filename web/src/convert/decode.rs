@@ -0,0 +1,137 @@
+//! Decode uploaded files in their own thread
+//!
+//! The file is decoded natively with the `decode` module of the music container, only unknown
+//! formats are passed to `ffmpeg`. The progress is reported while decoding. Dropping the converter
+//! stops the decoding after the current block and kills a running `ffmpeg`.
+
+use std::path::PathBuf;
+use std::result;
+use std::thread;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::{IntoFuture, Future, Stream};
+use futures::sync::mpsc::{channel, Receiver};
+use tokio_core::reactor::Handle;
+
+use hex_music_container::Configuration;
+use hex_music_container::decode::Decoder;
+use hex_music_container::error::Error as DecodeError;
+
+use crate::error::{Result, Error};
+
+pub struct State {
+    pub progress: f32,
+    pub desc: String,
+    /// Interleaved samples at 48kHz and their configuration, once the file is decoded
+    pub pcm: Option<(Configuration, Vec<i16>)>,
+    /// Reason of the failure, if the decoding failed
+    pub error: Option<String>
+}
+
+impl State {
+    pub fn empty(desc: String) -> State {
+        State {
+            progress: 0.0,
+            desc: desc,
+            pcm: None,
+            error: None
+        }
+    }
+}
+
+/// Decode a whole file, unless the decoding is cancelled
+///
+/// Returns `None` when cancelled, the decoder and `ffmpeg` are stopped by dropping it.
+fn decode<F: FnMut(f32)>(path: &PathBuf, cancelled: &AtomicBool, mut progress: F) -> Option<result::Result<(Configuration, Vec<i16>), DecodeError>> {
+    let mut decoder = match Decoder::open(path) {
+        Ok(decoder) => decoder,
+        Err(err) => return Some(Err(err))
+    };
+
+    let mut pcm = Vec::new();
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        match decoder.next_block() {
+            Ok(block) => pcm.extend_from_slice(&block),
+            Err(DecodeError::ReachedEnd) => break,
+            Err(err) => return Some(Err(err))
+        }
+
+        if let Some(value) = decoder.progress() {
+            progress(value);
+        }
+    }
+
+    Some(Ok((decoder.configuration(), pcm)))
+}
+
+pub struct Converter {
+    pub handle: Handle,
+    recv: Option<Receiver<State>>,
+    cancelled: Arc<AtomicBool>
+}
+
+impl Converter {
+    /// Decode a file, which is removed afterwards with `remove_input`
+    pub fn new(handle: Handle, desc: String, path: PathBuf, remove_input: bool) -> Converter {
+        let (sender, recv) = channel(10);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancelled2 = cancelled.clone();
+
+        thread::spawn(move || {
+            let mut sender2 = sender.clone();
+            let mut sender = sender;
+
+            // report every percent, further updates are dropped while the channel is full
+            let mut last = 0.0;
+            let res = decode(&path, &cancelled2, |progress| {
+                if progress - last >= 0.01 && progress < 1.0 {
+                    last = progress;
+                    let _ = sender.try_send(State { progress, desc: desc.clone(), pcm: None, error: None });
+                }
+            });
+
+            if remove_input {
+                let _ = fs::remove_file(&path);
+            }
+
+            let state = match res {
+                Some(Ok(pcm)) => State { progress: 1.0, desc: desc, pcm: Some(pcm), error: None },
+                Some(Err(err)) => State { progress: 0.0, desc: desc, pcm: None, error: Some(format!("Could not decode file: {:?}", err)) },
+                None => return
+            };
+
+            if sender2.try_send(state).is_err() {
+                eprintln!("Could not report the end of the decoding");
+            }
+        });
+
+        Converter {
+            handle: handle,
+            recv: Some(recv),
+            cancelled: cancelled
+        }
+    }
+
+    /// Take the stream of state changes, which exists only once
+    pub fn state(&mut self) -> Result<impl Stream<Item=State, Error=()>> {
+        self.recv.take().ok_or(Error::ChannelFailed)
+    }
+
+    pub fn spawn<T>(&self, hnd: T)
+    where T: Stream + 'static {
+        self.handle.spawn(hnd.for_each(|_| Ok(())).into_future().map(|_| ()).map_err(|_| ()));
+    }
+}
+
+impl Drop for Converter {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
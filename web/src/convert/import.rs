@@ -0,0 +1,363 @@
+//! Import of archives with many audio files
+//!
+//! The archive is unpacked with `unzip` or `tar` into a directory next to it, then every audio
+//! file is converted like a single upload, one after the other. The embedded tags of each file are
+//! read with the same logic as the `store` command of the CLI. Files which can't be converted are
+//! skipped, so that a single broken file doesn't stop the import of an album.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use futures::Future;
+use tokio_core::reactor::Handle;
+use tokio_process::CommandExt;
+
+use hex_database::{Track, TrackKey};
+use hex_database::utils::{Tags, AUDIO_EXTENSIONS};
+use hex_music_container::tags;
+use hex_server_protocol::PacketId;
+
+use super::{UploadState, Kill, killable};
+
+/// Result of the unpacking, once `unzip` or `tar` has exited
+type Unpacked = Rc<RefCell<Option<Result<(), String>>>>;
+
+/// An imported track
+struct Entry {
+    key: TrackKey,
+    tags: Tags,
+    /// Name of the file, orders tracks without number
+    file: String
+}
+
+/// Collect all audio files in a directory and its children
+///
+/// Archives may contain symbolic links to anywhere on the server, they are skipped.
+fn audio_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+
+    for path in entries.filter_map(|x| x.ok()).map(|x| x.path()) {
+        let file_type = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => continue
+        };
+
+        if file_type.is_symlink() {
+            continue;
+        } else if file_type.is_dir() {
+            audio_files(&path, files);
+        } else if file_type.is_file() {
+            let is_audio = path.extension()
+                .and_then(|x| x.to_str())
+                .map(|x| AUDIO_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+                .unwrap_or(false);
+
+            // skip metadata of macOS archives
+            let hidden = path.file_name().and_then(|x| x.to_str()).map(|x| x.starts_with("._")).unwrap_or(true);
+
+            if is_audio && !hidden {
+                files.push(path);
+            }
+        }
+    }
+}
+
+/// Group tracks by album, ordered by disc and track number
+///
+/// Tracks without album are grouped under the given name, usually the name of the archive.
+fn albums(name: &str, entries: &[Entry]) -> Vec<(String, Vec<TrackKey>)> {
+    let mut albums: Vec<(String, Vec<&Entry>)> = Vec::new();
+
+    for entry in entries {
+        let album = entry.tags.album.clone().unwrap_or_else(|| name.to_string());
+
+        match albums.iter_mut().find(|x| x.0 == album) {
+            Some(album) => album.1.push(entry),
+            None => albums.push((album, vec![entry]))
+        }
+    }
+
+    albums.into_iter().map(|(album, mut entries)| {
+        entries.sort_by_key(|x| (x.tags.disc.unwrap_or(1), x.tags.track.unwrap_or(u32::max_value()), x.file.clone()));
+
+        // the same track can appear twice in an archive
+        let mut keys: Vec<TrackKey> = Vec::new();
+        for entry in entries {
+            if !keys.contains(&entry.key) {
+                keys.push(entry.key);
+            }
+        }
+
+        (album, keys)
+    }).collect()
+}
+
+pub struct ImportState {
+    handle: Handle,
+    id: PacketId,
+    /// Directory containing the unpacked archive
+    dir: PathBuf,
+    unpacked: Unpacked,
+    /// Stops `unzip` or `tar`, if it could be started
+    kill: Option<Kill>,
+    /// Audio files waiting for their conversion, in reverse order
+    files: Option<Vec<PathBuf>>,
+    num_files: usize,
+    /// The file currently converted
+    current: Option<(String, Tags, UploadState)>,
+    entries: Vec<Entry>,
+    skipped: Vec<String>
+}
+
+impl ImportState {
+    /// Unpack an archive to `dir`, `format` is the extension of the archive
+    pub fn new(handle: Handle, id: PacketId, archive: &Path, format: &str, dir: PathBuf) -> ImportState {
+        let unpacked = Rc::new(RefCell::new(None));
+
+        let _ = fs::remove_dir_all(&dir);
+        let child = fs::create_dir_all(&dir)
+            .and_then(|_| match format {
+                "zip" => Command::new("unzip").arg("-q").arg("-o").arg(archive).arg("-d").arg(&dir).spawn_async(&handle),
+                // tar detects the compression by itself
+                _ => Command::new("tar").arg("-xf").arg(archive).arg("-C").arg(&dir).spawn_async(&handle)
+            });
+
+        let kill = match child {
+            Ok(child) => {
+                let unpacked2 = unpacked.clone();
+                let (exit, kill) = killable(child);
+
+                handle.spawn(exit.then(move |res| {
+                    *unpacked2.borrow_mut() = Some(match res {
+                        Ok(Some(ref status)) if status.success() => Ok(()),
+                        Ok(Some(status)) => Err(format!("Could not unpack archive, exited with {}", status)),
+                        Ok(None) => Err("Unpacking was stopped".into()),
+                        Err(err) => Err(format!("Could not unpack archive: {}", err))
+                    });
+
+                    Ok::<(), ()>(())
+                }));
+
+                Some(kill)
+            },
+            Err(err) => {
+                *unpacked.borrow_mut() = Some(Err(format!("Could not unpack archive: {}", err)));
+
+                None
+            }
+        };
+
+        ImportState {
+            handle, id, dir, unpacked, kill,
+            files: None,
+            num_files: 0,
+            current: None,
+            entries: Vec::new(),
+            skipped: Vec::new()
+        }
+    }
+
+    /// Advance the import, returns a converted track with its tags applied
+    ///
+    /// The track has to be added to the library, a track which can't be added is passed to `skip`.
+    pub fn tick(&mut self, data_path: PathBuf) -> Option<Track> {
+        if self.files.is_none() {
+            match *self.unpacked.borrow() {
+                Some(Ok(())) => {},
+                _ => return None
+            }
+
+            let mut files = Vec::new();
+            audio_files(&self.dir, &mut files);
+            files.sort();
+            files.reverse();
+
+            self.num_files = files.len();
+            self.files = Some(files);
+        }
+
+        if self.current.is_none() {
+            let path = self.files.as_mut()?.pop()?;
+            let file = path.file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+            let tags = Tags::from_pairs(tags::read(&path));
+            let upload = UploadState::converting_file(self.handle.clone(), file.clone(), self.id, &path);
+
+            self.current = Some((file, tags, upload));
+        }
+
+        let (file, tags, mut upload) = self.current.take()?;
+
+        if let Some(mut track) = upload.tick(data_path) {
+            tags.apply(&mut track);
+            if track.title.is_none() {
+                track.title = Path::new(&file).file_stem().and_then(|x| x.to_str()).map(|x| x.to_string());
+            }
+
+            self.entries.push(Entry { key: track.key, tags, file });
+
+            return Some(track);
+        }
+
+        match upload.error().map(|x| x.to_string()) {
+            Some(reason) => self.skipped.push(format!("{}: {}", file, reason)),
+            None => self.current = Some((file, tags, upload))
+        }
+
+        None
+    }
+
+    /// Skip the last returned track, because it couldn't be added to the library
+    pub fn skip(&mut self, reason: String) {
+        if let Some(entry) = self.entries.pop() {
+            self.skipped.push(format!("{}: {}", entry.file, reason));
+        }
+    }
+
+    /// Stop the unpacking and the conversion of the current file
+    pub fn cancel(&mut self) {
+        if let Some(ref mut kill) = self.kill {
+            kill.kill();
+        }
+
+        if let Some((_, _, ref mut upload)) = self.current {
+            upload.cancel();
+        }
+    }
+
+    /// Reason of the failure, if the archive couldn't be unpacked or contains no audio
+    pub fn error(&self) -> Option<String> {
+        match *self.unpacked.borrow() {
+            Some(Err(ref reason)) => return Some(reason.clone()),
+            _ => {}
+        }
+
+        if self.is_finished() && self.entries.is_empty() {
+            if self.skipped.is_empty() {
+                Some("Archive contains no audio files".into())
+            } else {
+                Some(format!("No file could be imported: {}", self.skipped.join(", ")))
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Check whether all files were converted or skipped
+    pub fn is_finished(&self) -> bool {
+        self.current.is_none() && self.files.as_ref().map(|x| x.is_empty()).unwrap_or(false)
+    }
+
+    /// Name of the current step
+    pub fn kind(&self) -> &str {
+        match self.current {
+            Some((_, _, ref upload)) => upload.kind(),
+            None if self.files.is_none() => "unpacking",
+            None => "importing"
+        }
+    }
+
+    /// Name of the file currently converted
+    pub fn desc(&self) -> String {
+        self.current.as_ref().map(|x| x.0.clone()).unwrap_or_default()
+    }
+
+    /// Progress over all files
+    pub fn progress(&self) -> f32 {
+        if self.num_files == 0 {
+            return 0.0;
+        }
+
+        let current = self.current.as_ref().map(|x| x.2.progress()).unwrap_or(0.0);
+        let done = self.entries.len() + self.skipped.len();
+
+        (done as f32 + current) / self.num_files as f32
+    }
+
+    /// All imported tracks
+    pub fn tracks(&self) -> Vec<TrackKey> {
+        let mut keys: Vec<TrackKey> = Vec::new();
+        for entry in &self.entries {
+            if !keys.contains(&entry.key) {
+                keys.push(entry.key);
+            }
+        }
+
+        keys
+    }
+
+    /// Imported tracks grouped by album, see `albums`
+    pub fn albums(&self, name: &str) -> Vec<(String, Vec<TrackKey>)> {
+        albums(name, &self.entries)
+    }
+
+    /// Reasons for each skipped file
+    pub fn skipped(&self) -> Vec<String> {
+        self.skipped.clone()
+    }
+}
+
+impl Drop for ImportState {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use hex_database::TrackKey;
+    use hex_database::utils::Tags;
+    use super::{albums, audio_files, Entry};
+
+    fn entry(key: u8, album: Option<&str>, track: Option<u32>, file: &str) -> Entry {
+        Entry {
+            key: TrackKey::from_vec(&[key; 16]),
+            tags: Tags { album: album.map(|x| x.to_string()), track, ..Tags::default() },
+            file: file.into()
+        }
+    }
+
+    #[test]
+    fn group_by_album() {
+        let entries = vec![
+            entry(1, Some("B"), Some(2), "b2.mp3"),
+            entry(2, None, None, "z.mp3"),
+            entry(3, Some("B"), Some(1), "b1.mp3"),
+            entry(4, None, None, "a.mp3"),
+            entry(3, Some("B"), Some(1), "copy.mp3")
+        ];
+
+        let albums = albums("archive", &entries);
+        let key = |x: u8| TrackKey::from_vec(&[x; 16]);
+
+        assert_eq!(albums, vec![
+            ("B".to_string(), vec![key(3), key(1)]),
+            ("archive".to_string(), vec![key(4), key(2)])
+        ]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skip_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        fs::create_dir(dir.path().join("cd1")).unwrap();
+        fs::write(dir.path().join("cd1/01.mp3"), b"").unwrap();
+        fs::write(dir.path().join("._02.mp3"), b"").unwrap();
+        fs::write(outside.path().join("secret.flac"), b"").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret.flac"), dir.path().join("03.flac")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("cd2")).unwrap();
+
+        let mut files = Vec::new();
+        audio_files(dir.path(), &mut files);
+
+        assert_eq!(files, vec![dir.path().join("cd1/01.mp3")]);
+    }
+}
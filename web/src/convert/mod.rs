@@ -1,30 +1,96 @@
-pub mod ffmpeg;
+pub mod decode;
 pub mod youtube;
 pub mod opus;
-pub mod download;
+pub mod import;
 
 use std::mem;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+use std::io;
+use std::fs;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::process::ExitStatus;
 
 use tokio_core::reactor::Handle;
-use futures::{Stream, Future, IntoFuture};
+use tokio_process::Child;
+use futures::{future, Stream, Future};
+use futures::future::Either;
+use futures::sync::oneshot;
 
 use hex_database::{Track, TrackKey};
+use hex_music_container::Configuration;
 use hex_server_protocol::PacketId;
 
-pub use self::download::DownloadState;
+pub use self::import::ImportState;
+
+/// Reason why an external program failed, if it did
+type Failure = Rc<RefCell<Option<String>>>;
+
+/// Stops an external program, which is no longer needed
+pub struct Kill(Option<oneshot::Sender<()>>);
+
+impl Kill {
+    pub fn kill(&mut self) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// Wait for an external program until it exits or is killed
+///
+/// Resolves to `None` if the program was killed.
+fn killable(child: Child) -> (impl Future<Item = Option<ExitStatus>, Error = io::Error>, Kill) {
+    let (sender, recv) = oneshot::channel();
+
+    // a dropped handle leaves the program running
+    let killed = recv.or_else(|_| future::empty::<(), ()>());
+
+    let exit = child.select2(killed).then(|res| match res {
+        Ok(Either::A((status, _))) => Ok(Some(status)),
+        Ok(Either::B((_, mut child))) => {
+            let _ = child.kill();
+
+            Ok(None)
+        },
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B(_)) => Ok(None)
+    });
+
+    (exit, Kill(Some(sender)))
+}
+
+/// Wait for an external program in the background and remember a failure
+fn watch(handle: &Handle, child: Child, name: &'static str) -> (Failure, Kill) {
+    let failure = Rc::new(RefCell::new(None));
+    let failure2 = failure.clone();
+    let (exit, kill) = killable(child);
+
+    handle.spawn(exit.then(move |res| {
+        match res {
+            Ok(Some(ref status)) if status.success() => {},
+            Ok(Some(status)) => *failure2.borrow_mut() = Some(format!("{} exited with {}", name, status)),
+            Ok(None) => *failure2.borrow_mut() = Some(format!("{} was stopped", name)),
+            Err(err) => *failure2.borrow_mut() = Some(format!("{} failed: {}", name, err))
+        }
+
+        Ok::<(), ()>(())
+    }));
+
+    (failure, kill)
+}
 
 pub enum UploadState {
     YoutubeDownload {
         downloader: youtube::Downloader,
         state: Rc<RefCell<youtube::State>>,
+        failure: Failure,
+        kill: Kill,
         id: PacketId
     },
-    ConvertingFFMPEG {
-        converter: ffmpeg::Converter,
-        state: Rc<RefCell<ffmpeg::State>>,
+    Decoding {
+        converter: decode::Converter,
+        state: Rc<RefCell<decode::State>>,
         id: PacketId
     },
     ConvertingOpus {
@@ -32,7 +98,9 @@ pub enum UploadState {
         state: Rc<RefCell<opus::State>>,
         id: PacketId
     },
-    Finished(Option<(PacketId, String, TrackKey)>)
+    Finished(Option<(PacketId, String, TrackKey)>),
+    /// The upload failed with a reason
+    Failed(String)
 }
 
 impl UploadState {
@@ -42,55 +110,73 @@ impl UploadState {
         let state = Rc::new(RefCell::new(youtube::State::empty()));
         let state2 = state.clone();
 
-        let hnd = dwnd.state().map(move |x| {
-            *(*state2).borrow_mut() = x;
+        let hnd = match dwnd.state() {
+            Ok(states) => states.map(move |x| {
+                *(*state2).borrow_mut() = x;
 
-            ()
-        });
+                ()
+            }),
+            Err(err) => return UploadState::Failed(format!("{:?}", err))
+        };
 
         dwnd.spawn(hnd);
-        handle.spawn(dwnd.child().into_future().map(|_| ()).map_err(|_| ()));
+        let (failure, kill) = match dwnd.child() {
+            Ok(child) => watch(&handle, child, "youtube-dl"),
+            Err(err) => return UploadState::Failed(format!("{:?}", err))
+        };
 
         UploadState::YoutubeDownload {
             downloader: dwnd,
             state: state,
+            failure: failure,
+            kill: kill,
             id: id
         }
     }
 
-    pub fn converting_ffmpeg(handle: Handle, desc: String, id: PacketId, data: &[u8], format: &str) -> UploadState {
-        let mut dwnd = ffmpeg::Converter::new(handle.clone(), desc.clone(), data, format).unwrap();
+    /// Convert a file on disk, e.g. an upload received in chunks, which is kept afterwards
+    pub fn converting_file(handle: Handle, desc: String, id: PacketId, path: &Path) -> UploadState {
+        UploadState::decoding(handle, desc, id, path.to_path_buf(), false)
+    }
+
+    fn decoding(handle: Handle, desc: String, id: PacketId, path: PathBuf, remove_input: bool) -> UploadState {
+        let mut dwnd = decode::Converter::new(handle, desc.clone(), path, remove_input);
 
-        let state = Rc::new(RefCell::new(ffmpeg::State::empty(desc, PathBuf::new(), PathBuf::new())));
+        let state = Rc::new(RefCell::new(decode::State::empty(desc)));
         let state2 = state.clone();
 
-        let hnd = dwnd.state().map(move |x| {
-            *(*state2).borrow_mut() = x;
+        let hnd = match dwnd.state() {
+            Ok(states) => states.map(move |x| {
+                *(*state2).borrow_mut() = x;
 
-            ()
-        });
+                ()
+            }),
+            Err(err) => return UploadState::Failed(format!("{:?}", err))
+        };
 
         dwnd.spawn(hnd);
-        handle.spawn(dwnd.child().into_future().map(|_| ()).map_err(|_| ()));
 
-        UploadState::ConvertingFFMPEG {
+        UploadState::Decoding {
             converter: dwnd,
             state: state,
             id: id
         }
     }
 
-    pub fn converting_opus(handle: Handle, id: PacketId, desc: String, samples: &[i16], duration: f32, num_channel: u32, data_path: PathBuf) -> UploadState {
-        let mut dwnd = opus::Converter::new(handle.clone(), desc.clone(), Vec::from(samples), duration, num_channel, data_path);
+    pub fn converting_opus(handle: Handle, id: PacketId, desc: String, samples: &[i16], duration: f32, conf: Configuration, data_path: PathBuf) -> UploadState {
+        let mut dwnd = opus::Converter::new(handle.clone(), desc.clone(), Vec::from(samples), duration, conf, data_path);
 
         let state = Rc::new(RefCell::new(opus::State::empty(desc)));
         let state2 = state.clone();
 
-        let hnd = dwnd.state().map(move |x| {
-            *(*state2).borrow_mut() = x;
+        let hnd = match dwnd.state() {
+            Ok(states) => states.map(move |x| {
+                *(*state2).borrow_mut() = x;
 
-            ()
-        });
+                ()
+            }),
+            Err(err) => return UploadState::Failed(format!("{:?}", err))
+        };
 
         dwnd.spawn(hnd);
         //handle.spawn(dwnd.child().into_future().map(|_| ()).map_err(|_| ()));
@@ -107,23 +193,27 @@ impl UploadState {
         let item = mem::replace(self, UploadState::Finished(None));
 
         let (next, ret): (Option<UploadState>, Option<Track>) = match &item {
-            UploadState::YoutubeDownload { ref state, ref id, ref downloader } => {
+            UploadState::YoutubeDownload { ref state, ref id, ref downloader, ref failure, .. } => {
                 let state = state.borrow();
-                if state.progress >= 1.0 {
-                    //TODO
-                    (Some(UploadState::converting_ffmpeg(downloader.handle.clone(), state.file.clone(), id.clone(), &state.get_content().unwrap(), state.format())), None)
+                if let Some(ref reason) = *failure.borrow() {
+                    (Some(UploadState::Failed(reason.clone())), None)
+                } else if state.progress >= 1.0 {
+                    // the download is removed after decoding
+                    (Some(UploadState::decoding(downloader.handle.clone(), state.file.clone(), id.clone(), PathBuf::from(&state.file), true)), None)
                 } else {
                     (None, None)
                 }
 
             },
-            UploadState::ConvertingFFMPEG { ref id, ref state, ref converter } => {
-                let state = state.borrow();
+            UploadState::Decoding { ref id, ref state, ref converter } => {
+                let mut state = state.borrow_mut();
 
-                if state.progress >= 0.999 {
-                    let (data, num_channel, duration) = state.read();
+                if let Some(ref reason) = state.error {
+                    (Some(UploadState::Failed(reason.clone())), None)
+                } else if let Some((conf, pcm)) = state.pcm.take() {
+                    let duration = pcm.len() as f32 / conf.num_channels() as f32 / 48000.0;
 
-                    (Some(UploadState::converting_opus(converter.handle.clone(), id.clone(), state.desc.clone(), &data, duration as f32, num_channel, data_path)), None)
+                    (Some(UploadState::converting_opus(converter.handle.clone(), id.clone(), state.desc.clone(), &pcm, duration, conf, data_path)), None)
                 } else {
                     (None, None)
                 }
@@ -131,7 +221,9 @@ impl UploadState {
             UploadState::ConvertingOpus { state, id, .. } => {
                 let state = state.borrow();
 
-                if state.progress >= 1.0 {
+                if let Some(ref reason) = state.error {
+                    (Some(UploadState::Failed(reason.clone())), None)
+                } else if state.progress >= 1.0 {
                     if let Some(ref track) = state.data {
                         (Some(UploadState::Finished(Some((id.clone(), state.desc.clone(), track.key.clone())))), Some(track.clone()))
                     } else {
@@ -153,6 +245,21 @@ impl UploadState {
         ret
     }
 
+    /// Stop all external programs and threads of the upload
+    ///
+    /// A decoding thread stops when the upload is dropped.
+    pub fn cancel(&mut self) {
+        if let UploadState::YoutubeDownload { ref mut kill, ref state, .. } = *self {
+            kill.kill();
+
+            // remove the partial download
+            let file = state.borrow().file.clone();
+            if !file.is_empty() {
+                let _ = fs::remove_file(file);
+            }
+        }
+    }
+
     pub fn should_retain(&self) -> bool {
         match self {
             UploadState::Finished(None) => false,
@@ -163,46 +270,56 @@ impl UploadState {
     pub fn kind(&self) -> &str {
         match *self {
             UploadState::YoutubeDownload { .. } => "youtube_download",
-            UploadState::ConvertingFFMPEG { .. } => "converting_ffmpeg",
+            UploadState::Decoding { .. } => "decoding",
             UploadState::ConvertingOpus { .. } => "converting_opus",
-            UploadState::Finished(_) => "finished"
+            UploadState::Finished(_) => "finished",
+            UploadState::Failed(_) => "failed"
         }
     }
     pub fn progress(&self) -> f32 {
         match *self {
             UploadState::YoutubeDownload { ref state, .. } => state.borrow().progress,
-            UploadState::ConvertingFFMPEG { ref state, .. } => state.borrow().progress,
+            UploadState::Decoding { ref state, .. } => state.borrow().progress,
             UploadState::ConvertingOpus { ref state, .. } => state.borrow().progress,
-            UploadState::Finished(_) => 1.0
+            UploadState::Finished(_) => 1.0,
+            UploadState::Failed(_) => 0.0
         }
     }
     pub fn id(&self) -> Option<PacketId> {
         match *self {
             UploadState::YoutubeDownload { ref id, .. } => Some(id.clone()),
-            UploadState::ConvertingFFMPEG { ref id, .. } => Some(id.clone()),
+            UploadState::Decoding { ref id, .. } => Some(id.clone()),
             UploadState::ConvertingOpus { ref id, .. } => Some(id.clone()),
             UploadState::Finished(Some((ref id, _, _))) => Some(id.clone()),
-            UploadState::Finished(None) => None
+            UploadState::Finished(None) | UploadState::Failed(_) => None
         }
     }
 
     pub fn track_key(&self) -> Option<TrackKey> {
         match self {
             UploadState::YoutubeDownload { .. } => None,
-            UploadState::ConvertingFFMPEG { .. } => None,
+            UploadState::Decoding { .. } => None,
             UploadState::ConvertingOpus { .. } => None,
             UploadState::Finished(Some((_, _, ref track_key))) => Some(track_key.clone()),
-            UploadState::Finished(None) => None
+            UploadState::Finished(None) | UploadState::Failed(_) => None
         }
     }
     pub fn desc(&self) -> String {
         match self {
             UploadState::YoutubeDownload { ref state, .. } => state.borrow().file.clone(),
-            UploadState::ConvertingFFMPEG { ref state, .. } => state.borrow().desc.clone(),
+            UploadState::Decoding { ref state, .. } => state.borrow().desc.clone(),
             UploadState::ConvertingOpus { ref state, .. } => state.borrow().desc.clone(),
             UploadState::Finished(Some((_, ref desc, _))) => desc.clone(),
             _ => "".into()
         }
     }
 
+    /// Reason of the failure, if the upload failed
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            UploadState::Failed(ref reason) => Some(reason),
+            _ => None
+        }
+    }
+
 }
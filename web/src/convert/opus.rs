@@ -8,14 +8,16 @@ use tokio_core::reactor::Handle;
 
 use crate::error::{Result, Error};
 
-use hex_music_container::{Container, Configuration};
+use hex_music_container::{Container, Configuration, Metadata};
 
 use hex_database::Track;
 
 pub struct State {
     pub progress: f32,
     pub desc: String,
-    pub data: Option<Track>
+    pub data: Option<Track>,
+    /// Reason of the failure, if the conversion failed
+    pub error: Option<String>
 }
 
 impl State {
@@ -23,27 +25,35 @@ impl State {
         State {
             progress: 0.0,
             desc: desc,
-            data: None
+            data: None,
+            error: None
         }
     }
 }
 
-fn worker(mut sender: Sender<State>, desc: String, samples: Vec<i16>, duration: f32, num_channel: u32, data_path: PathBuf) -> Result<Track> {
+fn worker(mut sender: Sender<State>, desc: String, samples: Vec<i16>, duration: f32, conf: Configuration, data_path: PathBuf) -> Result<Track> {
     //loop {
         // calculate the acousticid of the file
     
-    let fingerprint = hex_database::utils::get_fingerprint(num_channel as u16, &samples)
+    let fingerprint = hex_database::utils::get_fingerprint(conf.num_channels() as u16, &samples)
         .map_err(|_| Error::AcousticID)?;
 
     let track = Track::empty(fingerprint, duration.into());
 
-    let file = File::create(data_path.join(track.key.to_path())).unwrap();
+    let file = File::create(data_path.join(track.key.to_path()))
+        .map_err(|err| Error::Io(err))?;
 
-    sender.try_send(State { progress: 0.0, desc: desc, data: None })
+    sender.try_send(State { progress: 0.0, desc: desc, data: None, error: None })
         .map_err(|_| Error::ChannelFailed)?;
 
+    // store the fingerprint, the remaining metadata is added with the track
+    let metadata = Metadata {
+        fingerprint: Some(track.fingerprint.clone()),
+        ..Metadata::default()
+    };
+
     // TODO realtime
-    Container::save_pcm(Configuration::Stereo, samples, file, None)
+    Container::save_pcm_with_metadata(conf, samples, file, None, &metadata)
         .map_err(|err| Error::MusicContainer(err))?;
 
     Ok(track)
@@ -55,15 +65,19 @@ pub struct Converter {
 }
 
 impl Converter {
-    pub fn new(handle: Handle, desc: String, samples: Vec<i16>, duration: f32, num_channel: u32, data_path: PathBuf) -> Converter {
+    pub fn new(handle: Handle, desc: String, samples: Vec<i16>, duration: f32, conf: Configuration, data_path: PathBuf) -> Converter {
         let (sender, recv) = channel(10);
 
         thread::spawn(move || {
             let mut sender2 = sender.clone();
-            let res = worker(sender, desc.clone(), samples, duration, num_channel, data_path).unwrap();
-
-            sender2.try_send(State { progress: 1.0, desc: desc, data: Some(res) })
-                .map_err(|_| Error::ChannelFailed).unwrap();
+            let state = match worker(sender, desc.clone(), samples, duration, conf, data_path) {
+                Ok(res) => State { progress: 1.0, desc: desc, data: Some(res), error: None },
+                Err(err) => State { progress: 0.0, desc: desc, data: None, error: Some(format!("{:?}", err)) }
+            };
+
+            if sender2.try_send(state).is_err() {
+                eprintln!("Could not report the end of the Opus conversion");
+            }
         });
 
         Converter {
@@ -72,12 +86,9 @@ impl Converter {
         }
     }
 
-    pub fn state(&mut self) -> impl Stream<Item=State, Error=()> {
-        if let Some(recv) = self.recv.take() {
-            return recv;
-        } else {
-            panic!("Call just once");
-        }
+    /// Take the stream of state changes, which exists only once
+    pub fn state(&mut self) -> Result<impl Stream<Item=State, Error=()>> {
+        self.recv.take().ok_or(Error::ChannelFailed)
     }
 
     pub fn spawn<T>(&self, hnd: T)
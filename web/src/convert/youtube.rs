@@ -1,5 +1,4 @@
-use std::io::{self, Read};
-use std::fs::{self, File};
+use std::io;
 use std::process::Command;
 use std::process::Stdio;
 use std::result;
@@ -75,25 +74,7 @@ impl State {
             progress: 0.0
         }
     }
-
-    pub fn format(&self) -> &str {
-        self.file.rsplit(".").next().unwrap()
-    }
-
-    pub fn get_content(&self) -> Result<Vec<u8>> {
-        let mut file = File::open(&self.file)
-            .map_err(|_| Error::ConvertYoutube)?;
-
-        let mut tmp = Vec::new();
-        file.read_to_end(&mut tmp).unwrap();
-
-        fs::remove_file(&self.file)
-            .map_err(|_| Error::ConvertYoutube)?;
-        //debug!("Read {} bytes from youtube", nread);
-
-        Ok(tmp)
-    }
-}   
+}
 
 pub struct Downloader {
     pub handle: Handle,
@@ -125,11 +106,12 @@ impl Downloader {
         }
     }
 
-    pub fn state(&mut self) -> impl Stream<Item=State, Error=StateError> {
+    /// Take the stream of state changes, which exists only once
+    pub fn state(&mut self) -> Result<impl Stream<Item=State, Error=StateError>> {
         if let (Some(out), Some(err)) = (self.stdout.take(), self.stderr.take()) {
             let mut state = State::empty();
 
-            out.chain(err).map(move |msg| {
+            Ok(out.chain(err).map(move |msg| {
                 println!("Msg: {}", msg);
 
                 if msg.contains("Destination: ") {
@@ -152,10 +134,9 @@ impl Downloader {
                 state.clone()
             }).map_err(|_| {
                 StateError::IO("".into())
-            })
-
+            }))
         } else {
-            panic!("You can't call state twice!");
+            Err(Error::ChannelFailed)
         }
     }
 
@@ -164,7 +145,8 @@ impl Downloader {
         self.handle.spawn(hnd.for_each(|_| Ok(())).into_future().map(|_| ()).map_err(|_| ()));
     }
 
-    pub fn child(&mut self) -> Child {
-        self.child.take().unwrap()
+    /// Take the process of `youtube-dl`, which exists only once
+    pub fn child(&mut self) -> Result<Child> {
+        self.child.take().ok_or(Error::ChannelFailed)
     }
 }
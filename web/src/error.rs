@@ -3,7 +3,7 @@
 use std::{io, result};
 use hex_database;
 use hex_music_container;
-use hex_server_protocol;
+use hex_server_protocol::{self, AnswerError};
 
 /// Our custom `Result` using the `Error` struct
 pub type Result<T> = result::Result<T, Error>;
@@ -25,10 +25,54 @@ pub enum Error {
     AcousticIDResponse(String),
     /// Wrong metadata section in the answer
     AcousticIDMetadata,
+    /// Failed lookup in a metadata backend
+    Metadata(String),
     /// Could not convert with FFMPEG
     ConvertFFMPEG,
     /// Could not download with youtube-dl
     ConvertYoutube,
     /// Channel failed
-    ChannelFailed
+    ChannelFailed,
+    /// The request needs a login
+    NotAuthenticated,
+    /// The role of the user doesn't allow the request
+    PermissionDenied,
+    /// Invalid TLS configuration
+    Tls(String),
+    /// Invalid chunk or session of an upload
+    Upload(String),
+    /// Invalid format or tracks of an export
+    Export(String),
+    /// Invalid or unsupported image
+    Image(String),
+    /// The request doesn't fit the pending requests of the connection
+    InvalidRequest(String),
+    /// The requested object doesn't exist
+    NotFound
+}
+
+/// Convert to the error sent to the client, internal details are only kept as description
+impl From<Error> for AnswerError {
+    fn from(err: Error) -> AnswerError {
+        use hex_music_container::error::Error as ContainerError;
+
+        match err {
+            Error::Database(hex_database::Error::NotFound) => AnswerError::NotFound,
+            Error::Database(hex_database::Error::AlreadyExists) => AnswerError::AlreadyExists,
+            Error::Database(hex_database::Error::WrongCredentials) => AnswerError::WrongCredentials,
+            Error::Database(err) => AnswerError::Database(format!("{:?}", err)),
+            Error::MusicContainer(ContainerError::ReachedEnd) => AnswerError::StreamEnded,
+            Error::MusicContainer(err) => AnswerError::Audio(format!("{:?}", err)),
+            Error::NotAuthenticated => AnswerError::NotAuthenticated,
+            Error::PermissionDenied => AnswerError::PermissionDenied,
+            Error::Upload(msg) => AnswerError::Upload(msg),
+            Error::Export(msg) => AnswerError::Export(msg),
+            Error::Image(msg) => AnswerError::Image(msg),
+            Error::InvalidRequest(msg) => AnswerError::InvalidRequest(msg),
+            Error::NotFound => AnswerError::NotFound,
+            Error::Metadata(msg) | Error::AcousticIDResponse(msg) => AnswerError::Metadata(msg),
+            Error::AcousticIDMetadata => AnswerError::Metadata("Invalid answer of AcoustID".into()),
+            err => AnswerError::Internal(format!("{:?}", err))
+        }
+    }
 }
@@ -0,0 +1,475 @@
+//! Export tracks as ZIP archive
+//!
+//! An export job only checks the format and tracks and then links to `/exports/<job>.zip`, which
+//! the user who created the job can download for a day. The archive is never stored: each track
+//! is transcoded with its tags and cover while the archive is streamed to the client, so that
+//! nothing is written to the data section. Files are named `NN - Title.ext` after their position
+//! in the export.
+
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc32fast::Hasher;
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::{self, channel};
+use hyper::{Body, Chunk};
+
+use hex_database::Track;
+
+use crate::error::{Result, Error};
+use crate::tags;
+use crate::transcode::{self, Format};
+
+/// Seconds after which the link of an export expires
+pub const EXPIRE: i64 = 24 * 60 * 60;
+
+/// Maximal length of a title in a file name
+const MAX_TITLE: usize = 100;
+
+/// Size of the chunks sent to the client
+const CHUNK_SIZE: usize = 65536;
+
+/// Seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Check whether an export created at the given time in seconds since the UNIX epoch has expired
+pub fn is_expired(created: i64) -> bool {
+    now() - created > EXPIRE
+}
+
+/// Replace characters which are not allowed in file names of common file systems
+fn sanitize(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|x| if x.is_control() || "/\\:*?\"<>|".contains(x) { '_' } else { x })
+        .take(MAX_TITLE)
+        .collect();
+
+    // leading dots hide files, trailing dots and spaces are stripped by Windows
+    name.trim_matches(|x| x == '.' || x == ' ').to_string()
+}
+
+/// Name of a track in the archive, e.g. `03 - Title.mp3`
+///
+/// The number is padded to the width of the largest number.
+pub fn file_name(track: &Track, number: usize, count: usize, format: Format) -> String {
+    let width = count.to_string().len().max(2);
+
+    let title = match track.title.as_ref().map(|x| sanitize(x)) {
+        Some(ref title) if !title.is_empty() => title.clone(),
+        _ => track.key.to_string()
+    };
+
+    format!("{:0width$} - {}.{}", number, title, format.extension(), width = width)
+}
+
+/// Time and date of a moment in seconds since the UNIX epoch in the MS-DOS format of ZIP files
+fn dos_time(secs: i64) -> (u16, u16) {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // civil date of a day, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    // MS-DOS dates start in 1980
+    let year = (year - 1980).max(0).min(127);
+
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60) / 2;
+    let date = year << 9 | month << 5 | day;
+
+    (time as u16, date as u16)
+}
+
+/// An entry of the central directory
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    /// Offset of the local header
+    offset: u64
+}
+
+/// Writer of ZIP archives, which never seeks
+///
+/// Entries are stored without compression, audio files are already compressed. The size and
+/// checksum of an entry are unknown when its local header is written, so they follow the data in
+/// a data descriptor. ZIP64 records are added once the archive grows beyond 4 GiB, a single entry
+/// has to be smaller.
+pub struct ZipStream<W: Write> {
+    out: W,
+    /// Number of bytes written to `out`
+    offset: u64,
+    /// Modification time and date of all entries
+    time: (u16, u16),
+    entries: Vec<Entry>,
+    /// Checksum of the current entry
+    hasher: Option<Hasher>
+}
+
+impl<W: Write> ZipStream<W> {
+    pub fn new(out: W) -> ZipStream<W> {
+        ZipStream {
+            out,
+            offset: 0,
+            time: dos_time(now()),
+            entries: Vec::new(),
+            hasher: None
+        }
+    }
+
+    /// Write headers and descriptors, which are not part of an entry
+    fn raw(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.write_all(buf)?;
+        self.offset += buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Start a new entry, everything written afterwards is its content
+    pub fn start_file(&mut self, name: &str) -> io::Result<()> {
+        self.finish_file()?;
+
+        self.entries.push(Entry { name: name.into(), crc: 0, size: 0, offset: self.offset });
+
+        // the sizes and checksum are zero and follow in the data descriptor
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        buf.extend_from_slice(&20u16.to_le_bytes());
+        // data descriptor and UTF-8 names
+        buf.extend_from_slice(&0x0808u16.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&self.time.0.to_le_bytes());
+        buf.extend_from_slice(&self.time.1.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+
+        self.raw(&buf)?;
+        self.hasher = Some(Hasher::new());
+
+        Ok(())
+    }
+
+    /// Write the data descriptor of the current entry
+    fn finish_file(&mut self) -> io::Result<()> {
+        let hasher = match self.hasher.take() {
+            Some(hasher) => hasher,
+            None => return Ok(())
+        };
+
+        let (crc, size) = match self.entries.last_mut() {
+            Some(entry) => {
+                entry.crc = hasher.finalize();
+
+                (entry.crc, entry.size)
+            },
+            None => return Ok(())
+        };
+
+        if size >= 0xffffffff {
+            return Err(io::Error::new(io::ErrorKind::Other, "entry is larger than 4 GiB"));
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf.extend_from_slice(&(size as u32).to_le_bytes());
+        buf.extend_from_slice(&(size as u32).to_le_bytes());
+
+        self.raw(&buf)
+    }
+
+    /// Write the central directory and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_file()?;
+
+        let start = self.offset;
+        let mut buf = Vec::new();
+        for entry in &self.entries {
+            // the offset is moved to a ZIP64 field, if it doesn't fit
+            let large = entry.offset >= 0xffffffff;
+
+            buf.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            // made by UNIX, version 4.5
+            buf.extend_from_slice(&(3u16 << 8 | 45).to_le_bytes());
+            buf.extend_from_slice(&(if large { 45u16 } else { 20 }).to_le_bytes());
+            buf.extend_from_slice(&0x0808u16.to_le_bytes());
+            buf.extend_from_slice(&0u16.to_le_bytes());
+            buf.extend_from_slice(&self.time.0.to_le_bytes());
+            buf.extend_from_slice(&self.time.1.to_le_bytes());
+            buf.extend_from_slice(&entry.crc.to_le_bytes());
+            buf.extend_from_slice(&(entry.size as u32).to_le_bytes());
+            buf.extend_from_slice(&(entry.size as u32).to_le_bytes());
+            buf.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&(if large { 12u16 } else { 0 }).to_le_bytes());
+            // comment, disk and internal attributes
+            buf.extend_from_slice(&[0; 6]);
+            buf.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            buf.extend_from_slice(&(entry.offset.min(0xffffffff) as u32).to_le_bytes());
+            buf.extend_from_slice(entry.name.as_bytes());
+
+            if large {
+                buf.extend_from_slice(&1u16.to_le_bytes());
+                buf.extend_from_slice(&8u16.to_le_bytes());
+                buf.extend_from_slice(&entry.offset.to_le_bytes());
+            }
+        }
+
+        let (num, size) = (self.entries.len() as u64, buf.len() as u64);
+
+        if num >= 0xffff || size >= 0xffffffff || start >= 0xffffffff {
+            let end = start + size;
+
+            // ZIP64 end of central directory record and its locator
+            buf.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            buf.extend_from_slice(&44u64.to_le_bytes());
+            buf.extend_from_slice(&(3u16 << 8 | 45).to_le_bytes());
+            buf.extend_from_slice(&45u16.to_le_bytes());
+            buf.extend_from_slice(&[0; 8]);
+            buf.extend_from_slice(&num.to_le_bytes());
+            buf.extend_from_slice(&num.to_le_bytes());
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(&start.to_le_bytes());
+
+            buf.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&end.to_le_bytes());
+            buf.extend_from_slice(&1u32.to_le_bytes());
+        }
+
+        buf.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&(num.min(0xffff) as u16).to_le_bytes());
+        buf.extend_from_slice(&(num.min(0xffff) as u16).to_le_bytes());
+        buf.extend_from_slice(&(size.min(0xffffffff) as u32).to_le_bytes());
+        buf.extend_from_slice(&(start.min(0xffffffff) as u32).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+
+        self.raw(&buf)?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ZipStream<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num = self.out.write(buf)?;
+        self.offset += num as u64;
+
+        if let (Some(hasher), Some(entry)) = (self.hasher.as_mut(), self.entries.last_mut()) {
+            hasher.update(&buf[..num]);
+            entry.size += num as u64;
+        }
+
+        Ok(num)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Write the transcoded and tagged track to the current entry of an archive
+fn write_track<W: Write>(zip: &mut ZipStream<W>, data_path: &Path, track: &Track, number: usize, format: Format) -> Result<()> {
+    if format == Format::Ogg {
+        return transcode::write_ogg(data_path, track, zip);
+    }
+
+    let mut container = transcode::open_container(data_path, track)?;
+    let cover = transcode::cover(&mut container);
+    let cover = cover.as_ref().map(Vec::as_slice);
+
+    let mut args: Vec<String> = Vec::new();
+    match format {
+        Format::Mp3 => {
+            // the ID3 tag is written in front of the audio, the Xing header can't be updated
+            // in a stream
+            tags::write_id3(&mut *zip, track, number, cover)
+                .map_err(|err| Error::Io(err))?;
+
+            args.extend(["-id3v2_version", "0", "-write_xing", "0", "-q:a", "2"].iter().map(|x| x.to_string()));
+        },
+        _ => {
+            for (name, value) in tags::text_tags(track, number) {
+                args.push("-metadata".into());
+                args.push(format!("{}={}", name, value));
+            }
+        }
+    }
+
+    let mut child = transcode::spawn_ffmpeg(container, format, &args)?;
+    let mut stdout = child.stdout.take().unwrap();
+
+    let res = match format {
+        Format::Flac => tags::copy_flac(&mut stdout, zip, cover),
+        _ => io::copy(&mut stdout, zip)
+    };
+
+    // ffmpeg would block on the pipe, if the client stopped the download
+    drop(stdout);
+    if res.is_err() {
+        let _ = child.kill();
+    }
+
+    let status = child.wait();
+    res.map_err(|err| Error::Io(err))?;
+
+    match status {
+        Ok(ref status) if status.success() => Ok(()),
+        _ => Err(Error::ConvertFFMPEG)
+    }
+}
+
+/// Write all tracks to an archive
+fn write_archive<W: Write>(out: W, data_path: &Path, tracks: &[Track], format: Format) -> Result<()> {
+    let mut zip = ZipStream::new(out);
+
+    for (i, track) in tracks.iter().enumerate() {
+        zip.start_file(&file_name(track, i + 1, tracks.len(), format))
+            .map_err(|err| Error::Io(err))?;
+
+        write_track(&mut zip, data_path, track, i + 1, format)?;
+    }
+
+    zip.finish()
+        .map(|_| ())
+        .map_err(|err| Error::Io(err))
+}
+
+/// Send everything written to the body of a response, waiting for the client to consume it
+struct BodyWriter(Option<mpsc::Sender<io::Result<Vec<u8>>>>);
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = self.0.take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "download was stopped"))?;
+
+        match sender.send(Ok(buf.to_vec())).wait() {
+            Ok(sender) => {
+                self.0 = Some(sender);
+
+                Ok(buf.len())
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "download was stopped"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stream an archive with the tracks, which are transcoded in a thread
+///
+/// The transcoding stops when the client stops the download. A failed track aborts the response,
+/// so that the client doesn't take a truncated archive for a complete one.
+pub fn stream(data_path: PathBuf, tracks: Vec<Track>, format: Format) -> Body {
+    let (sender, recv) = channel(16);
+
+    thread::spawn(move || {
+        let mut out = BufWriter::with_capacity(CHUNK_SIZE, BodyWriter(Some(sender)));
+
+        if let Err(err) = write_archive(&mut out, &data_path, &tracks, format) {
+            eprintln!("Could not export tracks: {:?}", err);
+
+            if let Some(sender) = out.get_mut().0.take() {
+                let _ = sender.send(Err(io::Error::new(io::ErrorKind::Other, "export failed"))).wait();
+            }
+        }
+    });
+
+    Body::wrap_stream(recv.then(|res| match res {
+        Ok(Ok(buf)) => Ok(Chunk::from(buf)),
+        Ok(Err(err)) => Err(err),
+        Err(()) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "export stopped"))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::path::Path;
+    use zip::ZipArchive;
+    use hex_database::{Track, TrackKey};
+    use crate::transcode::Format;
+    use super::{file_name, dos_time, write_archive, ZipStream};
+
+    fn track() -> Track {
+        Track {
+            key: TrackKey::from_vec(&[0xab; 16]),
+            fingerprint: Vec::new(),
+            title: Some("../AC/DC: Back in Black?".into()),
+            album: None,
+            interpret: None,
+            people: None,
+            composer: None,
+            duration: 1.0,
+            favs_count: 0
+        }
+    }
+
+    #[test]
+    fn safe_names() {
+        let mut track = track();
+
+        assert_eq!(file_name(&track, 3, 12, Format::Mp3), "03 - _AC_DC_ Back in Black_.mp3");
+        assert_eq!(file_name(&track, 7, 100, Format::Flac), "007 - _AC_DC_ Back in Black_.flac");
+
+        track.title = Some(" ..".into());
+        assert_eq!(file_name(&track, 1, 1, Format::Ogg), format!("01 - {}.ogg", "ab".repeat(16)));
+    }
+
+    #[test]
+    fn streamed_archive() {
+        let mut zip = ZipStream::new(Vec::new());
+        zip.start_file("01 - First.mp3").unwrap();
+        zip.write_all(b"first").unwrap();
+        zip.start_file("02 - Zweite Straße.mp3").unwrap();
+        zip.write_all(&[7; 100000]).unwrap();
+
+        // the archive is read with the central directory and data descriptors
+        let mut archive = ZipArchive::new(Cursor::new(zip.finish().unwrap())).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut content = Vec::new();
+        archive.by_name("01 - First.mp3").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"first");
+
+        content.clear();
+        archive.by_index(1).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, vec![7; 100000]);
+        assert_eq!(archive.by_index(1).unwrap().name(), "02 - Zweite Straße.mp3");
+    }
+
+    #[test]
+    fn empty_and_failed() {
+        let mut out = Vec::new();
+        write_archive(&mut out, Path::new("/nonexistent"), &[], Format::Mp3).unwrap();
+        assert_eq!(ZipArchive::new(Cursor::new(out)).unwrap().len(), 0);
+
+        // tracks without audio fail the whole archive
+        assert!(write_archive(Vec::new(), Path::new("/nonexistent"), &[track()], Format::Mp3).is_err());
+    }
+
+    #[test]
+    fn dos_dates() {
+        // 2020-02-29 12:34:56 UTC
+        assert_eq!(dos_time(1582979696), (12 << 11 | 34 << 5 | 28, 40 << 9 | 2 << 5 | 29));
+        // earlier dates are clamped to 1980
+        assert_eq!(dos_time(0), (0, 1 << 5 | 1));
+    }
+}
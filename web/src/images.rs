@@ -0,0 +1,199 @@
+//! Store cover images of playlists
+//!
+//! Uploaded images are validated, scaled down to a cover of at most 1024 pixels and stored as JPEG
+//! in the data section, named after the hash of the stored content. As the name is the same on
+//! every peer, images are replicated with the same packets as audio files. Thumbnails are created
+//! next to them in the `thumbnails` folder, those of replicated images on first use.
+
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageOutputFormat, GenericImageView};
+use image::imageops::FilterType;
+use image::io::Reader as ImageReader;
+use sha2::{Digest, Sha256};
+
+use hex_database::{ImageKey, TrackKey};
+
+use crate::error::{Error, Result};
+
+/// Maximal size of an uploaded image in bytes
+const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Maximal width and height of an uploaded image, larger ones are rejected before decoding
+const MAX_DIMENSION: u32 = 8192;
+
+/// Maximal width and height of a stored cover
+const COVER_SIZE: u32 = 1024;
+
+/// Maximal width and height of a thumbnail
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Quality of the encoded JPEG images
+const QUALITY: u8 = 90;
+
+/// Key of an image, the first 16 bytes of the hash of its content
+pub fn image_key(data: &[u8]) -> ImageKey {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+
+    TrackKey::from_vec(&hasher.result()[0..16])
+}
+
+/// Path of a stored cover
+pub fn image_path(data_path: &Path, key: ImageKey) -> PathBuf {
+    data_path.join(key.to_path())
+}
+
+/// Path of the thumbnail of a cover
+pub fn thumbnail_path(data_path: &Path, key: ImageKey) -> PathBuf {
+    data_path.join("thumbnails").join(format!("{}.jpg", key))
+}
+
+/// Scale an image down to fit in a square, keeping its aspect ratio
+fn fit(image: DynamicImage, size: u32) -> DynamicImage {
+    let (width, height) = GenericImageView::dimensions(&image);
+
+    if width <= size && height <= size {
+        image
+    } else {
+        image.resize(size, size, FilterType::Lanczos3)
+    }
+}
+
+/// Encode an image as JPEG without alpha channel
+fn encode(image: DynamicImage) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+
+    DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut buf, ImageOutputFormat::Jpeg(QUALITY))
+        .map_err(|err| Error::Image(err.to_string()))?;
+
+    Ok(buf.into_inner())
+}
+
+/// Check an uploaded image and decode it
+fn decode(data: &[u8]) -> Result<DynamicImage> {
+    if data.len() > MAX_BYTES {
+        return Err(Error::Image(format!("Image is larger than {} MiB", MAX_BYTES / 1024 / 1024)));
+    }
+
+    let reader = || ImageReader::new(Cursor::new(data)).with_guessed_format()
+        .map_err(|err| Error::Image(err.to_string()));
+
+    if reader()?.format().is_none() {
+        return Err(Error::Image("Unknown image format".into()));
+    }
+
+    // the header is enough to know the size
+    let (width, height) = reader()?.into_dimensions()
+        .map_err(|err| Error::Image(err.to_string()))?;
+
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(Error::Image(format!("Invalid image size {}x{}", width, height)));
+    }
+
+    reader()?.decode()
+        .map_err(|err| Error::Image(err.to_string()))
+}
+
+/// Validate an uploaded image and store it as cover with a thumbnail
+pub fn store(data_path: &Path, data: &[u8]) -> Result<ImageKey> {
+    let image = decode(data)?;
+
+    let cover = encode(fit(image, COVER_SIZE))?;
+    let key = image_key(&cover);
+
+    let path = image_path(data_path, key);
+    if !path.exists() {
+        fs::write(&path, &cover)
+            .map_err(|err| Error::Io(err))?;
+    }
+
+    thumbnail(data_path, key)?;
+
+    Ok(key)
+}
+
+/// Get the thumbnail of a stored cover, creating it if necessary
+pub fn thumbnail(data_path: &Path, key: ImageKey) -> Result<PathBuf> {
+    let path = thumbnail_path(data_path, key);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let cover = fs::read(image_path(data_path, key))
+        .map_err(|err| Error::Io(err))?;
+
+    let image = image::load_from_memory(&cover)
+        .map_err(|err| Error::Image(err.to_string()))?;
+
+    fs::create_dir_all(data_path.join("thumbnails"))
+        .map_err(|err| Error::Io(err))?;
+
+    fs::write(&path, encode(fit(image, THUMBNAIL_SIZE))?)
+        .map_err(|err| Error::Io(err))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat, GenericImageView, RgbaImage};
+
+    use super::{store, thumbnail, image_key, image_path, thumbnail_path};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| image::Rgba([(x % 256) as u8, (y % 256) as u8, 128, 200]));
+
+        let mut buf = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image).write_to(&mut buf, ImageOutputFormat::Png).unwrap();
+
+        buf.into_inner()
+    }
+
+    #[test]
+    fn store_cover() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let key = store(dir.path(), &png(2048, 1024)).unwrap();
+        let cover = fs::read(image_path(dir.path(), key)).unwrap();
+        assert_eq!(image_key(&cover), key);
+        assert_eq!(GenericImageView::dimensions(&image::load_from_memory(&cover).unwrap()), (1024, 512));
+
+        let thumb = fs::read(thumbnail_path(dir.path(), key)).unwrap();
+        assert_eq!(GenericImageView::dimensions(&image::load_from_memory(&thumb).unwrap()), (256, 128));
+
+        // the same content results in the same key
+        assert_eq!(store(dir.path(), &png(2048, 1024)).unwrap(), key);
+
+        // small images are kept in their size
+        let key = store(dir.path(), &png(100, 50)).unwrap();
+        let cover = fs::read(image_path(dir.path(), key)).unwrap();
+        assert_eq!(GenericImageView::dimensions(&image::load_from_memory(&cover).unwrap()), (100, 50));
+    }
+
+    #[test]
+    fn replicated_thumbnail() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = store(dir.path(), &png(600, 600)).unwrap();
+
+        // a replicated cover comes without thumbnail
+        fs::remove_file(thumbnail_path(dir.path(), key)).unwrap();
+
+        let path = thumbnail(dir.path(), key).unwrap();
+        assert_eq!(GenericImageView::dimensions(&image::load_from_memory(&fs::read(path).unwrap()).unwrap()), (256, 256));
+    }
+
+    #[test]
+    fn invalid_images() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(store(dir.path(), b"no image at all").is_err());
+        assert!(store(dir.path(), &png(10, 10)[0..40]).is_err());
+        assert!(thumbnail(dir.path(), image_key(b"missing")).is_err());
+    }
+}
@@ -0,0 +1,651 @@
+//! Server-wide scheduler of background jobs
+//!
+//! Downloads from YouTube, conversions of uploaded files, imports of uploaded archives, exports
+//! of archives and lookups of missing metadata are queued as persistent jobs, independent of the connection which created them.
+//! The scheduler starts queued jobs as long as less than the configured number are running and
+//! advances them every second. Failed jobs are queued again a few times before they fail for good;
+//! jobs interrupted by a restart of the server start from the beginning.
+//!
+//! The content of uploaded files is kept in the `jobs` folder of the data section until the job
+//! has finished, so that a failed attempt can be repeated. Uploads in chunks are checked against
+//! their hash in a thread before the conversion starts. It is removed once the job is done,
+//! failed for good or was cancelled; such jobs can't be retried. Cancelling a running job kills its
+//! external programs. Finished jobs are removed from the database after a month. Exports only
+//! check their tracks, the archive is streamed on download, see the `export` module.
+//!
+//! Changed metadata is written to the audio files by a separate thread, because the whole file
+//! has to be rewritten.
+
+use std::{fs, io, thread};
+use std::fs::File;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+
+use tokio_core::reactor::Handle;
+
+use crate::error::{Result, Error};
+use crate::convert::{UploadState, ImportState};
+use crate::metadata::{Lookup, EnrichState, is_incomplete};
+use crate::playlists::Playlists;
+use crate::upload;
+use crate::transcode::Format;
+
+use hex_database::{Track, TrackKey, PlaylistKey, Reader, Writer, Accounts, Jobs, Job, JobId, JobKind, JobStatus, JobResult};
+use hex_database::utils::Tags;
+use hex_music_container::{Container, Metadata, tags};
+use hex_server_protocol::{PacketId, objects::{UploadProgress, DownloadProgress}};
+
+/// Number of attempts before a job fails for good
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Seconds after which finished, failed and cancelled jobs are removed
+const JOB_LIFETIME: u64 = 30 * 24 * 60 * 60;
+
+/// A running job
+enum Task {
+    /// The hash of an upload in chunks is checked, before the actual task starts
+    Verify(Receiver<std::result::Result<(), String>>),
+    Upload(UploadState),
+    /// The tracks of an export exist, the archive is created while downloading it
+    Export,
+    Import(ImportState),
+    Enrich(EnrichState)
+}
+
+impl Task {
+    /// Stop the external programs and threads of the task
+    fn cancel(&mut self) {
+        match self {
+            Task::Upload(upload) => upload.cancel(),
+            Task::Import(import) => import.cancel(),
+            Task::Verify(_) | Task::Export | Task::Enrich(_) => {}
+        }
+    }
+}
+
+/// Content of an uploaded file
+pub enum Input<'a> {
+    /// Content received in a single message
+    Data(&'a [u8]),
+    /// A complete upload in chunks, which is moved to the job and checked against its hash
+    Upload(&'a Path, &'a str)
+}
+
+/// End of a running job
+enum Outcome {
+    /// The upload matches its hash, the actual task can start
+    Verified,
+    Track(Track),
+    Archive(String),
+    /// All files of an archive are imported
+    Imported,
+    /// All tracks are looked up
+    Enriched,
+    Failed(String)
+}
+
+/// Packet id passed to the converters of a job
+fn packet_id(id: JobId) -> PacketId {
+    [id as u32, (id >> 32) as u32, 0, 0]
+}
+
+/// Name of a job status as shown in the progress of uploads
+fn status_name(status: JobStatus) -> &'static str {
+    match status {
+        JobStatus::Queued => "queued",
+        JobStatus::Running => "running",
+        JobStatus::Failed => "failed",
+        JobStatus::Done => "finished",
+        JobStatus::Cancelled => "cancelled"
+    }
+}
+
+/// Scheduler owning all running jobs
+pub struct Scheduler {
+    handle: Handle,
+    jobs: Jobs,
+    /// Owners of the playlists created by imports
+    accounts: Accounts,
+    read: Reader,
+    write: Writer,
+    /// Keys of the playlists created by imports
+    playlists: Playlists,
+    /// Path to the data section
+    data_path: PathBuf,
+    /// Maximal number of running jobs
+    limit: usize,
+    running: HashMap<JobId, Task>,
+    /// Metadata lookup, shared with the connections
+    lookup: Arc<Lookup>,
+    /// Minimal score of a suggestion applied by an enrichment
+    min_score: f32,
+    /// Audio files waiting for their metadata
+    metadata: Sender<(PathBuf, Metadata)>
+}
+
+/// Write the metadata of audio files until the scheduler is dropped
+///
+/// The cover image is not part of the database, therefore the one of the file is kept.
+fn write_metadata(queue: Receiver<(PathBuf, Metadata)>) {
+    for (path, mut metadata) in queue {
+        if metadata.cover.is_none() {
+            metadata.cover = File::open(&path).ok()
+                .and_then(|x| Container::load(x).ok())
+                .and_then(|mut x| x.metadata().ok())
+                .and_then(|x| x.and_then(|x| x.cover));
+        }
+
+        if let Err(err) = hex_music_container::write_metadata(&path, &metadata) {
+            eprintln!("Could not store metadata in {:?}: {:?}", path, err);
+        }
+    }
+}
+
+impl Scheduler {
+    /// Create a scheduler and queue all jobs again, which were interrupted by a restart
+    pub fn new(handle: Handle, path: &Path, read: Reader, write: Writer, playlists: Playlists, limit: usize, metadata: &hex_conf::Metadata) -> Result<Scheduler> {
+        let jobs = Jobs::from_file(path.join("music.db"))
+            .map_err(|err| Error::Database(err))?;
+
+        let accounts = Accounts::from_file(path.join("music.db"))
+            .map_err(|err| Error::Database(err))?;
+
+        let lookup = Lookup::new(metadata, &path.join("music.db"))?;
+
+        let interrupted = jobs.requeue()
+            .map_err(|err| Error::Database(err))?;
+
+        if interrupted > 0 {
+            info!("Queued {} interrupted jobs again", interrupted);
+        }
+
+        match jobs.prune(JOB_LIFETIME) {
+            Ok(0) => {},
+            Ok(num) => info!("Removed {} old jobs", num),
+            Err(err) => eprintln!("Could not remove old jobs: {:?}", err)
+        }
+
+        let data_path = path.join("data");
+        fs::create_dir_all(data_path.join("jobs"))
+            .map_err(|err| Error::Io(err))?;
+
+        let (sender, queue) = channel();
+        thread::spawn(move || write_metadata(queue));
+
+        Ok(Scheduler {
+            handle, jobs, accounts, read, write, playlists, data_path,
+            limit: limit.max(1),
+            running: HashMap::new(),
+            lookup: Arc::new(lookup),
+            min_score: metadata.min_score,
+            metadata: sender
+        })
+    }
+
+    /// Store the metadata of a track in its audio file, so that it can be recovered without the
+    /// database
+    pub fn store_metadata(&self, key: TrackKey) {
+        let track = match self.read.get_track(key) {
+            Ok(track) => track,
+            Err(_) => return
+        };
+
+        let metadata = Metadata {
+            title: track.title,
+            album: track.album,
+            interpret: track.interpret,
+            people: track.people,
+            composer: track.composer,
+            fingerprint: Some(track.fingerprint),
+            cover: None,
+            peer: Some(self.write.peer_id())
+        };
+
+        let _ = self.metadata.send((self.data_path.join(key.to_path()), metadata));
+    }
+
+    /// Metadata lookup with the cache of this peer
+    pub fn lookup(&self) -> Arc<Lookup> {
+        self.lookup.clone()
+    }
+
+    /// Path of the stored content of an upload
+    fn input_path(&self, id: JobId) -> PathBuf {
+        self.data_path.join("jobs").join(id.to_string())
+    }
+
+    /// Path of the expected hash of an upload in chunks, until it is checked
+    fn hash_path(&self, id: JobId) -> PathBuf {
+        self.input_path(id).with_extension("sha256")
+    }
+
+    /// Remove the stored content of an upload and the unpacked files of an import
+    fn remove_input(&self, id: JobId) {
+        let path = self.input_path(id);
+
+        let _ = fs::remove_dir_all(path.with_extension("d"));
+        let _ = fs::remove_file(self.hash_path(id));
+        let _ = fs::remove_file(path);
+    }
+
+    /// Queue a new job, `input` is the content of an uploaded file
+    pub fn add(&mut self, kind: JobKind, input: Option<Input>, owner: Option<&str>) -> Result<Job> {
+        let job = self.jobs.add(kind, owner)
+            .map_err(|err| Error::Database(err))?;
+
+        let res = match input {
+            Some(Input::Data(data)) => fs::write(self.input_path(job.id), data),
+            Some(Input::Upload(path, hash)) => fs::rename(path, self.input_path(job.id))
+                .and_then(|_| fs::write(self.hash_path(job.id), hash)),
+            None => Ok(())
+        };
+
+        if let Err(err) = res {
+            let _ = self.jobs.cancel(job.id);
+
+            return Err(Error::Io(err));
+        }
+
+        self.schedule();
+
+        Ok(job)
+    }
+
+    /// Get a single job with the progress of the running step
+    pub fn job(&self, id: JobId) -> Result<Job> {
+        self.jobs.job(id)
+            .map(|job| self.with_progress(job))
+            .map_err(|err| Error::Database(err))
+    }
+
+    /// Get all jobs, the latest first
+    pub fn jobs(&self) -> Vec<Job> {
+        self.jobs.jobs().into_iter()
+            .map(|job| self.with_progress(job))
+            .collect()
+    }
+
+    /// Cancel a job, killing the external programs of a running one
+    pub fn cancel(&mut self, id: JobId) -> Result<()> {
+        self.jobs.cancel(id)
+            .map_err(|err| Error::Database(err))?;
+
+        if let Some(mut task) = self.running.remove(&id) {
+            task.cancel();
+        }
+
+        self.remove_input(id);
+        self.schedule();
+
+        Ok(())
+    }
+
+    /// Queue a failed or cancelled job again
+    ///
+    /// Uploads and imports can't be retried, because their content was removed.
+    pub fn retry(&mut self, id: JobId) -> Result<Job> {
+        let job = self.jobs.job(id)
+            .map_err(|err| Error::Database(err))?;
+
+        match job.kind {
+            JobKind::Upload { .. } | JobKind::Import { .. } if !self.input_path(id).exists() => {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "Content of the upload is missing")));
+            },
+            _ => {}
+        }
+
+        self.jobs.retry(id)
+            .map_err(|err| Error::Database(err))?;
+
+        self.schedule();
+        self.job(id)
+    }
+
+    /// Add the progress of a running job
+    fn with_progress(&self, mut job: Job) -> Job {
+        match self.running.get(&job.id) {
+            Some(Task::Verify(_)) => {
+                job.stage = Some("verifying".into());
+            },
+            Some(Task::Upload(upload)) => {
+                job.stage = Some(upload.kind().into());
+                job.progress = upload.progress();
+            },
+            Some(Task::Import(import)) => {
+                job.stage = Some(import.kind().into());
+                job.progress = import.progress();
+            },
+            Some(Task::Enrich(enrich)) => {
+                job.stage = Some("enriching".into());
+                job.progress = enrich.progress();
+            },
+            Some(Task::Export) | None => {}
+        }
+
+        job
+    }
+
+    /// Progress of an upload job in the format of `AskUploadProgress`
+    pub fn upload_progress(&self, id: JobId, packet: PacketId) -> Option<UploadProgress> {
+        let job = self.job(id).ok()?;
+
+        let desc = match (&job.kind, self.running.get(&id)) {
+            (_, Some(Task::Upload(upload))) if !upload.desc().is_empty() => upload.desc(),
+            (_, Some(Task::Import(import))) if !import.desc().is_empty() => import.desc(),
+            (JobKind::Youtube { url }, _) => url.clone(),
+            (JobKind::Upload { name, .. }, _) | (JobKind::Import { name, .. }, _) => name.clone(),
+            (JobKind::Export { .. }, _) | (JobKind::Enrich, _) => return None
+        };
+
+        let key = match job.result {
+            Some(JobResult::Track(key)) => Some(key),
+            _ => None
+        };
+
+        let status = job.status;
+
+        Some(UploadProgress {
+            desc: desc,
+            kind: job.stage.unwrap_or_else(|| status_name(status).into()),
+            progress: job.progress,
+            id: packet,
+            key: key
+        })
+    }
+
+    /// Progress of an export job in the format of `AskDownloadProgress`
+    pub fn export_progress(&self, id: JobId, packet: PacketId) -> Option<DownloadProgress> {
+        let job = self.job(id).ok()?;
+
+        let format = match job.kind {
+            JobKind::Export { format, .. } => format,
+            _ => return None
+        };
+
+        let download = match job.result {
+            Some(JobResult::Archive(path)) => Some(path),
+            _ => None
+        };
+
+        Some(DownloadProgress {
+            id: packet,
+            format: format,
+            progress: job.progress,
+            download: download
+        })
+    }
+
+    /// Start the tasks of a job
+    fn run(&self, job: &Job) -> Result<Task> {
+        let id = packet_id(job.id);
+
+        // hash the whole upload in a thread, a mismatching upload can't succeed in a later attempt
+        if let Ok(hash) = fs::read_to_string(self.hash_path(job.id)) {
+            let path = self.input_path(job.id);
+            let (sender, recv) = channel();
+
+            thread::spawn(move || {
+                let res = upload::verify(&path, &hash);
+                if let Err(Error::Upload(_)) = res {
+                    let _ = fs::remove_file(&path);
+                }
+
+                let _ = sender.send(res.map_err(|err| format!("{:?}", err)));
+            });
+
+            return Ok(Task::Verify(recv));
+        }
+
+        match job.kind {
+            JobKind::Youtube { ref url } => {
+                Ok(Task::Upload(UploadState::youtube(id, url, self.handle.clone())))
+            },
+            JobKind::Upload { ref name, .. } => {
+                let path = self.input_path(job.id);
+
+                if !path.exists() {
+                    return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "Content of the upload is missing")));
+                }
+
+                Ok(Task::Upload(UploadState::converting_file(self.handle.clone(), name.clone(), id, &path)))
+            },
+            JobKind::Export { ref format, ref tracks } => {
+                Format::from_extension(format)
+                    .ok_or_else(|| Error::Export(format!("Unknown format {}", format)))?;
+
+                for key in tracks {
+                    self.read.get_track(*key).map_err(|err| Error::Database(err))?;
+                }
+
+                Ok(Task::Export)
+            },
+            JobKind::Import { ref format, .. } => {
+                let path = self.input_path(job.id);
+
+                if !path.exists() {
+                    return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "Content of the upload is missing")));
+                }
+
+                let dir = path.with_extension("d");
+
+                Ok(Task::Import(ImportState::new(self.handle.clone(), id, &path, format, dir)))
+            },
+            JobKind::Enrich => {
+                let tracks = self.read.get_tracks().into_iter()
+                    .filter(is_incomplete)
+                    .collect();
+
+                Ok(Task::Enrich(EnrichState::new(self.lookup.clone(), tracks, self.min_score)))
+            }
+        }
+    }
+
+    /// Start queued jobs while there are free slots
+    fn schedule(&mut self) {
+        for job in self.jobs.queued() {
+            if self.running.len() >= self.limit {
+                break;
+            }
+
+            let job = match self.jobs.start(job.id) {
+                Ok(job) => job,
+                Err(err) => {
+                    eprintln!("Could not start job {}: {:?}", job.id, err);
+                    continue;
+                }
+            };
+
+            match self.run(&job) {
+                Ok(task) => { self.running.insert(job.id, task); },
+                Err(err) => self.failed(job.id, format!("{:?}", err))
+            }
+        }
+    }
+
+    /// Remember the failure of a job, it is queued again until it has made enough attempts
+    fn failed(&mut self, id: JobId, reason: String) {
+        self.running.remove(&id);
+
+        match self.jobs.fail(id, &reason, MAX_ATTEMPTS) {
+            Ok(status) => {
+                info!("Job {} failed ({:?}): {}", id, status, reason);
+
+                if status == JobStatus::Failed {
+                    self.remove_input(id);
+                }
+            },
+            Err(err) => eprintln!("Could not store failure of job {}: {:?}", id, err)
+        }
+    }
+
+    /// Finish a job with its result
+    fn finished(&mut self, id: JobId, result: JobResult) {
+        self.running.remove(&id);
+
+        if let Err(err) = self.jobs.finish(id, result) {
+            eprintln!("Could not finish job {}: {:?}", id, err);
+        }
+
+        // the content of uploads is no longer needed
+        self.remove_input(id);
+    }
+
+    /// Create a playlist with the tracks of an album
+    fn add_playlist(&self, title: String, tracks: &[TrackKey], owner: Option<&str>) -> hex_database::Result<PlaylistKey> {
+        let key = self.playlists.create(&self.read, &self.write, title)?.key;
+
+        for track in tracks {
+            self.write.add_to_playlist(*track, key)?;
+        }
+
+        match owner {
+            Some(owner) => self.accounts.set_owner(key, owner).map(|_| key),
+            None => Ok(key)
+        }
+    }
+
+    /// Finish an import and create a playlist for each album, if requested
+    fn imported(&mut self, id: JobId) {
+        let import = match self.running.remove(&id) {
+            Some(Task::Import(import)) => import,
+            _ => return
+        };
+
+        let job = match self.jobs.job(id) {
+            Ok(job) => job,
+            Err(err) => {
+                eprintln!("Could not finish job {}: {:?}", id, err);
+                return;
+            }
+        };
+
+        let mut skipped = import.skipped();
+        let mut playlists = Vec::new();
+
+        if let JobKind::Import { ref name, playlists: true, .. } = job.kind {
+            // tracks without album are named after the archive
+            let name = ["zip", "tgz", "gz", "bz2", "xz", "tar"].iter()
+                .fold(name.as_str(), |name, ext| name.trim_end_matches(&*format!(".{}", ext)));
+
+            for (title, tracks) in import.albums(name) {
+                match self.add_playlist(title.clone(), &tracks, job.owner.as_ref().map(String::as_str)) {
+                    Ok(key) => playlists.push(key),
+                    Err(err) => skipped.push(format!("Playlist {}: {:?}", title, err))
+                }
+            }
+        }
+
+        self.finished(id, JobResult::Import { tracks: import.tracks(), playlists, skipped });
+    }
+
+    /// Advance all running jobs and start queued ones
+    pub fn tick(&mut self) {
+        let ids: Vec<JobId> = self.running.keys().cloned().collect();
+
+        for id in ids {
+            let data_path = self.data_path.clone();
+
+            let outcome = match self.running.get_mut(&id) {
+                Some(Task::Verify(recv)) => match recv.try_recv() {
+                    Ok(Ok(())) => Some(Outcome::Verified),
+                    Ok(Err(reason)) => Some(Outcome::Failed(reason)),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => Some(Outcome::Failed("Could not check the hash of the upload".into()))
+                },
+                Some(Task::Upload(upload)) => match upload.tick(data_path) {
+                    Some(track) => Some(Outcome::Track(track)),
+                    None => upload.error().map(|x| Outcome::Failed(x.to_string()))
+                },
+                Some(Task::Export) => Some(Outcome::Archive(format!("/exports/{}.zip", id))),
+                Some(Task::Import(import)) => {
+                    if let Some(track) = import.tick(data_path) {
+                        let key = track.key;
+
+                        // tracks already in the library are only added to the playlists
+                        if self.read.get_track(key).is_err() {
+                            match self.write.add_track(track) {
+                                Ok(_) => self.store_metadata(key),
+                                Err(err) => import.skip(format!("Could not add track: {:?}", err))
+                            }
+                        }
+                    }
+
+                    match import.error() {
+                        Some(reason) => Some(Outcome::Failed(reason)),
+                        None if import.is_finished() => Some(Outcome::Imported),
+                        None => None
+                    }
+                },
+                Some(Task::Enrich(enrich)) => {
+                    while let Some((key, suggestion)) = enrich.tick() {
+                        // the track may have changed during the lookup, only missing fields are filled
+                        let res = self.read.get_track(key).and_then(|mut track| {
+                            suggestion.fill(&mut track);
+
+                            self.write.update_track(key,
+                                track.title.as_ref().map(String::as_str),
+                                track.album.as_ref().map(String::as_str),
+                                track.interpret.as_ref().map(String::as_str),
+                                track.people.as_ref().map(String::as_str),
+                                track.composer.as_ref().map(String::as_str)
+                            )
+                        });
+
+                        match res {
+                            Ok(_) => self.store_metadata(key),
+                            Err(_) => enrich.skip()
+                        }
+                    }
+
+                    if enrich.is_finished() {
+                        Some(Outcome::Enriched)
+                    } else {
+                        None
+                    }
+                },
+                None => None
+            };
+
+            match outcome {
+                Some(Outcome::Verified) => {
+                    let _ = fs::remove_file(self.hash_path(id));
+
+                    match self.jobs.job(id).map_err(|err| Error::Database(err)).and_then(|job| self.run(&job)) {
+                        Ok(task) => { self.running.insert(id, task); },
+                        Err(err) => self.failed(id, format!("{:?}", err))
+                    }
+                },
+                Some(Outcome::Track(mut track)) => {
+                    let key = track.key;
+
+                    // uploaded files may carry their own tags
+                    Tags::from_pairs(tags::read(&self.input_path(id))).apply(&mut track);
+
+                    match self.write.add_track(track) {
+                        Ok(_) => {
+                            self.store_metadata(key);
+                            self.finished(id, JobResult::Track(key));
+                        },
+                        Err(err) => self.failed(id, format!("Could not add track: {:?}", err))
+                    }
+                },
+                Some(Outcome::Archive(path)) => self.finished(id, JobResult::Archive(path)),
+                Some(Outcome::Imported) => self.imported(id),
+                Some(Outcome::Enriched) => {
+                    let result = match self.running.get(&id) {
+                        Some(Task::Enrich(enrich)) => JobResult::Enrich { tagged: enrich.tagged(), unmatched: enrich.unmatched() },
+                        _ => continue
+                    };
+
+                    self.finished(id, result);
+                },
+                Some(Outcome::Failed(reason)) => self.failed(id, reason),
+                None => {}
+            }
+        }
+
+        self.schedule();
+    }
+}
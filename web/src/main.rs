@@ -22,6 +22,20 @@
 //! ```
 //!
 //! and can then be passed as an argument. (e.g. `./target/release/hex_server conf.toml`)
+//!
+//! Both the websocket server and the HTTP server are encrypted with TLS, once a certificate is
+//! configured. With `client_ca` only clients with a certificate signed by this authority are
+//! accepted:
+//!
+//! ```toml
+//! [tls]
+//! certificate = "/etc/hex/cert.pem"
+//! key = "/etc/hex/key.pem"
+//! client_ca = "/etc/hex/clients.pem"
+//! ```
+//!
+//! The HTTP server also accepts websocket connections at `/ws`, so a single port is enough to use
+//! the frontend.
 
 #[macro_use]
 extern crate log;
@@ -32,15 +46,29 @@ extern crate serde_derive;
 
 mod error;
 mod webserver;
-mod acousticid;
+mod metadata;
 mod convert;
 mod server;
 mod state;
+mod transcode;
+mod subsonic;
+mod tls;
+mod jobs;
+mod upload;
+mod tags;
+mod export;
+mod images;
+mod party;
+mod playlists;
 
 use std::thread;
 use std::path::PathBuf;
 use std::net::SocketAddr;
 
+use futures::sync::mpsc::unbounded;
+
+use crate::playlists::Playlists;
+
 /// Main function spinning up all server
 fn main() {
     env_logger::init();
@@ -55,15 +83,25 @@ fn main() {
 
     println!("Configuration: {:#?}", conf);
 
+    // websocket connections upgraded by the webserver are processed by the websocket server
+    let (upgrades, upgraded) = unbounded();
+
+    // both servers write to the same instance, hence all changes are replicated to the peers
+    let instance = server::instance(&conf, &path);
+    let playlists = Playlists::new();
+
     // start the webserver in a seperate thread if it is mentioned in the configuration
     if let Some(webserver) = conf.webserver.clone() {
         let data_path = path.join("data");
+        let db_path = path.join("music.db");
         let addr = SocketAddr::new(conf.host.clone(), webserver.port);
+        let tls = conf.tls.clone();
+        let (read, write, playlists) = (instance.reader(), instance.writer(), playlists.clone());
         thread::spawn(move || {
-            webserver::create_webserver(addr, webserver.path.clone(), data_path.clone());
+            webserver::create_webserver(addr, webserver.path.clone(), data_path.clone(), db_path, read, write, playlists, tls, upgrades);
         });
     }
 
     // start the websocket server in the main thread
-    server::start(conf, path)
+    server::start(conf, path, instance, playlists, upgraded)
 }
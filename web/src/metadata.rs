@@ -0,0 +1,750 @@
+//! Look up the metadata of tracks in online databases
+//!
+//! A track is identified at AcoustID with its fingerprint and searched at MusicBrainz with its
+//! title and interpret. The backends are asked in the configured order, never faster than they
+//! allow, and their answers are parsed into `Suggestion`s with a score between zero and one. All
+//! suggestions of a track are cached in the database, so that the same track is looked up only
+//! once in a while. The lookup is shared by all connections and jobs; it is locked only to read
+//! and write the cache and to reserve the next slot of a backend, never while waiting for an
+//! answer.
+//!
+//! The score of AcoustID is the confidence that the fingerprint matches, while MusicBrainz ranks
+//! its search results by the similarity of their names. Such a text match says nothing about the
+//! audio, so an enrichment job, which looks up every track with missing metadata in its own
+//! thread, only fills the missing fields with the best AcoustID suggestion if its score is high
+//! enough.
+
+use std::str;
+use std::thread;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use curl::easy::{Easy, Form, List};
+use serde_json::{self, Value};
+
+use hex_database::{Track, TrackKey, Suggestion, Suggestions};
+
+use crate::error::{Error, Result};
+
+/// Maximal number of suggestions returned for a track
+const MAX_SUGGESTIONS: usize = 10;
+
+/// Source of suggestions which matched the fingerprint of a track
+const ACOUSTID: &str = "acoustid";
+
+/// Identification of Hex at MusicBrainz, which rejects anonymous clients
+const USER_AGENT: &str = "hex/0.1 ( https://github.com/bytesnake/hex )";
+
+/// Maximal time to connect to a backend
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal time of a whole request, including the answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Chromaprint algorithm of the fingerprints, `TEST2` is the default of `fpcalc`
+const ALGORITHM: u8 = 1;
+
+/// A database which suggests metadata of a track
+pub trait Backend: Send + Sync {
+    /// Name of the backend, stored as source of its suggestions
+    fn name(&self) -> &'static str;
+
+    /// Minimal time between two lookups
+    fn interval(&self) -> Duration;
+
+    /// Suggest metadata of a track, best match first
+    fn lookup(&self, track: &Track) -> Result<Vec<Suggestion>>;
+}
+
+/// Perform a request with curl and parse the answer as JSON
+fn perform(mut easy: Easy) -> Result<Value> {
+    let mut dst = Vec::new();
+
+    // a backend which doesn't answer must not stall the lookups of other tracks
+    easy.connect_timeout(CONNECT_TIMEOUT)
+        .map_err(|err| Error::Metadata(err.to_string()))?;
+    easy.timeout(TIMEOUT)
+        .map_err(|err| Error::Metadata(err.to_string()))?;
+
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            dst.extend_from_slice(data);
+            Ok(data.len())
+        }).map_err(|err| Error::Metadata(err.to_string()))?;
+
+        transfer.perform().map_err(|err| Error::Metadata(err.to_string()))?;
+    }
+
+    let res_str = str::from_utf8(&dst)
+        .map_err(|_| Error::Metadata("Answer is not valid UTF-8".into()))?;
+
+    serde_json::from_str(res_str)
+        .map_err(|err| Error::Metadata(err.to_string()))
+}
+
+/// Join the names of all artists of a recording
+fn artists(value: &Value) -> Option<String> {
+    let names: Vec<&str> = value.as_array()?.iter()
+        .filter_map(|x| x["name"].as_str())
+        .collect();
+
+    if names.is_empty() {
+        None
+    } else {
+        Some(names.join(", "))
+    }
+}
+
+/// Parse the answer of an AcoustID lookup with the `recordings releasegroups` meta
+pub fn parse_acoustid(value: &Value) -> Result<Vec<Suggestion>> {
+    match value["status"].as_str() {
+        Some("ok") => {},
+        Some("error") => return Err(match value["error"]["message"].as_str() {
+            Some(msg) => Error::AcousticIDResponse(msg.into()),
+            None => Error::AcousticIDMetadata
+        }),
+        _ => return Err(Error::AcousticIDMetadata)
+    }
+
+    let results = value["results"].as_array()
+        .ok_or(Error::AcousticIDMetadata)?;
+
+    let mut suggestions = Vec::new();
+    for result in results {
+        let score = result["score"].as_f64().unwrap_or(0.0) as f32;
+
+        for recording in result["recordings"].as_array().into_iter().flatten() {
+            let suggestion = Suggestion {
+                source: ACOUSTID.into(),
+                recording: recording["id"].as_str().map(|x| x.to_string()),
+                title: recording["title"].as_str().map(|x| x.to_string()),
+                album: recording["releasegroups"][0]["title"].as_str().map(|x| x.to_string()),
+                interpret: artists(&recording["artists"]),
+                composer: None,
+                score
+            };
+
+            // recordings without metadata only carry their id
+            if suggestion.title.is_some() || suggestion.album.is_some() || suggestion.interpret.is_some() {
+                suggestions.push(suggestion);
+            }
+        }
+    }
+
+    Ok(suggestions)
+}
+
+/// Parse the answer of a recording search at MusicBrainz
+pub fn parse_musicbrainz(value: &Value) -> Result<Vec<Suggestion>> {
+    let recordings = value["recordings"].as_array()
+        .ok_or(Error::Metadata("MusicBrainz answer contains no recordings".into()))?;
+
+    Ok(recordings.iter().map(|recording| {
+        // older versions of the API return the score as string
+        let score = recording["score"].as_f64()
+            .or_else(|| recording["score"].as_str().and_then(|x| x.parse().ok()))
+            .unwrap_or(0.0);
+
+        Suggestion {
+            source: "musicbrainz".into(),
+            recording: recording["id"].as_str().map(|x| x.to_string()),
+            title: recording["title"].as_str().map(|x| x.to_string()),
+            album: recording["releases"][0]["title"].as_str().map(|x| x.to_string()),
+            interpret: artists(&recording["artist-credit"]),
+            composer: None,
+            score: (score / 100.0) as f32
+        }
+    }).collect())
+}
+
+/// Pack small values into a little-endian bit stream with `bits` bits per value
+fn pack_bits(values: &[u8], bits: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; (values.len() * bits + 7) / 8];
+
+    for (i, value) in values.iter().enumerate() {
+        for bit in 0..bits {
+            if value & (1 << bit) != 0 {
+                let pos = i * bits + bit;
+                buf[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+    }
+
+    buf
+}
+
+/// Compress a fingerprint like `chromaprint_encode_fingerprint` of Chromaprint
+///
+/// Each item is XORed with its predecessor and stored as the distances between its set bits:
+/// three bits per distance, larger distances continue with five more bits in a second section. The
+/// header contains the algorithm and the number of items.
+pub fn compress_fingerprint(algorithm: u8, fingerprint: &[u32]) -> Vec<u8> {
+    let mut normal = Vec::new();
+    let mut exceptional = Vec::new();
+
+    let mut last = 0;
+    for item in fingerprint {
+        let mut x = item ^ last;
+        let (mut bit, mut last_bit) = (1, 0);
+        last = *item;
+
+        while x != 0 {
+            if x & 1 != 0 {
+                let distance = bit - last_bit;
+                if distance >= 7 {
+                    normal.push(7);
+                    exceptional.push(distance - 7);
+                } else {
+                    normal.push(distance);
+                }
+
+                last_bit = bit;
+            }
+
+            x >>= 1;
+            bit += 1;
+        }
+
+        normal.push(0);
+    }
+
+    let len = fingerprint.len() as u32;
+    let mut buf = vec![algorithm, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    buf.extend(pack_bits(&normal, 3));
+    buf.extend(pack_bits(&exceptional, 5));
+
+    buf
+}
+
+/// Identify tracks by their fingerprint at acoustid.org
+pub struct AcoustId {
+    /// Client key registered at AcoustID
+    client: String
+}
+
+impl AcoustId {
+    pub fn new(client: &str) -> AcoustId {
+        AcoustId { client: client.into() }
+    }
+
+    /// Fields of the lookup form, the fingerprint is compressed and encoded like `fpcalc` does
+    fn fields(&self, track: &Track) -> Vec<(&'static str, String)> {
+        let fingerprint = compress_fingerprint(ALGORITHM, &track.fingerprint);
+
+        vec![
+            ("client", self.client.clone()),
+            ("fingerprint", base64::encode_config(&fingerprint, base64::URL_SAFE_NO_PAD)),
+            ("duration", (track.duration as u32).to_string()),
+            ("meta", "recordings releasegroups".into())
+        ]
+    }
+}
+
+impl Backend for AcoustId {
+    fn name(&self) -> &'static str {
+        ACOUSTID
+    }
+
+    fn interval(&self) -> Duration {
+        // at most three requests per second
+        Duration::from_millis(334)
+    }
+
+    fn lookup(&self, track: &Track) -> Result<Vec<Suggestion>> {
+        let mut easy = Easy::new();
+        easy.url("https://api.acoustid.org/v2/lookup")
+            .map_err(|_| Error::AcousticIDMetadata)?;
+
+        let mut form = Form::new();
+        for (name, value) in self.fields(track) {
+            form.part(name).contents(value.as_bytes()).add()
+                .map_err(|_| Error::AcousticIDMetadata)?;
+        }
+
+        easy.httppost(form)
+            .map_err(|_| Error::AcousticIDMetadata)?;
+
+        parse_acoustid(&perform(easy)?)
+    }
+}
+
+/// Search tracks by title and interpret at musicbrainz.org
+pub struct MusicBrainz;
+
+impl Backend for MusicBrainz {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+
+    fn interval(&self) -> Duration {
+        // at most a single request per second
+        Duration::from_secs(1)
+    }
+
+    fn lookup(&self, track: &Track) -> Result<Vec<Suggestion>> {
+        // there is nothing to search for without title
+        let title = match track.title {
+            Some(ref title) => title,
+            None => return Ok(Vec::new())
+        };
+
+        let mut query = format!("recording:\"{}\"", title.replace('"', ""));
+        if let Some(ref interpret) = track.interpret {
+            query.push_str(&format!(" AND artist:\"{}\"", interpret.replace('"', "")));
+        }
+
+        let mut easy = Easy::new();
+        let query = easy.url_encode(query.as_bytes());
+
+        easy.url(&format!("https://musicbrainz.org/ws/2/recording?fmt=json&limit={}&query={}", MAX_SUGGESTIONS, query))
+            .map_err(|err| Error::Metadata(err.to_string()))?;
+        easy.useragent(USER_AGENT)
+            .map_err(|err| Error::Metadata(err.to_string()))?;
+
+        let mut headers = List::new();
+        headers.append("Accept: application/json")
+            .map_err(|err| Error::Metadata(err.to_string()))?;
+        easy.http_headers(headers)
+            .map_err(|err| Error::Metadata(err.to_string()))?;
+
+        parse_musicbrainz(&perform(easy)?)
+    }
+}
+
+/// Create the backends of the configuration, unknown names are skipped
+fn backends(conf: &hex_conf::Metadata) -> Vec<Box<dyn Backend>> {
+    conf.backends.iter().filter_map(|name| match name.as_str() {
+        "acoustid" => Some(Box::new(AcoustId::new(&conf.acoustid_client)) as Box<dyn Backend>),
+        "musicbrainz" => Some(Box::new(MusicBrainz) as Box<dyn Backend>),
+        name => {
+            eprintln!("Unknown metadata backend {}", name);
+            None
+        }
+    }).collect()
+}
+
+/// Cached and rate-limited lookup in all backends
+pub struct Lookup {
+    /// Backends with the time from which on the next lookup is allowed
+    backends: Vec<(Box<dyn Backend>, Mutex<Option<Instant>>)>,
+    cache: Mutex<Suggestions>,
+    /// Maximal age of cached suggestions in seconds
+    max_age: i64
+}
+
+impl Lookup {
+    /// Create the configured backends with a cache in the database file
+    pub fn new(conf: &hex_conf::Metadata, db_path: &Path) -> Result<Lookup> {
+        let cache = Suggestions::from_file(db_path)
+            .map_err(|err| Error::Database(err))?;
+
+        Ok(Lookup::with_backends(backends(conf), cache, conf.cache_days as i64 * 24 * 3600))
+    }
+
+    pub fn with_backends(backends: Vec<Box<dyn Backend>>, cache: Suggestions, max_age: i64) -> Lookup {
+        Lookup {
+            backends: backends.into_iter().map(|x| (x, Mutex::new(None))).collect(),
+            cache: Mutex::new(cache),
+            max_age
+        }
+    }
+
+    /// Suggest metadata of a track, best match first
+    ///
+    /// A failing backend is skipped as long as another one answers. Results are only cached if
+    /// all backends answered.
+    pub fn suggestions(&self, track: &Track) -> Result<Vec<Suggestion>> {
+        let cached = self.cache.lock()
+            .map_err(|_| Error::Metadata("Lookup failed in another thread".into()))?
+            .get(track.key, self.max_age);
+
+        if let Some(suggestions) = cached {
+            return Ok(suggestions);
+        }
+
+        let mut suggestions = Vec::new();
+        let mut error = None;
+
+        for (backend, next) in &self.backends {
+            // reserve the next slot, concurrent lookups wait for their own one
+            let slot = {
+                let mut next = next.lock()
+                    .map_err(|_| Error::Metadata("Lookup failed in another thread".into()))?;
+
+                let now = Instant::now();
+                let slot = next.map(|x| x.max(now)).unwrap_or(now);
+                *next = Some(slot + backend.interval());
+
+                slot
+            };
+
+            let now = Instant::now();
+            if slot > now {
+                thread::sleep(slot - now);
+            }
+
+            let res = backend.lookup(track);
+
+            match res {
+                Ok(result) => suggestions.extend(result),
+                Err(err) => {
+                    eprintln!("Could not look up {} at {}: {:?}", track.key, backend.name(), err);
+                    error = Some(err);
+                }
+            }
+        }
+
+        suggestions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+
+        // different recordings often carry the same metadata
+        let mut unique: Vec<Suggestion> = Vec::new();
+        for suggestion in suggestions {
+            let known = unique.iter().any(|x| x.title == suggestion.title && x.album == suggestion.album && x.interpret == suggestion.interpret);
+
+            if !known && unique.len() < MAX_SUGGESTIONS {
+                unique.push(suggestion);
+            }
+        }
+
+        match error {
+            Some(err) => if unique.is_empty() {
+                return Err(err);
+            },
+            None => match self.cache.lock() {
+                Ok(cache) => if let Err(err) = cache.set(track.key, &unique) {
+                    eprintln!("Could not cache suggestions of {}: {:?}", track.key, err);
+                },
+                Err(_) => eprintln!("Could not cache suggestions of {}, the cache failed in another thread", track.key)
+            }
+        }
+
+        Ok(unique)
+    }
+}
+
+/// Check whether a track misses any metadata an enrichment could fill
+pub fn is_incomplete(track: &Track) -> bool {
+    track.title.is_none() || track.album.is_none() || track.interpret.is_none()
+}
+
+/// Look up the metadata of many tracks in a thread
+pub struct EnrichState {
+    /// Best suggestion of tracks with a good match
+    recv: Receiver<(TrackKey, Suggestion)>,
+    /// Number of looked up tracks
+    done: Arc<AtomicUsize>,
+    num_tracks: usize,
+    /// Stops the thread, once the job is dropped
+    cancelled: Arc<AtomicBool>,
+    tagged: Vec<TrackKey>,
+    finished: bool
+}
+
+impl EnrichState {
+    /// Look up all tracks and send AcoustID suggestions with at least `min_score`
+    pub fn new(lookup: Arc<Lookup>, tracks: Vec<Track>, min_score: f32) -> EnrichState {
+        let (sender, recv) = channel();
+        let done = Arc::new(AtomicUsize::new(0));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let num_tracks = tracks.len();
+
+        let (done2, cancelled2) = (done.clone(), cancelled.clone());
+        thread::spawn(move || {
+            for track in tracks {
+                if cancelled2.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                // suggestions are sorted by their score
+                let best = lookup.suggestions(&track).ok()
+                    .and_then(|x| x.into_iter().find(|x| x.source == ACOUSTID));
+
+                if let Some(best) = best.filter(|x| x.score >= min_score) {
+                    if sender.send((track.key, best)).is_err() {
+                        break;
+                    }
+                }
+
+                done2.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        EnrichState {
+            recv, done, num_tracks, cancelled,
+            tagged: Vec::new(),
+            finished: false
+        }
+    }
+
+    /// Advance the enrichment, returns a track and the suggestion which should be applied to it
+    ///
+    /// Tracks which can't be updated are passed to `skip`.
+    pub fn tick(&mut self) -> Option<(TrackKey, Suggestion)> {
+        match self.recv.try_recv() {
+            Ok((key, suggestion)) => {
+                self.tagged.push(key);
+
+                Some((key, suggestion))
+            },
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.finished = true;
+
+                None
+            }
+        }
+    }
+
+    /// Forget the last returned track, because it couldn't be updated
+    pub fn skip(&mut self) {
+        self.tagged.pop();
+    }
+
+    /// Check whether all tracks are looked up and their suggestions applied
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Progress over all tracks
+    pub fn progress(&self) -> f32 {
+        if self.num_tracks == 0 {
+            return 1.0;
+        }
+
+        self.done.load(Ordering::Relaxed) as f32 / self.num_tracks as f32
+    }
+
+    /// All tagged tracks
+    pub fn tagged(&self) -> Vec<TrackKey> {
+        self.tagged.clone()
+    }
+
+    /// Number of tracks without a good match
+    pub fn unmatched(&self) -> usize {
+        self.num_tracks - self.tagged.len()
+    }
+}
+
+impl Drop for EnrichState {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use std::thread;
+
+    use serde_json::json;
+    use hex_database::{Track, Suggestion, Suggestions};
+
+    use super::{Backend, Lookup, EnrichState, AcoustId, parse_acoustid, parse_musicbrainz, compress_fingerprint};
+    use crate::error::{Error, Result};
+
+    /// Backend answering with fixed suggestions and counting its lookups
+    struct Fixed {
+        suggestions: Vec<Suggestion>,
+        calls: Arc<AtomicUsize>
+    }
+
+    impl Backend for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_millis(0)
+        }
+
+        fn lookup(&self, _: &Track) -> Result<Vec<Suggestion>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            Ok(self.suggestions.clone())
+        }
+    }
+
+    /// Backend which is always offline
+    struct Offline;
+
+    impl Backend for Offline {
+        fn name(&self) -> &'static str {
+            "offline"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_millis(0)
+        }
+
+        fn lookup(&self, _: &Track) -> Result<Vec<Suggestion>> {
+            Err(Error::Metadata("offline".into()))
+        }
+    }
+
+    fn suggestion(title: &str, score: f32) -> Suggestion {
+        Suggestion {
+            source: "acoustid".into(),
+            recording: None,
+            title: Some(title.into()),
+            album: Some("Album".into()),
+            interpret: Some("Band".into()),
+            composer: None,
+            score
+        }
+    }
+
+    fn track(n: u32) -> Track {
+        Track::empty(vec![n, n + 1, n + 2], 10.0)
+    }
+
+    #[test]
+    fn parse_answers() {
+        let acoustid = json!({
+            "status": "ok",
+            "results": [{
+                "id": "abc",
+                "score": 0.92,
+                "recordings": [
+                    { "id": "rec1", "title": "Song", "artists": [{ "name": "A" }, { "name": "B" }], "releasegroups": [{ "title": "Album", "type": "Album" }] },
+                    { "id": "rec2" }
+                ]
+            }]
+        });
+
+        let suggestions = parse_acoustid(&acoustid).unwrap();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].recording, Some("rec1".into()));
+        assert_eq!(suggestions[0].interpret, Some("A, B".into()));
+        assert_eq!(suggestions[0].album, Some("Album".into()));
+        assert_eq!(suggestions[0].score, 0.92);
+
+        let error = json!({ "status": "error", "error": { "message": "invalid API key" } });
+        assert!(parse_acoustid(&error).is_err());
+
+        let musicbrainz = json!({
+            "recordings": [
+                { "id": "rec3", "score": 85, "title": "Song", "artist-credit": [{ "name": "C" }], "releases": [{ "title": "Live" }] },
+                { "id": "rec4", "score": "40", "title": "Other" }
+            ]
+        });
+
+        let suggestions = parse_musicbrainz(&musicbrainz).unwrap();
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0].score, 0.85);
+        assert_eq!(suggestions[0].album, Some("Live".into()));
+        assert_eq!(suggestions[1].score, 0.4);
+        assert_eq!(suggestions[1].interpret, None);
+    }
+
+    #[test]
+    fn compressed_fingerprint() {
+        // vectors of the tests of Chromaprint
+        assert_eq!(compress_fingerprint(0, &[1]), vec![0, 0, 0, 1, 1]);
+        assert_eq!(compress_fingerprint(0, &[7]), vec![0, 0, 0, 1, 73, 0]);
+        assert_eq!(compress_fingerprint(0, &[1 << 6]), vec![0, 0, 0, 1, 7, 0]);
+        assert_eq!(compress_fingerprint(0, &[1 << 8]), vec![0, 0, 0, 1, 7, 2]);
+        assert_eq!(compress_fingerprint(0, &[1, 0]), vec![0, 0, 0, 2, 65, 0]);
+        assert_eq!(compress_fingerprint(0, &[1, 1]), vec![0, 0, 0, 2, 1, 0]);
+
+        // the lookup sends the compressed fingerprint in URL-safe base64 without padding
+        let track = Track::empty(vec![1, 7, 1 << 8], 184.6);
+        let fields = AcoustId::new("key").fields(&track);
+        let field = |name: &str| fields.iter().find(|x| x.0 == name).map(|x| x.1.clone()).unwrap();
+
+        let fingerprint = base64::decode_config(&field("fingerprint"), base64::URL_SAFE_NO_PAD).unwrap();
+        assert!(!field("fingerprint").contains('='));
+        assert_eq!(fingerprint[..4], [1, 0, 0, 3]);
+        assert_eq!(fingerprint, compress_fingerprint(1, &track.fingerprint));
+        assert_eq!((field("client"), field("duration")), ("key".to_string(), "184".to_string()));
+        assert_eq!(field("meta"), "recordings releasegroups");
+    }
+
+    #[test]
+    fn cached_lookup() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = Fixed { suggestions: vec![suggestion("Low", 0.3), suggestion("High", 0.9), suggestion("High", 0.5)], calls: calls.clone() };
+
+        let lookup = Lookup::with_backends(vec![Box::new(backend)], Suggestions::from_file(file.path()).unwrap(), 60);
+
+        // sorted by score without duplicates
+        let suggestions = lookup.suggestions(&track(1)).unwrap();
+        assert_eq!(suggestions.iter().map(|x| x.score).collect::<Vec<_>>(), vec![0.9, 0.3]);
+
+        assert_eq!(lookup.suggestions(&track(1)).unwrap(), suggestions);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        lookup.suggestions(&track(2)).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn failing_backend() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backend = Fixed { suggestions: vec![suggestion("Song", 0.9)], calls: calls.clone() };
+
+        let lookup = Lookup::with_backends(vec![Box::new(Offline), Box::new(backend)], Suggestions::from_file(file.path()).unwrap(), 60);
+
+        // partial results are returned, but not cached
+        assert_eq!(lookup.suggestions(&track(1)).unwrap().len(), 1);
+        lookup.suggestions(&track(1)).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let offline = Lookup::with_backends(vec![Box::new(Offline)], Suggestions::from_file(file.path()).unwrap(), 60);
+        assert!(offline.suggestions(&track(3)).is_err());
+    }
+
+    #[test]
+    fn enrich() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let backend = Fixed { suggestions: vec![suggestion("Song", 0.9)], calls: Arc::new(AtomicUsize::new(0)) };
+        let lookup = Lookup::with_backends(vec![Box::new(backend)], Suggestions::from_file(file.path()).unwrap(), 60);
+        let lookup = Arc::new(lookup);
+
+        let mut state = EnrichState::new(lookup.clone(), vec![track(1), track(2)], 0.8);
+        let mut tagged = Vec::new();
+        while !state.is_finished() {
+            if let Some((key, suggestion)) = state.tick() {
+                assert_eq!(suggestion.title, Some("Song".into()));
+                tagged.push(key);
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(tagged, vec![track(1).key, track(2).key]);
+        assert_eq!(state.unmatched(), 0);
+        assert_eq!(state.progress(), 1.0);
+
+        // the suggestion is below the minimal score
+        let mut state = EnrichState::new(lookup, vec![track(3)], 0.95);
+        while !state.is_finished() {
+            assert!(state.tick().is_none());
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(state.tagged(), vec![]);
+        assert_eq!(state.unmatched(), 1);
+
+        // an exact match of the title at MusicBrainz isn't applied without asking
+        let text = Suggestion { source: "musicbrainz".into(), ..suggestion("Text", 1.0) };
+        let backend = Fixed { suggestions: vec![text, suggestion("Song", 0.85)], calls: Arc::new(AtomicUsize::new(0)) };
+        let lookup = Lookup::with_backends(vec![Box::new(backend)], Suggestions::from_file(file.path()).unwrap(), 60);
+
+        let mut state = EnrichState::new(Arc::new(lookup), vec![track(4)], 0.8);
+        let mut applied = Vec::new();
+        while !state.is_finished() {
+            applied.extend(state.tick().map(|(_, x)| x.title));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(applied, vec![Some("Song".into())]);
+    }
+}
@@ -0,0 +1,400 @@
+//! Shared playback of several connections
+//!
+//! A party has a queue of tracks, the current track and its position, and lives in the server as
+//! long as it has members. Every connection can join a party and control it with commands, while
+//! a single member plays the audio as output. The output reports its position regularly and the
+//! end of every track, after which the party continues with the most voted track of the queue.
+//!
+//! Every change is pushed to the other members with the id of their `JoinParty` request. The
+//! position reported by the output is only part of the next change, to avoid pushing a packet
+//! every few seconds.
+
+use std::collections::{BTreeMap, HashMap};
+
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded};
+
+use hex_database::TrackKey;
+use hex_server_protocol::PacketId;
+use hex_server_protocol::objects::{Party, PartyId, PartyCommand, QueuedTrack};
+
+use crate::error::{Error, Result};
+
+/// Identification of a connection
+pub type ConnectionId = usize;
+
+/// A connection in a party and the id of its joining request
+struct Member {
+    connection: ConnectionId,
+    id: PacketId
+}
+
+/// A queued track and the users who voted for it
+struct Entry {
+    key: TrackKey,
+    voters: Vec<String>
+}
+
+struct State {
+    name: String,
+    current: Option<TrackKey>,
+    queue: Vec<Entry>,
+    playing: bool,
+    position: f64,
+    output: Option<ConnectionId>,
+    members: Vec<Member>
+}
+
+impl State {
+    fn new(name: String) -> State {
+        State {
+            name,
+            current: None,
+            queue: Vec::new(),
+            playing: false,
+            position: 0.0,
+            output: None,
+            members: Vec::new()
+        }
+    }
+
+    fn party(&self, id: PartyId) -> Party {
+        Party {
+            id,
+            name: self.name.clone(),
+            current: self.current,
+            queue: self.queue.iter().map(|x| QueuedTrack { key: x.key, votes: x.voters.len() as u32 }).collect(),
+            playing: self.playing,
+            position: self.position,
+            output: self.output.is_some(),
+            members: self.members.len() as u32
+        }
+    }
+
+    /// Continue with the first track of the queue, the party stops at its end
+    fn advance(&mut self) {
+        self.position = 0.0;
+        self.current = if self.queue.is_empty() {
+            None
+        } else {
+            Some(self.queue.remove(0).key)
+        };
+
+        if self.current.is_none() {
+            self.playing = false;
+        }
+    }
+
+    /// Apply a command, returns whether the members should be notified
+    ///
+    /// * `output` - is the command sent by the output of the party?
+    fn apply(&mut self, command: PartyCommand, voter: String, output: bool) -> Result<bool> {
+        match command {
+            // only the output knows where the playback is
+            PartyCommand::Position(_) | PartyCommand::Ended(_) if !output => {
+                return Err(Error::InvalidRequest("Only the output of the party reports the playback".into()));
+            },
+            PartyCommand::Queue(key) => {
+                if self.queue.iter().any(|x| x.key == key) {
+                    return Err(Error::InvalidRequest("Track is already queued".into()));
+                }
+
+                self.queue.push(Entry { key, voters: Vec::new() });
+
+                // a playing party waits for the next track
+                if self.playing && self.current.is_none() {
+                    self.advance();
+                }
+            },
+            PartyCommand::Remove(key) => {
+                let len = self.queue.len();
+                self.queue.retain(|x| x.key != key);
+
+                if self.queue.len() == len {
+                    return Err(Error::InvalidRequest("Track is not queued".into()));
+                }
+            },
+            PartyCommand::Vote(key) => {
+                let entry = self.queue.iter_mut().find(|x| x.key == key)
+                    .ok_or_else(|| Error::InvalidRequest("Track is not queued".into()))?;
+
+                if entry.voters.contains(&voter) {
+                    return Err(Error::InvalidRequest("Already voted for this track".into()));
+                }
+
+                entry.voters.push(voter);
+
+                // the sort is stable, tracks with the same votes keep their order
+                self.queue.sort_by(|a, b| b.voters.len().cmp(&a.voters.len()));
+            },
+            PartyCommand::Play => {
+                if self.current.is_none() {
+                    self.advance();
+                }
+
+                self.playing = self.current.is_some();
+            },
+            PartyCommand::Pause => {
+                self.playing = false;
+            },
+            PartyCommand::Skip => {
+                let playing = self.playing;
+                self.advance();
+                self.playing = playing && self.current.is_some();
+            },
+            PartyCommand::Seek(position) => {
+                if self.current.is_none() || position < 0.0 {
+                    return Err(Error::InvalidRequest(format!("Can't seek to {}", position)));
+                }
+
+                self.position = position;
+            },
+            PartyCommand::Position(position) => {
+                self.position = position;
+
+                return Ok(false);
+            },
+            PartyCommand::Ended(key) => {
+                // the track may be skipped already
+                if self.current != Some(key) {
+                    return Ok(false);
+                }
+
+                self.advance();
+                self.playing = self.current.is_some();
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// All parties of the server, shared by every connection
+pub struct Parties {
+    parties: BTreeMap<PartyId, State>,
+    /// Senders of the pushed changes of every connection
+    connections: HashMap<ConnectionId, UnboundedSender<(PacketId, Party)>>,
+    next_party: PartyId,
+    next_connection: ConnectionId
+}
+
+impl Default for Parties {
+    fn default() -> Parties {
+        Parties::new()
+    }
+}
+
+impl Parties {
+    pub fn new() -> Parties {
+        Parties {
+            parties: BTreeMap::new(),
+            connections: HashMap::new(),
+            next_party: 1,
+            next_connection: 0
+        }
+    }
+
+    /// Register a connection, returns its id and the changes pushed to it
+    pub fn connect(&mut self) -> (ConnectionId, UnboundedReceiver<(PacketId, Party)>) {
+        let (sender, receiver) = unbounded();
+        let connection = self.next_connection;
+
+        self.next_connection += 1;
+        self.connections.insert(connection, sender);
+
+        (connection, receiver)
+    }
+
+    /// Remove a closed connection from its party
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        self.leave(connection);
+        self.connections.remove(&connection);
+    }
+
+    /// Get all parties
+    pub fn list(&self) -> Vec<Party> {
+        self.parties.iter().map(|(id, x)| x.party(*id)).collect()
+    }
+
+    /// Start a new party without members
+    pub fn create(&mut self, name: String) -> Party {
+        let id = self.next_party;
+        self.next_party += 1;
+
+        let state = State::new(name);
+        let party = state.party(id);
+        self.parties.insert(id, state);
+
+        party
+    }
+
+    /// Party of a connection
+    fn joined(&self, connection: ConnectionId) -> Option<PartyId> {
+        self.parties.iter()
+            .find(|(_, x)| x.members.iter().any(|x| x.connection == connection))
+            .map(|(id, _)| *id)
+    }
+
+    /// Join a party, leaving the previous one
+    pub fn join(&mut self, connection: ConnectionId, id: PacketId, party: PartyId, output: bool) -> Result<Party> {
+        if !self.parties.contains_key(&party) {
+            return Err(Error::NotFound);
+        }
+
+        // joining again replaces the membership, without ending the party
+        if self.joined(connection) == Some(party) {
+            let state = self.parties.get_mut(&party).unwrap();
+            state.members.retain(|x| x.connection != connection);
+        } else {
+            self.leave(connection);
+        }
+
+        let state = self.parties.get_mut(&party).unwrap();
+        state.members.push(Member { connection, id });
+
+        if output {
+            state.output = Some(connection);
+        }
+
+        self.notify(party, connection);
+
+        Ok(self.parties[&party].party(party))
+    }
+
+    /// Leave the joined party, returns whether the connection was a member
+    ///
+    /// A party without members ends.
+    pub fn leave(&mut self, connection: ConnectionId) -> bool {
+        let party = match self.joined(connection) {
+            Some(party) => party,
+            None => return false
+        };
+
+        let state = self.parties.get_mut(&party).unwrap();
+        state.members.retain(|x| x.connection != connection);
+
+        if state.output == Some(connection) {
+            state.output = None;
+            state.playing = false;
+        }
+
+        if state.members.is_empty() {
+            self.parties.remove(&party);
+        } else {
+            self.notify(party, connection);
+        }
+
+        true
+    }
+
+    /// Control the party of a connection
+    ///
+    /// * `voter` - name of the user, or of the connection without accounts
+    pub fn command(&mut self, connection: ConnectionId, voter: String, command: PartyCommand) -> Result<Party> {
+        let party = self.joined(connection)
+            .ok_or_else(|| Error::InvalidRequest("Not a member of a party".into()))?;
+
+        let state = self.parties.get_mut(&party).unwrap();
+        let output = state.output == Some(connection);
+
+        if state.apply(command, voter, output)? {
+            self.notify(party, connection);
+        }
+
+        Ok(self.parties[&party].party(party))
+    }
+
+    /// Push the state of a party to all members, except the connection causing the change
+    fn notify(&mut self, party: PartyId, except: ConnectionId) {
+        let state = &self.parties[&party];
+        let value = state.party(party);
+
+        for member in state.members.iter().filter(|x| x.connection != except) {
+            if let Some(sender) = self.connections.get(&member.connection) {
+                // the connection may be closing
+                let _ = sender.unbounded_send((member.id, value.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    use hex_database::TrackKey;
+    use hex_server_protocol::objects::PartyCommand;
+
+    use super::Parties;
+
+    fn key(x: u8) -> TrackKey {
+        TrackKey::from_vec(&[x; 16])
+    }
+
+    #[test]
+    fn queue_and_votes() {
+        let mut parties = Parties::new();
+        let (a, _) = parties.connect();
+        let party = parties.create("Kitchen".into());
+
+        // only members control a party
+        assert!(parties.command(a, "alice".into(), PartyCommand::Play).is_err());
+        parties.join(a, [1; 4], party.id, true).unwrap();
+
+        for x in 1..4 {
+            parties.command(a, "alice".into(), PartyCommand::Queue(key(x))).unwrap();
+        }
+        assert!(parties.command(a, "alice".into(), PartyCommand::Queue(key(1))).is_err());
+
+        // the most voted track comes first
+        parties.command(a, "alice".into(), PartyCommand::Vote(key(3))).unwrap();
+        assert!(parties.command(a, "alice".into(), PartyCommand::Vote(key(3))).is_err());
+        let state = parties.command(a, "bob".into(), PartyCommand::Vote(key(2))).unwrap();
+        assert_eq!(state.queue.iter().map(|x| x.key).collect::<Vec<_>>(), vec![key(3), key(2), key(1)]);
+
+        let state = parties.command(a, "alice".into(), PartyCommand::Play).unwrap();
+        assert_eq!((state.current, state.playing), (Some(key(3)), true));
+
+        // a skipped track may still be reported as ended
+        parties.command(a, "alice".into(), PartyCommand::Skip).unwrap();
+        let state = parties.command(a, "alice".into(), PartyCommand::Ended(key(3))).unwrap();
+        assert_eq!(state.current, Some(key(2)));
+
+        parties.command(a, "alice".into(), PartyCommand::Ended(key(2))).unwrap();
+        let state = parties.command(a, "alice".into(), PartyCommand::Ended(key(1))).unwrap();
+        assert_eq!((state.current, state.playing), (None, false));
+    }
+
+    #[test]
+    fn members() {
+        let mut parties = Parties::new();
+        let (a, updates) = parties.connect();
+        let (b, _) = parties.connect();
+        let party = parties.create("Kitchen".into());
+
+        parties.join(a, [1; 4], party.id, false).unwrap();
+        let state = parties.join(b, [2; 4], party.id, true).unwrap();
+        assert_eq!((state.members, state.output), (2, true));
+
+        // only the output reports the playback
+        assert!(parties.command(a, "a".into(), PartyCommand::Position(9.0)).is_err());
+        assert!(parties.command(a, "a".into(), PartyCommand::Ended(key(1))).is_err());
+
+        // position reports are not pushed
+        parties.command(b, "b".into(), PartyCommand::Position(3.0)).unwrap();
+        parties.command(b, "b".into(), PartyCommand::Queue(key(1))).unwrap();
+
+        // without output the party pauses
+        parties.disconnect(b);
+        assert!(!parties.list()[0].output);
+
+        // the party ends with its last member
+        assert!(parties.leave(a));
+        assert!(parties.list().is_empty());
+        parties.disconnect(a);
+
+        let pushed = updates.collect().wait().unwrap();
+        assert_eq!(pushed.len(), 3);
+        assert!(pushed.iter().all(|(id, _)| *id == [1; 4]));
+        assert_eq!(pushed[1].1.position, 3.0);
+    }
+}
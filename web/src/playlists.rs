@@ -0,0 +1,33 @@
+//! Creation of new playlists
+//!
+//! A new playlist takes the key after the latest one. The webserver and the websocket server run
+//! in different threads and would otherwise hand out the same key twice, hence the lookup of the
+//! latest key and the write of the playlist happen while holding a common lock.
+
+use std::sync::{Arc, Mutex};
+
+use hex_database::{Reader, Writer, Playlist, Result};
+
+/// Allocates the keys of new playlists, shared by all servers
+#[derive(Clone)]
+pub struct Playlists {
+    lock: Arc<Mutex<()>>
+}
+
+impl Playlists {
+    pub fn new() -> Playlists {
+        Playlists {
+            lock: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// Create an empty playlist with the next free key
+    pub fn create(&self, read: &Reader, write: &Writer, title: String) -> Result<Playlist> {
+        let _guard = self.lock.lock().unwrap();
+
+        let playlist = Playlist::new(read.last_playlist_key()? + 1, title, write.peer_id());
+        write.add_playlist(playlist.clone())?;
+
+        Ok(playlist)
+    }
+}
@@ -1,39 +1,71 @@
 //! Websocket server implementation
 //!
 //! The websocket uses Tokio under the hood and manages a state for each connection. It also shares
-//! the latest token to all clients and logs every events concerning connecting and disconnecting. 
+//! the latest token to all clients and logs every events concerning connecting and disconnecting.
+//!
+//! Connections are either accepted at the own port, optionally encrypted with TLS, or upgraded by
+//! the webserver at `/ws`. The latter allows to serve the frontend and the websocket on a single
+//! port.
+//!
+//! Every change of the database is forwarded to all connections together with its sequence
+//! number. The state of a connection decides whether and how the change is pushed to the client.
 
+use std::io;
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use websocket::WebSocketError;
 use websocket::message::OwnedMessage;
-use websocket::server::InvalidConnection;
-use websocket::r#async::Server;
+use websocket::codec::ws::{MessageCodec, Context};
+use websocket::server::upgrade::r#async::{IntoWs, Upgrade};
 
-use tokio_core::reactor::{Handle, Core};
-use futures::{Future, Sink, Stream, sync::mpsc::{Sender, channel}};
+use tokio_core::reactor::{Handle, Core, Interval, Timeout};
+use tokio_core::net::TcpListener;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_codec::Decoder;
+use futures::{future, stream, Future, Sink, Stream, sync::mpsc::{Sender, UnboundedReceiver, channel}};
+use hyper::upgrade::Upgraded;
 
 use crate::state::State;
+use crate::jobs::Scheduler;
+use crate::party::Parties;
+use crate::playlists::Playlists;
+use crate::upload::Uploads;
+use crate::tls;
 use hex_conf::Conf;
 
-use hex_server_protocol::{Answer, AnswerAction};
-use hex_database::{Instance, GossipConf, TransitionAction};
-
-/// Start the websocket server, supplied with a configuration
-pub fn start(conf: Conf, path: PathBuf) {
-	let mut core = Core::new().unwrap();
-	let handle = core.handle();
+use hex_database::{Instance, GossipConf, TransitionAction, Accounts, History, Queues, Reader, Writer, Files};
+use hex_server_protocol::Encoding;
 
-	// bind to the server
-    let addr = ("0.0.0.0", conf.server.port);
-
-    let incoming = Server::bind(addr, &handle).unwrap().incoming();
+/// Shared items of all connections
+#[derive(Clone)]
+struct Shared {
+    handle: Handle,
+    path: PathBuf,
+    read: Reader,
+    write: Writer,
+    /// Files of other peers, e.g. replicated cover images
+    files: Files,
+    /// Keys of new playlists, shared with the webserver
+    playlists: Playlists,
+    scheduler: Rc<RefCell<Scheduler>>,
+    /// Unfinished uploads in chunks
+    uploads: Uploads,
+    /// Shared playback sessions of the connections
+    parties: Rc<RefCell<Parties>>,
+    broadcasts: Rc<RefCell<Vec<Sender<(u64, TransitionAction)>>>>
+}
 
+/// Open the database and join the configured peers
+///
+/// The instance is shared by all servers, so that every change is replicated and broadcasted.
+pub fn instance(conf: &Conf, path: &Path) -> Instance {
     let mut gossip = GossipConf::new();
-    
+
     if let Some(ref peer) = conf.peer {
         gossip = gossip.addr((conf.host, peer.port));
         gossip = gossip.id(peer.id());
@@ -42,99 +74,269 @@ pub fn start(conf: Conf, path: PathBuf) {
         gossip = gossip.discover(peer.discover);
     }
 
-    let mut instance = Instance::from_file(&path.join("music.db"), gossip);
-    let (read, write) = (instance.reader(), instance.writer());
+    Instance::from_file(&path.join("music.db"), gossip)
+}
+
+/// Start the websocket server, supplied with a configuration
+///
+/// * `instance` - Database shared with the webserver
+/// * `playlists` - Keys of new playlists, shared with the webserver
+/// * `upgrades` - Connections upgraded to websockets by the webserver
+pub fn start(conf: Conf, path: PathBuf, mut instance: Instance, playlists: Playlists, upgrades: UnboundedReceiver<Upgraded>) {
+	let mut core = Core::new().unwrap();
+	let handle = core.handle();
+
+	// bind to the server
+    let addr = SocketAddr::new("0.0.0.0".parse().unwrap(), conf.server.port);
+
+    let incoming = TcpListener::bind(&addr, &handle).unwrap().incoming();
+
+    let acceptor = conf.tls.as_ref().map(|x| tls::acceptor(x).expect("Could not load TLS configuration"));
+
+    match Accounts::from_file(&path.join("music.db")) {
+        Ok(ref accounts) if accounts.is_enabled() => {},
+        Ok(_) => warn!("No user account exists, every client has full access to the library"),
+        Err(err) => eprintln!("Could not open user accounts: {:?}", err)
+    }
+
+    let scheduler = Scheduler::new(handle.clone(), &path, instance.reader(), instance.writer(), playlists.clone(), conf.server.jobs, &conf.metadata)
+        .expect("Could not start the job scheduler");
 
-    let broadcasts: Rc<RefCell<Vec<Sender<TransitionAction>>>> = Rc::new(RefCell::new(Vec::new()));
+    let uploads = Uploads::new(&path.join("data"), conf.server.max_upload * 1024 * 1024);
 
+    let shared = Shared {
+        handle: handle.clone(),
+        path: path,
+        read: instance.reader(),
+        write: instance.writer(),
+        files: instance.files(),
+        playlists,
+        scheduler: Rc::new(RefCell::new(scheduler)),
+        uploads,
+        parties: Rc::new(RefCell::new(Parties::new())),
+        broadcasts: Rc::new(RefCell::new(Vec::new()))
+    };
 
 	// a stream of incoming connections
+    let shared2 = shared.clone();
 	let f = incoming
         .map(|x| Some(x))
         // we don't wanna save the stream if it drops
-        .or_else(|InvalidConnection { error, .. }| {
+        .or_else(|error| {
             eprintln!("Error = {:?}", error);
 
-            Ok(None)
+            Ok::<_, ()>(None)
         }).filter_map(|x| x)
-        .for_each(|(upgrade, addr)| {
+        .for_each(move |(stream, addr)| {
+            let shared = shared2.clone();
+
+            // the handshake of TLS and the websocket upgrade can take time, don't block other
+            // connections
+            match acceptor {
+                Some(ref acceptor) => {
+                    let timeout = Timeout::new(tls::HANDSHAKE_TIMEOUT, &handle)
+                        .expect("Could not create timer");
+
+                    let f = acceptor.accept(stream)
+                        .select2(timeout)
+                        .map_err(move |_| info!("TLS handshake with {} failed", addr))
+                        .and_then(move |res| match res {
+                            future::Either::A((stream, _)) => Ok(stream),
+                            future::Either::B(_) => {
+                                info!("TLS handshake with {} timed out", addr);
+                                Err(())
+                            }
+                        })
+                        .and_then(move |stream| upgrade(stream, addr, shared));
+
+                    spawn_future(f, &handle);
+                },
+                None => spawn_future(upgrade(stream, addr, shared), &handle)
+            }
+
+            Ok(())
+        });
+
+    // connections upgraded by the webserver have already finished the handshake
+    let shared2 = shared.clone();
+    let upgraded = upgrades.for_each(move |stream| {
+        let client = MessageCodec::default(Context::Server).framed(stream);
+
+        info!("Got a connection from the webserver");
+        spawn_future(connection(client, "webserver".into(), shared2.clone()), &shared2.handle);
+
+        Ok(())
+    });
+
+    // forward changes in the order of the history, with their sequence numbers
+    let history = History::from_file(&shared.path.join("music.db"))
+        .expect("Could not open history of changes");
+    let mut last = history.cursor().unwrap_or(0);
+
+    let tmp = shared.broadcasts.clone();
+    let c = instance.for_each(move |_| {
+        let changes = match history.since(last, usize::max_value()) {
+            Ok(changes) => changes,
+            Err(err) => {
+                eprintln!("Could not read history: {:?}", err);
+                return Ok(());
+            }
+        };
+
+        let mut senders = tmp.borrow_mut();
+
+        senders.retain(|x| !x.is_closed());
+
+        for (seq, action) in changes {
+            last = seq;
+
+            for i in &mut *senders {
+                if let Err(err) = i.try_send((seq, action.clone())) {
+                    eprintln!("Got error: {}", err);
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    // advance the background jobs
+    let tmp = shared.scheduler.clone();
+    let jobs = Interval::new(Duration::from_secs(1), &shared.handle)
+        .expect("Could not create timer")
+        .for_each(move |_| {
+            tmp.borrow_mut().tick();
+
+            Ok(())
+        });
+
+	core.run(Future::join4(f, upgraded, c.map_err(|_| ()), jobs.map_err(|_| ()))).unwrap();
+}
+
+/// Upgrade a stream to a websocket connection
+fn upgrade<S>(stream: S, addr: SocketAddr, shared: Shared) -> impl Future<Item = (), Error = ()>
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    stream.into_ws()
+        .map_err(move |(_, _, _, err)| info!("Invalid websocket connection from {}: {:?}", addr, err))
+        .and_then(move |upgrade: Upgrade<S>| {
             info!("Got a connection from {} (to {})", addr, upgrade.uri());
             // check if it has the protocol we want
             if !upgrade.protocols().iter().any(|s| s == "rust-websocket") {
                 // reject it if it doesn't
-                spawn_future(upgrade.reject(), &handle);
-                return Ok(());
+                spawn_future(upgrade.reject(), &shared.handle);
+                return future::Either::A(future::ok(()));
             }
 
-            let handle2 = handle.clone();
-            let path_cpy = path.clone();
-            let (read, write) = (read.clone(), write.clone());
-            let (s, r) = channel(1024);
-
-            broadcasts.borrow_mut().push(s);
-
             // accept the request to be a ws connection if it does
             let f = upgrade
                 .use_protocol("rust-websocket")
                 .accept()
-                .and_then(move |(s,_)| {
-                    let mut state = State::new(handle2, &path_cpy, read, write);
-
-                    let (sink, stream) = s.split();
-
-                    let stream = stream.filter_map(move |m| {
-                        match m {
-                            OwnedMessage::Ping(p) => Some(OwnedMessage::Pong(p)),
-                            OwnedMessage::Pong(_) => None,
-                            OwnedMessage::Text(_) => Some(OwnedMessage::Text("Text not supported".into())),
-                            OwnedMessage::Binary(data) => state.process(data).map(|x| OwnedMessage::Binary(x)),
-                            OwnedMessage::Close(_) => {
-                                info!("Client disconnected from {}", addr);
-                                Some(OwnedMessage::Close(None))
-                            }
-                        }
-                    })
-                    .or_else(|e| {
-                        eprintln!("Got websocket error = {:?}", e);
-
-                        Ok(OwnedMessage::Close(None))
-                    });
-
-                    // forward transitions
-                    let push = r.and_then(|x| {
-                        Answer::new([0u32; 4], Ok(AnswerAction::Transition(x))).to_buf()
-                            .map(|x| OwnedMessage::Binary(x))
-                            .map_err(|_| ())
-                    }).map_err(|_| WebSocketError::NoDataAvailable);
-
-                    Stream::select(stream, push)
-                        .forward(sink)
-                        .and_then(move |(_, sink)| {
-                            sink.send(OwnedMessage::Close(None))
-                        })
-                });
+                .map_err(|err| eprintln!("Could not accept connection: {:?}", err))
+                .and_then(move |(client, _)| connection(client, addr.to_string(), shared));
 
-            spawn_future(f, &handle);
+            future::Either::B(f)
+        })
+}
 
-            Ok(())
-        });
+/// Process the requests of a single websocket connection
+fn connection<C>(client: C, origin: String, shared: Shared) -> impl Future<Item = (), Error = ()>
+    where C: Stream<Item = OwnedMessage, Error = WebSocketError> + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError> + 'static
+{
+    let Shared { handle, path, read, write, files, playlists, scheduler, uploads, parties, broadcasts } = shared;
 
-    let tmp = broadcasts.clone();
-    let c = instance.for_each(|x| {
-        let mut senders = tmp.borrow_mut();
+    // close the connection, if the tables of this connection can't be opened
+    let db_path = path.join("music.db");
+    let tables = Accounts::from_file(&db_path)
+        .and_then(|accounts| Ok((accounts, History::from_file(&db_path)?, Queues::from_file(&db_path)?)));
 
-        senders.retain(|x| !x.is_closed());
+    let (accounts, history, queues) = match tables {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Could not open the database for {}: {:?}", origin, err);
+
+            return future::Either::A(client.send(OwnedMessage::Close(None)).map(|_| ()).map_err(|_| ()));
+        }
+    };
+
+    let (s, r) = channel(1024);
+    broadcasts.borrow_mut().push(s);
+
+    let ticks = Interval::new(Duration::from_secs(1), &handle)
+        .expect("Could not create timer");
+    let state = Rc::new(RefCell::new(State::new(handle, &path, read, write, files, playlists, accounts, queues, history, scheduler, parties, uploads)));
+    let party_updates = state.borrow_mut().party_updates()
+        .expect("Changes of parties are taken once");
+    let deferred = state.borrow_mut().deferred_answers()
+        .expect("Deferred answers are taken once");
 
-        for i in &mut *senders {
-            if let Err(err) = i.try_send(x.clone()) {
-                eprintln!("Got error: {}", err);
+    let (sink, stream) = client.split();
+
+    let tmp = state.clone();
+    let stream = stream.filter_map(move |m| {
+        match m {
+            OwnedMessage::Ping(p) => Some(OwnedMessage::Pong(p)),
+            OwnedMessage::Pong(_) => None,
+            OwnedMessage::Text(text) => tmp.borrow_mut().process(text.into_bytes(), Encoding::Json).map(|x| message(x, Encoding::Json)),
+            OwnedMessage::Binary(data) => tmp.borrow_mut().process(data, Encoding::Bincode).map(|x| message(x, Encoding::Bincode)),
+            OwnedMessage::Close(_) => {
+                info!("Client disconnected from {}", origin);
+                Some(OwnedMessage::Close(None))
             }
         }
+    })
+    .or_else(|e| {
+        eprintln!("Got websocket error = {:?}", e);
 
-        Ok(())
+        Ok(OwnedMessage::Close(None))
     });
 
-	core.run(Future::join(f, c)).unwrap();
+    // forward changes
+    let tmp = state.clone();
+    let tmp2 = state.clone();
+    let push = r.filter_map(move |(seq, action)| tmp.borrow_mut().notify(seq, action))
+        .map(move |x| message(x, tmp2.borrow().encoding()))
+        .map_err(|_| WebSocketError::NoDataAvailable);
+
+    // push changes of the joined party
+    let (tmp, tmp2) = (state.clone(), state.clone());
+    let party = party_updates.filter_map(move |(id, party)| tmp.borrow().notify_party(id, party))
+        .map(move |x| message(x, tmp2.borrow().encoding()))
+        .map_err(|_| WebSocketError::NoDataAvailable);
+
+    // send answers of requests processed in other threads
+    let (tmp, tmp2) = (state.clone(), state.clone());
+    let deferred = deferred.filter_map(move |(id, deferred)| tmp.borrow_mut().finish_deferred(id, deferred))
+        .map(move |x| message(x, tmp2.borrow().encoding()))
+        .map_err(|_| WebSocketError::NoDataAvailable);
+
+    // push the progress of uploads to subscribers
+    let tmp = state.clone();
+    let uploads = ticks.map(move |_| stream::iter_ok::<_, io::Error>(tmp.borrow_mut().notify_uploads()))
+        .flatten()
+        .map(move |x| message(x, state.borrow().encoding()))
+        .map_err(|_| WebSocketError::NoDataAvailable);
+
+    let f = Stream::select(stream, push)
+        .select(party)
+        .select(deferred)
+        .select(uploads)
+        .forward(sink)
+        .and_then(move |(_, sink)| {
+            sink.send(OwnedMessage::Close(None))
+        })
+        .map(|_| ())
+        .map_err(|err| eprintln!("Connection closed with error = {:?}", err));
+
+    future::Either::B(f)
+}
+
+/// Wrap an encoded packet in a message, JSON is sent in text frames
+fn message(buf: Vec<u8>, encoding: Encoding) -> OwnedMessage {
+    match encoding {
+        Encoding::Bincode => OwnedMessage::Binary(buf),
+        Encoding::Json => OwnedMessage::Text(String::from_utf8_lossy(&buf).into_owned())
+    }
 }
 
 fn spawn_future<F, I, E>(f: F, handle: &Handle)
@@ -1,25 +1,55 @@
 //! Connection based state
 //!
-//! Every client has an own state which contains a byte buffer, uploads, downloads, pending
+//! Every client has an own state which contains a byte buffer, its uploads and downloads, pending
 //! requests and the database connection. The state exists as long as the connection and for
 //! example allows the client to create an iterator of search results.
+//!
+//! Once a user account exists, every request needs a login and is checked against the role of the
+//! user. Listeners may only change their own playlists, editors can manage the whole library and
+//! admins the user accounts as well.
+//!
+//! Cover images of playlists are stored in the data section and replicated like audio files: a
+//! playlist received from another peer references its cover, which is requested from the peers
+//! once the playlist is sent to a client.
+//!
+//! A client can subscribe to changes of the library. Changes are then pushed as typed events with
+//! the id of the subscription, filtered to the objects of interest. Each event carries the
+//! sequence number of the change, which is used as cursor to get missed changes after a reconnect.
+//!
+//! The play queue is stored in the database instead, so that a client resumes the current track
+//! after a reconnect. It belongs to the logged in user, or without login to the device id sent
+//! with `GetQueue`.
 
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::collections::HashMap;
 use std::slice;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::thread;
 
+use futures::Future;
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
 use tokio_core::reactor::Handle;
 
 use crate::error::{Result, Error};
 
-use crate::convert::{UploadState, download::{DownloadState}};
+use crate::jobs::{Scheduler, Input};
+use crate::upload::Uploads;
+use crate::transcode::Format;
+use crate::images;
+use crate::party::{Parties, ConnectionId};
+use crate::playlists::Playlists;
 
-use hex_database::{self, Track, Token, Reader, Writer, Playlist};
-use hex_music_container::{self, Configuration, Container};
-use hex_server_protocol::{Request, Answer, RequestAction, AnswerAction, PacketId, objects::UploadProgress};
+use hex_database::{self, Track, TrackKey, Token, Reader, Writer, Files, Playlist, PlaylistKey, ImageKey, Accounts, User, Role, History, TransitionAction, JobId, JobKind, Queue, Queues};
+use hex_music_container::{self, Configuration, Container, Normalization};
+use hex_server_protocol::{Request, Answer, RequestAction, AnswerAction, AnswerError, PacketId, Encoding, version, objects::{UploadProgress, StreamFormat, Subscription, Event, Notification, Party, PartyCommand}};
 
-use crate::acousticid;
+/// Bitrate of re-encoded Opus streams in bits per second
+const OPUS_BITRATE: i32 = 128000;
+
+/// Maximal number of missed changes, sent to a resuming subscriber
+const MISSED_LIMIT: usize = 1000;
 
 /// A pending request
 ///
@@ -35,7 +65,8 @@ enum RequestState {
     /// A running stream
     Stream {
         track: hex_database::Track,
-        container: Container<File>
+        container: Container<File>,
+        format: StreamFormat
     }
 }
 
@@ -48,19 +79,105 @@ pub struct State {
     /// Open connection to the database
     pub read: Reader,
     pub write: Writer,
+    /// Files of other peers
+    files: Files,
+    /// Keys of new playlists, shared with the webserver
+    playlists: Playlists,
     /// Path to the data section
     data_path: PathBuf,
-    /// All uploads
-    uploads: Vec<UploadState>,
-    /// All downloads
-    downloads: Vec<DownloadState>,
+    /// Background jobs of all connections
+    scheduler: Rc<RefCell<Scheduler>>,
+    /// Upload jobs of this connection and the id of the creating request
+    uploads: Vec<(JobId, PacketId)>,
+    /// Export jobs of this connection and the id of the creating request
+    downloads: Vec<(JobId, PacketId)>,
+    /// Unfinished uploads in chunks, shared by all connections
+    sessions: Uploads,
     /// Have we inserted a token last time?
-    token_avail: bool
+    token_avail: bool,
+    /// User accounts of this peer
+    accounts: Accounts,
+    /// Logged in user and the token of the session
+    user: Option<(User, String)>,
+    /// Play queues of all users and devices
+    queues: Queues,
+    /// Device of the connection, identifies the queue without login
+    device: Option<String>,
+    /// Ordered changes of the database
+    history: History,
+    /// Id of the subscribing request and its filter
+    subscription: Option<(PacketId, Vec<Subscription>)>,
+    /// Sequence number of the latest change sent to the client
+    cursor: u64,
+    /// Encoding of the last request, used for the answers and pushed changes
+    encoding: Encoding,
+    /// Parties of all connections
+    parties: Rc<RefCell<Parties>>,
+    /// Identification of this connection in the parties
+    connection: ConnectionId,
+    /// Changes of the joined party, until taken by the connection
+    party_updates: Option<UnboundedReceiver<(PacketId, Party)>>,
+    /// Results of requests processed in another thread
+    answers: UnboundedSender<(PacketId, Deferred)>,
+    /// Receiver of those results, until taken by the connection
+    deferred: Option<UnboundedReceiver<(PacketId, Deferred)>>
+}
+
+/// Result of a request which was processed in another thread
+pub enum Deferred {
+    /// The finished answer
+    Answer(std::result::Result<AnswerAction, AnswerError>),
+    /// A stored cover, which is set as image of the playlist
+    PlaylistImage(PlaylistKey, std::result::Result<Option<ImageKey>, AnswerError>)
+}
+
+/// Role needed to perform a request, `None` if the request is possible without login
+///
+/// Every request is listed, so that a new request has to be classified before it compiles.
+/// Requests concerning a single playlist are further restricted to its owner by `modify_playlist`.
+fn required_role(action: &RequestAction) -> Option<Role> {
+    match action {
+        RequestAction::Login { .. } | RequestAction::Authenticate { .. } | RequestAction::Logout |
+        RequestAction::Hello { .. } => None,
+
+        RequestAction::Search { .. } | RequestAction::GetTrack { .. } |
+        RequestAction::StreamNext { .. } | RequestAction::StreamEnd | RequestAction::StreamSeek { .. } |
+        RequestAction::AddPlaylist { .. } | RequestAction::DeletePlaylist { .. } |
+        RequestAction::SetPlaylistImage { .. } | RequestAction::AddToPlaylist { .. } |
+        RequestAction::DeleteFromPlaylist { .. } | RequestAction::UpdatePlaylist { .. } |
+        RequestAction::GetPlaylists | RequestAction::GetPlaylist { .. } | RequestAction::GetPlaylistsOfTrack { .. } |
+        RequestAction::VoteForTrack { .. } | RequestAction::GetVotes |
+        RequestAction::GetToken { .. } | RequestAction::LastToken | RequestAction::GetSummary |
+        RequestAction::Download { .. } | RequestAction::AskDownloadProgress |
+        RequestAction::ChangePassword { .. } |
+        RequestAction::Subscribe { .. } | RequestAction::Unsubscribe |
+        RequestAction::GetParties | RequestAction::CreateParty { .. } | RequestAction::JoinParty { .. } |
+        RequestAction::LeaveParty | RequestAction::PartyCommand { .. } |
+        RequestAction::GetQueue { .. } | RequestAction::Enqueue { .. } | RequestAction::ReorderQueue { .. } |
+        RequestAction::Dequeue { .. } | RequestAction::ClearQueue |
+        RequestAction::UpdateQueuePosition { .. } => Some(Role::Listener),
+
+        RequestAction::UpdateTrack { .. } | RequestAction::DeleteTrack { .. } |
+        RequestAction::GetSuggestion { .. } | RequestAction::UploadYoutube { .. } |
+        RequestAction::UploadTrack { .. } | RequestAction::AskUploadProgress |
+        RequestAction::CreateToken | RequestAction::UpdateToken { .. } | RequestAction::GetTransitions |
+        RequestAction::GetJobs | RequestAction::CancelJob { .. } | RequestAction::RetryJob { .. } |
+        RequestAction::BeginUpload { .. } | RequestAction::UploadChunk { .. } | RequestAction::GetUploadSession { .. } |
+        RequestAction::CommitUpload { .. } | RequestAction::AbortUpload { .. } |
+        RequestAction::CommitImport { .. } | RequestAction::ApplySuggestion { .. } |
+        RequestAction::EnrichTracks => Some(Role::Editor),
+
+        RequestAction::GetUsers | RequestAction::AddUser { .. } |
+        RequestAction::DeleteUser { .. } | RequestAction::SetUserRole { .. } => Some(Role::Admin)
+    }
 }
 
 impl State {
     /// Create a new `State` from a configuration
-    pub fn new(handle: Handle, path: &Path, read: Reader, write: Writer) -> State {
+    pub fn new(handle: Handle, path: &Path, read: Reader, write: Writer, files: Files, playlists: Playlists, accounts: Accounts, queues: Queues, history: History, scheduler: Rc<RefCell<Scheduler>>, parties: Rc<RefCell<Parties>>, sessions: Uploads) -> State {
+        let (connection, party_updates) = parties.borrow_mut().connect();
+        let (answers, deferred) = mpsc::unbounded();
+
         State {
             handle: handle,
             reqs: HashMap::new(),
@@ -68,15 +185,427 @@ impl State {
             uploads: Vec::new(),
             downloads: Vec::new(),
             token_avail: false,
-            read, write
+            user: None,
+            device: None,
+            subscription: None,
+            cursor: 0,
+            encoding: Encoding::Bincode,
+            party_updates: Some(party_updates),
+            deferred: Some(deferred),
+            read, write, files, playlists, accounts, queues, history, scheduler, parties, sessions, connection, answers
+        }
+    }
+
+    /// Take the changes of the joined party, pushed to the client with `notify_party`
+    pub fn party_updates(&mut self) -> Option<UnboundedReceiver<(PacketId, Party)>> {
+        self.party_updates.take()
+    }
+
+    /// Create the packet of a changed party, with the id of the joining request
+    pub fn notify_party(&self, id: PacketId, party: Party) -> Option<Vec<u8>> {
+        self.encode(&Answer::new(id, Ok(AnswerAction::Party(party))))
+    }
+
+    /// Take the results of requests processed in another thread, answered with `finish_deferred`
+    pub fn deferred_answers(&mut self) -> Option<UnboundedReceiver<(PacketId, Deferred)>> {
+        self.deferred.take()
+    }
+
+    /// Finish a request processed in another thread and create the packet of its answer
+    pub fn finish_deferred(&mut self, id: PacketId, deferred: Deferred) -> Option<Vec<u8>> {
+        let answer = match deferred {
+            Deferred::Answer(answer) => answer,
+            Deferred::PlaylistImage(key, image) => image.and_then(|image| {
+                self.write.set_playlist_image(key, image)
+                    .map(|_| AnswerAction::SetPlaylistImage(image))
+                    .map_err(|err| Error::Database(err).into())
+            })
+        };
+
+        self.encode(&Answer::new(id, answer))
+    }
+
+    /// Process a request in another thread, the result is sent to `finish_deferred`
+    fn defer<F: FnOnce() -> Deferred + Send + 'static>(&self, id: PacketId, f: F) {
+        let answers = self.answers.clone();
+
+        thread::spawn(move || {
+            if answers.unbounded_send((id, f())).is_err() {
+                info!("Connection closed before the request {:?} was processed", id);
+            }
+        });
+    }
+
+    /// Look up suggestions for a track in another thread, the backends may take seconds to answer
+    fn lookup_suggestions(&self, id: PacketId, track: Track) {
+        let lookup = self.scheduler.borrow().lookup();
+
+        self.defer(id, move || {
+            Deferred::Answer(lookup.suggestions(&track)
+                .map(|x| AnswerAction::GetSuggestion { key: track.key, suggestions: x })
+                .map_err(AnswerError::from))
+        });
+    }
+
+    /// Check whether the logged in user may perform a request
+    ///
+    /// Without any account everybody has full access.
+    fn authorize(&self, action: &RequestAction) -> Result<()> {
+        let role = match required_role(action) {
+            Some(role) => role,
+            None => return Ok(())
+        };
+
+        if !self.accounts.is_enabled() {
+            return Ok(());
+        }
+
+        match self.user {
+            Some((ref user, _)) if user.role >= role => Ok(()),
+            Some(_) => Err(Error::PermissionDenied),
+            None => Err(Error::NotAuthenticated)
+        }
+    }
+
+    /// Check whether the logged in user may change a playlist
+    fn modify_playlist(&self, key: PlaylistKey) -> Result<()> {
+        match self.user {
+            Some((ref user, _)) if !self.accounts.may_modify(user, key) => Err(Error::PermissionDenied),
+            _ => Ok(())
+        }
+    }
+
+    /// Ask the other peers for missing cover images of playlists
+    fn fetch_images<'a, I: IntoIterator<Item = &'a Playlist>>(&self, playlists: I) {
+        for key in playlists.into_iter().filter_map(|x| x.image) {
+            if !images::image_path(&self.data_path, key).exists() {
+                self.handle.spawn(self.files.ask_for_file(key)
+                    .map_err(move |err| info!("Could not fetch image {}: {:?}", key, err)));
+            }
+        }
+    }
+
+    /// Name of the logged in user, if any
+    fn owner(&self) -> Option<String> {
+        self.user.as_ref().map(|x| x.0.name.clone())
+    }
+
+    /// Name of the logged in user
+    fn user_name(&self) -> Result<String> {
+        self.user.as_ref()
+            .map(|x| x.0.name.clone())
+            .ok_or(Error::NotAuthenticated)
+    }
+
+    /// Owner of the play queue, the logged in user or the device of the connection
+    fn queue_owner(&self) -> Result<String> {
+        match (&self.user, &self.device) {
+            (Some((user, _)), _) => Ok(format!("user:{}", user.name)),
+            (None, Some(device)) => Ok(format!("device:{}", device)),
+            (None, None) => Err(Error::InvalidRequest("No queue selected, call GetQueue first".into()))
+        }
+    }
+
+    /// Change the stored play queue, `change` returns `false` for an invalid index
+    fn change_queue<F>(&self, change: F) -> Result<Queue>
+        where F: FnOnce(&mut Queue) -> bool
+    {
+        let owner = self.queue_owner()?;
+        let mut queue = self.queues.get(&owner)
+            .map_err(|err| Error::Database(err))?;
+
+        if !change(&mut queue) {
+            return Err(Error::InvalidRequest("Invalid index of the queue".into()));
+        }
+
+        self.queues.set(&owner, queue)
+            .map_err(|err| Error::Database(err))
+    }
+
+    /// Check whether the subscription of the client includes an event
+    ///
+    /// An empty filter includes every change of the library, but no upload progress.
+    fn subscribed(&self, event: &Event) -> Option<PacketId> {
+        let (id, filter) = self.subscription.as_ref()?;
+
+        let included = match event {
+            Event::Upload(_) => filter.contains(&Subscription::Uploads),
+            _ => filter.is_empty() || filter.iter().any(|x| x.matches(event))
+        };
+
+        if included {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
+    /// Convert a change to an event
+    ///
+    /// Playlists in transitions carry no cover, it is taken from the database.
+    fn event(&self, action: TransitionAction) -> Event {
+        match Event::from(action) {
+            Event::Playlist(mut playlist) => {
+                playlist.image = self.read.get_playlist(playlist.key).ok()
+                    .and_then(|(x, _)| x.image);
+
+                Event::Playlist(playlist)
+            },
+            event => event
+        }
+    }
+
+    /// Subscribe to changes, returns the changes missed since `cursor`
+    fn subscribe(&mut self, id: PacketId, filter: Vec<Subscription>, cursor: Option<u64>) -> Result<AnswerAction> {
+        let latest = self.history.cursor()
+            .map_err(|err| Error::Database(err))?;
+
+        let changes = match cursor {
+            Some(cursor) => self.history.since(cursor, MISSED_LIMIT + 1)
+                .map_err(|err| Error::Database(err))?,
+            None => Vec::new()
+        };
+
+        self.subscription = Some((id, filter));
+        self.cursor = latest;
+
+        // the client has to reload everything if it missed too much
+        if changes.len() > MISSED_LIMIT {
+            return Ok(AnswerAction::Subscribe { missed: Vec::new(), cursor: latest, complete: false });
+        }
+
+        let missed = changes.into_iter()
+            .filter(|(seq, _)| *seq <= latest)
+            .map(|(seq, action)| Notification { cursor: seq, event: self.event(action) })
+            .filter(|x| self.subscribed(&x.event).is_some())
+            .collect();
+
+        Ok(AnswerAction::Subscribe { missed, cursor: latest, complete: true })
+    }
+
+    /// Create the packet of a change for this client
+    ///
+    /// Without a subscription the change is pushed as `Transition`, otherwise only if the
+    /// subscription includes it. Changes already sent in answer to `Subscribe` are skipped.
+    pub fn notify(&mut self, cursor: u64, action: TransitionAction) -> Option<Vec<u8>> {
+        if self.subscription.is_none() {
+            return self.encode(&Answer::new([0u32; 4], Ok(AnswerAction::Transition(action))));
+        }
+
+        if cursor <= self.cursor {
+            return None;
+        }
+
+        self.cursor = cursor;
+
+        let event = self.event(action);
+        let id = self.subscribed(&event)?;
+
+        self.encode(&Answer::new(id, Ok(AnswerAction::Notification(Notification { cursor, event }))))
+    }
+
+    /// Progress of the uploads of this connection
+    ///
+    /// Finished uploads are reported a single time.
+    fn upload_progress(&mut self) -> Vec<UploadProgress> {
+        let scheduler = self.scheduler.borrow();
+        let mut infos = Vec::new();
+
+        self.uploads.retain(|(job, id)| {
+            match scheduler.upload_progress(*job, *id) {
+                Some(info) => {
+                    let running = info.kind != "finished" && info.kind != "failed" && info.kind != "cancelled";
+                    infos.push(info);
+
+                    running
+                },
+                None => false
+            }
+        });
+
+        infos
+    }
+
+    /// Create the packets of upload progress, if the client subscribed to uploads
+    pub fn notify_uploads(&mut self) -> Vec<Vec<u8>> {
+        if self.uploads.is_empty() {
+            return Vec::new();
+        }
+
+        let id = match self.subscription {
+            Some((id, ref filter)) if filter.contains(&Subscription::Uploads) => id,
+            _ => return Vec::new()
+        };
+
+        let cursor = self.cursor;
+
+        self.upload_progress().into_iter()
+            .map(|x| Notification { cursor, event: Event::Upload(x) })
+            .filter_map(|x| self.encode(&Answer::new(id, Ok(AnswerAction::Notification(x)))))
+            .collect()
+    }
+
+    /// Get the next packets of a stream, opening the track in the first call
+    fn stream_next(&mut self, id: PacketId, key: Option<TrackKey>, binaural: bool, format: StreamFormat) -> Result<AnswerAction> {
+        if !self.reqs.contains_key(&id) {
+            let key = key.ok_or_else(|| Error::InvalidRequest("The first call of a stream needs a key".into()))?;
+            let track = self.read.get_track(key)
+                .map_err(|err| Error::Database(err))?;
+
+            let file = File::open(self.data_path.join(key.to_path()))
+                .map_err(|err| Error::Io(err))?;
+            let mut container = Container::<File>::load(file)
+                .map_err(|err| Error::MusicContainer(err))?;
+            container.set_normalization(Normalization::Track);
+
+            self.reqs.insert(id, RequestState::Stream { container, track, format });
+        }
+
+        let (container, format) = match self.reqs.get_mut(&id) {
+            Some(&mut RequestState::Stream { ref mut container, format, .. }) => (container, format),
+            _ => return Err(Error::InvalidRequest("Id is used by a search".into()))
+        };
+
+        let conf = if binaural {
+            Configuration::Binaural
+        } else {
+            Configuration::Stereo
+        };
+
+        // re-encode the stream to stereo Opus, the browser decodes it with WebCodecs
+        if format == StreamFormat::Opus {
+            let mut packets = Vec::new();
+            for _ in 0..10 {
+                match container.next_packet_opus(conf.clone(), OPUS_BITRATE) {
+                    Ok(packet) => packets.push(packet),
+                    Err(hex_music_container::error::Error::ReachedEnd) => break,
+                    Err(err) => return Err(Error::MusicContainer(err))
+                }
+            }
+
+            if packets.is_empty() {
+                Err(Error::MusicContainer(hex_music_container::error::Error::ReachedEnd))
+            } else {
+                Ok(AnswerAction::StreamPackets(packets))
+            }
+        } else {
+            let mut pcm = Vec::new();
+            for _ in 0..10 {
+                match container.next_packet(conf.clone()) {
+                    Ok(data) => {
+                        let data = unsafe {
+                            slice::from_raw_parts(
+                                data.as_ptr() as *const u8,
+                                data.len() * 2
+                            )
+                        };
+
+                        pcm.extend_from_slice(data);
+                    },
+                    Err(hex_music_container::error::Error::ReachedEnd) => break,
+                    Err(err) => return Err(Error::MusicContainer(err))
+                }
+            }
+
+            if pcm.is_empty() {
+                Err(Error::MusicContainer(hex_music_container::error::Error::ReachedEnd))
+            } else {
+                Ok(AnswerAction::StreamNext(pcm))
+            }
         }
     }
 
-    pub fn process_request(&mut self, req: Request) -> Answer {
+    /// Seek in a running stream
+    fn stream_seek(&mut self, id: PacketId, sample: u32) -> Result<AnswerAction> {
+        let (container, track) = match self.reqs.get_mut(&id) {
+            Some(&mut RequestState::Stream { ref mut container, ref track, .. }) => (container, track),
+            _ => return Err(Error::InvalidRequest("No stream with this id".into()))
+        };
+
+        if sample as f64 > track.duration * 48000.0 {
+            return Err(Error::InvalidRequest(format!("Sample {} is beyond the end of the track", sample)));
+        }
+
+        container.seek_to_sample(sample);
+
+        Ok(AnswerAction::StreamSeek { sample })
+    }
+
+    /// Process a decoded request
+    ///
+    /// Returns `None` if the request is answered later, with an answer from `deferred_answers`.
+    pub fn process_request(&mut self, req: Request) -> Option<Answer> {
         let Request { id, msg } = req;
         let mut remove = false;
 
+        if let Err(err) = self.authorize(&msg) {
+            return Some(Answer::new(id, Err(err.into())));
+        }
+
         let answ = match msg {
+            RequestAction::Login { name, password } => {
+                self.accounts.login(&name, &password)
+                    .map(|(token, user)| {
+                        self.user = Some((user.clone(), token.clone()));
+
+                        AnswerAction::Login { token, user }
+                    })
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::Authenticate { token } => {
+                self.accounts.session(&token)
+                    .map(|user| {
+                        self.user = Some((user.clone(), token));
+
+                        AnswerAction::Authenticate(user)
+                    })
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::Logout => {
+                let res = match self.user.take() {
+                    Some((_, token)) => self.accounts.logout(&token),
+                    None => Ok(())
+                };
+
+                res.map(|_| AnswerAction::Logout)
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::ChangePassword { password } => {
+                self.user_name()
+                    .and_then(|name| {
+                        self.accounts.set_password(&name, &password)
+                            .and_then(|_| self.accounts.login(&name, &password))
+                            .map_err(|err| Error::Database(err))
+                    })
+                    .map(|(token, user)| {
+                        self.user = Some((user, token.clone()));
+
+                        AnswerAction::ChangePassword(token)
+                    })
+            },
+            RequestAction::GetUsers => {
+                Ok(AnswerAction::GetUsers(self.accounts.users()))
+            },
+            RequestAction::AddUser { name, password, role } => {
+                self.accounts.add_user(&name, &password, role)
+                    .map(|user| AnswerAction::AddUser(user))
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::DeleteUser { name } => {
+                self.accounts.delete_user(&name)
+                    .and_then(|_| self.queues.remove(&format!("user:{}", name)))
+                    .map(|_| AnswerAction::DeleteUser)
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::SetUserRole { name, role } => {
+                self.accounts.set_role(&name, role)
+                    .map(|_| AnswerAction::SetUserRole)
+                    .map_err(|err| Error::Database(err))
+            },
+            RequestAction::GetVotes => {
+                self.user_name()
+                    .map(|name| AnswerAction::GetVotes(self.accounts.votes(&name)))
+            },
             RequestAction::GetTrack { key } => {
                 self.read.get_track(key)
                     .map(|x| AnswerAction::Track(x))
@@ -90,7 +619,11 @@ impl State {
                     people.as_ref().map(String::as_str), 
                     composer.as_ref().map(String::as_str)
                 )
-                    .map(|x| AnswerAction::UpdateTrack(x))
+                    .map(|x| {
+                        self.scheduler.borrow().store_metadata(key);
+
+                        AnswerAction::UpdateTrack(x)
+                    })
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::Search { query } => {
@@ -102,7 +635,7 @@ impl State {
 
                 let (query, seek) = match prior_state {
                     &mut RequestState::Search{ ref mut query, ref mut seek } => (query, seek),
-                    _ => panic!("blub")
+                    _ => return Some(Answer::new(id, Err(Error::InvalidRequest("Id is used by a stream".into()).into())))
                 };
 
                 self.read.search_limited(&query, *seek)
@@ -121,89 +654,12 @@ impl State {
                     })
                     .map_err(|err| Error::Database(err))
             },
-            RequestAction::StreamNext { key } => {
-                let State {
-                    ref data_path,
-                    ref read,
-                    ref mut reqs,
-                    ..
-                } = *self;
-
-                let entry = reqs.entry(id.clone());
-
-                let prior_state = entry
-                    .or_insert_with(|| {
-                        //collection.add_event(Action::PlaySong(key.unwrap()).with_origin(origin)).unwrap();
-                        let file = File::open(data_path.join(key.unwrap().to_path())).unwrap();
-
-                        RequestState::Stream {
-                            container: Container::<File>::load(file).unwrap(),
-                            track: read.get_track(key.unwrap()).unwrap()
-                        }
-                    });
-                
-                let container = match prior_state {
-                    &mut RequestState::Stream { ref mut container, .. } => container,
-                    _ => panic!("blub")
-                };
-
-
-                let mut pcm = Ok(Vec::new());
-                for _ in 0..10 {
-                    let data = container.next_packet(Configuration::Stereo)
-                        .map(|x| {
-                            unsafe {
-                                slice::from_raw_parts(
-                                    x.as_ptr() as *const u8,
-                                    x.len() * 2
-                                )
-                            }
-                        });
-
-                    match data {
-                        Ok(data) => {
-                            match pcm {
-                                Ok(ref mut pcm) => pcm.extend(data.into_iter()),
-                                _ => {}
-                            }
-                        },
-                        Err(err) => {
-                            match err {
-                                hex_music_container::error::Error::ReachedEnd => {},
-                                err => pcm = Err(Error::MusicContainer(err))
-                            }
-
-                            break;
-                        }
-                    }
-                }
-
-                match pcm {
-                    Ok(pcm) => {
-                        if pcm.len() == 0 {
-                            Err(Error::MusicContainer(hex_music_container::error::Error::ReachedEnd))
-                        } else {
-                            Ok(AnswerAction::StreamNext(pcm))
-                        }
-                    },
-                    Err(err) => Err(err)
-                }
+            RequestAction::StreamNext { key, binaural, format } => {
+                self.stream_next(id, key, binaural, format)
             },
 
             RequestAction::StreamSeek { sample } => {
-                let (container, track) = match self.reqs.get_mut(&id).unwrap() {
-                    &mut RequestState::Stream { ref mut container, ref mut track } => (container, track),
-                    _ => panic!("blub")
-                };
-
-                if sample as f64 > track.duration * 48000.0 {
-                    panic!("blub");
-                }
-                
-                //let pos = self.collection.stream_seek(pos, &track, &mut file);
-                container.seek_to_sample(sample as u32);
-
-                Ok(AnswerAction::StreamSeek { sample })
+                self.stream_seek(id, sample)
             },
 
             RequestAction::StreamEnd => {
@@ -213,61 +669,109 @@ impl State {
             },
 
             RequestAction::GetSuggestion { key } => {
-                let suggestion = self.read.get_track(key)
-                    .map_err(|x| Error::Database(x))
-                    .and_then(|x| acousticid::get_metadata(&x.fingerprint, x.duration as u32));
+                match self.read.get_track(key) {
+                    Ok(track) => {
+                        // answered by the thread of the lookup
+                        self.lookup_suggestions(id, track);
 
-                suggestion.map(|x| AnswerAction::GetSuggestion {
-                        key: key.clone(),
-                        data: x
-                })
+                        return None;
+                    },
+                    Err(err) => Err(Error::Database(err))
+                }
             },
+            RequestAction::ApplySuggestion { key, suggestion } => {
+                self.read.get_track(key)
+                    .and_then(|mut track| {
+                        suggestion.apply(&mut track);
 
-            RequestAction::AddPlaylist { name } => {
-                let key = self.read.last_playlist_key().unwrap() + 1;
-                let playlist = Playlist {
-                    key: key.clone(),
-                    title: name,
-                    desc: None,
-                    tracks: Vec::new(),
-                    origin: self.write.peer_id()
-                };
+                        self.write.update_track(key,
+                            track.title.as_ref().map(String::as_str),
+                            track.album.as_ref().map(String::as_str),
+                            track.interpret.as_ref().map(String::as_str),
+                            track.people.as_ref().map(String::as_str),
+                            track.composer.as_ref().map(String::as_str)
+                        ).map(|_| track)
+                    })
+                    .map(|track| {
+                        self.scheduler.borrow().store_metadata(key);
 
-                self.write.add_playlist(playlist.clone())
-                    .map(|_| AnswerAction::AddPlaylist(playlist))
+                        AnswerAction::ApplySuggestion(track)
+                    })
                     .map_err(|err| Error::Database(err))
             },
 
-            RequestAction::DeletePlaylist { key } => {
-                self.write.delete_playlist(key)
-                    .map(|_| AnswerAction::DeletePlaylist)
+            RequestAction::AddPlaylist { name } => {
+                self.playlists.create(&self.read, &self.write, name)
+                    .and_then(|playlist| match self.user {
+                        // the playlist belongs to the user who created it
+                        Some((ref user, _)) => self.accounts.set_owner(playlist.key, &user.name).map(|_| playlist),
+                        None => Ok(playlist)
+                    })
+                    .map(|playlist| AnswerAction::AddPlaylist(playlist))
                     .map_err(|err| Error::Database(err))
             },
 
+            RequestAction::DeletePlaylist { key } => {
+                self.modify_playlist(key).and_then(|_| {
+                    self.write.delete_playlist(key)
+                        .map(|_| AnswerAction::DeletePlaylist)
+                        .map_err(|err| Error::Database(err))
+                })
+            },
+
             RequestAction::UpdatePlaylist { key, title, desc } => {
-                self.write.update_playlist(key, title, desc, None)
-                    .map(|_| AnswerAction::UpdatePlaylist)
-                    .map_err(|err| Error::Database(err))
+                self.modify_playlist(key).and_then(|_| {
+                    self.write.update_playlist(key, title, desc, None)
+                        .map(|_| AnswerAction::UpdatePlaylist)
+                        .map_err(|err| Error::Database(err))
+                })
             },
 
             RequestAction::SetPlaylistImage { key, image } => {
-                Ok(AnswerAction::SetPlaylistImage)
+                let res = self.modify_playlist(key)
+                    .and_then(|_| self.read.get_playlist(key).map_err(|err| Error::Database(err)));
+
+                match res {
+                    // decoding and scaling the image takes a while, it is set once stored
+                    Ok(_) => {
+                        let data_path = self.data_path.clone();
+                        self.defer(id, move || {
+                            let image = if image.is_empty() {
+                                Ok(None)
+                            } else {
+                                images::store(&data_path, &image).map(Some)
+                            };
+
+                            Deferred::PlaylistImage(key, image.map_err(AnswerError::from))
+                        });
+
+                        return None;
+                    },
+                    Err(err) => Err(err)
+                }
             },
 
             RequestAction::AddToPlaylist { key, playlist } => {
-                self.write.add_to_playlist(key, playlist)
-                    .map(|_| AnswerAction::AddToPlaylist)
-                    .map_err(|err| Error::Database(err))
+                self.modify_playlist(playlist).and_then(|_| {
+                    self.write.add_to_playlist(key, playlist)
+                        .map(|_| AnswerAction::AddToPlaylist)
+                        .map_err(|err| Error::Database(err))
+                })
             },
 
             RequestAction::DeleteFromPlaylist { key, playlist } => {
-                self.write.delete_from_playlist(key, playlist)
-                    .map(|_| AnswerAction::DeleteFromPlaylist)
-                    .map_err(|err| Error::Database(err))
+                self.modify_playlist(playlist).and_then(|_| {
+                    self.write.delete_from_playlist(key, playlist)
+                        .map(|_| AnswerAction::DeleteFromPlaylist)
+                        .map_err(|err| Error::Database(err))
+                })
             },
 
             RequestAction::GetPlaylists => {
-                Ok(AnswerAction::GetPlaylists(self.read.get_playlists()))
+                let playlists = self.read.get_playlists();
+                self.fetch_images(&playlists);
+
+                Ok(AnswerAction::GetPlaylists(playlists))
             },
 
             RequestAction::GetPlaylist { key }=> {
@@ -277,6 +781,8 @@ impl State {
                             track.fingerprint = vec![];
                         }
 
+                        self.fetch_images(Some(&x.0));
+
                         AnswerAction::GetPlaylist(x)
                     })
                     .map_err(|err| Error::Database(err))
@@ -284,7 +790,11 @@ impl State {
 
             RequestAction::GetPlaylistsOfTrack { key } => {
                 self.read.get_playlists_of_track(key)
-                    .map(|x| AnswerAction::GetPlaylistsOfTrack(x))
+                    .map(|x| {
+                        self.fetch_images(&x);
+
+                        AnswerAction::GetPlaylistsOfTrack(x)
+                    })
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::DeleteTrack { key } => {
@@ -297,51 +807,40 @@ impl State {
             },
 
             RequestAction::UploadYoutube { path } => {
-                let handle = self.handle.clone();
+                let owner = self.owner();
+                let job = self.scheduler.borrow_mut().add(JobKind::Youtube { url: path }, None, owner.as_ref().map(String::as_str));
 
-                self.uploads.push(UploadState::youtube(id.clone(), &path, handle));
+                job.map(|job| {
+                    self.uploads.push((job.id, id.clone()));
 
-                Ok(AnswerAction::UploadYoutube)
+                    AnswerAction::UploadYoutube
+                })
             },
 
             RequestAction::UploadTrack { name, format, data } => {
-                println!("Got track buffer with: {}", data.len());
-                let handle = self.handle.clone();
+                let owner = self.owner();
+                let job = self.scheduler.borrow_mut().add(JobKind::Upload { name, format }, Some(Input::Data(&data)), owner.as_ref().map(String::as_str));
 
-                self.uploads.push(UploadState::converting_ffmpeg(handle, name, id.clone(), &data, &format));
+                job.map(|job| {
+                    self.uploads.push((job.id, id.clone()));
 
-                Ok(AnswerAction::UploadTrack)
+                    AnswerAction::UploadTrack
+                })
             },
 
             RequestAction::AskUploadProgress => {
-                // tick each item
-                for item in &mut self.uploads {
-                    if let Some(track) = item.tick(self.data_path.clone()) {
-                        self.write.add_track(track).unwrap();
-                    }
-                }
-
-                // collect update informations
-                let infos = self.uploads.iter().filter_map(|item| {
-                    let id = item.id()?;
-
-                    Some(UploadProgress {
-                        desc: item.desc().clone(),
-                        kind: item.kind().into(),
-                        progress: item.progress(),
-                        id: id,
-                        key: item.track_key()
-                    })
-                }).collect();
-
-                // delete finished uploads
-                self.uploads.retain(|x| x.should_retain());
-
-                Ok(AnswerAction::AskUploadProgress(infos))
+                Ok(AnswerAction::AskUploadProgress(self.upload_progress()))
             },
 
             RequestAction::VoteForTrack { key } => {
-                self.write.vote_for_track(key)
+                // every user votes only once for a track, the global count is the number of users
+                let new_vote = match self.user {
+                    Some((ref user, _)) => self.accounts.vote(&user.name, key),
+                    None => Ok(true)
+                };
+
+                new_vote
+                    .and_then(|new_vote| if new_vote { self.write.vote_for_track(key) } else { Ok(()) })
                     .map(|_| AnswerAction::VoteForTrack)
                     .map_err(|err| Error::Database(err))
             },
@@ -366,9 +865,12 @@ impl State {
                     .map_err(|err| Error::Database(err))
             },
             RequestAction::CreateToken => {
-                let id = self.read.last_token_id().unwrap() + 1;
+                let token_id = match self.read.last_token_id() {
+                    Ok(token_id) => token_id + 1,
+                    Err(err) => return Some(Answer::new(id, Err(Error::Database(err).into())))
+                };
                 let token = Token {
-                    token: id,
+                    token: token_id,
                     key: None,
                     played: Vec::new(),
                     pos: None,
@@ -400,24 +902,188 @@ impl State {
                 Ok(AnswerAction::GetTransitions(self.read.get_transitions()))
             },
             RequestAction::Download { format, tracks } => {
-                let id = id.clone();
-                tracks.into_iter()
-                    .map(|x| self.read.get_track(x)
-                        .map_err(|err| Error::Database(err))
+                let owner = self.owner();
+
+                Format::from_extension(&format)
+                    .ok_or(Error::Export(format!("Unknown format {}", format)))
+                    .and_then(|_| tracks.iter()
+                        .map(|x| self.read.get_track(*x)
+                            .map_err(|err| Error::Database(err))
+                        )
+                        .collect::<Result<Vec<Track>>>()
                     )
-                    .collect::<Result<Vec<Track>>>()
-                    .map(|tracks| {
-                        self.downloads.push(DownloadState::new(self.handle.clone(), id, format, tracks, 2, self.data_path.clone()));
+                    .and_then(|_| self.scheduler.borrow_mut().add(JobKind::Export { format, tracks }, None, owner.as_ref().map(String::as_str)))
+                    .map(|job| {
+                        self.downloads.push((job.id, id.clone()));
 
                         AnswerAction::Download
                     })
             },
             RequestAction::AskDownloadProgress => {
-                let res = self.downloads.iter_mut()
-                    .map(|x| x.progress())
+                let scheduler = self.scheduler.borrow();
+                let res = self.downloads.iter()
+                    .filter_map(|(job, id)| scheduler.export_progress(*job, *id))
                     .collect();
 
                 Ok(AnswerAction::AskDownloadProgress(res))
+            },
+            RequestAction::GetJobs => {
+                Ok(AnswerAction::GetJobs(self.scheduler.borrow().jobs()))
+            },
+            RequestAction::CancelJob { id } => {
+                self.scheduler.borrow_mut().cancel(id)
+                    .map(|_| AnswerAction::CancelJob)
+            },
+            RequestAction::RetryJob { id } => {
+                self.scheduler.borrow_mut().retry(id)
+                    .map(|job| AnswerAction::RetryJob(job))
+            },
+            RequestAction::BeginUpload { name, format, size, hash } => {
+                self.sessions.begin(name, format, size, &hash, self.owner())
+                    .map(|session| AnswerAction::BeginUpload(session))
+            },
+            RequestAction::UploadChunk { id, index, data } => {
+                self.sessions.chunk(&id, index, &data)
+                    .map(|_| AnswerAction::UploadChunk)
+            },
+            RequestAction::GetUploadSession { id } => {
+                self.sessions.session(&id)
+                    .map(|session| AnswerAction::GetUploadSession(session))
+            },
+            RequestAction::CommitUpload { id: session } => {
+                let owner = self.owner();
+
+                // the converting job takes over the received file
+                let job = self.sessions.commit(&session)
+                    .and_then(|(name, format, path)| self.scheduler.borrow_mut().add(JobKind::Upload { name, format }, Some(Input::Upload(&path, &session)), owner.as_ref().map(String::as_str)));
+
+                job.map(|job| {
+                    self.uploads.push((job.id, id.clone()));
+
+                    AnswerAction::CommitUpload(job.id)
+                })
+            },
+            RequestAction::AbortUpload { id } => {
+                let owner = self.owner();
+
+                self.sessions.abort(&id, owner.as_ref().map(String::as_str))
+                    .map(|_| AnswerAction::AbortUpload)
+            },
+            RequestAction::CommitImport { id: session, playlists } => {
+                let owner = self.owner();
+
+                let job = self.sessions.commit(&session)
+                    .and_then(|(name, format, path)| self.scheduler.borrow_mut().add(JobKind::Import { name, format, playlists }, Some(Input::Upload(&path, &session)), owner.as_ref().map(String::as_str)));
+
+                job.map(|job| {
+                    self.uploads.push((job.id, id.clone()));
+
+                    AnswerAction::CommitImport(job.id)
+                })
+            },
+            RequestAction::Subscribe { filter, cursor } => {
+                self.subscribe(id, filter, cursor)
+            },
+            RequestAction::EnrichTracks => {
+                let owner = self.owner();
+
+                self.scheduler.borrow_mut().add(JobKind::Enrich, None, owner.as_ref().map(String::as_str))
+                    .map(|job| AnswerAction::EnrichTracks(job.id))
+            },
+            RequestAction::Unsubscribe => {
+                self.subscription = None;
+
+                Ok(AnswerAction::Unsubscribe)
+            },
+            RequestAction::Hello { version: client_version, capabilities } => {
+                match version::negotiate(client_version, &capabilities) {
+                    Ok((version, capabilities)) => Ok(AnswerAction::Hello { version, capabilities }),
+                    Err(err) => return Some(Answer::new(id, Err(err)))
+                }
+            },
+            RequestAction::GetParties => {
+                Ok(AnswerAction::GetParties(self.parties.borrow().list()))
+            },
+            RequestAction::CreateParty { name } => {
+                Ok(AnswerAction::CreateParty(self.parties.borrow_mut().create(name)))
+            },
+            RequestAction::JoinParty { id: party, output } => {
+                self.parties.borrow_mut().join(self.connection, id, party, output)
+                    .map(|x| AnswerAction::JoinParty(x))
+            },
+            RequestAction::LeaveParty => {
+                if self.parties.borrow_mut().leave(self.connection) {
+                    Ok(AnswerAction::LeaveParty)
+                } else {
+                    Err(Error::InvalidRequest("Not a member of a party".into()))
+                }
+            },
+            RequestAction::PartyCommand { command } => {
+                // without accounts every connection has its own votes
+                let voter = self.owner()
+                    .unwrap_or_else(|| format!("#{}", self.connection));
+
+                // only tracks of the library can be played
+                let known = match command {
+                    PartyCommand::Queue(key) => self.read.get_track(key).map(|_| ()).map_err(|err| Error::Database(err)),
+                    _ => Ok(())
+                };
+
+                known.and_then(|_| self.parties.borrow_mut().command(self.connection, voter, command))
+                    .map(|x| AnswerAction::PartyCommand(x))
+            },
+            RequestAction::GetQueue { device } => {
+                if device.is_empty() {
+                    Err(Error::InvalidRequest("The device needs an id".into()))
+                } else {
+                    self.device = Some(device);
+
+                    self.queue_owner()
+                        .and_then(|owner| {
+                            let mut queue = self.queues.get(&owner)
+                                .map_err(|err| Error::Database(err))?;
+
+                            // drop deleted tracks, removing from the end keeps the indices valid
+                            let missing = (0..queue.tracks.len()).rev()
+                                .filter(|i| self.read.get_track(queue.tracks[*i]).is_err())
+                                .collect::<Vec<_>>();
+
+                            if missing.is_empty() {
+                                return Ok(queue);
+                            }
+
+                            for i in missing {
+                                queue.remove(i as u32);
+                            }
+
+                            self.queues.set(&owner, queue)
+                                .map_err(|err| Error::Database(err))
+                        })
+                        .map(|x| AnswerAction::GetQueue(x))
+                }
+            },
+            RequestAction::Enqueue { tracks, index } => {
+                // only tracks of the library can be queued
+                tracks.iter().try_for_each(|key| self.read.get_track(*key).map(|_| ()))
+                    .map_err(|err| Error::Database(err))
+                    .and_then(|_| self.change_queue(|queue| queue.enqueue(&tracks, index)))
+                    .map(|x| AnswerAction::Enqueue(x))
+            },
+            RequestAction::ReorderQueue { order } => {
+                self.change_queue(|queue| queue.reorder(&order))
+                    .map(|x| AnswerAction::ReorderQueue(x))
+            },
+            RequestAction::Dequeue { index } => {
+                self.change_queue(|queue| queue.remove(index))
+                    .map(|x| AnswerAction::Dequeue(x))
+            },
+            RequestAction::ClearQueue => {
+                self.change_queue(|queue| { queue.clear(); true })
+                    .map(|_| AnswerAction::ClearQueue)
+            },
+            RequestAction::UpdateQueuePosition { current, pos } => {
+                self.change_queue(|queue| queue.seek(current, pos))
+                    .map(|_| AnswerAction::UpdateQueuePosition)
             }
         };
 
@@ -428,20 +1094,500 @@ impl State {
 
         //println!("Outgoing: {:?}", answ);
 
-        Answer::new(id, answ.map_err(|err| format!("{:?}", err)))
+        Some(Answer::new(id, answ.map_err(AnswerError::from)))
     }
 
     /// Process a single packet
     ///
-    /// * `origin` - where does the request originates from
-    /// * `msg` - what is the content of the message
-    /// * `gtoken` - globally shared token, used to change the token in frontend
-    pub fn process(&mut self, buf: Vec<u8>) -> Option<Vec<u8>> {
+    /// * `buf` - the encoded request
+    /// * `encoding` - encoding of the request, answers and pushed changes use the same
+    pub fn process(&mut self, buf: Vec<u8>, encoding: Encoding) -> Option<Vec<u8>> {
         //println!("Process buf {}", buf.len());
-        Request::try_from(&buf)
-            .map(|req| self.process_request(req))
-            .and_then(|answer| answer.to_buf())
-            .map_err(|err| { println!("Parse error: {:?}", err); err})
+        self.encoding = encoding;
+
+        let answer = match Request::decode(&buf, encoding) {
+            Ok(req) => self.process_request(req)?,
+            Err(err) => {
+                println!("Parse error: {:?}", err);
+
+                // requests of newer clients are answered, if at least the id is readable
+                let id = Request::peek_id(&buf, encoding)?;
+                Answer::new(id, Err(AnswerError::InvalidRequest(format!("Unknown request in protocol version {}", version::VERSION))))
+            }
+        };
+
+        self.encode(&answer)
+    }
+
+    /// Encoding of the packets of this connection
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Encode an answer like the requests of this connection
+    fn encode(&self, answer: &Answer) -> Option<Vec<u8>> {
+        answer.encode(self.encoding)
+            .map_err(|err| eprintln!("Could not encode answer: {:?}", err))
             .ok()
     }
 }
+
+impl Drop for State {
+    /// Leave the party with the connection
+    fn drop(&mut self) {
+        self.parties.borrow_mut().disconnect(self.connection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use futures::{Future, Stream};
+    use tokio_core::reactor::Core;
+
+    use std::fs::{self, File};
+
+    use hex_database::{Instance, GossipConf, Accounts, History, Track, TrackKey, Queue, Queues, Role, Suggestion};
+    use hex_music_container::{Container, Configuration};
+    use hex_server_protocol::{Request, RequestAction, AnswerAction, AnswerError, PacketId, version, objects::{StreamFormat, PartyCommand, Subscription}};
+
+    use crate::jobs::Scheduler;
+    use crate::party::Parties;
+    use crate::upload::Uploads;
+    use super::State;
+
+    /// Pseudo-random numbers with xorshift, reproducible for a seed, and the key of a stored track
+    struct Random(u64, TrackKey);
+
+    /// Number of variants of `RequestAction`
+    const VARIANTS: usize = 63;
+
+    /// Index of the variant of a request
+    ///
+    /// Every variant is listed, so that a new request is added to `Random::action` as well.
+    fn variant(action: &RequestAction) -> usize {
+        match action {
+            RequestAction::Search { .. } => 0,
+            RequestAction::GetTrack { .. } => 1,
+            RequestAction::StreamNext { .. } => 2,
+            RequestAction::StreamEnd => 3,
+            RequestAction::StreamSeek { .. } => 4,
+            RequestAction::UpdateTrack { .. } => 5,
+            RequestAction::GetSuggestion { .. } => 6,
+            RequestAction::AddPlaylist { .. } => 7,
+            RequestAction::DeletePlaylist { .. } => 8,
+            RequestAction::SetPlaylistImage { .. } => 9,
+            RequestAction::AddToPlaylist { .. } => 10,
+            RequestAction::DeleteFromPlaylist { .. } => 11,
+            RequestAction::UpdatePlaylist { .. } => 12,
+            RequestAction::GetPlaylists => 13,
+            RequestAction::GetPlaylist { .. } => 14,
+            RequestAction::GetPlaylistsOfTrack { .. } => 15,
+            RequestAction::DeleteTrack { .. } => 16,
+            RequestAction::UploadYoutube { .. } => 17,
+            RequestAction::UploadTrack { .. } => 18,
+            RequestAction::VoteForTrack { .. } => 19,
+            RequestAction::AskUploadProgress => 20,
+            RequestAction::GetToken { .. } => 21,
+            RequestAction::UpdateToken { .. } => 22,
+            RequestAction::CreateToken => 23,
+            RequestAction::LastToken => 24,
+            RequestAction::GetSummary => 25,
+            RequestAction::GetTransitions => 26,
+            RequestAction::Download { .. } => 27,
+            RequestAction::AskDownloadProgress => 28,
+            RequestAction::Login { .. } => 29,
+            RequestAction::Authenticate { .. } => 30,
+            RequestAction::Logout => 31,
+            RequestAction::ChangePassword { .. } => 32,
+            RequestAction::GetUsers => 33,
+            RequestAction::AddUser { .. } => 34,
+            RequestAction::DeleteUser { .. } => 35,
+            RequestAction::SetUserRole { .. } => 36,
+            RequestAction::GetVotes => 37,
+            RequestAction::Subscribe { .. } => 38,
+            RequestAction::Unsubscribe => 39,
+            RequestAction::GetJobs => 40,
+            RequestAction::CancelJob { .. } => 41,
+            RequestAction::RetryJob { .. } => 42,
+            RequestAction::BeginUpload { .. } => 43,
+            RequestAction::UploadChunk { .. } => 44,
+            RequestAction::GetUploadSession { .. } => 45,
+            RequestAction::CommitUpload { .. } => 46,
+            RequestAction::AbortUpload { .. } => 47,
+            RequestAction::CommitImport { .. } => 48,
+            RequestAction::ApplySuggestion { .. } => 49,
+            RequestAction::EnrichTracks => 50,
+            RequestAction::Hello { .. } => 51,
+            RequestAction::GetParties => 52,
+            RequestAction::CreateParty { .. } => 53,
+            RequestAction::JoinParty { .. } => 54,
+            RequestAction::LeaveParty => 55,
+            RequestAction::PartyCommand { .. } => 56,
+            RequestAction::GetQueue { .. } => 57,
+            RequestAction::Enqueue { .. } => 58,
+            RequestAction::ReorderQueue { .. } => 59,
+            RequestAction::Dequeue { .. } => 60,
+            RequestAction::ClearQueue => 61,
+            RequestAction::UpdateQueuePosition { .. } => 62
+        }
+    }
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            self.0
+        }
+
+        fn below(&mut self, max: u64) -> u64 {
+            self.next() % max
+        }
+
+        fn bytes(&mut self, max: u64) -> Vec<u8> {
+            let len = self.below(max);
+
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn string(&mut self) -> String {
+            let len = self.below(12);
+
+            (0..len).map(|_| (b'a' + self.below(26) as u8) as char).collect()
+        }
+
+        fn option<T, F: FnOnce(&mut Random) -> T>(&mut self, f: F) -> Option<T> {
+            if self.below(2) == 0 { None } else { Some(f(self)) }
+        }
+
+        /// Key of an unknown track
+        fn unknown(&mut self) -> TrackKey {
+            TrackKey::from_vec(&(0..16).map(|_| self.next() as u8).collect::<Vec<_>>())
+        }
+
+        /// Key of the stored track or an unknown one
+        fn key(&mut self) -> TrackKey {
+            if self.below(2) == 0 { self.1 } else { self.unknown() }
+        }
+
+        /// Names and passwords are taken from small sets, so that logins succeed
+        fn name(&mut self) -> String {
+            ["alice", "bob"][self.below(2) as usize].into()
+        }
+
+        fn password(&mut self) -> String {
+            ["secret", "other"][self.below(2) as usize].into()
+        }
+
+        fn role(&mut self) -> Role {
+            [Role::Listener, Role::Editor, Role::Admin][self.below(3) as usize]
+        }
+
+        /// Ids are taken from a small set, so that requests collide with pending ones
+        fn id(&mut self) -> PacketId {
+            [self.below(4) as u32, 0, 0, 0]
+        }
+
+        fn action(&mut self) -> RequestAction {
+            match self.below(VARIANTS as u64) {
+                0 => RequestAction::Search { query: self.string() },
+                1 => RequestAction::GetTrack { key: self.key() },
+                2 => RequestAction::StreamNext {
+                    key: self.option(|x| x.key()),
+                    binaural: self.below(2) == 0,
+                    format: if self.below(2) == 0 { StreamFormat::Pcm } else { StreamFormat::Opus }
+                },
+                3 => RequestAction::StreamEnd,
+                4 => RequestAction::StreamSeek { sample: self.below(96000) as u32 },
+                5 => RequestAction::UpdateTrack { key: self.key(), title: Some(self.string()), album: self.option(|x| x.string()), interpret: None, people: None, composer: self.option(|x| x.string()) },
+                6 => RequestAction::GetSuggestion { key: self.key() },
+                7 => RequestAction::AddPlaylist { name: self.string() },
+                8 => RequestAction::DeletePlaylist { key: self.below(4) as i64 },
+                9 => RequestAction::SetPlaylistImage { key: self.below(4) as i64, image: self.bytes(64) },
+                10 => RequestAction::AddToPlaylist { key: self.key(), playlist: self.below(4) as i64 },
+                11 => RequestAction::DeleteFromPlaylist { key: self.key(), playlist: self.below(4) as i64 },
+                12 => RequestAction::UpdatePlaylist { key: self.below(4) as i64, title: Some(self.string()), desc: self.option(|x| x.string()) },
+                13 => RequestAction::GetPlaylists,
+                14 => RequestAction::GetPlaylist { key: self.below(4) as i64 },
+                15 => RequestAction::GetPlaylistsOfTrack { key: self.key() },
+                // the stored track is kept for the other requests
+                16 => RequestAction::DeleteTrack { key: self.unknown() },
+                17 => RequestAction::UploadYoutube { path: self.string() },
+                18 => RequestAction::UploadTrack { name: self.string(), format: self.string(), data: self.bytes(64) },
+                19 => RequestAction::VoteForTrack { key: self.key() },
+                20 => RequestAction::AskUploadProgress,
+                21 => RequestAction::GetToken { token: self.below(4) as i64 },
+                22 => RequestAction::UpdateToken {
+                    token: self.below(4) as i64,
+                    key: self.option(|x| x.below(4) as i64),
+                    played: self.option(|x| vec![x.key()]),
+                    pos: self.option(|x| x.below(400) as f64)
+                },
+                23 => RequestAction::CreateToken,
+                24 => RequestAction::LastToken,
+                25 => RequestAction::GetSummary,
+                26 => RequestAction::GetTransitions,
+                27 => RequestAction::Download { format: ["mp3", "opus", "wav"][self.below(3) as usize].into(), tracks: vec![self.key()] },
+                28 => RequestAction::AskDownloadProgress,
+                29 => RequestAction::Login { name: self.name(), password: self.password() },
+                30 => RequestAction::Authenticate { token: self.string() },
+                31 => RequestAction::Logout,
+                32 => RequestAction::ChangePassword { password: self.password() },
+                33 => RequestAction::GetUsers,
+                34 => RequestAction::AddUser { name: self.name(), password: self.password(), role: self.role() },
+                35 => RequestAction::DeleteUser { name: self.name() },
+                36 => RequestAction::SetUserRole { name: self.name(), role: self.role() },
+                37 => RequestAction::GetVotes,
+                38 => RequestAction::Subscribe {
+                    filter: match self.below(3) {
+                        0 => Vec::new(),
+                        1 => vec![Subscription::Tracks, Subscription::Uploads],
+                        _ => vec![Subscription::Playlist(self.below(4) as i64)]
+                    },
+                    cursor: self.option(|x| x.below(8))
+                },
+                39 => RequestAction::Unsubscribe,
+                40 => RequestAction::GetJobs,
+                41 => RequestAction::CancelJob { id: self.below(4) as i64 },
+                42 => RequestAction::RetryJob { id: self.below(4) as i64 },
+                43 => RequestAction::BeginUpload { name: self.string(), format: self.string(), size: self.below(256), hash: self.string() },
+                44 => RequestAction::UploadChunk { id: self.string(), index: self.below(4) as u32, data: self.bytes(64) },
+                45 => RequestAction::GetUploadSession { id: self.string() },
+                46 => RequestAction::CommitUpload { id: self.string() },
+                47 => RequestAction::AbortUpload { id: self.string() },
+                48 => RequestAction::CommitImport { id: self.string(), playlists: self.below(2) == 0 },
+                49 => RequestAction::ApplySuggestion {
+                    key: self.key(),
+                    suggestion: Suggestion {
+                        source: "acoustid".into(),
+                        recording: None,
+                        title: self.option(|x| x.string()),
+                        album: self.option(|x| x.string()),
+                        interpret: self.option(|x| x.string()),
+                        composer: None,
+                        score: self.below(100) as f32 / 100.0
+                    }
+                },
+                50 => RequestAction::EnrichTracks,
+                51 => RequestAction::Hello { version: self.below(3) as u32, capabilities: vec![self.string(), "opus".into()] },
+                52 => RequestAction::GetParties,
+                53 => RequestAction::CreateParty { name: self.string() },
+                54 => RequestAction::JoinParty { id: self.below(3) as u32, output: self.below(2) == 0 },
+                55 => RequestAction::LeaveParty,
+                56 => RequestAction::PartyCommand {
+                    command: match self.below(9) {
+                        0 => PartyCommand::Queue(self.key()),
+                        1 => PartyCommand::Remove(self.key()),
+                        2 => PartyCommand::Vote(self.key()),
+                        3 => PartyCommand::Play,
+                        4 => PartyCommand::Pause,
+                        5 => PartyCommand::Skip,
+                        6 => PartyCommand::Seek(self.below(400) as f64 - 100.0),
+                        7 => PartyCommand::Position(self.below(400) as f64),
+                        _ => PartyCommand::Ended(self.key())
+                    }
+                },
+                57 => RequestAction::GetQueue { device: self.string() },
+                58 => RequestAction::Enqueue { tracks: vec![self.key()], index: self.option(|x| x.below(4) as u32) },
+                59 => RequestAction::ReorderQueue { order: (0..self.below(4) as u32).rev().collect() },
+                60 => RequestAction::Dequeue { index: self.below(4) as u32 },
+                61 => RequestAction::ClearQueue,
+                _ => RequestAction::UpdateQueuePosition { current: self.option(|x| x.below(4) as u32), pos: self.option(|x| x.below(400) as f64) }
+            }
+        }
+    }
+
+    /// Store a track of a second with its audio file
+    fn store_track(state: &State) -> TrackKey {
+        let mut track = Track::empty(vec![7, 8, 9], 1.0);
+        track.title = Some("Sine".into());
+
+        let pcm: Vec<i16> = (0..48000 * 2).map(|i| (((i / 2) as f64 * 0.05).sin() * 8000.0) as i16).collect();
+        fs::create_dir_all(&state.data_path).unwrap();
+        let file = File::create(state.data_path.join(track.key.to_path())).unwrap();
+        Container::save_pcm(Configuration::Stereo, pcm, file, None).unwrap();
+
+        state.write.add_track(track.clone()).unwrap();
+
+        track.key
+    }
+
+    fn state(core: &Core, path: &::std::path::Path) -> State {
+        let instance = Instance::from_file(&path.join("music.db"), GossipConf::new());
+        let accounts = Accounts::from_file(&path.join("music.db")).unwrap().with_iterations(1000);
+        let history = History::from_file(&path.join("music.db")).unwrap();
+        let queues = Queues::from_file(&path.join("music.db")).unwrap();
+        let playlists = Playlists::new();
+        // tests never look up metadata online
+        let metadata = hex_conf::Metadata { backends: Vec::new(), ..hex_conf::Metadata::default() };
+        let scheduler = Scheduler::new(core.handle(), path, instance.reader(), instance.writer(), playlists.clone(), 1, &metadata).unwrap();
+
+        State::new(core.handle(), path, instance.reader(), instance.writer(), instance.files(), playlists, accounts, queues, history, Rc::new(RefCell::new(scheduler)), Rc::new(RefCell::new(Parties::new())), Uploads::new(&path.join("data"), 1 << 30))
+    }
+
+    #[test]
+    fn invalid_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Core::new().unwrap();
+        let mut state = state(&core, dir.path());
+
+        let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::StreamSeek { sample: 0 })).unwrap();
+        match answer.msg {
+            Err(AnswerError::InvalidRequest(_)) => {},
+            x => panic!("Seek without stream answered with {:?}", x)
+        }
+
+        let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::StreamNext { key: None, binaural: false, format: StreamFormat::Pcm })).unwrap();
+        match answer.msg {
+            Err(AnswerError::InvalidRequest(_)) => {},
+            x => panic!("Stream without key answered with {:?}", x)
+        }
+
+        let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::GetTrack { key: TrackKey::from_vec(&[0; 16]) })).unwrap();
+        assert!(answer.msg.is_err());
+
+        // a search keeps its id, which can't be used by a stream
+        let answer = state.process_request(Request::new([2, 0, 0, 0], RequestAction::Search { query: String::new() })).unwrap();
+        match answer.msg {
+            Ok(AnswerAction::SearchResult { .. }) => {},
+            x => panic!("Search answered with {:?}", x)
+        }
+    }
+
+    #[test]
+    fn hello() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Core::new().unwrap();
+        let mut state = state(&core, dir.path());
+
+        let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::Hello { version: version::VERSION, capabilities: vec!["opus".into(), "unknown".into()] })).unwrap();
+        match answer.msg {
+            Ok(AnswerAction::Hello { version: negotiated, capabilities }) => {
+                assert_eq!(negotiated, version::VERSION);
+                assert_eq!(capabilities, vec!["opus".to_string()]);
+            },
+            x => panic!("Hello answered with {:?}", x)
+        }
+
+        let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::Hello { version: 0, capabilities: Vec::new() })).unwrap();
+        assert_eq!(answer.msg.err(), Some(AnswerError::Incompatible { version: 0, min_version: version::MIN_VERSION }));
+    }
+
+    #[test]
+    fn party() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Core::new().unwrap();
+        let mut state = state(&core, dir.path());
+        let updates = state.party_updates().unwrap();
+
+        let party = match state.process_request(Request::new([1, 0, 0, 0], RequestAction::CreateParty { name: "Kitchen".into() })).unwrap().msg {
+            Ok(AnswerAction::CreateParty(party)) => party,
+            x => panic!("CreateParty answered with {:?}", x)
+        };
+
+        let answer = state.process_request(Request::new([2, 0, 0, 0], RequestAction::JoinParty { id: party.id + 1, output: true })).unwrap();
+        assert_eq!(answer.msg.err(), Some(AnswerError::NotFound));
+
+        let answer = state.process_request(Request::new([2, 0, 0, 0], RequestAction::JoinParty { id: party.id, output: true })).unwrap();
+        match answer.msg {
+            Ok(AnswerAction::JoinParty(party)) => assert_eq!((party.members, party.output), (1, true)),
+            x => panic!("JoinParty answered with {:?}", x)
+        }
+
+        // only tracks of the library can be queued
+        let answer = state.process_request(Request::new([3, 0, 0, 0], RequestAction::PartyCommand { command: PartyCommand::Queue(TrackKey::from_vec(&[1; 16])) })).unwrap();
+        assert!(answer.msg.is_err());
+
+        let key = store_track(&state);
+        let answer = state.process_request(Request::new([3, 0, 0, 0], RequestAction::PartyCommand { command: PartyCommand::Queue(key) })).unwrap();
+        match answer.msg {
+            Ok(AnswerAction::PartyCommand(party)) => assert_eq!(party.queue.len(), 1),
+            x => panic!("PartyCommand answered with {:?}", x)
+        }
+
+        // the party ends with the connection
+        let parties = state.parties.clone();
+        drop(state);
+        assert!(parties.borrow().list().is_empty());
+
+        // changes of the own connection are not pushed
+        assert!(updates.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Core::new().unwrap();
+        let tracks = vec![Track::empty(vec![1], 1.0), Track::empty(vec![2], 1.0), Track::empty(vec![3], 1.0)];
+        let (a, b, c) = (tracks[0].key, tracks[1].key, tracks[2].key);
+
+        {
+            let mut state = state(&core, dir.path());
+            for track in tracks {
+                state.write.add_track(track).unwrap();
+            }
+
+            let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::Enqueue { tracks: vec![a], index: None })).unwrap();
+            assert!(answer.msg.is_err());
+
+            match state.process_request(Request::new([1, 0, 0, 0], RequestAction::GetQueue { device: "phone".into() })).unwrap().msg {
+                Ok(AnswerAction::GetQueue(queue)) => assert_eq!(queue, Queue::default()),
+                x => panic!("GetQueue answered with {:?}", x)
+            }
+
+            // only tracks of the library can be queued
+            let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::Enqueue { tracks: vec![a, TrackKey::from_vec(&[1; 16])], index: None })).unwrap();
+            assert!(answer.msg.is_err());
+
+            match state.process_request(Request::new([1, 0, 0, 0], RequestAction::Enqueue { tracks: vec![a, b, c], index: None })).unwrap().msg {
+                Ok(AnswerAction::Enqueue(queue)) => assert_eq!(queue.tracks, vec![a, b, c]),
+                x => panic!("Enqueue answered with {:?}", x)
+            }
+
+            let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::UpdateQueuePosition { current: Some(2), pos: Some(42.5) })).unwrap();
+            assert!(answer.msg.is_ok());
+
+            let answer = state.process_request(Request::new([1, 0, 0, 0], RequestAction::Dequeue { index: 3 })).unwrap();
+            assert_eq!(answer.msg.err(), Some(AnswerError::InvalidRequest("Invalid index of the queue".into())));
+        }
+
+        // a new connection of the same device continues at the same position, deleted tracks are dropped
+        let mut state = state(&core, dir.path());
+        state.write.delete_track(a).unwrap();
+        match state.process_request(Request::new([1, 0, 0, 0], RequestAction::GetQueue { device: "phone".into() })).unwrap().msg {
+            Ok(AnswerAction::GetQueue(queue)) => assert_eq!((queue.tracks, queue.current, queue.pos), (vec![b, c], Some(1), Some(42.5))),
+            x => panic!("GetQueue answered with {:?}", x)
+        }
+
+        // other devices have their own queue
+        match state.process_request(Request::new([1, 0, 0, 0], RequestAction::GetQueue { device: "laptop".into() })).unwrap().msg {
+            Ok(AnswerAction::GetQueue(queue)) => assert!(queue.tracks.is_empty()),
+            x => panic!("GetQueue answered with {:?}", x)
+        }
+    }
+
+    #[test]
+    fn random_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Core::new().unwrap();
+        let mut state = state(&core, dir.path());
+
+        let key = store_track(&state);
+        let mut random = Random(0x2545_f491_4f6c_dd1d, key);
+        let mut seen = vec![false; VARIANTS];
+        for _ in 0..2000 {
+            let req = Request::new(random.id(), random.action());
+            seen[variant(&req.msg)] = true;
+            // lookups of suggestions are answered by another thread
+            if let Some(answer) = state.process_request(req) {
+                assert!(answer.to_buf().is_ok());
+            }
+        }
+
+        assert!(seen.iter().all(|x| *x), "Not every request was generated");
+    }
+}
@@ -0,0 +1,917 @@
+//! Subsonic compatible REST API
+//!
+//! Many mobile and desktop players (DSub, Symfonium, Sonixd, ..) speak the Subsonic API. This
+//! module maps the most important calls onto the database, so that these players can browse and
+//! play the library. Calls are served at `/rest/<method>` (with or without the `.view` suffix) and
+//! answered in XML or, with the `f=json` parameter, in JSON.
+//!
+//! Hex has no notion of artists and albums, therefore they are derived from the `interpret` and
+//! `album` fields of the tracks. Their ids encode the names in hexadecimal, tracks are identified
+//! by their key and playlists by their number. A star is a vote for the track and `unstar` takes
+//! the vote of the user back.
+//!
+//! Once user accounts exist, every call has to carry the name and password of a user in the `u`
+//! and `p` parameters. Passwords are only stored hashed, therefore the token authentication of
+//! newer clients (`t` and `s` parameters) is not supported. Players repeat the credentials with
+//! every call, hence verified credentials are remembered for a minute, see `Credentials`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use serde_json::{Value, Map, json};
+
+use hex_database::{Reader, Writer, Accounts, User, Track, TrackKey, Playlist, PlaylistKey, search::SearchQuery};
+
+use crate::transcode::Format;
+use crate::playlists::Playlists;
+
+/// Version of the Subsonic API
+const API_VERSION: &str = "1.16.1";
+
+/// Name used for tracks without interpret or album
+const UNKNOWN: &str = "Unknown";
+
+/// Verified credentials are remembered for a minute
+const CREDENTIALS_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Error codes of the Subsonic API
+const ERROR_GENERIC: u32 = 0;
+const ERROR_MISSING_PARAMETER: u32 = 10;
+const ERROR_WRONG_CREDENTIALS: u32 = 40;
+const ERROR_TOKEN_AUTHENTICATION: u32 = 41;
+const ERROR_NOT_FOUND: u32 = 70;
+
+/// Access to the music library
+///
+/// This is implemented by the database and can be replaced in tests.
+pub trait Library {
+    /// All tracks in the library
+    fn tracks(&self) -> Vec<Track>;
+    /// A single track
+    fn track(&self, key: TrackKey) -> Option<Track>;
+    /// Search for tracks with a query
+    fn search(&self, query: &str) -> Vec<Track>;
+    /// All playlists in the library
+    fn playlists(&self) -> Vec<Playlist>;
+    /// A playlist with its tracks
+    fn playlist(&self, key: PlaylistKey) -> Option<(Playlist, Vec<Track>)>;
+    /// Vote for a track
+    fn vote(&self, key: TrackKey) -> bool;
+    /// Take back the vote for a track
+    fn unvote(&self, key: TrackKey) -> bool;
+    /// Create a new playlist and return its key
+    fn add_playlist(&self, title: String) -> Option<PlaylistKey>;
+    /// Update the title and tracks of a playlist
+    fn update_playlist(&self, key: PlaylistKey, title: Option<String>, tracks: Option<Vec<TrackKey>>) -> bool;
+}
+
+/// The database as library
+///
+/// Votes and new playlists are attributed to the user, if accounts are enabled.
+pub struct Database<'a> {
+    pub read: &'a Reader,
+    pub write: &'a Writer,
+    pub playlists: &'a Playlists,
+    pub accounts: &'a Accounts,
+    pub user: Option<&'a User>
+}
+
+impl<'a> Library for Database<'a> {
+    fn tracks(&self) -> Vec<Track> {
+        self.read.get_tracks()
+    }
+
+    fn track(&self, key: TrackKey) -> Option<Track> {
+        self.read.get_track(key).ok()
+    }
+
+    fn search(&self, query: &str) -> Vec<Track> {
+        match self.read.search_prep(SearchQuery::new(query)) {
+            Ok(mut query) => self.read.search(&mut query).collect(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    fn playlists(&self) -> Vec<Playlist> {
+        self.read.get_playlists()
+    }
+
+    fn playlist(&self, key: PlaylistKey) -> Option<(Playlist, Vec<Track>)> {
+        self.read.get_playlist(key).ok()
+    }
+
+    fn vote(&self, key: TrackKey) -> bool {
+        // every user votes only once for a track
+        let new_vote = match self.user {
+            Some(user) => self.accounts.vote(&user.name, key),
+            None => Ok(true)
+        };
+
+        match new_vote {
+            Ok(true) => self.write.vote_for_track(key).is_ok(),
+            Ok(false) => true,
+            Err(_) => false
+        }
+    }
+
+    fn unvote(&self, key: TrackKey) -> bool {
+        // only votes of the user are taken back
+        let removed = match self.user {
+            Some(user) => self.accounts.unvote(&user.name, key),
+            None => Ok(true)
+        };
+
+        match removed {
+            Ok(true) => self.write.unvote_for_track(key).is_ok(),
+            Ok(false) => true,
+            Err(_) => false
+        }
+    }
+
+    fn add_playlist(&self, title: String) -> Option<PlaylistKey> {
+        let key = self.playlists.create(self.read, self.write, title).ok()?.key;
+
+        if let Some(user) = self.user {
+            self.accounts.set_owner(key, &user.name).ok()?;
+        }
+
+        Some(key)
+    }
+
+    fn update_playlist(&self, key: PlaylistKey, title: Option<String>, tracks: Option<Vec<TrackKey>>) -> bool {
+        if let Some(user) = self.user {
+            if !self.accounts.may_modify(user, key) {
+                return false;
+            }
+        }
+
+        self.write.update_playlist(key, title, None, tracks).is_ok()
+    }
+}
+
+/// Check the credentials of a call
+///
+/// Returns the user of the call, or `None` if no account exists. Failed attempts are answered with
+/// the error reply.
+pub fn authenticate(accounts: &Accounts, params: &Params) -> Result<Option<User>, Reply> {
+    if !accounts.is_enabled() {
+        return Ok(None);
+    }
+
+    if params.get("t").is_some() {
+        return Err(Reply::Failed(ERROR_TOKEN_AUTHENTICATION, "Token authentication not supported, use the password instead".into()));
+    }
+
+    let name = params.get("u").ok_or_else(|| missing("u"))?;
+    let password = params.get("p").and_then(decode_password).ok_or_else(|| missing("p"))?;
+
+    accounts.check(name, &password)
+        .map(Some)
+        .map_err(|_| Reply::Failed(ERROR_WRONG_CREDENTIALS, "Wrong username or password".into()))
+}
+
+/// Cache of verified credentials, shared by all connections
+///
+/// Checking a password with PBKDF2 takes a noticeable time and players load covers and tracks with
+/// many parallel calls. Only a hash of the password is kept, a new password or role takes effect
+/// after at most `CREDENTIALS_LIFETIME`.
+#[derive(Clone, Default)]
+pub struct Credentials(Arc<Mutex<HashMap<(String, Vec<u8>), (User, Instant)>>>);
+
+impl Credentials {
+    /// Check the credentials of a call like `authenticate`, but answer from the cache if possible
+    ///
+    /// The accounts are only locked while checking them, never together with the database.
+    pub fn authenticate(&self, accounts: &Mutex<Accounts>, params: &Params) -> Result<Option<User>, Reply> {
+        let key = match (params.get("u"), params.get("p").and_then(decode_password)) {
+            (Some(name), Some(password)) if params.get("t").is_none() => (name.to_string(), Sha256::digest(password.as_bytes()).to_vec()),
+            _ => return authenticate(&accounts.lock().unwrap(), params)
+        };
+
+        {
+            let mut cache = self.0.lock().unwrap();
+            cache.retain(|_, x| x.1.elapsed() < CREDENTIALS_LIFETIME);
+
+            if let Some((user, _)) = cache.get(&key) {
+                return Ok(Some(user.clone()));
+            }
+        }
+
+        let user = authenticate(&accounts.lock().unwrap(), params)?;
+        if let Some(ref user) = user {
+            self.0.lock().unwrap().insert(key, (user.clone(), Instant::now()));
+        }
+
+        Ok(user)
+    }
+}
+
+/// Parameters of a call, parsed from the query string
+#[derive(Debug, Default)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// Parse an URL encoded query string
+    pub fn parse(query: &str) -> Params {
+        Params(query.split('&')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let mut parts = x.splitn(2, '=');
+                let key = parts.next().unwrap_or("");
+                let val = parts.next().unwrap_or("");
+
+                (percent_decode(key), percent_decode(val))
+            })
+            .collect())
+    }
+
+    /// Get the first value of a parameter
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|x| x.0 == name).map(|x| x.1.as_str())
+    }
+
+    /// Get all values of a repeated parameter
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0.iter().filter(|x| x.0 == name).map(|x| x.1.as_str()).collect()
+    }
+
+    /// Get a numeric parameter with a default value
+    fn get_num(&self, name: &str, default: usize) -> usize {
+        self.get(name).and_then(|x| x.parse().ok()).unwrap_or(default)
+    }
+
+    /// Check if the answer should be formatted as JSON
+    pub fn json(&self) -> bool {
+        self.get("f").map(|x| x.starts_with("json")).unwrap_or(false)
+    }
+}
+
+/// Decode a percent encoded string
+fn percent_decode(input: &str) -> String {
+    let input = input.as_bytes();
+    let mut out = Vec::with_capacity(input.len());
+
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < input.len() => {
+                match u8::from_str_radix(&String::from_utf8_lossy(&input[i+1..i+3]), 16) {
+                    Ok(x) => {
+                        out.push(x);
+                        i += 2;
+                    },
+                    Err(_) => out.push(b'%')
+                }
+            },
+            x => out.push(x)
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decode a password, which is either given in plain text or hex encoded with an `enc:` prefix
+fn decode_password(password: &str) -> Option<String> {
+    if !password.starts_with("enc:") {
+        return Some(password.to_string());
+    }
+
+    let hex = password.trim_start_matches("enc:");
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..hex.len() / 2)
+        .map(|i| hex.get(i*2..i*2+2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Result of a call
+#[derive(Debug)]
+pub enum Reply {
+    /// The call succeeded with a (possibly empty) payload
+    Ok(Value),
+    /// The call failed with an error code and message
+    Failed(u32, String),
+    /// The audio of a track should be sent
+    Stream(Track, Format)
+}
+
+/// Encode a string in hexadecimal
+fn to_hex(val: &str) -> String {
+    val.bytes().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Decode a string from hexadecimal
+fn from_hex(val: &str) -> Option<String> {
+    if val.len() % 2 != 0 {
+        return None;
+    }
+
+    let buf = (0..val.len() / 2)
+        .map(|i| u8::from_str_radix(val.get(i*2..i*2+2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(buf).ok()
+}
+
+fn artist_name(track: &Track) -> &str {
+    track.interpret.as_ref().map(|x| x.as_str()).unwrap_or(UNKNOWN)
+}
+
+fn album_name(track: &Track) -> &str {
+    track.album.as_ref().map(|x| x.as_str()).unwrap_or(UNKNOWN)
+}
+
+fn artist_id(artist: &str) -> String {
+    format!("ar-{}", to_hex(artist))
+}
+
+/// Albums are identified by the interpret and name, different interprets can have albums with
+/// the same name
+fn album_id(artist: &str, album: &str) -> String {
+    format!("al-{}", to_hex(&format!("{}\u{1f}{}", artist, album)))
+}
+
+/// Parse an album id to the interpret and name
+fn parse_album_id(id: &str) -> Option<(String, String)> {
+    if !id.starts_with("al-") {
+        return None;
+    }
+
+    let name = from_hex(&id[3..])?;
+    let mut parts = name.splitn(2, '\u{1f}');
+
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+/// Parse a track id, which is the key in hexadecimal
+fn parse_track_id(id: &str) -> Option<TrackKey> {
+    if id.len() == 32 && id.chars().all(|x| x.is_digit(16)) {
+        Some(TrackKey::from_str(id))
+    } else {
+        None
+    }
+}
+
+/// Describe a track as Subsonic song
+fn song(track: &Track) -> Value {
+    let (artist, album) = (artist_name(track), album_name(track));
+
+    let mut song = json!({
+        "id": track.key.to_string(),
+        "parent": album_id(artist, album),
+        "isDir": false,
+        "title": track.title.clone().unwrap_or_else(|| track.key.to_string()),
+        "album": album,
+        "artist": artist,
+        "duration": track.duration.round() as u64,
+        "bitRate": 128,
+        "suffix": Format::Ogg.extension(),
+        "contentType": Format::Ogg.mime(),
+        "type": "music",
+        "albumId": album_id(artist, album),
+        "artistId": artist_id(artist)
+    });
+
+    // the time of a vote isn't recorded
+    if track.favs_count > 0 {
+        song["starred"] = json!("1970-01-01T00:00:00.000Z");
+    }
+
+    song
+}
+
+/// An album with its tracks
+struct Album<'a> {
+    artist: &'a str,
+    name: &'a str,
+    tracks: Vec<&'a Track>
+}
+
+impl<'a> Album<'a> {
+    fn to_json(&self, with_songs: bool) -> Value {
+        let mut album = json!({
+            "id": album_id(self.artist, self.name),
+            "name": self.name,
+            "artist": self.artist,
+            "artistId": artist_id(self.artist),
+            "songCount": self.tracks.len(),
+            "duration": self.tracks.iter().map(|x| x.duration).sum::<f64>().round() as u64
+        });
+
+        if with_songs {
+            album["song"] = self.tracks.iter().map(|x| song(x)).collect();
+        }
+
+        album
+    }
+}
+
+/// Group tracks into albums of each interpret, sorted by name
+fn albums(tracks: &[Track]) -> BTreeMap<&str, BTreeMap<&str, Album<'_>>> {
+    let mut artists: BTreeMap<&str, BTreeMap<&str, Album<'_>>> = BTreeMap::new();
+
+    for track in tracks {
+        let (artist, name) = (artist_name(track), album_name(track));
+
+        artists.entry(artist).or_insert_with(BTreeMap::new)
+            .entry(name).or_insert_with(|| Album { artist, name, tracks: Vec::new() })
+            .tracks.push(track);
+    }
+
+    artists
+}
+
+fn artist_json(name: &str, albums: &BTreeMap<&str, Album<'_>>) -> Value {
+    json!({
+        "id": artist_id(name),
+        "name": name,
+        "albumCount": albums.len()
+    })
+}
+
+/// Describe a playlist, optionally with all of its tracks
+fn playlist_json(playlist: &Playlist, tracks: Option<&[Track]>) -> Value {
+    let mut json = json!({
+        "id": playlist.key.to_string(),
+        "name": playlist.title,
+        "songCount": playlist.tracks.len(),
+        "public": true,
+        "owner": "hex"
+    });
+
+    if let Some(ref desc) = playlist.desc {
+        json["comment"] = json!(desc);
+    }
+
+    if let Some(tracks) = tracks {
+        json["duration"] = json!(tracks.iter().map(|x| x.duration).sum::<f64>().round() as u64);
+        json["entry"] = tracks.iter().map(song).collect();
+    }
+
+    json
+}
+
+fn missing(name: &str) -> Reply {
+    Reply::Failed(ERROR_MISSING_PARAMETER, format!("Required parameter '{}' is missing", name))
+}
+
+fn not_found(what: &str) -> Reply {
+    Reply::Failed(ERROR_NOT_FOUND, format!("{} not found", what))
+}
+
+/// Execute a single call of the API
+pub fn handle<L: Library>(lib: &L, method: &str, params: &Params) -> Reply {
+    match method {
+        "ping" => Reply::Ok(json!({})),
+        "getLicense" => Reply::Ok(json!({"license": {"valid": true}})),
+        "getMusicFolders" => Reply::Ok(json!({"musicFolders": {"musicFolder": [{"id": 1, "name": "Hex"}]}})),
+        "getArtists" | "getIndexes" => {
+            let tracks = lib.tracks();
+
+            // group the artists by their first letter
+            let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+            for (name, albums) in albums(&tracks) {
+                let letter = name.chars().next().map(|x| x.to_uppercase().collect()).unwrap_or_else(|| "#".into());
+
+                index.entry(letter).or_insert_with(Vec::new).push(artist_json(name, &albums));
+            }
+
+            let index: Vec<Value> = index.into_iter()
+                .map(|(name, artists)| json!({"name": name, "artist": artists}))
+                .collect();
+
+            let name = if method == "getArtists" { "artists" } else { "indexes" };
+            Reply::Ok(json!({name: {"ignoredArticles": "", "index": index}}))
+        },
+        "getArtist" => {
+            let name = match params.get("id") {
+                Some(id) if id.starts_with("ar-") => from_hex(&id[3..]),
+                Some(_) => None,
+                None => return missing("id")
+            };
+
+            let tracks = lib.tracks();
+            let artists = albums(&tracks);
+
+            match name.as_ref().and_then(|x| artists.get(x.as_str()).map(|albums| (x, albums))) {
+                Some((name, albums)) => {
+                    let mut artist = artist_json(name, albums);
+                    artist["album"] = albums.values().map(|x| x.to_json(false)).collect();
+
+                    Reply::Ok(json!({"artist": artist}))
+                },
+                None => not_found("Artist")
+            }
+        },
+        "getAlbum" => {
+            let id = match params.get("id") {
+                Some(id) => parse_album_id(id),
+                None => return missing("id")
+            };
+
+            let tracks = lib.tracks();
+            let artists = albums(&tracks);
+
+            let album = id.as_ref().and_then(|(artist, name)| {
+                artists.get(artist.as_str()).and_then(|x| x.get(name.as_str()))
+            });
+
+            match album {
+                Some(album) => Reply::Ok(json!({"album": album.to_json(true)})),
+                None => not_found("Album")
+            }
+        },
+        "getSong" => {
+            match params.get("id").map(|x| parse_track_id(x).and_then(|key| lib.track(key))) {
+                Some(Some(track)) => Reply::Ok(json!({"song": song(&track)})),
+                Some(None) => not_found("Song"),
+                None => missing("id")
+            }
+        },
+        "search2" | "search3" => {
+            let query = match params.get("query") {
+                Some(query) => query.trim_matches('"'),
+                None => return missing("query")
+            };
+
+            // an empty query is used by some clients to synchronise the whole library
+            let songs = if query.is_empty() { lib.tracks() } else { lib.search(query) };
+            let songs: Vec<Value> = songs.iter()
+                .skip(params.get_num("songOffset", 0))
+                .take(params.get_num("songCount", 20))
+                .map(song)
+                .collect();
+
+            let tracks = lib.tracks();
+            let artists = albums(&tracks);
+            let query = query.to_lowercase();
+
+            let artist: Vec<Value> = artists.iter()
+                .filter(|(name, _)| name.to_lowercase().contains(&query))
+                .skip(params.get_num("artistOffset", 0))
+                .take(params.get_num("artistCount", 20))
+                .map(|(name, albums)| artist_json(name, albums))
+                .collect();
+
+            let album: Vec<Value> = artists.values()
+                .flat_map(|x| x.values())
+                .filter(|x| x.name.to_lowercase().contains(&query))
+                .skip(params.get_num("albumOffset", 0))
+                .take(params.get_num("albumCount", 20))
+                .map(|x| x.to_json(false))
+                .collect();
+
+            let name = if method == "search3" { "searchResult3" } else { "searchResult2" };
+            Reply::Ok(json!({name: {"artist": artist, "album": album, "song": songs}}))
+        },
+        "getStarred" | "getStarred2" => {
+            let songs: Vec<Value> = lib.tracks().iter()
+                .filter(|x| x.favs_count > 0)
+                .map(song)
+                .collect();
+
+            let name = if method == "getStarred2" { "starred2" } else { "starred" };
+            Reply::Ok(json!({name: {"song": songs}}))
+        },
+        "star" | "unstar" => {
+            let ids = params.get_all("id");
+            if ids.is_empty() && params.get("albumId").is_none() && params.get("artistId").is_none() {
+                return missing("id");
+            }
+
+            for id in ids {
+                let done = match parse_track_id(id) {
+                    Some(key) if method == "star" => lib.vote(key),
+                    Some(key) => lib.unvote(key),
+                    None => false
+                };
+
+                if !done {
+                    return not_found("Song");
+                }
+            }
+
+            Reply::Ok(json!({}))
+        },
+        "getPlaylists" => {
+            let playlists: Vec<Value> = lib.playlists().iter()
+                .map(|x| playlist_json(x, None))
+                .collect();
+
+            Reply::Ok(json!({"playlists": {"playlist": playlists}}))
+        },
+        "getPlaylist" => {
+            match params.get("id").map(|x| x.parse().ok().and_then(|key| lib.playlist(key))) {
+                Some(Some((playlist, tracks))) => Reply::Ok(json!({"playlist": playlist_json(&playlist, Some(&tracks))})),
+                Some(None) => not_found("Playlist"),
+                None => missing("id")
+            }
+        },
+        "createPlaylist" => {
+            let tracks = match params.get_all("songId").into_iter().map(parse_track_id).collect::<Option<Vec<TrackKey>>>() {
+                Some(tracks) => tracks,
+                None => return not_found("Song")
+            };
+
+            // either update an existing playlist or create a new one
+            let (key, title) = match (params.get("playlistId"), params.get("name")) {
+                (Some(key), name) => match key.parse() {
+                    Ok(key) => (key, name.map(|x| x.to_string())),
+                    Err(_) => return not_found("Playlist")
+                },
+                (None, Some(name)) => match lib.add_playlist(name.to_string()) {
+                    Some(key) => (key, None),
+                    None => return Reply::Failed(ERROR_GENERIC, "Could not create playlist".into())
+                },
+                (None, None) => return missing("name")
+            };
+
+            if !lib.update_playlist(key, title, Some(tracks)) {
+                return not_found("Playlist");
+            }
+
+            match lib.playlist(key) {
+                Some((playlist, tracks)) => Reply::Ok(json!({"playlist": playlist_json(&playlist, Some(&tracks))})),
+                None => not_found("Playlist")
+            }
+        },
+        "stream" | "download" => {
+            let track = match params.get("id") {
+                Some(id) => parse_track_id(id).and_then(|key| lib.track(key)),
+                None => return missing("id")
+            };
+
+            let format = match params.get("format") {
+                Some("mp3") => Format::Mp3,
+                Some("flac") => Format::Flac,
+                _ => Format::Ogg
+            };
+
+            match track {
+                Some(track) => Reply::Stream(track, format),
+                None => not_found("Song")
+            }
+        },
+        _ => Reply::Failed(ERROR_GENERIC, format!("Method '{}' is not supported", method))
+    }
+}
+
+/// Escape a string for XML attributes
+fn escape(val: &str) -> String {
+    val.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Convert a JSON value to XML elements
+///
+/// Subsonic uses a direct mapping between both: scalar fields become attributes, objects become
+/// child elements and arrays repeated child elements.
+fn to_xml(name: &str, val: &Value, out: &mut String) {
+    match val {
+        Value::Array(items) => {
+            for item in items {
+                to_xml(name, item, out);
+            }
+        },
+        Value::Object(fields) => {
+            out.push_str(&format!("<{}", name));
+            for (key, val) in fields {
+                match val {
+                    Value::Array(_) | Value::Object(_) => {},
+                    Value::String(x) => out.push_str(&format!(" {}=\"{}\"", key, escape(x))),
+                    x => out.push_str(&format!(" {}=\"{}\"", key, x))
+                }
+            }
+
+            let children: Vec<_> = fields.iter()
+                .filter(|(_, val)| val.is_array() || val.is_object())
+                .collect();
+
+            if children.is_empty() {
+                out.push_str("/>");
+            } else {
+                out.push('>');
+                for (key, val) in children {
+                    to_xml(key, val, out);
+                }
+                out.push_str(&format!("</{}>", name));
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Render the reply of a call to its content type and body
+///
+/// Replies to be streamed have to be handled by the caller.
+pub fn render(reply: &Reply, json: bool) -> (&'static str, String) {
+    let mut response = Map::new();
+
+    match reply {
+        Reply::Ok(Value::Object(payload)) => {
+            response.insert("status".into(), json!("ok"));
+            response.insert("version".into(), json!(API_VERSION));
+            response.extend(payload.clone());
+        },
+        Reply::Failed(code, message) => {
+            response.insert("status".into(), json!("failed"));
+            response.insert("version".into(), json!(API_VERSION));
+            response.insert("error".into(), json!({"code": code, "message": message}));
+        },
+        _ => {
+            response.insert("status".into(), json!("ok"));
+            response.insert("version".into(), json!(API_VERSION));
+        }
+    }
+
+    if json {
+        ("application/json", json!({"subsonic-response": response}).to_string())
+    } else {
+        response.insert("xmlns".into(), json!("http://subsonic.org/restapi"));
+
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        to_xml("subsonic-response", &Value::Object(response), &mut out);
+
+        ("text/xml; charset=utf-8", out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use serde_json::{self, Value};
+    use std::sync::Mutex;
+
+    use hex_database::{Accounts, Role, Track, TrackKey, Playlist, PlaylistKey};
+
+    use super::{Library, Params, Reply, Credentials, handle, render, percent_decode, decode_password};
+
+    /// Library held in memory, loaded from a fixture
+    struct Fixture {
+        tracks: Vec<Track>,
+        playlists: RefCell<Vec<Playlist>>
+    }
+
+    impl Fixture {
+        fn load() -> Fixture {
+            let library: Value = serde_json::from_str(include_str!("../tests/fixtures/subsonic/library.json")).unwrap();
+
+            Fixture {
+                tracks: serde_json::from_value(library["tracks"].clone()).unwrap(),
+                playlists: RefCell::new(serde_json::from_value(library["playlists"].clone()).unwrap())
+            }
+        }
+    }
+
+    impl Library for Fixture {
+        fn tracks(&self) -> Vec<Track> {
+            self.tracks.clone()
+        }
+
+        fn track(&self, key: TrackKey) -> Option<Track> {
+            self.tracks.iter().find(|x| x.key == key).cloned()
+        }
+
+        fn search(&self, query: &str) -> Vec<Track> {
+            self.tracks.iter()
+                .filter(|x| x.title.as_ref().map(|x| x.to_lowercase().contains(&query.to_lowercase())).unwrap_or(false))
+                .cloned()
+                .collect()
+        }
+
+        fn playlists(&self) -> Vec<Playlist> {
+            self.playlists.borrow().clone()
+        }
+
+        fn playlist(&self, key: PlaylistKey) -> Option<(Playlist, Vec<Track>)> {
+            self.playlists.borrow().iter().find(|x| x.key == key).map(|x| {
+                let tracks = x.tracks.iter().filter_map(|key| self.track(*key)).collect();
+
+                (x.clone(), tracks)
+            })
+        }
+
+        fn vote(&self, key: TrackKey) -> bool {
+            self.track(key).is_some()
+        }
+
+        fn unvote(&self, key: TrackKey) -> bool {
+            self.track(key).is_some()
+        }
+
+        fn add_playlist(&self, title: String) -> Option<PlaylistKey> {
+            let key = self.playlists.borrow().iter().map(|x| x.key).max().unwrap_or(0) + 1;
+            self.playlists.borrow_mut().push(Playlist { key, title, desc: None, tracks: Vec::new(), origin: Vec::new(), image: None });
+
+            Some(key)
+        }
+
+        fn update_playlist(&self, key: PlaylistKey, title: Option<String>, tracks: Option<Vec<TrackKey>>) -> bool {
+            match self.playlists.borrow_mut().iter_mut().find(|x| x.key == key) {
+                Some(playlist) => {
+                    if let Some(title) = title {
+                        playlist.title = title;
+                    }
+                    if let Some(tracks) = tracks {
+                        playlist.tracks = tracks;
+                    }
+
+                    true
+                },
+                None => false
+            }
+        }
+    }
+
+    /// Replay a recorded request and compare the response
+    fn replay(fixture: &str) {
+        let fixture: Value = serde_json::from_str(fixture).unwrap();
+        let library = Fixture::load();
+
+        let request = fixture["request"].as_str().unwrap();
+        let mut parts = request.splitn(2, '?');
+        let method = parts.next().unwrap().trim_start_matches("/rest/").trim_end_matches(".view");
+        let params = Params::parse(parts.next().unwrap_or(""));
+
+        let reply = handle(&library, method, &params);
+        let (_, body) = render(&reply, params.json());
+
+        match fixture["response"] {
+            Value::String(ref expected) => assert_eq!(&body, expected, "{}", request),
+            ref expected => assert_eq!(&serde_json::from_str::<Value>(&body).unwrap(), expected, "{}", request)
+        }
+    }
+
+    #[test]
+    fn fixtures() {
+        replay(include_str!("../tests/fixtures/subsonic/ping.json"));
+        replay(include_str!("../tests/fixtures/subsonic/getArtists.json"));
+        replay(include_str!("../tests/fixtures/subsonic/getAlbum.json"));
+        replay(include_str!("../tests/fixtures/subsonic/search3.json"));
+        replay(include_str!("../tests/fixtures/subsonic/getPlaylists.json"));
+        replay(include_str!("../tests/fixtures/subsonic/star.json"));
+        replay(include_str!("../tests/fixtures/subsonic/unstar.json"));
+        replay(include_str!("../tests/fixtures/subsonic/createPlaylist.json"));
+        replay(include_str!("../tests/fixtures/subsonic/missingParameter.json"));
+    }
+
+    #[test]
+    fn stream() {
+        let library = Fixture::load();
+        let params = Params::parse("id=00112233445566778899AABBCCDDEEFF&format=mp3&u=user");
+
+        match handle(&library, "stream", &params) {
+            Reply::Stream(track, format) => {
+                assert_eq!(track.key, library.tracks[0].key);
+                assert_eq!(format, crate::transcode::Format::Mp3);
+            },
+            reply => panic!("unexpected reply {:?}", reply)
+        }
+    }
+
+    #[test]
+    fn credentials() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let accounts = Mutex::new(Accounts::from_file(file.path()).unwrap().with_iterations(1000));
+        let credentials = Credentials::default();
+
+        // without accounts nothing is cached
+        assert_eq!(credentials.authenticate(&accounts, &Params::parse("u=alice&p=secret")).unwrap(), None);
+        accounts.lock().unwrap().add_user("alice", "secret", Role::Listener).unwrap();
+
+        assert!(credentials.authenticate(&accounts, &Params::parse("u=alice&p=wrong")).is_err());
+        assert!(credentials.authenticate(&accounts, &Params::parse("u=alice")).is_err());
+        assert_eq!(credentials.authenticate(&accounts, &Params::parse("u=alice&p=secret")).unwrap().unwrap().name, "alice");
+
+        // verified credentials are not checked again, the encoding of the password doesn't matter
+        accounts.lock().unwrap().set_password("alice", "other").unwrap();
+        assert!(credentials.authenticate(&accounts, &Params::parse("u=alice&p=enc:736563726574")).is_ok());
+        assert!(credentials.authenticate(&accounts, &Params::parse("u=alice&p=wrong")).is_err());
+        assert!(credentials.authenticate(&accounts, &Params::parse("u=bob&p=secret")).is_err());
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(percent_decode("Daft+Punk%20%26%20Friends"), "Daft Punk & Friends");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%C3%A4"), "ä");
+    }
+
+    #[test]
+    fn password() {
+        assert_eq!(decode_password("sesame"), Some("sesame".into()));
+        assert_eq!(decode_password("enc:736573616d65"), Some("sesame".into()));
+        assert_eq!(decode_password("enc:7365736"), None);
+        assert_eq!(decode_password("enc:zz"), None);
+    }
+}
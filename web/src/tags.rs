@@ -0,0 +1,186 @@
+//! Embed the metadata of a track in exported audio files
+//!
+//! `ffmpeg` can write text tags, but needs a second input for cover images. Instead the tags of
+//! MP3 files are written as ID3v2.4 header in front of the audio with the `id3` crate, FLAC files
+//! get an additional picture block and Ogg Opus files carry the picture block in a comment. In all
+//! cases the transcoded audio is streamed without temporary files.
+
+use std::io::{self, Read, Write};
+
+use id3::{Tag, TagLike, Version};
+use id3::frame::{Picture, PictureType};
+
+use hex_database::Track;
+
+/// Picture type of the front cover in FLAC picture blocks
+const FRONT_COVER: u8 = 3;
+
+/// FLAC metadata block type of a picture
+const FLAC_PICTURE: u8 = 6;
+
+/// Guess the MIME type of a cover image from its signature
+pub fn image_mime(image: &[u8]) -> &'static str {
+    if image.starts_with(b"\x89PNG") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Text tags of a track as pairs of `ffmpeg` metadata keys and values
+///
+/// `number` is the position of the track in the export.
+pub fn text_tags(track: &Track, number: usize) -> Vec<(&'static str, String)> {
+    let mut tags: Vec<(&'static str, String)> = vec![("title", &track.title), ("album", &track.album), ("artist", &track.interpret), ("composer", &track.composer)]
+        .into_iter()
+        .filter_map(|(name, val)| val.as_ref().map(|x| (name, x.clone())))
+        .collect();
+
+    tags.push(("track", number.to_string()));
+
+    tags
+}
+
+/// Write an ID3v2.4 tag with the text tags and an optional cover
+pub fn write_id3<W: Write>(out: W, track: &Track, number: usize, cover: Option<&[u8]>) -> io::Result<()> {
+    let mut tag = Tag::new();
+
+    for (name, value) in text_tags(track, number) {
+        match name {
+            "title" => tag.set_title(value),
+            "album" => tag.set_album(value),
+            "artist" => tag.set_artist(value),
+            "composer" => tag.set_text("TCOM", value),
+            _ => tag.set_track(number as u32)
+        }
+    }
+
+    if let Some(cover) = cover {
+        tag.add_frame(Picture {
+            mime_type: image_mime(cover).into(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.to_vec()
+        });
+    }
+
+    tag.write_to(out, Version::Id3v24)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+/// Content of a FLAC picture block with a front cover
+///
+/// Ogg files embed the same structure encoded in Base64 as `METADATA_BLOCK_PICTURE` comment.
+pub fn flac_picture(cover: &[u8]) -> Vec<u8> {
+    let mime = image_mime(cover);
+
+    let mut block = Vec::with_capacity(32 + mime.len() + cover.len());
+    block.extend_from_slice(&(FRONT_COVER as u32).to_be_bytes());
+    block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    block.extend_from_slice(mime.as_bytes());
+    // no description, the dimensions and colors are unknown
+    block.extend_from_slice(&[0; 20]);
+    block.extend_from_slice(&(cover.len() as u32).to_be_bytes());
+    block.extend_from_slice(cover);
+
+    block
+}
+
+/// Copy a FLAC stream and insert a picture block after the existing metadata blocks
+pub fn copy_flac<R: Read, W: Write>(mut from: R, to: &mut W, cover: Option<&[u8]>) -> io::Result<u64> {
+    // the size of a metadata block is limited to 24 bits
+    let picture = match cover.map(flac_picture) {
+        Some(ref picture) if picture.len() > 0xffffff => return io::copy(&mut from, to),
+        Some(picture) => picture,
+        None => return io::copy(&mut from, to)
+    };
+
+    let mut marker = [0u8; 4];
+    from.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a FLAC stream"));
+    }
+
+    to.write_all(&marker)?;
+    let mut written = 4;
+
+    // the last metadata block is marked by the highest bit of its header
+    loop {
+        let mut header = [0u8; 4];
+        from.read_exact(&mut header)?;
+
+        let last = header[0] & 0x80 != 0;
+        let len = (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+
+        let mut block = vec![0u8; len];
+        from.read_exact(&mut block)?;
+
+        header[0] &= 0x7f;
+        to.write_all(&header)?;
+        to.write_all(&block)?;
+        written += 4 + len as u64;
+
+        if last {
+            break;
+        }
+    }
+
+    let len = picture.len();
+    to.write_all(&[0x80 | FLAC_PICTURE, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    to.write_all(&picture)?;
+    written += 4 + len as u64;
+
+    Ok(written + io::copy(&mut from, to)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_database::{Track, TrackKey};
+    use std::io::Cursor;
+    use id3::{Tag, TagLike};
+    use super::{write_id3, copy_flac, flac_picture};
+
+    fn track() -> Track {
+        Track {
+            key: TrackKey::from_vec(&[0; 16]),
+            fingerprint: Vec::new(),
+            title: Some("Süß".into()),
+            album: None,
+            interpret: Some("Band".into()),
+            people: None,
+            composer: None,
+            duration: 1.0,
+            favs_count: 0
+        }
+    }
+
+    #[test]
+    fn id3_frames() {
+        let mut buf = Vec::new();
+        write_id3(&mut buf, &track(), 7, Some(b"\x89PNG")).unwrap();
+        assert_eq!(&buf[..5], b"ID3\x04\x00");
+
+        let tag = Tag::read_from2(Cursor::new(&buf)).unwrap();
+        assert_eq!(tag.title(), Some("Süß"));
+        assert_eq!(tag.artist(), Some("Band"));
+        assert_eq!((tag.album(), tag.track()), (None, Some(7)));
+
+        let picture = tag.pictures().next().unwrap();
+        assert_eq!((picture.mime_type.as_str(), &picture.data[..]), ("image/png", &b"\x89PNG"[..]));
+    }
+
+    #[test]
+    fn insert_picture() {
+        let mut stream = b"fLaC\x80\x00\x00\x02ab".to_vec();
+        stream.extend_from_slice(b"frames");
+
+        let mut out = Vec::new();
+        copy_flac(&stream[..], &mut out, Some(b"jpeg")).unwrap();
+
+        let picture = flac_picture(b"jpeg");
+        assert_eq!(&out[..10], b"fLaC\x00\x00\x00\x02ab");
+        assert_eq!(&out[10..14], &[0x86, 0, 0, picture.len() as u8]);
+        assert_eq!(&out[14..14 + picture.len()], &picture[..]);
+        assert_eq!(&out[14 + picture.len()..], b"frames");
+    }
+}
@@ -0,0 +1,138 @@
+//! TLS configuration of the websocket server and webserver
+//!
+//! Both servers share the same certificate. If a client authority is configured, only clients
+//! presenting a certificate signed by it can connect.
+//!
+//! A client has `HANDSHAKE_TIMEOUT` to finish its handshake. Handshakes run concurrently without
+//! limit, so that stalled clients can't hold back others.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream, sync::mpsc};
+use tokio::timer::Timeout;
+
+use rustls::{ServerConfig, NoClientAuth, AllowAnyAuthenticatedClient, RootCertStore, Certificate, PrivateKey};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::TlsAcceptor;
+
+use hex_conf::Tls;
+
+use crate::error::{Result, Error};
+
+/// Time a client has to finish the TLS handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a PEM file
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::Io(err))
+}
+
+/// Load a certificate chain
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    certs(&mut open(path)?)
+        .map_err(|_| Error::Tls(format!("Invalid certificate in {}", path.display())))
+}
+
+/// Load the first private key, either in PKCS8 or RSA format
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys = pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| Error::Tls(format!("Invalid private key in {}", path.display())))?;
+
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?)
+            .map_err(|_| Error::Tls(format!("Invalid private key in {}", path.display())))?;
+    }
+
+    keys.into_iter().next()
+        .ok_or_else(|| Error::Tls(format!("No private key found in {}", path.display())))
+}
+
+/// Create an acceptor of TLS connections from the configuration
+pub fn acceptor(conf: &Tls) -> Result<TlsAcceptor> {
+    let mut config = match conf.client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            roots.add_pem_file(&mut open(path)?)
+                .map_err(|_| Error::Tls(format!("Invalid client authority in {}", path.display())))?;
+
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        },
+        None => ServerConfig::new(NoClientAuth::new())
+    };
+
+    config.set_single_cert(load_certificates(&conf.certificate)?, load_key(&conf.key)?)
+        .map_err(|err| Error::Tls(format!("{:?}", err)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Perform the handshakes of incoming connections and yield the finished ones
+///
+/// Every handshake runs in its own task and is dropped after `timeout`, as are failed handshakes.
+/// Has to be called within a Tokio runtime.
+pub fn handshakes<I, H, F>(incoming: I, handshake: H, timeout: Duration) -> impl Stream<Item = F::Item, Error = io::Error>
+    where I: Stream + Send + 'static,
+          I::Error: Debug,
+          H: Fn(I::Item) -> F + Send + 'static,
+          F: Future + Send + 'static,
+          F::Item: Send + 'static,
+          F::Error: Debug
+{
+    let (sender, recv) = mpsc::unbounded();
+
+    let accept = incoming
+        .then(|x| Ok::<_, ()>(x.map_err(|err| info!("Could not accept connection: {:?}", err)).ok()))
+        .filter_map(|x| x)
+        .for_each(move |stream| {
+            let sender = sender.clone();
+
+            tokio::spawn(Timeout::new(handshake(stream), timeout)
+                .map(move |stream| { let _ = sender.unbounded_send(stream); })
+                .map_err(|err| info!("TLS handshake failed: {:?}", err)));
+
+            Ok(())
+        });
+
+    tokio::spawn(accept);
+
+    recv.map_err(|_| io::Error::new(io::ErrorKind::Other, "handshakes stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use futures::{Future, Stream, future, stream};
+    use tokio::runtime::Runtime;
+
+    use super::handshakes;
+
+    #[test]
+    fn stalled_handshake() {
+        // the first client never finishes its handshake
+        let incoming = stream::iter_ok::<_, io::Error>(vec![0, 1, 2]);
+        let handshake = |x: u32| -> Box<dyn Future<Item = u32, Error = io::Error> + Send> {
+            if x == 0 {
+                Box::new(future::empty())
+            } else {
+                Box::new(future::ok(x))
+            }
+        };
+
+        let mut runtime = Runtime::new().unwrap();
+        let accepted = runtime.block_on(future::lazy(move || {
+            handshakes(incoming, handshake, Duration::from_millis(100)).collect()
+        })).unwrap();
+
+        // the stream ends, once the stalled handshake is dropped
+        assert_eq!(accepted, vec![1, 2]);
+    }
+}
@@ -0,0 +1,349 @@
+//! Transcode tracks for players outside of the websocket protocol
+//!
+//! Most players understand neither the container nor the websocket protocol, but play plain audio
+//! files over HTTP. The Ogg Opus format is created natively by rendering the track to stereo,
+//! encoding it again and multiplexing the packets into Ogg pages. The result is cached in the
+//! `cache` directory, so that the file can be served with HTTP range requests. All other formats
+//! are piped through `ffmpeg` and streamed while transcoding.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::fs::{self, File};
+use std::thread;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::channel;
+use hyper::{Body, Chunk};
+use tempfile::NamedTempFile;
+use sha2::{Digest, Sha256};
+
+use hex_database::Track;
+use hex_music_container::{Container, Configuration, Normalization, error::Error as MusicError};
+
+use crate::error::{Result, Error};
+use crate::tags;
+
+/// Bitrate of the Ogg Opus files in bits per second
+const OPUS_BITRATE: i32 = 128000;
+
+/// Samples at the start of the stream which are discarded by the decoder (lookahead of the
+/// encoder)
+const PRE_SKIP: u16 = 312;
+
+/// Number of samples in each Opus packet
+const PACKET_SIZE: u64 = 1920;
+
+/// Number of Opus packets in a single Ogg page (about one second)
+const PACKETS_PER_PAGE: usize = 25;
+
+/// Larger covers are left out of Ogg files, the comment header has to fit in a single page
+const MAX_OGG_COVER: usize = 45 * 1024;
+
+/// Audio format of a transcoded track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Ogg,
+    Mp3,
+    Flac
+}
+
+impl Format {
+    /// Parse the format from a file extension
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match ext {
+            "ogg" | "opus" => Some(Format::Ogg),
+            "mp3" => Some(Format::Mp3),
+            "flac" => Some(Format::Flac),
+            _ => None
+        }
+    }
+
+    /// File extension of the format
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Ogg => "ogg",
+            Format::Mp3 => "mp3",
+            Format::Flac => "flac"
+        }
+    }
+
+    /// MIME type of the format
+    pub fn mime(&self) -> &'static str {
+        match *self {
+            Format::Ogg => "audio/ogg",
+            Format::Mp3 => "audio/mpeg",
+            Format::Flac => "audio/flac"
+        }
+    }
+}
+
+/// Lookup table of the Ogg checksum (CRC32 with polynomial 0x04c11db7, not reflected)
+fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for i in 0..256 {
+        let mut r = (i as u32) << 24;
+        for _ in 0..8 {
+            r = if r & 0x80000000 != 0 { (r << 1) ^ 0x04c11db7 } else { r << 1 };
+        }
+
+        table[i] = r;
+    }
+
+    table
+}
+
+/// Writes packets of a single logical stream to Ogg pages
+struct OggWriter<T: Write> {
+    inner: T,
+    serial: u32,
+    sequence: u32,
+    crc: [u32; 256]
+}
+
+impl<T: Write> OggWriter<T> {
+    fn new(inner: T, serial: u32) -> OggWriter<T> {
+        OggWriter { inner, serial, sequence: 0, crc: crc_table() }
+    }
+
+    /// Write a page containing complete packets, ending at the granule position
+    fn write_page(&mut self, packets: &[Vec<u8>], granule: u64, first: bool, last: bool) -> Result<()> {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat(255u8).take(packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+
+        let mut page = b"OggS".to_vec();
+        page.push(0);
+        page.push(if first { 0x02 } else { 0 } | if last { 0x04 } else { 0 });
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+
+        // the checksum is calculated with zeroed checksum field
+        let crc = page.iter().fold(0u32, |crc, x| (crc << 8) ^ self.crc[((crc >> 24) as u8 ^ *x) as usize]);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence += 1;
+        self.inner.write_all(&page).map_err(|err| Error::Io(err))
+    }
+}
+
+/// Header packets of an Ogg Opus stream, described in RFC 7845
+fn opus_headers(track: &Track, cover: Option<&[u8]>) -> (Vec<u8>, Vec<u8>) {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(2);
+    head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+
+    let mut comments: Vec<String> = vec![("TITLE", &track.title), ("ALBUM", &track.album), ("ARTIST", &track.interpret), ("COMPOSER", &track.composer)]
+        .into_iter()
+        .filter_map(|(name, val)| val.as_ref().map(|x| format!("{}={}", name, x)))
+        .collect();
+
+    if let Some(cover) = cover.filter(|x| x.len() <= MAX_OGG_COVER) {
+        comments.push(format!("METADATA_BLOCK_PICTURE={}", base64::encode(&tags::flac_picture(cover))));
+    }
+
+    let vendor = b"hex";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        tags.extend_from_slice(comment.as_bytes());
+    }
+
+    (head, tags)
+}
+
+/// Open the container of a track with normalised loudness
+pub fn open_container(data_path: &Path, track: &Track) -> Result<Container<File>> {
+    let file = File::open(data_path.join(track.key.to_path()))
+        .map_err(|err| Error::Io(err))?;
+
+    let mut container = Container::load(file)
+        .map_err(|err| Error::MusicContainer(err))?;
+
+    container.set_normalization(Normalization::Track);
+
+    Ok(container)
+}
+
+/// Cover image stored in the container of a track
+pub fn cover<T: io::Read + io::Seek>(container: &mut Container<T>) -> Option<Vec<u8>> {
+    container.metadata().ok()
+        .and_then(|x| x)
+        .and_then(|x| x.cover)
+}
+
+/// Render a track to an Ogg Opus file
+pub fn write_ogg<T: Write>(data_path: &Path, track: &Track, out: T) -> Result<()> {
+    let mut container = open_container(data_path, track)?;
+    // derive the stream serial number from the key
+    let serial = u32::from_str_radix(&track.key.to_string()[..8], 16).unwrap_or(0);
+    let mut writer = OggWriter::new(out, serial);
+
+    let cover = cover(&mut container);
+    let (head, tags) = opus_headers(track, cover.as_ref().map(Vec::as_slice));
+    writer.write_page(&[head], 0, true, false)?;
+    writer.write_page(&[tags], 0, false, false)?;
+
+    // the last granule position cuts the padding of the last packet
+    let end = container.samples() as u64 + PRE_SKIP as u64;
+
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut granule = 0;
+    loop {
+        let packet = match container.next_packet_opus(Configuration::Stereo, OPUS_BITRATE) {
+            Ok(packet) => packet,
+            Err(MusicError::ReachedEnd) => break,
+            Err(err) => return Err(Error::MusicContainer(err))
+        };
+
+        // a page can't have more than 255 lacing values
+        let lacing: usize = packets.iter().chain(Some(&packet)).map(|x| x.len() / 255 + 1).sum();
+        if packets.len() == PACKETS_PER_PAGE || lacing > 255 {
+            writer.write_page(&packets, granule.min(end), false, false)?;
+            packets.clear();
+        }
+
+        packets.push(packet);
+        granule += PACKET_SIZE;
+    }
+
+    writer.write_page(&packets, granule.min(end), false, true)
+}
+
+/// Name of the cached Ogg Opus file of a track
+///
+/// The tags are part of the file, hence the name contains a digest of them and changes once the
+/// track is updated.
+fn cache_name(track: &Track) -> String {
+    let mut hasher = Sha256::new();
+    for field in &[&track.title, &track.album, &track.interpret, &track.people, &track.composer] {
+        hasher.input(field.as_ref().map(String::as_bytes).unwrap_or(&[]));
+        hasher.input(&[0u8]);
+    }
+
+    let digest: String = hasher.result()[..4].iter().map(|x| format!("{:02x}", x)).collect();
+
+    format!("{}-{}.{}", track.key, digest, Format::Ogg.extension())
+}
+
+/// Get the path of a cached Ogg Opus file, transcoding the track if necessary
+pub fn cached_ogg(data_path: &Path, track: &Track) -> Result<PathBuf> {
+    let cache_path = data_path.join("cache");
+    let path = cache_path.join(cache_name(track));
+
+    // the cover is read from the container, which is rewritten when its metadata changes
+    let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+    if path.exists() && modified(&path) >= modified(&data_path.join(track.key.to_path())) {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&cache_path)
+        .map_err(|err| Error::Io(err))?;
+
+    // write to a temporary file first, concurrent requests shouldn't see a partial file
+    let mut file = NamedTempFile::new_in(&cache_path)
+        .map_err(|err| Error::Io(err))?;
+
+    write_ogg(data_path, track, &mut file)?;
+
+    file.persist(&path)
+        .map_err(|err| Error::Io(err.error))?;
+
+    // remove files of the track with outdated tags
+    let prefix = format!("{}-", track.key);
+    if let Ok(entries) = fs::read_dir(&cache_path) {
+        for entry in entries.filter_map(|x| x.ok()) {
+            let outdated = entry.file_name().to_str().map(|x| x.starts_with(&prefix)).unwrap_or(false);
+
+            if outdated && entry.path() != path {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+/// Start transcoding a track with `ffmpeg`, the result can be read from its standard output
+///
+/// The options in `args` are passed to the output, e.g. to add metadata.
+pub fn spawn_ffmpeg(mut container: Container<File>, format: Format, args: &[String]) -> Result<Child> {
+    let mut child = Command::new("ffmpeg")
+        .arg("-loglevel").arg("error")
+        .arg("-f").arg("s16le")
+        .arg("-ar").arg("48k")
+        .arg("-ac").arg("2")
+        .arg("-i").arg("pipe:0")
+        .args(args)
+        .arg("-f").arg(format.extension())
+        .arg("pipe:1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|_| Error::ConvertFFMPEG)?;
+
+    let mut stdin = child.stdin.take().unwrap();
+
+    // feed the decoded audio to ffmpeg, stops when the client disconnected and ffmpeg quit
+    thread::spawn(move || {
+        while let Ok(pcm) = container.next_packet(Configuration::Stereo) {
+            let mut buf = Vec::with_capacity(pcm.len() * 2);
+            for x in pcm {
+                buf.extend_from_slice(&x.to_le_bytes());
+            }
+
+            if stdin.write_all(&buf).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(child)
+}
+
+/// Transcode a track with `ffmpeg` and stream the result
+pub fn ffmpeg_stream(data_path: &Path, track: &Track, format: Format) -> Result<Body> {
+    let container = open_container(data_path, track)?;
+
+    let mut child = spawn_ffmpeg(container, format, &[])?;
+    let mut stdout = child.stdout.take().unwrap();
+
+    // forward the transcoded audio, waiting for the client to consume it
+    let (sender, recv) = channel::<Vec<u8>>(16);
+    thread::spawn(move || {
+        let mut sender = sender;
+        let mut buf = vec![0u8; 16384];
+
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => match sender.send(buf[..n].to_vec()).wait() {
+                    Ok(x) => sender = x,
+                    Err(_) => break
+                }
+            }
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+    });
+
+    Ok(Body::wrap_stream(recv.map(Chunk::from).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "transcoding failed"))))
+}
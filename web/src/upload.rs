@@ -0,0 +1,349 @@
+//! Uploads in chunks
+//!
+//! A large file is uploaded in numbered chunks of fixed size, so that a flaky connection only
+//! loses the chunk in transit. The session is named after the SHA-256 hash of the file and a client
+//! can therefore resume an interrupted upload from any connection, even after a restart of the
+//! server. Chunks are written to their position in a file of the `uploads` folder in the data
+//! section and the numbers of received chunks are stored next to it. Once all chunks arrived, the
+//! file becomes the input of a conversion job, which checks the hash of the file in its own thread
+//! with `verify` before the conversion starts.
+//!
+//! Only the user who began an upload can abort it, and uploads larger than the configured maximum
+//! are refused before any space is allocated.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use hex_server_protocol::objects::UploadSession;
+
+use crate::error::{Result, Error};
+
+/// Size of a single chunk in bytes
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Unfinished uploads are deleted after a week without new chunks
+const EXPIRE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Description of an upload, stored next to the received content
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    name: String,
+    format: String,
+    size: u64,
+    /// Sorted numbers of the received chunks
+    received: Vec<u32>,
+    /// User who began the upload, if accounts are enabled
+    #[serde(default)]
+    owner: Option<String>
+}
+
+impl Meta {
+    /// Number of chunks of the file
+    fn chunks(&self) -> u32 {
+        ((self.size + CHUNK_SIZE - 1) / CHUNK_SIZE) as u32
+    }
+
+    /// Expected length of a chunk, the last one can be shorter
+    fn chunk_len(&self, index: u32) -> u64 {
+        (self.size - index as u64 * CHUNK_SIZE).min(CHUNK_SIZE)
+    }
+
+    fn session(&self, id: &str) -> UploadSession {
+        let mut received: Vec<(u32, u32)> = Vec::new();
+
+        // merge consecutive numbers to ranges
+        for idx in &self.received {
+            match received.last_mut() {
+                Some(range) if range.1 == *idx => range.1 += 1,
+                _ => received.push((*idx, *idx + 1))
+            }
+        }
+
+        UploadSession {
+            id: id.into(),
+            chunk_size: CHUNK_SIZE,
+            chunks: self.chunks(),
+            received: received
+        }
+    }
+}
+
+/// Hash of a file as hex string
+fn hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .map_err(|err| Error::Io(err))?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+
+    loop {
+        let nread = file.read(&mut buf)
+            .map_err(|err| Error::Io(err))?;
+
+        if nread == 0 {
+            break;
+        }
+
+        hasher.input(&buf[..nread]);
+    }
+
+    Ok(hasher.result().iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+/// Check that the content of a finished upload matches the hash it is named after
+///
+/// Reads the whole file, hence it shouldn't be called on the event loop.
+pub fn verify(path: &Path, id: &str) -> Result<()> {
+    if hash(path)? == id {
+        Ok(())
+    } else {
+        Err(Error::Upload(format!("Upload {} doesn't match its hash", id)))
+    }
+}
+
+/// Unfinished uploads in the data section
+#[derive(Clone)]
+pub struct Uploads {
+    path: PathBuf,
+    /// Maximal size of an upload in bytes
+    max_size: u64
+}
+
+impl Uploads {
+    /// Uploads in the `uploads` folder of the data section, up to `max_size` bytes each
+    pub fn new(data_path: &Path, max_size: u64) -> Uploads {
+        Uploads {
+            path: data_path.join("uploads"),
+            max_size
+        }
+    }
+
+    /// Check that the id is a hash in hex, it is part of the file names
+    fn check_id(id: &str) -> Result<()> {
+        if id.len() == 64 && id.chars().all(|x| x.is_digit(16) && !x.is_uppercase()) {
+            Ok(())
+        } else {
+            Err(Error::Upload(format!("Invalid upload id {}", id)))
+        }
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.path.join(id).with_extension("json")
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.path.join(id)
+    }
+
+    fn load(&self, id: &str) -> Result<Meta> {
+        Uploads::check_id(id)?;
+
+        let file = File::open(self.meta_path(id))
+            .map_err(|_| Error::Upload(format!("No upload with id {}", id)))?;
+
+        serde_json::from_reader(file)
+            .map_err(|err| Error::Upload(format!("Invalid upload {}: {}", id, err)))
+    }
+
+    fn store(&self, id: &str, meta: &Meta) -> Result<()> {
+        let file = File::create(self.meta_path(id))
+            .map_err(|err| Error::Io(err))?;
+
+        serde_json::to_writer(file, meta)
+            .map_err(|err| Error::Upload(format!("Could not store upload {}: {}", id, err)))
+    }
+
+    /// Delete uploads which didn't receive a chunk for a long time
+    fn expire(&self) {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+
+            let expired = entry.metadata()
+                .and_then(|x| x.modified())
+                .ok()
+                .and_then(|x| SystemTime::now().duration_since(x).ok())
+                .map(|x| x > EXPIRE)
+                .unwrap_or(false);
+
+            if expired {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Begin a new upload or resume an existing one with the same hash
+    pub fn begin(&self, name: String, format: String, size: u64, hash: &str, owner: Option<String>) -> Result<UploadSession> {
+        let hash = hash.to_lowercase();
+        Uploads::check_id(&hash)?;
+
+        if size > self.max_size {
+            return Err(Error::Upload(format!("Upload of {} bytes exceeds the maximum of {} bytes", size, self.max_size)));
+        }
+
+        fs::create_dir_all(&self.path)
+            .map_err(|err| Error::Io(err))?;
+
+        self.expire();
+
+        if let Ok(meta) = self.load(&hash) {
+            if meta.size == size {
+                return Ok(meta.session(&hash));
+            }
+        }
+
+        File::create(self.content_path(&hash))
+            .and_then(|file| file.set_len(size))
+            .map_err(|err| Error::Io(err))?;
+
+        let meta = Meta { name, format, size, received: Vec::new(), owner };
+        self.store(&hash, &meta)?;
+
+        Ok(meta.session(&hash))
+    }
+
+    /// Write a single chunk to its position
+    pub fn chunk(&self, id: &str, index: u32, data: &[u8]) -> Result<()> {
+        let mut meta = self.load(id)?;
+
+        if index >= meta.chunks() {
+            return Err(Error::Upload(format!("Chunk {} exceeds the size of upload {}", index, id)));
+        }
+
+        if data.len() as u64 != meta.chunk_len(index) {
+            return Err(Error::Upload(format!("Chunk {} of upload {} has length {}, expected {}", index, id, data.len(), meta.chunk_len(index))));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(self.content_path(id))
+            .map_err(|err| Error::Io(err))?;
+
+        file.seek(SeekFrom::Start(index as u64 * CHUNK_SIZE))
+            .and_then(|_| file.write_all(data))
+            .map_err(|err| Error::Io(err))?;
+
+        if let Err(pos) = meta.received.binary_search(&index) {
+            meta.received.insert(pos, index);
+        }
+
+        self.store(id, &meta)
+    }
+
+    /// Get the received chunks of an upload
+    pub fn session(&self, id: &str) -> Result<UploadSession> {
+        self.load(id).map(|meta| meta.session(id))
+    }
+
+    /// Finish a complete upload, returns name, format and path of the content
+    ///
+    /// The caller takes over the content and has to check it with `verify`.
+    pub fn commit(&self, id: &str) -> Result<(String, String, PathBuf)> {
+        let meta = self.load(id)?;
+
+        if !meta.session(id).is_complete() {
+            return Err(Error::Upload(format!("Upload {} is missing chunks", id)));
+        }
+
+        fs::remove_file(self.meta_path(id))
+            .map_err(|err| Error::Io(err))?;
+
+        Ok((meta.name, meta.format, self.content_path(id)))
+    }
+
+    /// Delete an upload, only its owner may do so
+    pub fn abort(&self, id: &str, owner: Option<&str>) -> Result<()> {
+        let meta = self.load(id)?;
+
+        if meta.owner.as_ref().map(String::as_str) != owner {
+            return Err(Error::Upload(format!("Upload {} belongs to another user", id)));
+        }
+
+        let _ = fs::remove_file(self.content_path(id));
+
+        fs::remove_file(self.meta_path(id))
+            .map_err(|_| Error::Upload(format!("No upload with id {}", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use super::{Uploads, CHUNK_SIZE, verify};
+
+    fn hash_bytes(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn resume_and_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = Uploads::new(dir.path(), 1 << 20);
+
+        // two full chunks and a short one
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 10).map(|x| x as u8).collect();
+        let id = hash_bytes(&data);
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE as usize).collect();
+
+        let session = uploads.begin("song".into(), "flac".into(), data.len() as u64, &id, None).unwrap();
+        assert_eq!(session.chunks, 3);
+        assert!(session.received.is_empty());
+
+        assert!(uploads.chunk(&id, 2, chunks[0]).is_err());
+        assert!(uploads.chunk(&id, 3, chunks[2]).is_err());
+        uploads.chunk(&id, 2, chunks[2]).unwrap();
+        uploads.chunk(&id, 0, chunks[0]).unwrap();
+
+        // beginning again resumes the session
+        let session = uploads.begin("song".into(), "flac".into(), data.len() as u64, &id, None).unwrap();
+        assert_eq!(session.missing(), vec![1]);
+        assert!(uploads.commit(&id).is_err());
+
+        uploads.chunk(&id, 1, chunks[1]).unwrap();
+        assert_eq!(uploads.session(&id).unwrap().received, vec![(0, 3)]);
+
+        let (name, format, path) = uploads.commit(&id).unwrap();
+        assert_eq!((name.as_str(), format.as_str()), ("song", "flac"));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(verify(&path, &id).is_ok());
+        assert!(uploads.session(&id).is_err());
+    }
+
+    #[test]
+    fn wrong_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = Uploads::new(dir.path(), 1 << 20);
+        let id = hash_bytes(b"other");
+
+        uploads.begin("song".into(), "mp3".into(), 3, &id, None).unwrap();
+        uploads.chunk(&id, 0, b"abc").unwrap();
+
+        let (_, _, path) = uploads.commit(&id).unwrap();
+        assert!(verify(&path, &id).is_err());
+        assert!(uploads.session(&id).is_err());
+        assert!(uploads.begin("song".into(), "mp3".into(), 3, "../../etc/passwd", None).is_err());
+    }
+
+    #[test]
+    fn size_and_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = Uploads::new(dir.path(), 1 << 20);
+        let id = hash_bytes(b"abc");
+
+        // nothing is allocated for uploads above the maximum
+        assert!(uploads.begin("song".into(), "mp3".into(), 1 << 21, &id, None).is_err());
+        assert!(uploads.session(&id).is_err());
+
+        uploads.begin("song".into(), "mp3".into(), 3, &id, Some("alice".into())).unwrap();
+        assert!(uploads.abort(&id, Some("bob")).is_err());
+        assert!(uploads.abort(&id, None).is_err());
+        uploads.abort(&id, Some("alice")).unwrap();
+        assert!(uploads.session(&id).is_err());
+    }
+}
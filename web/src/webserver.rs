@@ -1,19 +1,62 @@
 //! The HTTP implementation serves the frontend
+//!
+//! Besides the frontend, tracks and playlists are offered to ordinary players (VLC, mpv, `<audio>`
+//! tags, ..) which can't use the websocket protocol:
+//!
+//!  * `/tracks/<key>.ogg` transcodes a track to Ogg Opus and supports range requests
+//!  * `/tracks/<key>.mp3` and `/tracks/<key>.flac` are transcoded with `ffmpeg` while streaming
+//!  * `/playlists/<key>.m3u8` lists all tracks of a playlist, the format of the tracks can be
+//!    chosen with `?format=mp3` and defaults to Ogg Opus
+//!  * `/exports/<job>.zip` streams the archive of a finished export job to the user who created
+//!    it, see the `export` module
+//!  * `/images/<key>.jpg` and `/thumbnails/<key>.jpg` serve cover images, which never change and
+//!    may be cached forever
+//!  * `/rest/<method>` implements the Subsonic API, see the `subsonic` module
+//!  * `/ws` upgrades to the websocket protocol, so that the frontend needs only a single port
+//!
+//! Once user accounts exist, tracks, playlists and images need the token of a session, either in
+//! the `session` cookie, which is set by the frontend, or in an `Authorization: Bearer` header.
+//! Session tokens are never part of a link, where they would end up in logs and the history of
+//! browsers. Players which support neither, open the tracks of a playlist with the links of its
+//! M3U file instead: each link carries a `?token=` signed for this playlist only, which expires
+//! with the lifetime of a session and grants access to nothing else. Other players should use the
+//! Subsonic API.
 
-use futures::{Async::*, Future, Poll, future};
+use futures::{Async::*, Future, Poll, Sink, Stream, future};
+use futures::sync::{oneshot, mpsc::{self, UnboundedSender}};
 use http::response::Builder as ResponseBuilder;
-use http::{Request, Response, StatusCode, header};
-use hyper::{Body, service::Service, header::{HeaderValue, CONTENT_TYPE}};
+use http::{Request, Response, StatusCode, Method, header};
+use hyper::{Body, Chunk, service::Service, upgrade::Upgraded, header::{HeaderValue, CONTENT_TYPE}};
 use hyper_staticfile::{Static, StaticFuture};
 use std::path::Path;
-use std::io::Error;
+use std::io::{self, Error, Read, Seek, SeekFrom};
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::str::FromStr;
+
+use tokio::net::TcpListener;
+use websocket::header::{WebSocketKey, WebSocketAccept};
+
+use hex_conf::Tls;
+
+use hex_database::{Reader, Writer, Accounts, Jobs, JobId, JobKind, JobStatus, TrackKey, PlaylistKey, Track};
+
+use crate::transcode::{self, Format};
+use crate::export;
+use crate::images;
+use crate::subsonic::{self, Credentials, Database, Params, Reply};
+use crate::playlists::Playlists;
+use crate::tls;
 
 /// Future returned from `MainService`.
 enum MainFuture {
     Root,
     Static((StaticFuture<Body>, PathBuf)),
+    Done(Option<Response<Body>>),
+    Transcode(oneshot::Receiver<Response<Body>>)
 }
 
 impl Future for MainFuture {
@@ -40,24 +83,542 @@ impl Future for MainFuture {
                 }
 
                 Ok(Ready(x))
+            },
+            MainFuture::Done(ref mut res) => {
+                Ok(Ready(res.take().expect("future polled twice")))
+            },
+            MainFuture::Transcode(ref mut recv) => {
+                let res = try_ready!(recv.poll()
+                    .map_err(|_| Error::new(io::ErrorKind::Other, "transcoding thread panicked")));
+
+                Ok(Ready(res))
             }
         }
     }
 }
 
+/// Create an empty response with a status code
+fn status(code: StatusCode) -> Response<Body> {
+    ResponseBuilder::new()
+        .status(code)
+        .body(Body::empty())
+        .expect("unable to build response")
+}
+
+/// Parse the first range of a `Range` header to an inclusive interval
+///
+/// Returns `None` if the range can't be satisfied.
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    let range = value.trim().trim_start_matches("bytes=").split(',').next()?.trim();
+    let mut parts = range.splitn(2, '-');
+    let (start, end) = (parts.next()?.trim(), parts.next()?.trim());
+
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // the last bytes of the file
+        (true, false) => {
+            let suffix: u64 = end.parse().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        },
+        // everything from the start
+        (false, true) => (start.parse().ok()?, len.checked_sub(1)?),
+        (false, false) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len.checked_sub(1)?)),
+        (true, true) => return None
+    };
+
+    if start > end || start >= len {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Serve a file with support for range requests
+fn serve_file(path: &Path, mime: &str, range: Option<String>, head: bool) -> io::Result<Response<Body>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut res = ResponseBuilder::new();
+    res.header(CONTENT_TYPE, mime)
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    let (start, end) = match range {
+        Some(range) => match parse_range(&range, len) {
+            Some((start, end)) => {
+                res.status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+
+                (start, end)
+            },
+            None => {
+                return Ok(ResponseBuilder::new()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(Body::empty())
+                    .expect("unable to build response"));
+            }
+        },
+        None if len == 0 => return Ok(res.body(Body::empty()).expect("unable to build response")),
+        None => (0, len - 1)
+    };
+
+    res.header(header::CONTENT_LENGTH, (end - start + 1).to_string());
+
+    if head {
+        return Ok(res.body(Body::empty()).expect("unable to build response"));
+    }
+
+    file.seek(SeekFrom::Start(start))?;
+
+    Ok(res.body(stream_file(file.take(end - start + 1))).expect("unable to build response"))
+}
+
+/// Stream a file in chunks, waiting for the client to consume them
+fn stream_file<T: Read + Send + 'static>(mut file: T) -> Body {
+    let (sender, recv) = mpsc::channel::<Vec<u8>>(16);
+    thread::spawn(move || {
+        let mut sender = sender;
+        let mut buf = vec![0u8; 65536];
+
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => match sender.send(buf[..n].to_vec()).wait() {
+                    Ok(x) => sender = x,
+                    Err(_) => break
+                }
+            }
+        }
+    });
+
+    Body::wrap_stream(recv.map(Chunk::from).map_err(|_| Error::new(io::ErrorKind::BrokenPipe, "reading file failed")))
+}
+
+/// Transcode a track in its own thread
+fn transcode_track(data_path: PathBuf, track: Track, format: Format, range: Option<String>, head: bool) -> MainFuture {
+    let (sender, recv) = oneshot::channel();
+
+    thread::spawn(move || {
+        let res = match format {
+            Format::Ogg => transcode::cached_ogg(&data_path, &track)
+                .map_err(|err| Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+                .and_then(|path| serve_file(&path, format.mime(), range, head)),
+            // the length of the streamed formats is unknown
+            _ if head => Ok(ResponseBuilder::new()
+                .header(CONTENT_TYPE, format.mime())
+                .header(header::ACCEPT_RANGES, "none")
+                .body(Body::empty())
+                .expect("unable to build response")),
+            _ => transcode::ffmpeg_stream(&data_path, &track, format)
+                .map_err(|err| Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+                .map(|body| ResponseBuilder::new()
+                    .header(CONTENT_TYPE, format.mime())
+                    .header(header::ACCEPT_RANGES, "none")
+                    .body(body)
+                    .expect("unable to build response"))
+        };
+
+        let res = res.unwrap_or_else(|err| {
+            eprintln!("Could not transcode track {}: {}", track.key, err);
+
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        });
+
+        let _ = sender.send(res);
+    });
+
+    MainFuture::Transcode(recv)
+}
+
+/// Get the value of a `Range` header
+fn range(req: &Request<Body>) -> Option<String> {
+    req.headers().get(header::RANGE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string())
+}
+
+/// Get the session token of a request from the `Authorization` header or the `session` cookie
+fn session_token(req: &Request<Body>) -> Option<String> {
+    let bearer = req.headers().get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| x.starts_with("Bearer "))
+        .map(|x| x["Bearer ".len()..].trim().to_string());
+
+    bearer.or_else(|| req.headers().get_all(header::COOKIE).iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .filter_map(|x| {
+            let mut parts = x.trim().splitn(2, '=');
+
+            match (parts.next(), parts.next()) {
+                (Some("session"), Some(token)) => Some(token.to_string()),
+                _ => None
+            }
+        })
+        .next())
+}
+
+/// Get the signed token of a playlist link from the `token` query parameter
+fn link_token(req: &Request<Body>) -> Option<String> {
+    Params::parse(req.uri().query().unwrap_or("")).get("token")
+        .map(|x| x.to_string())
+}
+
+/// Split the last component of a path into name and extension
+fn split_name(path: &str, prefix: &str) -> Option<(String, String)> {
+    let name = path.trim_start_matches(prefix);
+    let mut parts = name.rsplitn(2, '.');
+    let (ext, name) = (parts.next()?, parts.next()?);
+
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+
+    Some((name.to_string(), ext.to_lowercase()))
+}
+
+/// Parse a track key, which is encoded as hexadecimal string
+fn parse_track_key(name: &str) -> Option<TrackKey> {
+    if name.len() == 32 && name.chars().all(|x| x.is_digit(16)) {
+        Some(TrackKey::from_str(name))
+    } else {
+        None
+    }
+}
+
 /// The service should just offer all fields in a single directory
 struct MainService {
     static_: Static,
-    download: Static
+    download: Static,
+    data_path: PathBuf,
+    read: Arc<Mutex<Reader>>,
+    write: Arc<Mutex<Writer>>,
+    playlists: Playlists,
+    accounts: Arc<Mutex<Accounts>>,
+    /// Verified credentials of the Subsonic API
+    credentials: Credentials,
+    jobs: Arc<Mutex<Jobs>>,
+    upgrades: UnboundedSender<Upgraded>,
+    /// Connections are encrypted with TLS
+    secure: bool
 }
 
 impl MainService {
     /// Create a new service
-    fn new(path: &Path, data_path: &Path) -> MainService {
+    fn new(path: &Path, data_path: &Path, read: Arc<Mutex<Reader>>, write: Arc<Mutex<Writer>>, playlists: Playlists, accounts: Arc<Mutex<Accounts>>, credentials: Credentials, jobs: Arc<Mutex<Jobs>>, upgrades: UnboundedSender<Upgraded>, secure: bool) -> MainService {
         MainService {
             static_: Static::new(path),
-            download: Static::new(data_path.parent().unwrap())
+            download: Static::new(data_path.parent().unwrap()),
+            data_path: data_path.to_path_buf(),
+            read,
+            write,
+            playlists,
+            accounts,
+            credentials,
+            jobs,
+            upgrades,
+            secure
+        }
+    }
+
+    /// Check the session token of a request, if user accounts exist
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        let accounts = self.accounts.lock().unwrap();
+
+        !accounts.is_enabled() || session_token(req).map(|x| accounts.session(&x).is_ok()).unwrap_or(false)
+    }
+
+    /// Get the playlist of a signed link, if the request has a valid token
+    fn linked_playlist(&self, req: &Request<Body>) -> Option<PlaylistKey> {
+        link_token(req).and_then(|x| self.accounts.lock().unwrap().check_link_token(&x).ok())
+    }
+
+    /// Serve a single track in a certain format
+    fn track(&self, req: &Request<Body>) -> MainFuture {
+        let (key, format) = match split_name(req.uri().path(), "/tracks/") {
+            Some((name, ext)) => match (parse_track_key(&name), Format::from_extension(&ext)) {
+                (Some(key), Some(format)) => (key, format),
+                _ => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+            },
+            None => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        // links of a playlist only open its own tracks
+        if !self.authorized(req) {
+            let linked = self.linked_playlist(req)
+                .and_then(|x| self.read.lock().unwrap().get_playlist(x).ok())
+                .map(|(_, tracks)| tracks.iter().any(|x| x.key == key))
+                .unwrap_or(false);
+
+            if !linked {
+                return MainFuture::Done(Some(status(StatusCode::UNAUTHORIZED)));
+            }
+        }
+
+        let track = match self.read.lock().unwrap().get_track(key) {
+            Ok(track) => track,
+            Err(_) => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        transcode_track(self.data_path.clone(), track, format, range(req), req.method() == Method::HEAD)
+    }
+
+    /// Serve a cover image or its thumbnail
+    ///
+    /// Images are named after their content, hence the key is a strong validator and clients don't
+    /// need to ask again.
+    fn image(&self, req: &Request<Body>, thumbnail: bool) -> MainFuture {
+        if !self.authorized(req) {
+            return MainFuture::Done(Some(status(StatusCode::UNAUTHORIZED)));
         }
+
+        let prefix = if thumbnail { "/thumbnails/" } else { "/images/" };
+        let key = match split_name(req.uri().path(), prefix) {
+            Some((name, ref ext)) if ext == "jpg" => parse_track_key(&name),
+            _ => None
+        };
+
+        let key = match key {
+            Some(key) => key,
+            None => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        let etag = format!("\"{}\"", key);
+        let cached = req.headers().get(header::IF_NONE_MATCH)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.split(',').any(|x| x.trim() == etag || x.trim() == "*"))
+            .unwrap_or(false);
+
+        let path = if thumbnail {
+            images::thumbnail(&self.data_path, key).ok()
+        } else {
+            Some(images::image_path(&self.data_path, key))
+        };
+
+        // images which are not yet replicated are missing
+        let path = match path {
+            Some(path) if path.exists() => path,
+            _ => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        let mut res = ResponseBuilder::new();
+        res.header(header::ETAG, etag.as_str())
+            .header(header::CACHE_CONTROL, "private, max-age=31536000, immutable");
+
+        let res = if cached {
+            res.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+        } else {
+            let content = match fs::read(&path) {
+                Ok(content) => content,
+                Err(_) => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+            };
+
+            res.header(CONTENT_TYPE, "image/jpeg")
+                .header(header::CONTENT_LENGTH, content.len().to_string());
+
+            if req.method() == Method::HEAD {
+                res.body(Body::empty())
+            } else {
+                res.body(Body::from(content))
+            }
+        };
+
+        MainFuture::Done(Some(res.expect("unable to build response")))
+    }
+
+    /// Answer a call of the Subsonic API
+    fn subsonic(&self, req: &Request<Body>) -> MainFuture {
+        let method = req.uri().path().trim_start_matches("/rest/").trim_end_matches(".view");
+        let params = Params::parse(req.uri().query().unwrap_or(""));
+
+        // check the password before locking the database, it takes a while
+        let reply = match self.credentials.authenticate(&self.accounts, &params) {
+            Ok(user) => {
+                let (read, write, accounts) = (self.read.lock().unwrap(), self.write.lock().unwrap(), self.accounts.lock().unwrap());
+
+                subsonic::handle(&Database { read: &read, write: &write, playlists: &self.playlists, accounts: &accounts, user: user.as_ref() }, method, &params)
+            },
+            Err(reply) => reply
+        };
+
+        match reply {
+            Reply::Stream(track, format) => {
+                transcode_track(self.data_path.clone(), track, format, range(req), req.method() == Method::HEAD)
+            },
+            reply => {
+                let (content_type, body) = subsonic::render(&reply, params.json());
+
+                let res = ResponseBuilder::new()
+                    .header(CONTENT_TYPE, content_type)
+                    .body(Body::from(body))
+                    .expect("unable to build response");
+
+                MainFuture::Done(Some(res))
+            }
+        }
+    }
+
+    /// Create a M3U playlist pointing to the track endpoints
+    fn playlist(&self, req: &Request<Body>) -> MainFuture {
+        let params = Params::parse(req.uri().query().unwrap_or(""));
+
+        let key = match split_name(req.uri().path(), "/playlists/") {
+            Some((name, ref ext)) if ext == "m3u8" || ext == "m3u" => name.parse::<PlaylistKey>().ok(),
+            _ => None
+        };
+
+        // the playlist itself may be opened with one of its links as well
+        if !self.authorized(req) && (key.is_none() || self.linked_playlist(req) != key) {
+            return MainFuture::Done(Some(status(StatusCode::UNAUTHORIZED)));
+        }
+
+        let (playlist, tracks) = match key.map(|key| self.read.lock().unwrap().get_playlist(key)) {
+            Some(Ok(x)) => x,
+            _ => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        // players open the tracks without a session, sign the links for this playlist
+        let query = {
+            let accounts = self.accounts.lock().unwrap();
+
+            if accounts.is_enabled() {
+                match accounts.link_token(playlist.key) {
+                    Ok(token) => format!("?token={}", token),
+                    Err(_) => return MainFuture::Done(Some(status(StatusCode::INTERNAL_SERVER_ERROR)))
+                }
+            } else {
+                String::new()
+            }
+        };
+
+        let format = params.get("format")
+            .and_then(Format::from_extension)
+            .unwrap_or(Format::Ogg);
+
+        // some players don't resolve relative links, a proxy in front of us may terminate TLS
+        let scheme = req.headers().get("x-forwarded-proto")
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| *x == "http" || *x == "https")
+            .unwrap_or(if self.secure { "https" } else { "http" });
+
+        let base = req.headers().get(header::HOST)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| format!("{}://{}", scheme, x))
+            .unwrap_or_default();
+
+        let mut body = format!("#EXTM3U\n#PLAYLIST:{}\n", playlist.title);
+        for track in tracks {
+            let name = match (&track.interpret, &track.title) {
+                (Some(interpret), Some(title)) => format!("{} - {}", interpret, title),
+                (None, Some(title)) => title.clone(),
+                _ => track.key.to_string()
+            };
+
+            body.push_str(&format!("#EXTINF:{},{}\n{}/tracks/{}.{}{}\n", track.duration.round(), name, base, track.key, format.extension(), query));
+        }
+
+        let res = ResponseBuilder::new()
+            .header(CONTENT_TYPE, "application/vnd.apple.mpegurl; charset=utf-8")
+            .body(Body::from(body))
+            .expect("unable to build response");
+
+        MainFuture::Done(Some(res))
+    }
+
+    /// Stream the archive of a finished export job to its owner
+    fn export(&self, req: &Request<Body>) -> MainFuture {
+        // without accounts jobs have no owner
+        let user = {
+            let accounts = self.accounts.lock().unwrap();
+
+            if accounts.is_enabled() {
+                match session_token(req).and_then(|x| accounts.session(&x).ok()) {
+                    Some(user) => Some(user.name),
+                    None => return MainFuture::Done(Some(status(StatusCode::UNAUTHORIZED)))
+                }
+            } else {
+                None
+            }
+        };
+
+        let id = match split_name(req.uri().path(), "/exports/") {
+            Some((name, ref ext)) if ext == "zip" => name.parse::<JobId>().ok(),
+            _ => None
+        };
+
+        let job = match id.map(|id| self.jobs.lock().unwrap().job(id)) {
+            Some(Ok(job)) => job,
+            _ => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        let (format, keys) = match job.kind {
+            JobKind::Export { ref format, ref tracks } if job.status == JobStatus::Done => (Format::from_extension(format), tracks),
+            _ => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        if job.owner != user {
+            return MainFuture::Done(Some(status(StatusCode::FORBIDDEN)));
+        }
+
+        if export::is_expired(job.created) {
+            return MainFuture::Done(Some(status(StatusCode::GONE)));
+        }
+
+        let format = match format {
+            Some(format) => format,
+            None => return MainFuture::Done(Some(status(StatusCode::NOT_FOUND)))
+        };
+
+        // tracks deleted after the export are left out
+        let tracks = {
+            let read = self.read.lock().unwrap();
+
+            keys.iter().filter_map(|key| read.get_track(*key).ok()).collect()
+        };
+
+        // the length of the archive is unknown while streaming
+        let res = ResponseBuilder::new()
+            .header(CONTENT_TYPE, "application/zip")
+            .header(header::ACCEPT_RANGES, "none")
+            .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"hex-export-{}.zip\"", job.id).as_str())
+            .body(if req.method() == Method::HEAD { Body::empty() } else { export::stream(self.data_path.clone(), tracks, format) })
+            .expect("unable to build response");
+
+        MainFuture::Done(Some(res))
+    }
+
+    /// Upgrade the connection to the websocket protocol and hand it over to the websocket server
+    fn websocket(&self, req: Request<Body>) -> MainFuture {
+        let value = |name: header::HeaderName| req.headers().get(name).and_then(|x| x.to_str().ok());
+
+        let is_upgrade = value(header::UPGRADE).map(|x| x.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+        let has_protocol = value(header::SEC_WEBSOCKET_PROTOCOL).map(|x| x.split(',').any(|x| x.trim() == "rust-websocket")).unwrap_or(false);
+        let key = value(header::SEC_WEBSOCKET_KEY).and_then(|x| WebSocketKey::from_str(x).ok());
+
+        let key = match key {
+            Some(key) if is_upgrade && has_protocol => key,
+            _ => return MainFuture::Done(Some(status(StatusCode::BAD_REQUEST)))
+        };
+
+        let upgrades = self.upgrades.clone();
+        hyper::rt::spawn(req.into_body().on_upgrade()
+            .map(move |stream| {
+                if upgrades.unbounded_send(stream).is_err() {
+                    eprintln!("Websocket server is not running");
+                }
+            })
+            .map_err(|err| eprintln!("Could not upgrade connection: {}", err))
+        );
+
+        let res = ResponseBuilder::new()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_ACCEPT, WebSocketAccept::new(&key).serialize().as_str())
+            .header(header::SEC_WEBSOCKET_PROTOCOL, "rust-websocket")
+            .body(Body::empty())
+            .expect("unable to build response");
+
+        MainFuture::Done(Some(res))
     }
 }
 
@@ -73,6 +634,20 @@ impl Service for MainService {
 
         if req.uri().path() == "/" {
             MainFuture::Root
+        } else if req.uri().path().starts_with("/tracks/") {
+            self.track(&req)
+        } else if req.uri().path().starts_with("/playlists/") {
+            self.playlist(&req)
+        } else if req.uri().path().starts_with("/exports/") {
+            self.export(&req)
+        } else if req.uri().path().starts_with("/images/") {
+            self.image(&req, false)
+        } else if req.uri().path().starts_with("/thumbnails/") {
+            self.image(&req, true)
+        } else if req.uri().path().starts_with("/rest/") {
+            self.subsonic(&req)
+        } else if req.uri().path() == "/ws" {
+            self.websocket(req)
         } else {
             MainFuture::Static((self.static_.serve(req), path))
         }
@@ -84,11 +659,45 @@ impl Service for MainService {
 /// * `addr` - Listen to this address
 /// * `path` - Serve this directory
 /// * `data_path` - Serve the data from this directory
-pub fn create_webserver(addr: SocketAddr, path: PathBuf, data_path: PathBuf) {
-    let server = hyper::Server::bind(&addr)
-        .serve(move || future::ok::<_, Error>(MainService::new(&path, &data_path)))
-        .map_err(|e| eprintln!("server error: {}", e));
+/// * `db_path` - Open user accounts and jobs in this database
+/// * `read`, `write` - Access to the database shared with the websocket server
+/// * `playlists` - Keys of new playlists, shared with the websocket server
+/// * `tls` - Encrypt all connections with this configuration
+/// * `upgrades` - Pass connections upgraded to websockets to the websocket server
+pub fn create_webserver(addr: SocketAddr, path: PathBuf, data_path: PathBuf, db_path: PathBuf, read: Reader, write: Writer, playlists: Playlists, tls: Option<Tls>, upgrades: UnboundedSender<Upgraded>) {
+    let read = Arc::new(Mutex::new(read));
+    let write = Arc::new(Mutex::new(write));
+    let secure = tls.is_some();
+    let accounts = Arc::new(Mutex::new(Accounts::from_file(&db_path).expect("Could not open user accounts")));
+    let jobs = Arc::new(Mutex::new(Jobs::from_file(&db_path).expect("Could not open jobs")));
+    let credentials = Credentials::default();
+
+    let new_service = move || future::ok::<_, Error>(MainService::new(&path, &data_path, read.clone(), write.clone(), playlists.clone(), accounts.clone(), credentials.clone(), jobs.clone(), upgrades.clone(), secure));
+
+    match tls {
+        Some(tls) => {
+            let acceptor = tls::acceptor(&tls).expect("Could not load TLS configuration");
+            let listener = TcpListener::bind(&addr).expect("Could not bind web server");
+
+            // the handshakes are spawned on the runtime
+            let server = future::lazy(move || {
+                let incoming = tls::handshakes(listener.incoming(), move |stream| acceptor.accept(stream), tls::HANDSHAKE_TIMEOUT);
 
-    println!("Web server running on http://{}", addr);
-    hyper::rt::run(server);
+                hyper::Server::builder(incoming)
+                    .serve(new_service)
+                    .map_err(|e| eprintln!("server error: {}", e))
+            });
+
+            println!("Web server running on https://{}", addr);
+            hyper::rt::run(server);
+        },
+        None => {
+            let server = hyper::Server::bind(&addr)
+                .serve(new_service)
+                .map_err(|e| eprintln!("server error: {}", e));
+
+            println!("Web server running on http://{}", addr);
+            hyper::rt::run(server);
+        }
+    }
 }
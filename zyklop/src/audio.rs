@@ -3,40 +3,44 @@ use std::thread;
 use rb::{SpscRb, RB, RbProducer, RbConsumer, Producer, Consumer};
 use cpal::traits::HostTrait;
 use cpal::traits::EventLoopTrait;
-use cpal::traits::DeviceTrait;
+use hex_music_container::output;
 
 pub struct AudioDevice {
-    rb: SpscRb<i16>,
-    producer: Producer<i16>,
+    rb: SpscRb<f32>,
+    producer: Producer<f32>,
+    format: cpal::Format,
 }
 
 impl AudioDevice {
-    pub fn new() -> AudioDevice {
-        let rb = SpscRb::new(48000 * 3);
-        let (prod, cons) = (rb.producer(), rb.consumer());
-
+    pub fn new(channels: u16) -> AudioDevice {
         let host = cpal::default_host();
         let device = host.default_output_device().expect("Failed to get default output device");
+        let format = output::find_format(&device, channels);
 
-        if device.supported_output_formats().unwrap().filter(|x| x.channels == 2 && x.data_type == cpal::SampleFormat::I16).count() == 0 {
-            panic!("No suitable device found!");
-        }
-
-        let format = cpal::Format {
-            channels: 2,
-            sample_rate: cpal::SampleRate(48000),
-            data_type: cpal::SampleFormat::I16
-        };
+        // buffer a second of audio
+        let rb = SpscRb::new(format.sample_rate.0 as usize * format.channels as usize);
+        let (prod, cons) = (rb.producer(), rb.consumer());
 
-        thread::spawn(move || Self::run(cons, host, device, format));
+        thread::spawn(move || Self::run(cons, host, device, format.clone()));
 
         AudioDevice {
             rb: rb,
-            producer: prod
+            producer: prod,
+            format: format
         }
     }
 
-    pub fn buffer(&mut self, buf: &[i16]) {
+    /// Number of channels of the output device
+    pub fn channels(&self) -> usize {
+        self.format.channels as usize
+    }
+
+    /// Sample rate of the output device
+    pub fn sample_rate(&self) -> u32 {
+        self.format.sample_rate.0
+    }
+
+    pub fn buffer(&mut self, buf: &[f32]) {
         let mut written = 0;
         loop {
             let n = self.producer.write_blocking(&buf[written..]).expect("Couldn't queue block to buffer");
@@ -57,26 +61,40 @@ impl AudioDevice {
         self.format.clone()
     }*/
 
-    pub fn run(consumer: Consumer<i16>, host: cpal::Host, device: cpal::Device, format: cpal::Format) {
+    pub fn run(consumer: Consumer<f32>, host: cpal::Host, device: cpal::Device, format: cpal::Format) {
         let event_loop = host.event_loop();
 
         let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
         event_loop.play_stream(stream_id.clone()).unwrap();
 
-        let mut buf = vec![0i16; format.channels as usize];
+        let mut buf = vec![0.0f32; format.channels as usize];
 
         event_loop.run(move |_, data| {
             match data {
-                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) }) => {
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) }) => {
                     for sample in buffer.chunks_mut(format.channels as usize) {
                         let _ = consumer.read_blocking(&mut buf);
 
-                        let mut i = 0;
-                        for out in sample.iter_mut() {
-                            *out = buf[i];
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = *val;
+                        }
+                    }
+                },
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) }) => {
+                    for sample in buffer.chunks_mut(format.channels as usize) {
+                        let _ = consumer.read_blocking(&mut buf);
 
-                            i += 1;
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = (*val * 32767.0) as i16;
+                        }
+                    }
+                },
+                Ok(cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer) }) => {
+                    for sample in buffer.chunks_mut(format.channels as usize) {
+                        let _ = consumer.read_blocking(&mut buf);
 
+                        for (out, val) in sample.iter_mut().zip(buf.iter()) {
+                            *out = ((*val + 1.0) * 32767.5) as u16;
                         }
                     }
                 },
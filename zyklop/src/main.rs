@@ -8,6 +8,7 @@ use std::path::PathBuf;
 
 use events::Event;
 use hex_database::{Instance, Token, GossipConf};
+use hex_music_container::Configuration;
 
 fn main() {
     env_logger::init();
@@ -36,7 +37,37 @@ fn main() {
     //let (sender, receiver): (Sender<TrackKey>, Receiver<TrackKey>) = channel();
 
     let (events, push_new) = events::events();
-    let mut audio = audio::AudioDevice::new();
+    let layout = Configuration::from_name(&conf.playback.layout, &conf.playback.speakers)
+        .unwrap_or_else(|| {
+            eprintln!("Error: Unknown loudspeaker layout {}, using stereo", conf.playback.layout);
+            Configuration::Stereo
+        });
+
+    let mut audio = audio::AudioDevice::new(layout.num_channels() as u16);
+
+    // fall back to a layout matching the output device
+    let layout = if audio.channels() == layout.num_channels() {
+        layout
+    } else {
+        match Configuration::for_channels(audio.channels()) {
+            Ok(x) => x,
+            Err(_) => {
+                eprintln!("Error: Output device with {} channels not supported", audio.channels());
+                return;
+            }
+        }
+    };
+    let gain = token::GainMode::from_name(&conf.playback.normalization)
+        .unwrap_or_else(|| {
+            eprintln!("Error: Unknown normalization {}, using track", conf.playback.normalization);
+            token::GainMode::Track
+        });
+
+    let output = token::Output {
+        conf: layout,
+        sample_rate: audio.sample_rate(),
+        gain
+    };
 
     let mut token: Option<token::Current> = None;
     let mut create_counter = 0;
@@ -70,10 +101,10 @@ fn main() {
 
                         match read.get_token(num as i64) {
                             Ok((a, Some((_, b)))) => {
-                                token = Some(token::Current::new(a, b, instance.files(), data_path.clone()));
+                                token = Some(token::Current::new(a, b, instance.files(), data_path.clone(), output.clone()));
                             },
                             Ok((a, None)) => {
-                                token = Some(token::Current::new(a, Vec::new(), instance.files(), data_path.clone()));
+                                token = Some(token::Current::new(a, Vec::new(), instance.files(), data_path.clone(), output.clone()));
                             },
                             Err(hex_database::Error::NotFound) => {
                                 println!("Not found!");
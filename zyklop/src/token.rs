@@ -1,19 +1,49 @@
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use rand::{thread_rng, Rng};
 
 use hex_database::{Track, Token, TrackKey, Files};
-use hex_music_container::{Container, Configuration};
+use hex_music_container::{Container, Configuration, Loudness, Normalization};
 
 use crate::error::{Error, Result};
 
+/// Volume normalisation mode of the playback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainMode {
+    Off,
+    Track,
+    Album
+}
+
+impl GainMode {
+    pub fn from_name(name: &str) -> Option<GainMode> {
+        match name {
+            "off" => Some(GainMode::Off),
+            "track" => Some(GainMode::Track),
+            "album" => Some(GainMode::Album),
+            _ => None
+        }
+    }
+}
+
+/// Format of the audio device and volume normalisation
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub conf: Configuration,
+    pub sample_rate: u32,
+    pub gain: GainMode
+}
+
 pub struct Stream {
     pub track: Track,
-    container: Container<File>
+    container: Container<File>,
+    conf: Configuration,
+    sample_rate: u32
 }
 
 impl Stream {
-    pub fn new(track: Track, data_path: &Path, files: &Files) -> Result<Stream> {
+    pub fn new(track: Track, data_path: &Path, files: &Files, output: &Output, normalization: Normalization) -> Result<Stream> {
         println!("New Stream: {:?}", track.title);
         let path = data_path.join(track.key.to_path());
         
@@ -28,16 +58,20 @@ impl Stream {
         let file = File::open(data_path.join(track.key.to_path()))
             .map_err(|_| Error::NotAvailable)?;
         
-        let container = Container::load(file)
+        let mut container = Container::load(file)
             .map_err(|err| Error::MusicContainer(err))?;
 
+        container.set_normalization(normalization);
+
         Ok(Stream {
-            track, container
+            track, container,
+            conf: output.conf.clone(),
+            sample_rate: output.sample_rate
         })
     }
 
-    pub fn next(&mut self) -> Result<Vec<i16>> {
-        self.container.next_packet(Configuration::Stereo)
+    pub fn next(&mut self) -> Result<Vec<f32>> {
+        self.container.next_packet_f32(self.conf.clone(), self.sample_rate)
             .map_err(|err| Error::MusicContainer(err))
     }
 
@@ -55,12 +89,18 @@ pub struct Current {
     pub token: Token,
     not_played: Vec<Track>,
     played: Vec<Track>,
+    /// All tracks of the token, used to combine the loudness of an album
+    tracks: Vec<Track>,
+    /// Loudness of the albums played so far
+    albums: HashMap<String, Option<Loudness>>,
     data_path: PathBuf,
-    files: Files
+    files: Files,
+    output: Output
 }
 
 impl Current {
-    pub fn new(mut token: Token, mut tracks: Vec<Track>, files: Files, data_path: PathBuf) -> Current {
+    pub fn new(mut token: Token, mut tracks: Vec<Track>, files: Files, data_path: PathBuf, output: Output) -> Current {
+        let all_tracks = tracks.clone();
         let current_track_key = token.played.pop();
         let current_track = current_track_key.and_then(|track_key| {
             tracks.iter().position(|x| x.key == track_key)
@@ -76,13 +116,16 @@ impl Current {
             token: token.clone(),
             played,
             not_played,
+            tracks: all_tracks,
+            albums: HashMap::new(),
             data_path: data_path.clone(),
-            files
+            files,
+            output
         };
 
         match current_track {
             Some(track) => {
-                if let Ok(mut stream) = Stream::new(track, &data_path, &current.files) {
+                if let Ok(mut stream) = current.create_stream(track) {
                     println!("Load current track: {:?}", token.pos);
 
                     if let Some(pos) = token.pos {
@@ -119,7 +162,7 @@ impl Current {
         !self.not_played.is_empty() || !self.played.is_empty() || self.stream.is_some()
     }
 
-    pub fn next_packet(&mut self) -> Option<Vec<i16>> {
+    pub fn next_packet(&mut self) -> Option<Vec<f32>> {
         if self.stream.is_none() {
             self.next_track();
         }
@@ -128,10 +171,12 @@ impl Current {
         if let Some(ref mut stream) = self.stream {
             match stream.next() {
                 Ok(buf) => {
+                    let duration = buf.len() as f64 / self.output.conf.num_channels() as f64 / self.output.sample_rate as f64;
+
                     if let Some(ref mut pos) = self.token.pos {
-                        *pos += buf.len() as f64 / 2.0 / 48000.0;
+                        *pos += duration;
                     } else {
-                        self.token.pos = Some(buf.len() as f64 / 2.0 / 48000.0);
+                        self.token.pos = Some(duration);
                     }
             
                     return Some(buf);
@@ -152,8 +197,42 @@ impl Current {
         return self.next_packet();
     }
 
-    pub fn create_stream(&self, elm: Track, path: &Path) -> Result<Stream> {
-        Stream::new(elm, path, &self.files)
+    pub fn create_stream(&mut self, elm: Track) -> Result<Stream> {
+        let normalization = match self.output.gain {
+            GainMode::Off => Normalization::Off,
+            GainMode::Track => Normalization::Track,
+            GainMode::Album => self.album_loudness(&elm)
+                .map(|x| Normalization::Album(x))
+                .unwrap_or(Normalization::Track)
+        };
+
+        Stream::new(elm, &self.data_path, &self.files, &self.output, normalization)
+    }
+
+    /// Combine the loudness of all available tracks of the same album in this token
+    ///
+    /// The result is cached for each album, so the files are only read for the first track.
+    fn album_loudness(&mut self, track: &Track) -> Option<Loudness> {
+        let album = track.album.as_ref()?;
+
+        if let Some(loudness) = self.albums.get(album) {
+            return *loudness;
+        }
+
+        // a playlist can contain the same track several times
+        let mut keys = HashSet::new();
+        let data_path = &self.data_path;
+        let tracks: Vec<(Loudness, u32)> = self.tracks.iter()
+            .filter(|x| x.album.as_ref() == Some(album) && keys.insert(x.key))
+            .filter_map(|x| File::open(data_path.join(x.key.to_path())).ok())
+            .filter_map(|x| Container::load(x).ok())
+            .filter_map(|x| x.loudness().map(|l| (l, x.samples())))
+            .collect();
+
+        let loudness = Loudness::album(&tracks);
+        self.albums.insert(album.clone(), loudness);
+
+        loudness
     }
 
     pub fn next_track(&mut self) {
@@ -182,7 +261,7 @@ impl Current {
             }
 
             let elm = self.not_played.remove(0);
-            match self.create_stream(elm, &self.data_path) {
+            match self.create_stream(elm) {
                 Ok(stream) => self.stream = Some(stream),
                 Err(err) => {
                     eprintln!("Skipping track = {:?}", err);
@@ -203,7 +282,7 @@ impl Current {
         // if there are no tracks left, play the first not_played
         if self.played.is_empty() {
             let elm = self.not_played.remove(0);
-            match self.create_stream(elm, &self.data_path) {
+            match self.create_stream(elm) {
                 Ok(stream) => self.stream = Some(stream),
                 Err(err) => {
                     eprintln!("Skipping track = {:?}", err);
@@ -213,7 +292,7 @@ impl Current {
             self.token.pos = Some(0.0);
         } else {
             let elm = self.played.pop().unwrap();
-            match self.create_stream(elm, &self.data_path) {
+            match self.create_stream(elm) {
                 Ok(stream) => self.stream = Some(stream),
                 Err(err) => {
                     eprintln!("Skipping track = {:?}", err);